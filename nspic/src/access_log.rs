@@ -0,0 +1,204 @@
+// An access log, with a line for each request, so that operators can
+// see the traffic without a reverse proxy. Rejected requests, e.g.
+// unknown paths, are turned into responses here, so that they are
+// logged too.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::convert::Infallible;
+use std::time::Instant;
+
+use log::info;
+use serde::Serialize;
+use warp::{Filter, Reply};
+use warp::filters::BoxedFilter;
+use warp::http::{HeaderMap, Method};
+use warp::http::header;
+use warp::http::status::StatusCode;
+use warp::hyper::body::HttpBody;
+use warp::reply::Response;
+
+use crate::config::{AccessLogConfig, AccessLogFormat};
+use crate::proxy::{self, TrustedProxies};
+
+#[derive(Serialize)]
+struct Entry
+{
+    client: Option<String>,
+    method: String,
+    path: String,
+    status: u16,
+    bytes: Option<u64>,
+    latency_ms: f64,
+    user_agent: Option<String>,
+}
+
+impl Entry
+{
+    fn format(&self, format: AccessLogFormat) -> String
+    {
+        match format
+        {
+            AccessLogFormat::Text => {
+                let bytes = self.bytes.map(|b| b.to_string());
+                format!("{} {} {} {} {} {:.1}ms {:?}",
+                        self.client.as_deref().unwrap_or("-"), self.method,
+                        self.path, self.status, bytes.as_deref().unwrap_or("-"),
+                        self.latency_ms, self.user_agent.as_deref().unwrap_or("-"))
+            },
+            AccessLogFormat::Json => serde_json::to_string(self)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// The address of the client. A reverse proxy puts it first in
+/// X-Forwarded-For. With `trusted_proxies` in the config, the address
+/// is only taken from requests of those proxies.
+fn clientAddress(headers: &HeaderMap, remote: Option<SocketAddr>,
+                 trust_forwarded_for: bool, proxies: &TrustedProxies) ->
+    Option<String>
+{
+    if !proxies.isEmpty()
+    {
+        return proxy::clientAddress(headers, remote, proxies)
+            .map(|addr| addr.ip().to_string());
+    }
+    let forwarded = headers.get("x-forwarded-for")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.split(',').next())
+        .map(|addr| addr.trim().to_owned())
+        .filter(|addr| !addr.is_empty());
+    match forwarded
+    {
+        Some(addr) if trust_forwarded_for => Some(addr),
+        _ => remote.map(|addr| addr.ip().to_string()),
+    }
+}
+
+fn responseBytes(response: &Response) -> Option<u64>
+{
+    response.headers().get(header::CONTENT_LENGTH)
+        .and_then(|h| h.to_str().ok()).and_then(|h| h.parse().ok())
+        .or_else(|| response.body().size_hint().exact())
+}
+
+/// The response that warp would make for `rejection`.
+fn rejectionResponse(rejection: warp::Rejection) -> Response
+{
+    use warp::reject::{LengthRequired, MethodNotAllowed, PayloadTooLarge,
+                       UnsupportedMediaType};
+    let (status, message) = if rejection.is_not_found()
+    {
+        (StatusCode::NOT_FOUND, String::new())
+    }
+    else if let Some(e) = rejection.find::<MethodNotAllowed>()
+    {
+        (StatusCode::METHOD_NOT_ALLOWED, e.to_string())
+    }
+    else if let Some(e) = rejection.find::<PayloadTooLarge>()
+    {
+        (StatusCode::PAYLOAD_TOO_LARGE, e.to_string())
+    }
+    else if let Some(e) = rejection.find::<LengthRequired>()
+    {
+        (StatusCode::LENGTH_REQUIRED, e.to_string())
+    }
+    else if let Some(e) = rejection.find::<UnsupportedMediaType>()
+    {
+        (StatusCode::UNSUPPORTED_MEDIA_TYPE, e.to_string())
+    }
+    else
+    {
+        (StatusCode::BAD_REQUEST, format!("{:?}", rejection))
+    };
+    warp::reply::with_status(message, status).into_response()
+}
+
+/// Log each request to `route`.
+pub fn wrap(route: BoxedFilter<(Response,)>, log_config: AccessLogConfig,
+            proxies: Arc<TrustedProxies>) -> BoxedFilter<(Response,)>
+{
+    let route = route.recover(|r| async move {
+        Ok::<_, Infallible>(rejectionResponse(r))
+    }).map(Reply::into_response);
+    warp::any().map(Instant::now)
+        .and(warp::method()).and(warp::path::full())
+        .and(warp::header::headers_cloned()).and(warp::addr::remote())
+        .and(route)
+        .map(move |start: Instant, method: Method, path: warp::path::FullPath,
+                   headers: HeaderMap, remote: Option<SocketAddr>,
+                   response: Response| {
+            let entry = Entry {
+                client: clientAddress(&headers, remote,
+                                      log_config.trust_forwarded_for,
+                                      &proxies),
+                method: method.to_string(),
+                path: path.as_str().to_owned(),
+                status: response.status().as_u16(),
+                bytes: responseBytes(&response),
+                latency_ms: start.elapsed().as_secs_f64() * 1000.0,
+                user_agent: headers.get(header::USER_AGENT)
+                    .and_then(|h| h.to_str().ok()).map(|h| h.to_owned()),
+            };
+            info!(target: "access", "{}", entry.format(log_config.format));
+            response
+        }).boxed()
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use warp::http::HeaderValue;
+    use crate::config::Configuration;
+
+    #[test]
+    fn clientIsForwarded()
+    {
+        let remote = "10.0.0.1:1234".parse().ok();
+        let none = TrustedProxies::new(&Configuration::default());
+        let mut headers = HeaderMap::new();
+        assert_eq!(clientAddress(&headers, remote, true, &none),
+                   Some(String::from("10.0.0.1")));
+        headers.insert("x-forwarded-for",
+                       HeaderValue::from_static("203.0.113.5, 10.0.0.2"));
+        assert_eq!(clientAddress(&headers, remote, true, &none),
+                   Some(String::from("203.0.113.5")));
+        assert_eq!(clientAddress(&headers, remote, false, &none),
+                   Some(String::from("10.0.0.1")));
+        let proxies = TrustedProxies::new(&Configuration {
+            trusted_proxies: vec![String::from("10.0.0.0/8")],
+            ..Default::default()
+        });
+        assert_eq!(clientAddress(&headers, remote, false, &proxies),
+                   Some(String::from("203.0.113.5")));
+        assert_eq!(clientAddress(&headers, "192.0.2.1:80".parse().ok(), true,
+                                 &proxies),
+                   Some(String::from("192.0.2.1")));
+    }
+
+    #[test]
+    fn entryIsFormatted()
+    {
+        let entry = Entry {
+            client: Some(String::from("203.0.113.5")),
+            method: String::from("GET"),
+            path: String::from("/p/1"),
+            status: 200,
+            bytes: Some(512),
+            latency_ms: 1.5,
+            user_agent: None,
+        };
+        assert_eq!(entry.format(AccessLogFormat::Text),
+                   "203.0.113.5 GET /p/1 200 512 1.5ms \"-\"");
+        let json: serde_json::Value = serde_json::from_str(
+            &entry.format(AccessLogFormat::Json)).unwrap();
+        assert_eq!(json["status"], 200);
+        assert_eq!(json["user_agent"], serde_json::Value::Null);
+        assert_eq!(rejectionResponse(warp::reject::not_found()).status(),
+                   StatusCode::NOT_FOUND);
+    }
+}
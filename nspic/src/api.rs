@@ -0,0 +1,398 @@
+// Handlers of the JSON API under /api/v1.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use log::{debug, info};
+use serde::Deserialize;
+use serde_json::json;
+use tera::Tera;
+use warp::Reply;
+use warp::http::status::StatusCode;
+use warp::reply::Response;
+
+use crate::error::Error;
+use crate::config::Configuration;
+use crate::data;
+use crate::i18n::Catalog;
+use crate::auth::{validateCredential, Credential};
+use crate::rate_limit::RateLimiter;
+use crate::app::{absoluteUrl, fillImageSources, makePosts, tagsFromForm,
+                 UploadFields};
+use crate::webhook::{self, mediaInfo, postLink};
+use crate::quota;
+use crate::quarantine;
+use crate::post_pipeline::{pipelineIsFull, RawImage};
+
+/// Maximal number of posts in one response.
+const POSTS_COUNT_MAX: u64 = 100;
+/// Number of posts in each page of the manifest. This is smaller than
+/// the maximum of the posts API because the images are hashed.
+const MANIFEST_PAGE_SIZE: u64 = 50;
+
+/// Who is making an API request.
+pub struct ApiClient
+{
+    pub remote: Option<SocketAddr>,
+    pub api_key: Option<String>,
+}
+
+/// Apply the rate limit of the scope the client belongs to. Return a
+/// response if the request should be rejected.
+fn rateLimit(client: &ApiClient, limiter: &RateLimiter,
+             config: &Configuration) -> Result<Option<Response>, Error>
+{
+    let (id, per_minute) = if let Some(key) = &client.api_key
+    {
+        let key_config = config.api.keys.iter().find(|k| &k.key == key)
+            .ok_or_else(|| Error::HTTPStatus(StatusCode::UNAUTHORIZED,
+                                             String::from("Invalid API key")))?;
+        (format!("key:{}", key_config.name),
+         key_config.requests_per_minute.unwrap_or(
+             config.api.keyed_requests_per_minute))
+    }
+    else
+    {
+        (format!("ip:{}", client.remote.map(|a| a.ip().to_string())
+                 .unwrap_or_default()),
+         config.api.anonymous_requests_per_minute)
+    };
+    match limiter.check(&id, per_minute)
+    {
+        Ok(()) => Ok(None),
+        Err(wait) => {
+            debug!("Rate limited API client {}.", id);
+            Ok(Some(warp::reply::with_header(
+                Error::HTTPStatus(StatusCode::TOO_MANY_REQUESTS,
+                                  String::from("Too many requests")).toJson(),
+                "Retry-After", (wait.as_secs() + 1).to_string())
+                    .into_response()))
+        },
+    }
+}
+
+/// Posts in the order of the index, or only the ones in the album
+/// `album` or with the tag `tag`. With `format=html`, the posts are
+/// rendered as the cards of the index instead, for infinite
+/// scrolling, in the language of `catalog`.
+pub fn handlePosts(params: &HashMap<String, String>, client: &ApiClient,
+                   limiter: &RateLimiter, templates: &Tera, catalog: &Catalog,
+                   data_manager: &data::Manager, config: &Configuration) ->
+    Result<Response, Error>
+{
+    if let Some(res) = rateLimit(client, limiter, config)?
+    {
+        return Ok(res);
+    }
+    let param = |name: &str, default: u64| -> Result<u64, Error> {
+        match params.get(name)
+        {
+            Some(value) => value.parse().map_err(
+                |_| Error::HTTPStatus(StatusCode::BAD_REQUEST,
+                                      format!("Invalid parameter: {}", name))),
+            None => Ok(default),
+        }
+    };
+    let start = param("start", 0)?;
+    let count = std::cmp::min(param("count", 16)?, POSTS_COUNT_MAX);
+    let order = data::PostOrder::fromQuery(
+        params.get("order").map(|o| o.as_str()).unwrap_or("new"),
+        param("seed", 0)? as u32).ok_or_else(
+        || Error::HTTPStatus(StatusCode::BAD_REQUEST,
+                             String::from("Invalid parameter: order")))?;
+    let (mut posts, total) = if let Some(tag) = params.get("tag")
+    {
+        (data_manager.getPostsWithTag(tag, start, count, order)?,
+         data_manager.countPostsWithTag(tag)?)
+    }
+    else if params.contains_key("album")
+    {
+        let album_id = param("album", 0)? as i64;
+        (data_manager.getPostsInAlbum(album_id, start, count, order)?,
+         data_manager.countPostsInAlbum(album_id)?)
+    }
+    else
+    {
+        (data_manager.getPosts(start, count, order)?, data_manager.countPosts()?)
+    };
+    fillImageSources(&mut posts, config);
+    match params.get("format").map(|f| f.as_str())
+    {
+        None | Some("json") => Ok(warp::reply::json(&json!({
+            "posts": posts,
+            "total": total,
+        })).into_response()),
+        Some("html") => {
+            let mut context = tera::Context::new();
+            catalog.fillContext(&mut context);
+            context.insert("posts", &posts);
+            let html = templates.render("post_cards.html", &context).map_err(
+                |e| rterr!("Failed to render template: {}", e))?;
+            Ok(warp::reply::json(&json!({
+                "html": html,
+                "total": total,
+            })).into_response())
+        },
+        Some(_) => Err(Error::HTTPStatus(
+            StatusCode::BAD_REQUEST, String::from("Invalid parameter: format"))),
+    }
+}
+
+/// A manifest of all posts and their media files, with hashes and
+/// sizes, for archival crawlers and mirroring scripts. It is
+/// paginated by the `page` parameter starting from 1, old posts
+/// first, so that existing pages rarely change.
+pub fn handleManifest(params: &HashMap<String, String>, client: &ApiClient,
+                      limiter: &RateLimiter, data_manager: &data::Manager,
+                      config: &Configuration) -> Result<Response, Error>
+{
+    if let Some(res) = rateLimit(client, limiter, config)?
+    {
+        return Ok(res);
+    }
+    let page: u64 = match params.get("page")
+    {
+        Some(page) => page.parse().map_err(
+            |_| Error::HTTPStatus(StatusCode::BAD_REQUEST,
+                                  String::from("Invalid parameter: page")))?,
+        None => 1,
+    };
+    let page_count = std::cmp::max(
+        1, data_manager.countPosts()?.div_ceil(MANIFEST_PAGE_SIZE));
+    if page == 0 || page > page_count
+    {
+        return Err(Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()));
+    }
+    let posts = data_manager.getPosts((page - 1) * MANIFEST_PAGE_SIZE,
+                                      MANIFEST_PAGE_SIZE,
+                                      data::PostOrder::OldFirst)?;
+    let url_for = |name: &str, arg: &str| absoluteUrl(name, arg, config);
+    let mut entries = Vec::new();
+    for post in &posts
+    {
+        entries.push(json!({
+            "id": post.id,
+            "url": url_for("post", &post.urlArg()),
+            "desc": post.desc,
+            "time": post.upload_time.unix_timestamp(),
+            "media": post.images.iter().map(|img| mediaInfo(img, config))
+                .collect::<Result<Vec<_>, Error>>()?,
+        }));
+    }
+    let next = if page < page_count
+    {
+        Some(url_for("api_manifest", "") + &format!("?page={}", page + 1))
+    }
+    else
+    {
+        None
+    };
+    Ok(warp::reply::json(&json!({
+        "version": 1,
+        "page": page,
+        "page_count": page_count,
+        "next": next,
+        "posts": entries,
+    })).into_response())
+}
+
+pub fn handlePost(post_id: i64, client: &ApiClient, limiter: &RateLimiter,
+                  data_manager: &data::Manager, config: &Configuration) ->
+    Result<Response, Error>
+{
+    if let Some(res) = rateLimit(client, limiter, config)?
+    {
+        return Ok(res);
+    }
+    // The API has no sessions, so drafts and private posts don’t
+    // exist here.
+    let mut post = data_manager.findPostByID(post_id)?
+        .filter(|p| !p.needsSession()).ok_or_else(
+            || Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()))?;
+    fillImageSources(std::slice::from_mut(&mut post), config);
+    Ok(warp::reply::json(&post).into_response())
+}
+
+/// The disk usage of the library and its quota. This needs a session
+/// or an API token.
+pub fn handleLibrary(credential: Option<Credential>,
+                     data_manager: &data::Manager, config: &Configuration) ->
+    Result<Response, Error>
+{
+    if !validateCredential(&credential, data_manager, config)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    Ok(warp::reply::json(&quota::usage(data_manager, config)?).into_response())
+}
+
+/// Send a test payload to the webhook, and respond with how it went.
+/// This needs a session or an API token.
+pub fn handleWebhookTest(credential: Option<Credential>,
+                         data_manager: &data::Manager, config: &Configuration) ->
+    Result<Response, Error>
+{
+    if !validateCredential(&credential, data_manager, config)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    let result = tokio::task::block_in_place(
+        || webhook::sendTest(data_manager, config))?;
+    Ok(warp::reply::json(&result).into_response())
+}
+
+/// Make a public post of the image in the body of the request, so
+/// that `curl --data-binary` or a pasted image can post without a
+/// form. The description is in the `X-Desc` header, and the comma
+/// separated tags are in `X-Tags`, both percent-encoded if they are
+/// not ASCII. This needs a session or an API token.
+pub async fn handleRawUpload(body: bytes::Bytes, content_type: Option<String>,
+                             desc: Option<String>, tags: Option<String>,
+                             credential: Option<Credential>,
+                             data_manager: &data::Manager,
+                             config: &Configuration) -> Result<Response, Error>
+{
+    if !validateCredential(&credential, data_manager, config)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    if !content_type.map(|t| t.starts_with("image/")).unwrap_or(false)
+    {
+        return Err(Error::HTTPStatus(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            String::from("The body should be an image")));
+    }
+    if pipelineIsFull(config)
+    {
+        return Err(Error::HTTPStatus(
+            StatusCode::SERVICE_UNAVAILABLE,
+            String::from("Server is busy, try again later.")));
+    }
+    quota::check(0, data_manager, config)?;
+    let decode = |value: Option<String>| value.map(
+        |v| urlencoding::decode(&v).map(|v| v.into_owned()).unwrap_or(v))
+        .unwrap_or_default();
+    let fields = UploadFields {
+        desc: decode(desc).trim().to_owned(),
+        tags: tagsFromForm(&decode(tags)),
+        ..Default::default()
+    };
+    let mut raw = RawImage::fromBytes(&body, "upload", config)?;
+    raw.upload = Some(Box::new(quarantine::Upload {
+        group: format!("{:016x}", rand::random::<u64>()),
+        position: 0,
+        alt_text: None,
+        caption: None,
+        fields: fields.clone(),
+        post_id: None,
+    }));
+    let mut image = raw.processBlocking(config).await?;
+    fields.finishImage(&mut image, None, None);
+    info!("Uploaded an image through the API.");
+    let id = tokio::task::block_in_place(
+        || makePosts(&fields, vec![image], data_manager, config))?[0];
+    let post = data_manager.findPostByID(id)?.ok_or_else(
+        || rterr!("Post {} is gone after it is created", id))?;
+    Ok(warp::reply::with_status(warp::reply::json(&postLink(&post, config)),
+                                StatusCode::CREATED).into_response())
+}
+
+/// An image in a request to arrange the images of a post.
+#[derive(Deserialize)]
+pub struct ImageArrangement
+{
+    pub path: String,
+    #[serde(default)]
+    pub caption: String,
+}
+
+/// Put the images of a post in the order of `images`, which lists
+/// every image of the post, and set their captions. This needs a
+/// session or an API token.
+pub fn handleArrangeImages(post_id: i64, images: &[ImageArrangement],
+                           credential: Option<Credential>,
+                           data_manager: &data::Manager,
+                           config: &Configuration) -> Result<Response, Error>
+{
+    if !validateCredential(&credential, data_manager, config)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    let post = data_manager.findPostByID(post_id)?.ok_or_else(
+        || Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()))?;
+    let mut current: Vec<&str> = post.images.iter()
+        .filter_map(|img| img.path.to_str()).collect();
+    let mut requested: Vec<&str> = images.iter().map(|img| img.path.as_str())
+        .collect();
+    current.sort_unstable();
+    requested.sort_unstable();
+    if current != requested
+    {
+        return Err(Error::HTTPStatus(
+            StatusCode::BAD_REQUEST,
+            String::from("The images should be the ones of the post")));
+    }
+    let arrangement: Vec<(&str, &str)> = images.iter()
+        .map(|img| (img.path.as_str(), img.caption.trim())).collect();
+    data_manager.arrangeImages(post_id, &arrangement)?;
+    info!("Arranged the images of post {}.", post_id);
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// The album in a request to move a post.
+#[derive(Deserialize)]
+pub struct AlbumAssignment
+{
+    /// None to take the post out of its album.
+    pub album: Option<i64>,
+}
+
+/// The posts and the album in a request to move posts.
+#[derive(Deserialize)]
+pub struct AlbumMove
+{
+    pub posts: Vec<i64>,
+    /// None to take the posts out of their albums.
+    pub album: Option<i64>,
+}
+
+fn movePosts(post_ids: &[i64], album: Option<i64>, credential: Option<Credential>,
+             data_manager: &data::Manager, config: &Configuration) ->
+    Result<Response, Error>
+{
+    if !validateCredential(&credential, data_manager, config)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    if post_ids.is_empty()
+    {
+        return Err(Error::HTTPStatus(StatusCode::BAD_REQUEST,
+                                     String::from("No post to move")));
+    }
+    if !data_manager.movePostsToAlbum(post_ids, album)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::NOT_FOUND, String::from(
+            "The album or one of the posts doesn’t exist")));
+    }
+    info!("Moved {} posts to album {:?}.", post_ids.len(), album);
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Put a post into the album of the request. This needs a session or
+/// an API token.
+pub fn handleMovePost(post_id: i64, request: &AlbumAssignment,
+                      credential: Option<Credential>,
+                      data_manager: &data::Manager, config: &Configuration) ->
+    Result<Response, Error>
+{
+    movePosts(&[post_id], request.album, credential, data_manager, config)
+}
+
+/// Put all the posts of the request into its album, or none of them if
+/// one doesn’t exist. This needs a session or an API token.
+pub fn handleMovePosts(request: &AlbumMove, credential: Option<Credential>,
+                       data_manager: &data::Manager, config: &Configuration) ->
+    Result<Response, Error>
+{
+    movePosts(&request.posts, request.album, credential, data_manager, config)
+}
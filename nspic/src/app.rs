@@ -1,98 +1,648 @@
 use std::path::{PathBuf, Path};
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::net::SocketAddr;
 
-use log::{info, debug};
-use log::error as log_err;
+use log::{info, debug, warn};
 use tera::Tera;
 use time::OffsetDateTime;
 use warp::{Filter, Reply};
+use warp::filters::BoxedFilter;
 use warp::http::status::StatusCode;
 use warp::reply::Response;
-use futures_util::TryStreamExt;
-use serde_json::json;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use ring::hmac;
 
 use crate::error;
 use crate::error::Error;
 use crate::config::Configuration;
 use crate::data;
-use crate::post::{Image, Post};
+use crate::post::{Image, ImageSource, Location, Post, ShareLink, SourceSet,
+                  Visibility, cleanTags, expandSnippet, mimeTypeFromPath,
+                  slugify};
 use crate::utils::uriFromStr;
-use crate::auth::{handleLogin, validateSession, TOKEN_COOKIE};
+use crate::auth;
+use crate::auth::{createUrlToken, handleLogin, handleLoginForm,
+                  validateCredential, validateSession, Credential, TOKEN_COOKIE};
 use crate::to_response::ToResponse;
-use crate::post_pipeline::{UploadingImage, RawImage, uploadPart, imagePath};
+use crate::post_pipeline::{UploadingImage, RawImage, uploadPart, imagePath,
+                           pipelineIsFull, alternateFiles,
+                           brokenUpload, regenerateMissingThumbnail};
+use crate::delivery;
+use crate::websub;
+use crate::mail;
+use crate::matrix;
+use crate::api;
+use crate::quarantine;
+use crate::trash;
+use crate::duplicates;
+use crate::classifier;
+use crate::download;
+use crate::webhook;
+use crate::license::{self, licenseFromForm};
+use crate::tls;
+use crate::watch;
+use crate::schedule;
+use crate::cleanup;
+use crate::backup;
+use crate::access_log;
+use crate::security_headers;
+use crate::setup;
+use crate::feed;
+use crate::http_cache;
+use crate::sitemap;
+use crate::robots;
+use crate::geo;
+use crate::zip;
+use crate::fetch;
+use crate::quota;
+use crate::i18n::{self, Catalog, Catalogs};
+use crate::layout::{self, Layout};
+use crate::prefs::{self, Prefs};
+use crate::card;
+use crate::meta;
+use crate::print;
+use crate::views::{self, ViewCounter};
+use crate::page_cache::{self, CachedPage, PageCache};
+use crate::rate_limit::RateLimiter;
+use crate::proxy::{self, TrustedProxies};
+use crate::sendfile;
+use crate::image_files;
 
+/// The `Retry-After` of an upload rejected because the pipeline is
+/// busy.
+const UPLOAD_RETRY_AFTER_SEC: u64 = 30;
+const SHARE_LINK_DAYS_DEFAULT: i64 = 7;
+const SHARE_LINK_DAYS_MAX: i64 = 365;
+/// Maximal size of a JSON request body of the API.
+const API_BODY_BYTES_MAX: u64 = 64 * 1024;
+static LIKER_COOKIE: &str = "nspic-liker";
+const LIKER_COOKIE_LIFE_TIME_SEC: u64 = 10 * 365 * 24 * 3600;
+
+/// Page numbers to link to in the pagination of page `page` out of
+/// `page_count`, which are the first and last pages, and pages within
+/// `radius` of the current one. A gap in the sequence is marked by 0.
+fn pageWindow(page: u64, page_count: u64, radius: u64) -> Vec<u64>
+{
+    let mut result = Vec::new();
+    for p in 1..=page_count
+    {
+        if p == 1 || p == page_count || p.abs_diff(page) <= radius
+        {
+            if let Some(last) = result.last()
+            {
+                if p > last + 1
+                {
+                    result.push(0);
+                }
+            }
+            result.push(p);
+        }
+    }
+    result
+}
+
+#[allow(clippy::too_many_arguments)]
 fn handleIndex(templates: &Tera, params: &HashMap<String, String>,
-               data_manager: &data::Manager,
-               config: &Configuration) -> Result<Response, Error>
+               data_manager: &data::Manager, config: &Configuration,
+               catalog: &Catalog, prefs: &Prefs, layout: Layout,
+               cache: &PageCache, if_none_match: Option<String>) -> Result<Response, Error>
 {
-    let start: u64 = if let Some(index) =  params.get("start")
+    // The layout in the query wins over the cookie.
+    let query_layout = params.get("layout").and_then(|l| Layout::parse(l));
+    let layout = query_layout.unwrap_or(layout);
+    let cache_key = page_cache::key(
+        "/", params, &format!("{} {} {} {}", catalog.code, layout.value(),
+                              prefs.value(), config.site_info.url_domain));
+    if let Some(page) = cache.get(&cache_key, data_manager)
+    {
+        return Ok(http_cache::page(page.body, &if_none_match,
+                                   page.last_modified));
+    }
+    let generation = data_manager.generation();
+    if setup::needsSetup(data_manager, config)?
+    {
+        return Ok(warp::redirect::see_other(uriFromStr(
+            &(pathPrefix(&config.serve_under_path) + &urlFor("setup", "")))?)
+                  .into_response());
+    }
+    let page_size = std::cmp::max(1, config.page_size);
+    // Pages are numbered from 1. The `start` parameter is still
+    // accepted for old links.
+    let page: u64 = if let Some(page) = params.get("page")
     {
-        index.parse().map_err(|_| rterr!("Invalid parameter"))?
+        page.parse().map_err(|_| Error::HTTPStatus(
+            StatusCode::BAD_REQUEST, String::from("Invalid page")))?
+    }
+    else if let Some(index) = params.get("start")
+    {
+        let start: u64 = index.parse().map_err(|_| Error::HTTPStatus(
+            StatusCode::BAD_REQUEST, String::from("Invalid parameter")))?;
+        start / page_size + 1
     }
     else
     {
-        0
+        1
     };
-    let page_size = 16;
-    let post_count = data_manager.countPosts()?;
-    let posts = data_manager.getPosts(
-        start, page_size, data::PostOrder::NewFirst)?;
-    let mut context = tera::Context::new();
-    if post_count > start + page_size
+    let order_str = params.get("order").map(|o| o.as_str()).unwrap_or("new");
+    // The shuffled view needs a fixed seed across pages. Redirect to
+    // a URL with one.
+    let seed: u32 = match params.get("seed")
+    {
+        Some(seed) => seed.parse().map_err(|_| Error::HTTPStatus(
+            StatusCode::BAD_REQUEST, String::from("Invalid seed")))?,
+        None if order_str == "random" => {
+            let uri = format!("{}?order=random&seed={}",
+                              pathPrefix(&config.serve_under_path) + "/",
+                              rand::random::<u32>() >> 1);
+            return Ok(warp::redirect::found(uriFromStr(&uri)?).into_response());
+        },
+        None => 0,
+    };
+    let order = data::PostOrder::fromQuery(order_str, seed).ok_or_else(
+        || Error::HTTPStatus(StatusCode::BAD_REQUEST,
+                             String::from("Invalid order")))?;
+    // Appended to the pagination links.
+    let mut page_query = match order
+    {
+        data::PostOrder::NewFirst => String::new(),
+        data::PostOrder::OldFirst => String::from("&order=old"),
+        data::PostOrder::Random(seed) => format!("&order=random&seed={}", seed),
+        data::PostOrder::MostLiked => String::from("&order=liked"),
+        data::PostOrder::MostViewed => String::from("&order=viewed"),
+    };
+    if let Some(layout) = query_layout
     {
-        context.insert("next", &(start + page_size));
+        page_query += &format!("&layout={}", layout.value());
     }
-    if start > 0
+    // Only the posts in this album.
+    let album: Option<i64> = match params.get("album")
+    {
+        Some(album) => Some(album.parse().map_err(|_| Error::HTTPStatus(
+            StatusCode::BAD_REQUEST, String::from("Invalid album")))?),
+        None => None,
+    };
+    let post_count = match album
+    {
+        Some(album) => {
+            page_query += &format!("&album={}", album);
+            data_manager.countPostsInAlbum(album)?
+        },
+        None => data_manager.countPosts()?,
+    };
+    let page_count = std::cmp::max(1, post_count.div_ceil(page_size));
+    if page == 0 || page > page_count
+    {
+        return Err(Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()));
+    }
+    let mut posts = match album
+    {
+        Some(album) => data_manager.getPostsInAlbum(
+            album, (page - 1) * page_size, page_size, order)?,
+        None => data_manager.getPosts((page - 1) * page_size, page_size, order)?,
+    };
+    fillImageSources(&mut posts, config);
+    let mut context = tera::Context::new();
+    if let Some(album) = album
     {
-        let prev = if start < page_size
+        let album_path = data_manager.getAlbumPath(album)?;
+        if album_path.is_empty()
         {
-            0
+            return Err(Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()));
         }
-        else
-        {
-            start - page_size
-        };
-        context.insert("prev", &prev);
+        context.insert("album_path", &album_path);
+        context.insert("sub_albums", &data_manager.getAlbumSummaries(Some(album))?);
+    }
+    if page < page_count
+    {
+        context.insert("next", &(page + 1));
+        // Where infinite scrolling continues from.
+        context.insert("next_start", &(page * page_size));
+    }
+    if page > 1
+    {
+        context.insert("prev", &(page - 1));
     }
+    context.insert("page", &page);
+    context.insert("page_count", &page_count);
+    context.insert("pages", &pageWindow(page, page_count, 2));
+    context.insert("page_query", &page_query);
+    context.insert("page_size", &page_size);
+    context.insert("layout", &layout);
     context.insert("posts", &posts);
     context.insert("site_info", &config.site_info);
+    catalog.fillContext(&mut context);
+    prefs.fillContext(&mut context);
     let html = templates.render("index.html", &context).map_err(
         |e| rterr!("Failed to render template: {}", e))?;
+    let newest = data_manager.getPostUrlArgs(0, 1)?.first()
+        .and_then(|(_, t)| OffsetDateTime::from_unix_timestamp(*t).ok());
+    cache.put(cache_key, CachedPage { body: html.clone(), last_modified: newest },
+              generation);
+    Ok(http_cache::page(html, &if_none_match, newest))
+}
+
+/// The grid of top level albums, with their covers and post counts.
+fn handleAlbums(templates: &Tera, data_manager: &data::Manager,
+                config: &Configuration, catalog: &Catalog, prefs: &Prefs) ->
+    Result<Response, Error>
+{
+    let mut context = tera::Context::new();
+    context.insert("albums", &data_manager.getAlbumSummaries(None)?);
+    context.insert("site_info", &config.site_info);
+    catalog.fillContext(&mut context);
+    prefs.fillContext(&mut context);
+    let html = templates.render("albums.html", &context).map_err(
+        |e| rterr!("Failed to render template: {}", e))?;
     Ok(warp::reply::html(html).into_response())
 }
 
-fn handlePost(templates: &Tera, post_id: i64, data_manager: &data::Manager,
-              config: &Configuration) -> Result<Response, Error>
+/// Choose the image in the `image` field of `form` as the cover of
+/// the album, or go back to the default cover if it is empty, and go
+/// back to the post of the image.
+fn handleAlbumCover(album_id: i64, form: &HashMap<String, String>,
+                    data_manager: &data::Manager, config: &Configuration,
+                    token: Option<String>) -> Result<Response, Error>
+{
+    if !validateSession(&token, data_manager, config)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    let image = form.get("image").map(|i| i.as_str()).filter(|i| !i.is_empty());
+    if !data_manager.setAlbumCover(album_id, image)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()));
+    }
+    let back = match form.get("post").and_then(|id| id.parse().ok())
+    {
+        Some(id) => urlFor("post", &data_manager.findPostByID(id)?
+                           .map(|p| p.urlArg()).unwrap_or_else(|| format!("p/{}", id))),
+        None => urlFor("albums", ""),
+    };
+    Ok(warp::redirect::see_other(uriFromStr(
+        &(pathPrefix(&config.serve_under_path) + &back))?).into_response())
+}
+
+/// Put a post into the album in the `album` field of `form`, or take
+/// it out of its album if the field is empty, and go back to the post.
+fn handlePostAlbum(post_id: i64, form: &HashMap<String, String>,
+                   data_manager: &data::Manager, config: &Configuration,
+                   token: Option<String>) -> Result<Response, Error>
+{
+    if !validateSession(&token, data_manager, config)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    let album: Option<i64> = match form.get("album").filter(|a| !a.is_empty())
+    {
+        Some(album) => Some(album.parse().map_err(|_| Error::HTTPStatus(
+            StatusCode::BAD_REQUEST, String::from("Invalid album")))?),
+        None => None,
+    };
+    if !data_manager.movePostsToAlbum(&[post_id], album)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()));
+    }
+    let post = data_manager.findPostByID(post_id)?.ok_or_else(
+        || Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()))?;
+    Ok(warp::redirect::see_other(uriFromStr(
+        &(pathPrefix(&config.serve_under_path) +
+          &urlFor("post", &post.urlArg())))?)
+       .into_response())
+}
+
+/// Flag or unflag a post as sensitive from the post page.
+fn handlePostSensitive(post_id: i64, form: &HashMap<String, String>,
+                       data_manager: &data::Manager, config: &Configuration,
+                       token: Option<String>) -> Result<Response, Error>
+{
+    if !validateSession(&token, data_manager, config)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    let sensitive = form.get("sensitive").map(|s| s == "1").unwrap_or(false);
+    if !data_manager.setPostSensitive(post_id, sensitive)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()));
+    }
+    let post = data_manager.findPostByID(post_id)?.ok_or_else(
+        || Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()))?;
+    Ok(warp::redirect::see_other(uriFromStr(
+        &(pathPrefix(&config.serve_under_path) +
+          &urlFor("post", &post.urlArg())))?)
+       .into_response())
+}
+
+/// Set the license of a post from the post page. An empty license is
+/// the default.
+fn handlePostLicense(post_id: i64, form: &HashMap<String, String>,
+                     data_manager: &data::Manager, config: &Configuration,
+                     token: Option<String>) -> Result<Response, Error>
 {
+    if !validateSession(&token, data_manager, config)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    let license = licenseFromForm(form.get("License").map(|s| s.as_str())
+                                  .unwrap_or(""));
+    if !data_manager.setPostLicense(post_id, license.as_deref())?
+    {
+        return Err(Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()));
+    }
     let post = data_manager.findPostByID(post_id)?.ok_or_else(
         || Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()))?;
+    Ok(warp::redirect::see_other(uriFromStr(
+        &(pathPrefix(&config.serve_under_path) +
+          &urlFor("post", &post.urlArg())))?)
+       .into_response())
+}
+
+/// Find a post by `post_ref`, which is either the ID or the slug of
+/// the post.
+fn findPostByRef(post_ref: &str, data_manager: &data::Manager) ->
+    Result<Option<Post>, Error>
+{
+    match post_ref.parse::<i64>()
+    {
+        Ok(id) => data_manager.findPostByID(id),
+        Err(_) => data_manager.findPostBySlug(post_ref),
+    }
+}
+
+/// Give the visitor a liker cookie if it doesn’t have one, so that
+/// a visitor whose IP changes doesn’t like a post again.
+fn withLikerCookie(mut response: Response, liker: &Option<String>) -> Response
+{
+    if liker.is_none()
+    {
+        let cookie = format!("{}={}; Max-Age={}; Path=/; SameSite=Lax",
+                             LIKER_COOKIE, createUrlToken(),
+                             LIKER_COOKIE_LIFE_TIME_SEC);
+        if let Ok(value) = warp::http::HeaderValue::from_str(&cookie)
+        {
+            response.headers_mut().append(warp::http::header::SET_COOKIE, value);
+        }
+    }
+    response
+}
+
+/// The like key in `data_dir`, which is made if there is none yet.
+fn loadLikeKey(config: &Configuration) -> Result<String, Error>
+{
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    let path = Path::new(&config.data_dir).join("like-key");
+    if !path.exists()
+    {
+        info!("Creating like key at {:?}...", path);
+        let key: String = (0..4).map(|_| format!("{:016x}", rand::random::<u64>()))
+            .collect();
+        std::fs::OpenOptions::new().write(true).create_new(true).mode(0o600)
+            .open(&path).and_then(|mut f| f.write_all(key.as_bytes()))
+            .map_err(|e| rterr!("Failed to write {:?}: {}", path, e))?;
+    }
+    let key = std::fs::read_to_string(&path).map_err(
+        |e| rterr!("Failed to read {:?}: {}", path, e))?;
+    if key.trim().is_empty()
+    {
+        return Err(rterr!("Like key {:?} is empty", path));
+    }
+    Ok(key.trim().to_owned())
+}
+
+/// The hash of `ip` that is saved with a like, keyed with `like_key`.
+fn likeIpHash(ip: &str, config: &Configuration) -> Result<String, Error>
+{
+    let key = config.like_key.as_ref().ok_or_else(
+        || rterr!("No like key"))?;
+    let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes()),
+                         ip.as_bytes());
+    Ok(tag.as_ref().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Like a post anonymously.
+fn handleLike(post_ref: &str, liker: Option<String>,
+              remote: Option<SocketAddr>, limiter: &RateLimiter,
+              data_manager: &data::Manager, config: &Configuration) ->
+    Result<Response, Error>
+{
+    let ip = remote.map(|a| a.ip().to_string()).unwrap_or_default();
+    if limiter.check(&format!("like:{}", ip),
+                     config.api.anonymous_requests_per_minute).is_err()
+    {
+        return Err(Error::HTTPStatus(StatusCode::TOO_MANY_REQUESTS,
+                                     String::new()));
+    }
+    let post = findPostByRef(post_ref, data_manager)?
+        .filter(|p| !p.needsSession()).ok_or_else(
+            || Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()))?;
+    // Ignore cookies that were not made by withLikerCookie().
+    let liker = liker.filter(|l| l.len() <= 64 && l.chars().all(
+        |c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+    let ip_hash = likeIpHash(&ip, config)?;
+    if data_manager.addLike(post.id, liker.as_deref(), &ip_hash)?
+    {
+        debug!("Post {} is liked.", post.id);
+    }
+    Ok(warp::redirect::see_other(uriFromStr(
+        &(pathPrefix(&config.serve_under_path) +
+          &urlFor("post", &post.urlArg())))?)
+       .into_response())
+}
+
+/// Show a post. `post_ref` is either the ID or the slug of the post,
+/// and `path` is the path of the request without the leading slash.
+/// If that is not the path of the post under the URL scheme of the
+/// config, this redirects there.
+#[allow(clippy::too_many_arguments)]
+fn handlePost(templates: &Tera, path: &str, post_ref: &str,
+              data_manager: &data::Manager, views: &ViewCounter,
+              config: &Configuration, token: Option<String>, catalog: &Catalog,
+              prefs: &Prefs, if_none_match: Option<String>) ->
+    Result<Response, Error>
+{
+    let mut post = findPostByRef(post_ref, data_manager)?.ok_or_else(
+        || Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()))?;
     let mut context = tera::Context::new();
+    if post.needsSession()
+    {
+        if !validateSession(&token, data_manager, config)?
+        {
+            return Err(Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()));
+        }
+        if !post.draft
+        {
+            context.insert("share_links", &data_manager.getShareLinks(post.id)?);
+            context.insert("albums", &data_manager.getAlbums()?);
+        }
+    }
+    if path != post.urlArg()
+    {
+        let uri = uriFromStr(&(pathPrefix(&config.serve_under_path) +
+                               &urlFor("post", &post.urlArg())))?;
+        return Ok(warp::reply::with_status(
+            warp::redirect::see_other(uri), StatusCode::MOVED_PERMANENTLY)
+                  .into_response());
+    }
+    else if token.is_none()
+    {
+        // Only count views from visitors.
+        views.record(post.id);
+    }
+    post.views += views.pending(post.id);
+    fillImageSources(std::slice::from_mut(&mut post), config);
+    if !post.draft
+    {
+        let (prev_post, next_post) = data_manager.adjacentPosts(post.id)?;
+        context.insert("prev_post", &prev_post);
+        context.insert("next_post", &next_post);
+    }
+    if !post.draft && config.related_post_count > 0
+    {
+        context.insert("related_posts", &data_manager.getRelatedPosts(
+            &post, config.related_post_count)?);
+    }
+    context.insert("meta", &meta::postMeta(&post, None, config));
+    context.insert("license", &license::postLicense(&post, config));
     context.insert("post", &post);
     context.insert("site_info", &config.site_info);
+    catalog.fillContext(&mut context);
+    prefs.fillContext(&mut context);
     let html = templates.render("post.html", &context).map_err(
         |e| rterr!("Failed to render template: {}", e))?;
-    Ok(warp::reply::html(html).into_response())
+    Ok(http_cache::page(html, &if_none_match, None))
 }
 
-fn handleFeed(templates: &Tera, data_manager: &data::Manager,
-              config: &Configuration) -> Result<Response, Error>
+/// Show a post through a share link, without a session.
+fn handleShared(templates: &Tera, share_token: &str,
+                data_manager: &data::Manager, config: &Configuration,
+                catalog: &Catalog, prefs: &Prefs) ->
+    Result<String, Error>
 {
-    let feed_size = 10;
-    let posts = data_manager.getPosts(
-        0, feed_size, data::PostOrder::NewFirst)?;
+    let mut post = data_manager.findShareLink(share_token)?
+        .map(|link| data_manager.findPostByID(link.post_id)).transpose()?
+        .flatten().filter(|p| !p.draft).ok_or_else(
+            || Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()))?;
+    fillImageSources(std::slice::from_mut(&mut post), config);
     let mut context = tera::Context::new();
-    context.insert("posts", &posts);
+    context.insert("meta", &meta::postMeta(&post, Some(share_token), config));
+    context.insert("license", &license::postLicense(&post, config));
+    context.insert("post", &post);
     context.insert("site_info", &config.site_info);
-    let feed_str = templates.render("atom.xml", &context).map_err(
-        |e| rterr!("Failed to render template: {}", e))?;
+    catalog.fillContext(&mut context);
+    prefs.fillContext(&mut context);
+    templates.render("post.html", &context).map_err(
+        |e| rterr!("Failed to render template: {}", e))
+}
+
+/// Create a share link of a post that expires in the number of days
+/// in the form.
+fn handleShareCreate(post_id: i64, form: &HashMap<String, String>,
+                     data_manager: &data::Manager, config: &Configuration,
+                     token: Option<String>) -> Result<Response, Error>
+{
+    if !validateSession(&token, data_manager, config)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    let post = data_manager.findPostByID(post_id)?.ok_or_else(
+        || Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()))?;
+    let days: i64 = match form.get("ExpireDays").filter(|s| !s.is_empty())
+    {
+        Some(s) => s.parse().ok().filter(|d| (1..=SHARE_LINK_DAYS_MAX).contains(d))
+            .ok_or_else(|| Error::HTTPStatus(
+                StatusCode::BAD_REQUEST,
+                format!("Expiry must be 1 to {} days", SHARE_LINK_DAYS_MAX)))?,
+        None => SHARE_LINK_DAYS_DEFAULT,
+    };
+    data_manager.expireShareLinks()?;
+    data_manager.addShareLink(&ShareLink {
+        token: createUrlToken(),
+        post_id,
+        expire_time: OffsetDateTime::now_utc() + time::Duration::days(days),
+    })?;
+    Ok(warp::redirect::see_other(uriFromStr(
+        &(pathPrefix(&config.serve_under_path) +
+          &urlFor("post", &post.urlArg())))?)
+       .into_response())
+}
+
+fn handleShareRevoke(share_token: &str, data_manager: &data::Manager,
+                     config: &Configuration, token: Option<String>) ->
+    Result<Response, Error>
+{
+    if !validateSession(&token, data_manager, config)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    let link = data_manager.findShareLink(share_token)?.ok_or_else(
+        || Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()))?;
+    data_manager.deleteShareLink(share_token)?;
+    let url_arg = data_manager.findPostByID(link.post_id)?
+        .map(|p| p.urlArg()).unwrap_or_else(|| format!("p/{}", link.post_id));
+    Ok(warp::redirect::see_other(uriFromStr(
+        &(pathPrefix(&config.serve_under_path) + &urlFor("post", &url_arg)))?)
+       .into_response())
+}
+
+fn handleFeed(templates: &Tera, data_manager: &data::Manager,
+              config: &Configuration, cache: &PageCache) ->
+    Result<Response, Error>
+{
+    let cache_key = page_cache::key("/feed.xml", &HashMap::new(),
+                                    &config.site_info.url_domain);
+    let generation = data_manager.generation();
+    let feed_str = match cache.get(&cache_key, data_manager)
+    {
+        Some(page) => page.body,
+        None => {
+            let feed_str = renderFeed(templates, data_manager, config)?;
+            cache.put(cache_key, CachedPage {
+                body: feed_str.clone(), last_modified: None }, generation);
+            feed_str
+        },
+    };
     Ok(warp::reply::with_header(feed_str, "Content-Type",
                                 "application/atom+xml")
        .into_response())
 }
 
+fn renderFeed(templates: &Tera, data_manager: &data::Manager,
+              config: &Configuration) -> Result<String, Error>
+{
+    let posts = data_manager.getPosts(
+        0, config.feed_size, data::PostOrder::NewFirst)?;
+    // Raw XML of each post, in the same order as `posts`.
+    let media: Vec<String> = posts.iter()
+        .map(|p| feed::mediaElements(p, config)).collect();
+    let contents: Vec<String> = posts.iter()
+        .map(|p| feed::contentHtml(p, config)).collect();
+    let licenses: Vec<Option<license::License>> = posts.iter()
+        .map(|p| license::postLicense(p, config)).collect();
+    // An empty feed was last updated now, as far as readers can tell.
+    let updated = posts.first().map(|p| p.upload_time)
+        .unwrap_or_else(OffsetDateTime::now_utc)
+        .format(&time::format_description::well_known::Rfc3339)
+        .map_err(|e| rterr!("Failed to format time: {}", e))?;
+    let mut context = tera::Context::new();
+    context.insert("posts", &posts);
+    context.insert("media", &media);
+    context.insert("contents", &contents);
+    context.insert("licenses", &licenses);
+    context.insert("updated", &updated);
+    context.insert("feed_url", &websub::topicUrl(config));
+    context.insert("websub_hub", &config.websub_hub_url);
+    context.insert("site_info", &config.site_info);
+    templates.render("atom.xml", &context).map_err(
+        |e| rterr!("Failed to render template: {}", e))
+}
+
 fn handleDeleteConfirm(
     templates: &Tera, post_id: i64, data_manager: &data::Manager,
-    config: &Configuration, token: Option<String>) -> Result<Response, Error>
+    config: &Configuration, token: Option<String>, catalog: &Catalog,
+    prefs: &Prefs) ->
+    Result<Response, Error>
 {
     if validateSession(&token, data_manager, config)?
     {
@@ -101,6 +651,8 @@ fn handleDeleteConfirm(
         let mut context = tera::Context::new();
         context.insert("post", &post);
         context.insert("site_info", &config.site_info);
+        catalog.fillContext(&mut context);
+        prefs.fillContext(&mut context);
         let html = templates.render("delete_confirm.html", &context).map_err(
             |e| rterr!("Failed to render template: {}", e))?;
         Ok(warp::reply::html(html).into_response())
@@ -112,21 +664,12 @@ fn handleDeleteConfirm(
 }
 
 fn handleDelete(post_id: i64, data_manager: &data::Manager,
-                config: &Configuration, token: Option<String>) ->
+                config: &Configuration, credential: Option<Credential>) ->
     Result<Response, Error>
 {
-    if validateSession(&token, data_manager, config)?
+    if validateCredential(&credential, data_manager, config)?
     {
-        let post = data_manager.findPostByID(post_id)?.ok_or_else(
-            || Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()))?;
-        info!("Deleting post {}...", post_id);
-        data_manager.deletePost(post_id)?;
-        for image in post.images
-        {
-            info!("Deleting image file at {}...", image.path.display());
-            std::fs::remove_file(imagePath(&image, config))
-                .map_err(|_| rterr!("Failed to delete image file."))?
-        }
+        trash::delete(post_id, data_manager, config)?;
         Ok(warp::redirect::found(uriFromStr(&config.serve_under_path)?)
            .into_response())
     }
@@ -136,14 +679,54 @@ fn handleDelete(post_id: i64, data_manager: &data::Manager,
     }
 }
 
+/// Delete the image files of a post, but keep the post.
+fn handleRedact(post_id: i64, data_manager: &data::Manager,
+                config: &Configuration, credential: Option<Credential>) ->
+    Result<Response, Error>
+{
+    if !validateCredential(&credential, data_manager, config)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    let post = data_manager.findPostByID(post_id)?.ok_or_else(
+        || Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()))?;
+    let url_arg = post.urlArg();
+    info!("Redacting post {}...", post_id);
+    data_manager.redactPost(post_id)?;
+    for image in post.images
+    {
+        info!("Deleting image file at {}...", image.path.display());
+        let mut files = vec![imagePath(&image, config),
+                             Path::new(&config.image_dir).join(image.thumbnail()?)];
+        files.extend(alternateFiles(&image, config)?);
+        for file in files
+        {
+            match std::fs::remove_file(&file)
+            {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound =>
+                    return Err(rterr!("Failed to delete {:?}: {}", file, e)),
+                _ => {},
+            }
+        }
+    }
+    Ok(warp::redirect::see_other(uriFromStr(
+        &(pathPrefix(&config.serve_under_path) +
+          &urlFor("post", &url_arg)))?)
+       .into_response())
+}
+
 fn handleUploadPage(data_manager: &data::Manager, templates: &Tera,
-                    config: &Configuration, token: Option<String>) ->
+                    config: &Configuration, token: Option<String>,
+                    catalog: &Catalog, prefs: &Prefs) ->
     Result<Response, Error>
 {
     if validateSession(&token, data_manager, config)?
     {
         let mut context = tera::Context::new();
         context.insert("site_info", &config.site_info);
+        catalog.fillContext(&mut context);
+        prefs.fillContext(&mut context);
+        context.insert("snippets", &config.snippets);
         let html = templates.render("upload.html", &context).map_err(
             |e| rterr!("Failed to render template: {}", e))?;
         Ok(warp::reply::html(html).into_response())
@@ -154,144 +737,1041 @@ fn handleUploadPage(data_manager: &data::Manager, templates: &Tera,
     }
 }
 
-enum UploadPart
+/// The admin page. `new_api_token` is shown once after it is created,
+/// and `webhook_test` after the webhook is tested.
+#[allow(clippy::too_many_arguments)]
+fn handleAdmin(data_manager: &data::Manager, templates: &Tera,
+               config: &Configuration, token: Option<String>,
+               catalog: &Catalog, prefs: &Prefs,
+               new_api_token: Option<&str>,
+               webhook_test: Option<&webhook::TestResult>) ->
+    Result<Response, Error>
 {
-    Desc(String),
-    Image(RawImage),
+    if validateSession(&token, data_manager, config)?
+    {
+        let mut context = tera::Context::new();
+        context.insert("site_info", &config.site_info);
+        catalog.fillContext(&mut context);
+        prefs.fillContext(&mut context);
+        context.insert("quarantine", &quarantine::list(config)?);
+        context.insert("drafts", &data_manager.getDrafts()?);
+        context.insert("hidden_posts", &data_manager.getHiddenPosts()?);
+        context.insert("pending_posts", &data_manager.getPendingPosts()?);
+        context.insert("most_viewed", &data_manager.getMostViewed(10)?);
+        context.insert("api_tokens", &data_manager.getApiTokens()?);
+        context.insert("tag_suggestions", &data_manager.getSuggestedTags()?);
+        let usage = quota::usage(data_manager, config)?;
+        context.insert("library_used", &quota::formatBytes(usage.bytes_used));
+        context.insert("library_max", &usage.bytes_max.map(quota::formatBytes));
+        context.insert("library_percent", &usage.bytes_max.map(
+            |max| usage.bytes_used * 100 / std::cmp::max(max, 1)));
+        context.insert("new_api_token", &new_api_token);
+        context.insert("has_webhook", &config.webhook_url.is_some());
+        context.insert("webhook_test", &webhook_test);
+        let html = templates.render("admin.html", &context).map_err(
+            |e| rterr!("Failed to render template: {}", e))?;
+        Ok(warp::reply::html(html).into_response())
+    }
+    else
+    {
+        Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()))
+    }
 }
 
-fn webhookPayload(post: &Post, id: i64, config: &Configuration) ->
-    serde_json::value::Value
+fn handleQuarantineAction(id: &str, action: &str, data_manager: &data::Manager,
+                          config: &Configuration, token: Option<String>) ->
+    Result<Response, Error>
 {
-    let mut payload = json!({
-        "desc": post.desc,
-        "images": [],
-        "url": config.site_info.url_domain.clone() +
-            &urlFor("post", &id.to_string()),
-        "time": post.upload_time.unix_timestamp(),
-    });
-    for img in &post.images
+    if !validateSession(&token, data_manager, config)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    match action
     {
-        payload["images"].as_array_mut().unwrap()
-            .push(json!(config.site_info.url_domain.clone() +
-                        &urlFor("image_file", img.path.to_str().unwrap())));
+        "retry" => { quarantine::retry(id, data_manager, config)?; },
+        "discard" => quarantine::discard(id, config)?,
+        _ => return Err(Error::HTTPStatus(StatusCode::NOT_FOUND, String::new())),
     }
-    return payload
+    Ok(warp::redirect::see_other(uriFromStr(
+        &(pathPrefix(&config.serve_under_path) + &urlFor("admin", "")))?)
+       .into_response())
 }
 
-async fn handleUpload(token: Option<String>,
-                      form_data: warp::multipart::FormData,
-                      data_manager: &data::Manager,
-                      config: &Configuration) ->
-    Result<String, warp::Rejection>
+/// Create an API token with the name in the form, and show it on the
+/// admin page.
+fn handleApiTokenCreate(form: &HashMap<String, String>,
+                        data_manager: &data::Manager, templates: &Tera,
+                        config: &Configuration, token: Option<String>,
+                        catalog: &Catalog, prefs: &Prefs) ->
+    Result<Response, Error>
 {
-    if !validateSession(&token, data_manager, config).map_err(
-        |_| warp::reject::reject())?
+    if !validateSession(&token, data_manager, config)?
     {
-        return Err(warp::reject::reject());
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
     }
-    let mut desc = String::new();
-    let parts: Vec<_> = form_data.and_then(
-        |part| async move {
-            debug!("Got part: {}, {}, {}", part.name(),
-                   part.filename().or(Some("<no filename>")).unwrap(),
-                   part.content_type().or(Some("<no content type>")).unwrap());
-            let p: Result<UploadPart, Error> = match part.name()
-            {
-                "Desc" => {
-                    match uploadPart(part).await
-                    {
-                        Ok(data) => String::from_utf8(data)
-                            .map(|s| UploadPart::Desc(s))
-                            .map_err(|_| rterr!("Invalid description")),
-                        Err(e) => Err(e),
-                    }
-                },
-                "FileToUpload" => {
-                    let img = UploadingImage { part };
-                    let img = img.saveToTemp(config).await.map(
-                        |i| UploadPart::Image(i));
-                    img
-                },
-                _ => Err(rterr!("Unrecognized part: {}", part.name()))
-            };
-            // p is a Result<_, error::Error>. But this async stream
-            // thing requires a Result<_, warp::Error>. So here we
-            // just wrap an extra layer of Result<_, warp::Error>.
-            // Later we will just unwrap it.
-            Ok(p)
-        }).try_collect().await
-        // Unwrap the Result<_, warp::Error> here.
-        .unwrap();
-
-    let mut images: Vec<Image> = Vec::new();
-    for part in parts
+    let name = form.get("Name").map(|s| s.trim()).unwrap_or("");
+    if name.is_empty()
     {
-        let part = part.map_err(error::reject)?;
-        match part
-        {
-            UploadPart::Desc(s) => {desc = s;},
-            UploadPart::Image(img) => {
-                let image = img.resize(config).map_err(error::reject)?
-                    .makeThumbnail(config).map_err(error::reject)?
-                    .moveToLibrary(config).map_err(error::reject)?
-                    .makeRelativePath(config).map_err(error::reject)?
-                    .probeMetadata(config).map_err(error::reject)?;
-                images.push(image);
-            }
-        }
+        return Err(Error::HTTPStatus(StatusCode::BAD_REQUEST,
+                                     String::from("Token name is empty")));
     }
-    let mut post = Post::new();
-    post.desc = desc;
-    post.upload_time = OffsetDateTime::now_utc();
-    post.images = images;
-    // post.album_id = ???;
-    let new_id = data_manager.addPost(&post, None).map_err(error::reject)?;
+    let (_, api_token) = auth::createApiToken(name, data_manager)?;
+    handleAdmin(data_manager, templates, config, token, catalog, prefs,
+                Some(&api_token), None)
+}
 
-    // Call webhook
-    if let Some(url) = &config.webhook_url
+/// Send a test payload to the webhook, and show how it went on the
+/// admin page.
+fn handleWebhookTestAction(data_manager: &data::Manager, templates: &Tera,
+                           config: &Configuration, token: Option<String>,
+                           catalog: &Catalog, prefs: &Prefs) ->
+    Result<Response, Error>
+{
+    if !validateSession(&token, data_manager, config)?
     {
-        // Note that `post` doesn’t have an ID in it.
-        let payload = serde_json::to_vec(&webhookPayload(&post, new_id, config));
-        if let Err(e) = payload
-        {
-            log_err!("Invalid payload: {}.", e);
-        }
-        else
-        {
-            let result = ureq::post(url).set("Content-Type", "application/json")
-                .send_bytes(&payload.unwrap());
-            if let Err(e) = result
-            {
-                log_err!("Webhook failed: {}.", e);
-            }
-            else
-            {
-                let status = result.unwrap().status();
-                if status < 200 || status >= 300
-                {
-                    log_err!("Webhook failed with status {}.", status);
-                }
-            }
-        }
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
     }
-
-    Ok::<_, warp::Rejection>(String::from("Ok"))
+    let result = tokio::task::block_in_place(
+        || webhook::sendTest(data_manager, config))?;
+    handleAdmin(data_manager, templates, config, token, catalog, prefs, None,
+                Some(&result))
 }
 
-fn urlFor(name: &str, arg: &str) -> String
+fn handleApiTokenRevoke(id: i64, data_manager: &data::Manager,
+                        config: &Configuration, token: Option<String>) ->
+    Result<Response, Error>
 {
-    match name
+    if !validateSession(&token, data_manager, config)?
     {
-        "index" => String::from("/"),
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    auth::revokeApiToken(id, data_manager)?;
+    Ok(warp::redirect::see_other(uriFromStr(
+        &(pathPrefix(&config.serve_under_path) + &urlFor("admin", "")))?)
+       .into_response())
+}
+
+/// The visibility chosen in a form. An empty value means public.
+fn visibilityFromForm(value: &str) -> Result<Visibility, Error>
+{
+    if value.is_empty()
+    {
+        return Ok(Visibility::Public);
+    }
+    Visibility::fromStr(value).ok_or_else(
+        || Error::HTTPStatus(StatusCode::BAD_REQUEST,
+                             format!("Invalid visibility: {}", value)))
+}
+
+/// Publish a draft with the description in the form.
+fn handlePublish(post_id: i64, form: &HashMap<String, String>,
+                 data_manager: &data::Manager, config: &Configuration,
+                 token: Option<String>) -> Result<Response, Error>
+{
+    if !validateSession(&token, data_manager, config)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    let mut post = data_manager.findPostByID(post_id)?
+        .filter(|p| p.draft).ok_or_else(
+            || Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()))?;
+    post.desc = form.get("Desc").cloned().unwrap_or_default();
+    post.slug = makeSlug(form.get("Slug").map(|s| s.as_str()), &post.desc,
+                         data_manager)?;
+    post.visibility = visibilityFromForm(
+        form.get("Visibility").map(|s| s.as_str()).unwrap_or(""))?;
+    post.sensitive = form.get("Sensitive").is_some();
+    post.license = licenseFromForm(form.get("License").map(|s| s.as_str())
+                                   .unwrap_or(""));
+    post.upload_time = OffsetDateTime::now_utc();
+    post.draft = false;
+    info!("Publishing draft {}...", post_id);
+    data_manager.publishDraft(&post)?;
+    delivery::enqueue(&post, data_manager, config);
+    Ok(warp::redirect::see_other(uriFromStr(
+        &(pathPrefix(&config.serve_under_path) +
+          &urlFor("post", &post.urlArg())))?)
+       .into_response())
+}
+
+enum UploadPart
+{
+    Desc(String),
+    Slug(String),
+    Visibility(Visibility),
+    Snippet(String),
+    PublishAt(Option<OffsetDateTime>),
+    /// An image, with the index in the name of its part if there is
+    /// one, like `FileToUpload-2`.
+    Image(Option<usize>, RawImage),
+    /// The alt text of the image with this index, counting from 0 in
+    /// the order of the images in the upload.
+    AltText(usize, String),
+    /// The caption of the image with this index.
+    Caption(usize, String),
+    Latitude(Option<f64>),
+    Longitude(Option<f64>),
+    /// The images in an uploaded ZIP archive. They count as images of
+    /// the upload in place of the archive.
+    Zip(Vec<RawImage>),
+    /// Tags of the posts, like `cat, sofa`.
+    Tags(Vec<String>),
+    /// Make a post of each image, instead of one post of all images.
+    /// Each post has the description and the tags of the upload.
+    Split(bool),
+    /// Schedule the posts one queue interval after the newest post,
+    /// instead of at the publish time.
+    Queue(bool),
+    /// Flag the posts as sensitive.
+    Sensitive(bool),
+    /// The license of the posts, or None for the default.
+    License(Option<String>),
+    /// A part with this name that is not recognized.
+    Unknown(String),
+}
+
+/// Put the images of an upload in the order of their indices. An
+/// image without an index has the index of its place among the
+/// images, which is the order of the upload. Return the index and
+/// the image.
+fn orderImages(images: Vec<(Option<usize>, RawImage)>) -> Vec<(usize, RawImage)>
+{
+    let mut ordered: Vec<(usize, RawImage)> = images.into_iter().enumerate()
+        .map(|(i, (index, img))| (index.unwrap_or(i), img)).collect();
+    ordered.sort_by_key(|(index, _)| *index);
+    ordered
+}
+
+/// What an upload says about its posts. This is kept with an image
+/// of the upload that is quarantined, so that a retry makes the post
+/// that the upload would have made.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct UploadFields
+{
+    pub desc: String,
+    pub slug: String,
+    pub visibility: Visibility,
+    pub snippet: String,
+    /// Unix time.
+    pub publish_time: Option<i64>,
+    /// The latitude and longitude from the uploader.
+    pub coordinates: Option<(f64, f64)>,
+    pub tags: Vec<String>,
+    pub split: bool,
+    pub queue: bool,
+    pub sensitive: bool,
+    pub license: Option<String>,
+}
+
+impl UploadFields
+{
+    /// Give a processed image of the upload its texts, and what it
+    /// has from the upload.
+    pub fn finishImage(&self, image: &mut Image, alt_text: Option<&str>,
+                       caption: Option<&str>)
+    {
+        if let Some(text) = alt_text
+        {
+            image.alt_text = text.trim().to_owned();
+        }
+        if let Some(text) = caption
+        {
+            image.caption = text.trim().to_owned();
+        }
+        // The post is tagged with the keywords of its images, so this
+        // tags each post of a split upload.
+        image.keywords.extend(self.tags.iter().cloned());
+        // The location from the uploader wins over the EXIF.
+        if let Some((latitude, longitude)) = self.coordinates
+        {
+            image.location = Location::new(latitude, longitude);
+        }
+    }
+}
+
+/// Make the posts of an upload from its processed `images`, which
+/// is one post of all of them, or one post of each if the upload is
+/// split. Return the IDs of the posts.
+pub fn makePosts(fields: &UploadFields, images: Vec<Image>,
+                 data_manager: &data::Manager, config: &Configuration) ->
+    Result<Vec<i64>, Error>
+{
+    let snippet_text = if fields.snippet.is_empty()
+    {
+        None
+    }
+    else
+    {
+        Some(&config.snippets.iter().find(|s| s.name == fields.snippet)
+             .ok_or_else(|| rterr!("Unknown snippet: {}", fields.snippet))?
+             .text)
+    };
+    let publish_time = fields.publish_time.map(OffsetDateTime::from_unix_timestamp)
+        .transpose().map_err(|e| rterr!("Invalid publish time: {}", e))?;
+    let posts = if fields.split
+    {
+        images.into_iter().map(|img| vec![img]).collect()
+    }
+    else
+    {
+        vec![images]
+    };
+    let mut ids = Vec::new();
+    for images in posts
+    {
+        // Each queued post goes after the one before it.
+        let publish_time = if fields.queue
+        {
+            schedule::nextQueueSlot(data_manager, config)?
+        }
+        else
+        {
+            publish_time
+        };
+        let mut desc = fields.desc.clone();
+        if let Some(text) = snippet_text
+        {
+            let expanded = expandSnippet(
+                text, publish_time.unwrap_or_else(OffsetDateTime::now_utc),
+                images.len());
+            desc = if desc.is_empty()
+            {
+                expanded
+            }
+            else
+            {
+                expanded + "\n\n" + &desc
+            };
+        }
+        ids.push(createPost(desc, Some(&fields.slug), fields.visibility,
+                            fields.sensitive, fields.license.clone(),
+                            publish_time, images, data_manager, config)?);
+    }
+    Ok(ids)
+}
+
+/// A unique slug from `slug` given by the user, or from the
+/// description.
+fn makeSlug(slug: Option<&str>, desc: &str, data_manager: &data::Manager) ->
+    Result<Option<String>, Error>
+{
+    match slug.and_then(slugify).or_else(|| slugify(desc))
+    {
+        Some(s) => Ok(Some(data_manager.uniqueSlug(&s)?)),
+        None => Ok(None),
+    }
+}
+
+/// Add a draft consisting of `images` to the database. Return the ID
+/// of the draft.
+pub fn createDraft(images: Vec<Image>, data_manager: &data::Manager,
+                   config: &Configuration) -> Result<i64, Error>
+{
+    quota::admit(&images, data_manager, config)?;
+    let mut post = Post::new();
+    post.upload_time = OffsetDateTime::now_utc();
+    post.images = images;
+    post.draft = true;
+    let new_id = data_manager.addPost(&post, None)?;
+    classifier::enqueue(new_id, data_manager, config);
+    Ok(new_id)
+}
+
+/// Parse the comma separated tags from the upload form.
+pub fn tagsFromForm(value: &str) -> Vec<String>
+{
+    cleanTags(value.split(',').map(String::from))
+}
+
+/// Parse the publish time from the upload form, which is a Unix
+/// timestamp. Empty means now.
+fn publishTimeFromForm(value: &str) -> Result<Option<OffsetDateTime>, Error>
+{
+    let value = value.trim();
+    if value.is_empty()
+    {
+        return Ok(None);
+    }
+    value.parse().ok().and_then(|t| OffsetDateTime::from_unix_timestamp(t).ok())
+        .map(Some).ok_or_else(|| Error::HTTPStatus(
+            StatusCode::BAD_REQUEST, format!("Invalid publish time: {}", value)))
+}
+
+/// Parse a latitude or longitude in degrees from the upload form,
+/// which is at most `max` from 0. Empty means none.
+fn coordinateFromForm(value: &str, max: f64) -> Result<Option<f64>, Error>
+{
+    let value = value.trim();
+    if value.is_empty()
+    {
+        return Ok(None);
+    }
+    value.parse::<f64>().ok().filter(|c| c.abs() <= max).map(Some).ok_or_else(
+        || Error::HTTPStatus(StatusCode::BAD_REQUEST,
+                             format!("Invalid coordinate: {}", value)))
+}
+
+/// Add a new post consisting of `images` to the database, and queue
+/// its deliveries to the webhook and other services. If `slug` is
+/// None, it is generated from the description. If `publish_time` is
+/// in the future, the post is scheduled, and it is delivered when it
+/// goes live instead.
+/// The post takes the location of its first image that has one.
+/// Return the ID of the new post.
+#[allow(clippy::too_many_arguments)]
+pub fn createPost(desc: String, slug: Option<&str>, visibility: Visibility,
+                  sensitive: bool, license: Option<String>,
+                  publish_time: Option<OffsetDateTime>,
+                  images: Vec<Image>, data_manager: &data::Manager,
+                  config: &Configuration) -> Result<i64, Error>
+{
+    quota::admit(&images, data_manager, config)?;
+    duplicates::admit(&images, data_manager, config)?;
+    let now = OffsetDateTime::now_utc();
+    let mut post = Post::new();
+    post.slug = makeSlug(slug, &desc, data_manager)?;
+    post.visibility = visibility;
+    post.sensitive = sensitive;
+    post.license = license;
+    post.desc = desc;
+    post.upload_time = publish_time.filter(|t| t > &now).unwrap_or(now);
+    post.scheduled = post.upload_time > now;
+    post.images = images;
+    post.location = post.images.iter().find_map(|img| img.location.clone());
+    post.tags = cleanTags(
+        post.images.iter().flat_map(|img| img.keywords.iter().cloned()));
+    if let Some(location) = &mut post.location
+    {
+        if location.place.is_none()
+        {
+            location.place = geo::lookUpPlace(location, config);
+        }
+    }
+    // post.album_id = ???;
+    let new_id = data_manager.addPost(&post, None)?;
+    post.id = new_id;
+    classifier::enqueue(new_id, data_manager, config);
+    if post.scheduled
+    {
+        info!("Post {} is scheduled at {}.", new_id, post.upload_time);
+    }
+    else
+    {
+        delivery::enqueue(&post, data_manager, config);
+    }
+    Ok(new_id)
+}
+
+/// What the ZIP archives in the rest of an upload can still have.
+/// The archives are limited while they are extracted, so that a small
+/// archive of many or large files isn’t extracted in full.
+#[derive(Clone, Copy)]
+struct ZipBudget
+{
+    /// The number of images that the post can still have, or None if
+    /// each image is its own post.
+    images: Option<usize>,
+    /// The bytes of the extracted files.
+    bytes: u64,
+}
+
+impl ZipBudget
+{
+    fn new(config: &Configuration) -> Self
+    {
+        Self { images: Some(config.images_per_post_max),
+               bytes: config.upload_bytes_max }
+    }
+}
+
+/// Save the images in a ZIP archive from the upload form to temp
+/// files. Files that are not images are skipped. Return the images
+/// and what is left of `budget`.
+fn zipImages(data: &[u8], budget: ZipBudget, config: &Configuration) ->
+    Result<(Vec<RawImage>, ZipBudget), Error>
+{
+    let limits = zip::Limits {
+        entry_bytes_max: config.image_bytes_max,
+        total_bytes_max: budget.bytes,
+    };
+    let mut images: Vec<RawImage> = Vec::new();
+    let result = zip::readEntries(data, &limits, |name, content| {
+        let img = match RawImage::fromReader(content, name, config)
+        {
+            Ok(img) => img,
+            Err(Error::HTTPStatus(StatusCode::UNSUPPORTED_MEDIA_TYPE, _)) => {
+                info!("Skipping {} in the ZIP archive, which is not an image.",
+                      name);
+                return Ok(());
+            },
+            Err(e) => return Err(e),
+        };
+        images.push(img);
+        if budget.images.map(|max| images.len() > max).unwrap_or(false)
+        {
+            return Err(Error::HTTPStatus(StatusCode::PAYLOAD_TOO_LARGE, format!(
+                "A post can have at most {} images", config.images_per_post_max)));
+        }
+        Ok(())
+    }).map_err(|e| match e
+    {
+        Error::RuntimeError(msg) => Error::HTTPStatus(StatusCode::BAD_REQUEST, msg),
+        e => e,
+    });
+    let bytes = match result
+    {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            for img in images
+            {
+                std::fs::remove_file(&img.path).ok();
+            }
+            return Err(e);
+        },
+    };
+    if images.is_empty()
+    {
+        return Err(Error::HTTPStatus(
+            StatusCode::BAD_REQUEST,
+            String::from("There is no image in the ZIP archive")));
+    }
+    let left = ZipBudget {
+        images: budget.images.map(|max| max - images.len()),
+        bytes: budget.bytes - bytes,
+    };
+    Ok((images, left))
+}
+
+/// Serve a thumbnail that is not in the image dir by regenerating
+/// it, e.g. for an image imported without one. The request is passed
+/// on if it is not of a thumbnail.
+async fn handleMissingThumbnail(tail: warp::path::Tail, config: Configuration) ->
+    Result<Response, warp::Rejection>
+{
+    let thumbnail = PathBuf::from(tail.as_str());
+    let task_config = config.clone();
+    let thumb_file = tokio::task::spawn_blocking(
+        move || regenerateMissingThumbnail(&thumbnail, &task_config)).await
+        .map_err(|e| error::reject(rterr!("Failed to regenerate thumbnail: {}", e)))?
+        .map_err(error::reject)?
+        .ok_or_else(warp::reject::not_found)?;
+    if let Some(response) = sendfile::fileResponse(&thumb_file, &config)
+    {
+        return Ok(response);
+    }
+    let data = std::fs::read(&thumb_file).map_err(
+        |e| error::reject(rterr!("Failed to read {:?}: {}", thumb_file, e)))?;
+    Ok(http_cache::generatedImage(data, &thumb_file))
+}
+
+/// Serve a social card that is not in the image dir yet by rendering
+/// it.
+async fn handleMissingCard(name: String, data_manager: data::Manager,
+                           config: Configuration) ->
+    Result<Response, warp::Rejection>
+{
+    let task_config = config.clone();
+    let card_file = tokio::task::spawn_blocking(
+        move || card::renderMissingCard(&name, &data_manager, &task_config)).await
+        .map_err(|e| error::reject(rterr!("Failed to render card: {}", e)))?
+        .map_err(error::reject)?
+        .ok_or_else(warp::reject::not_found)?;
+    if let Some(response) = sendfile::fileResponse(&card_file, &config)
+    {
+        return Ok(response);
+    }
+    let data = std::fs::read(&card_file).map_err(
+        |e| error::reject(rterr!("Failed to read {:?}: {}", card_file, e)))?;
+    Ok(http_cache::generatedImage(data, &card_file))
+}
+
+/// The response of a rejected upload, in JSON if the client asks for
+/// it, so that the upload page can show `message`.
+fn uploadErrorResponse(status: StatusCode, message: &str,
+                       accept: Option<&str>) -> Response
+{
+    if error::wantsJson(accept)
+    {
+        Error::HTTPStatus(status, message.to_owned()).toJson()
+    }
+    else
+    {
+        warp::reply::with_status(warp::reply::html(format!(
+            "<!DOCTYPE HTML><html><body><p>{}</p></body></html>",
+            tera::escape_html(message))), status).into_response()
+    }
+}
+
+/// Read a part of an upload. An image is saved into a temp file. The
+/// images of a ZIP archive are limited by `budget`, which is updated.
+async fn readUploadPart(part: warp::multipart::Part, budget: &mut ZipBudget,
+                        config: &Configuration) -> Result<UploadPart, Error>
+{
+    debug!("Got part: {}, {}, {}", part.name(),
+           part.filename().unwrap_or("<no filename>"),
+           part.content_type().unwrap_or("<no content type>"));
+    match part.name()
+    {
+        "Desc" => {
+            match uploadPart(part).await
+            {
+                Ok(data) => String::from_utf8(data)
+                    .map(UploadPart::Desc)
+                    .map_err(|_| rterr!("Invalid description")),
+                Err(e) => Err(e),
+            }
+        },
+        "Slug" => {
+            match uploadPart(part).await
+            {
+                Ok(data) => String::from_utf8(data)
+                    .map(UploadPart::Slug)
+                    .map_err(|_| rterr!("Invalid slug")),
+                Err(e) => Err(e),
+            }
+        },
+        "Visibility" => {
+            match uploadPart(part).await
+            {
+                Ok(data) => String::from_utf8(data).ok()
+                    .and_then(|s| visibilityFromForm(&s).ok())
+                    .map(UploadPart::Visibility)
+                    .ok_or_else(|| rterr!("Invalid visibility")),
+                Err(e) => Err(e),
+            }
+        },
+        "License" => {
+            match uploadPart(part).await
+            {
+                Ok(data) => String::from_utf8(data)
+                    .map(|s| UploadPart::License(licenseFromForm(&s)))
+                    .map_err(|_| rterr!("Invalid license")),
+                Err(e) => Err(e),
+            }
+        },
+        "Snippet" => {
+            match uploadPart(part).await
+            {
+                Ok(data) => String::from_utf8(data)
+                    .map(UploadPart::Snippet)
+                    .map_err(|_| rterr!("Invalid snippet")),
+                Err(e) => Err(e),
+            }
+        },
+        "PublishAt" => {
+            match uploadPart(part).await
+            {
+                Ok(data) => String::from_utf8(data)
+                    .map_err(|_| rterr!("Invalid publish time"))
+                    .and_then(|s| publishTimeFromForm(&s))
+                    .map(UploadPart::PublishAt),
+                Err(e) => Err(e),
+            }
+        },
+        "Latitude" | "Longitude" => {
+            let is_latitude = part.name() == "Latitude";
+            match uploadPart(part).await
+            {
+                Ok(data) => String::from_utf8(data)
+                    .map_err(|_| rterr!("Invalid coordinate"))
+                    .and_then(|s| if is_latitude
+                              {
+                                  coordinateFromForm(&s, 90.0)
+                                      .map(UploadPart::Latitude)
+                              }
+                              else
+                              {
+                                  coordinateFromForm(&s, 180.0)
+                                      .map(UploadPart::Longitude)
+                              }),
+                Err(e) => Err(e),
+            }
+        },
+        "ZipToUpload" => {
+            match uploadPart(part).await
+            {
+                Ok(data) => {
+                    // Inflating is blocking work.
+                    let zip_budget = *budget;
+                    let zip_config = config.clone();
+                    let (images, left) = tokio::task::spawn_blocking(
+                        move || zipImages(&data, zip_budget, &zip_config)).await
+                        .map_err(|e| rterr!("Failed to extract ZIP archive: {}", e))??;
+                    *budget = left;
+                    Ok(UploadPart::Zip(images))
+                },
+                Err(e) => Err(e),
+            }
+        },
+        "UrlToUpload" => {
+            let url = String::from_utf8(uploadPart(part).await?)
+                .map_err(|_| rterr!("Invalid URL"))?;
+            let download_config = config.clone();
+            let (data, filename) = tokio::task::spawn_blocking(
+                move || fetch::download(&url, &download_config)).await
+                .map_err(|e| rterr!("Failed to download: {}", e))??;
+            RawImage::fromBytes(&data, &filename, config)
+                .map(|img| UploadPart::Image(None, img))
+        },
+        "Tags" => {
+            match uploadPart(part).await
+            {
+                Ok(data) => String::from_utf8(data)
+                    .map(|s| UploadPart::Tags(tagsFromForm(&s)))
+                    .map_err(|_| rterr!("Invalid tags")),
+                Err(e) => Err(e),
+            }
+        },
+        "Split" => {
+            match uploadPart(part).await
+            {
+                Ok(data) => Ok(UploadPart::Split(
+                    matches!(data.as_slice(), b"true" | b"on" | b"1"))),
+                Err(e) => Err(e),
+            }
+        },
+        "Queue" => {
+            match uploadPart(part).await
+            {
+                Ok(data) => Ok(UploadPart::Queue(
+                    matches!(data.as_slice(), b"true" | b"on" | b"1"))),
+                Err(e) => Err(e),
+            }
+        },
+        "Sensitive" => {
+            match uploadPart(part).await
+            {
+                Ok(data) => Ok(UploadPart::Sensitive(
+                    matches!(data.as_slice(), b"true" | b"on" | b"1"))),
+                Err(e) => Err(e),
+            }
+        },
+        name if name == "FileToUpload" || name.starts_with("FileToUpload-") => {
+            let index = name.strip_prefix("FileToUpload-").map(
+                |i| i.parse().map_err(|_| rterr!("Invalid part: {}", name)))
+                .transpose()?;
+            let img = UploadingImage { part };
+            img.saveToTemp(config).await.map(|i| UploadPart::Image(index, i))
+        },
+        name if name.starts_with("Alt-") || name.starts_with("Caption-") => {
+            let is_alt = name.starts_with("Alt-");
+            let index = name.split_once('-').unwrap().1.parse().map_err(
+                |_| rterr!("Invalid part: {}", name));
+            match (index, uploadPart(part).await)
+            {
+                (Ok(i), Ok(data)) => String::from_utf8(data)
+                    .map(|s| if is_alt
+                         {
+                             UploadPart::AltText(i, s)
+                         }
+                         else
+                         {
+                             UploadPart::Caption(i, s)
+                         })
+                    .map_err(|_| rterr!("Invalid text in an image part")),
+                (Err(e), _) | (_, Err(e)) => Err(e),
+            }
+        },
+        name if config.upload_ignore_unknown_parts =>
+            Ok(UploadPart::Unknown(name.to_owned())),
+        name => Err(rterr!("Unrecognized part: {}", name)),
+    }
+}
+
+/// Read all parts of an upload. If the body is broken, e.g. because
+/// the client aborted, the last part is a 400 error.
+async fn readUploadParts(form_data: warp::multipart::FormData,
+                         config: &Configuration) ->
+    Vec<Result<UploadPart, Error>>
+{
+    let mut parts = Vec::new();
+    let mut form_data = Box::pin(form_data);
+    let mut budget = ZipBudget::new(config);
+    while let Some(part) = form_data.next().await
+    {
+        match part
+        {
+            Ok(part) => {
+                let part = readUploadPart(part, &mut budget, config).await;
+                match &part
+                {
+                    Ok(UploadPart::Split(true)) => budget.images = None,
+                    Ok(UploadPart::Image(..)) => budget.images =
+                        budget.images.map(|n| n.saturating_sub(1)),
+                    _ => {},
+                }
+                parts.push(part);
+            },
+            Err(e) => {
+                parts.push(Err(brokenUpload(e)));
+                break;
+            },
+        }
+    }
+    parts
+}
+
+async fn handleUpload(credential: Option<Credential>,
+                      form_data: warp::multipart::FormData,
+                      accept: Option<String>,
+                      data_manager: &data::Manager,
+                      config: &Configuration) ->
+    Result<Response, warp::Rejection>
+{
+    if !validateCredential(&credential, data_manager, config).map_err(
+        |_| warp::reject::reject())?
+    {
+        return Err(warp::reject::reject());
+    }
+    if pipelineIsFull(config)
+    {
+        warn!("Too many images are waiting for processing. Rejecting upload.");
+        return Ok(warp::reply::with_header(
+            warp::reply::with_status("Server is busy, try again later.",
+                                     StatusCode::SERVICE_UNAVAILABLE),
+            "Retry-After", UPLOAD_RETRY_AFTER_SEC.to_string()).into_response());
+    }
+    // Don’t process the images if they can’t be kept anyway. Whether
+    // they fit is checked once their sizes are known.
+    match quota::check(0, data_manager, config)
+    {
+        Ok(()) => {},
+        Err(Error::HTTPStatus(status, message)) => {
+            warn!("Rejecting upload: {}", message);
+            return Ok(uploadErrorResponse(status, &message, accept.as_deref()));
+        },
+        Err(e) => return Err(error::reject(e)),
+    }
+    let parts = readUploadParts(form_data, config).await;
+
+    // Check the whole upload before processing any image.
+    let mut parts: Vec<Result<UploadPart, Error>> = parts.into_iter()
+        .flat_map(|part| match part
+        {
+            Ok(UploadPart::Zip(images)) => images.into_iter()
+                .map(|img| Ok(UploadPart::Image(None, img))).collect(),
+            part => vec![part],
+        }).collect();
+    let image_count = parts.iter()
+        .filter(|p| matches!(p, Ok(UploadPart::Image(..)))).count();
+    // With Split, each image is a post of its own.
+    let split = parts.iter().any(|p| matches!(p, Ok(UploadPart::Split(true))));
+    let error = if let Some(i) = parts.iter().position(|p| p.is_err())
+    {
+        parts.swap_remove(i).err()
+    }
+    else if !split && image_count > config.images_per_post_max
+    {
+        Some(Error::HTTPStatus(StatusCode::PAYLOAD_TOO_LARGE, format!(
+            "A post can have at most {} images", config.images_per_post_max)))
+    }
+    else
+    {
+        None
+    };
+    if let Some(e) = error
+    {
+        for part in parts
+        {
+            if let Ok(UploadPart::Image(_, img)) = part
+            {
+                std::fs::remove_file(&img.path).ok();
+            }
+        }
+        return match e
+        {
+            // E.g. a file that is too large, or not an image.
+            Error::HTTPStatus(status, message) => {
+                warn!("Rejecting upload: {}", message);
+                Ok(uploadErrorResponse(status, &message, accept.as_deref()))
+            },
+            e => Err(error::reject(e)),
+        };
+    }
+
+    let mut fields = UploadFields::default();
+    let mut raw_images = Vec::new();
+    let mut alt_texts = HashMap::new();
+    let mut captions = HashMap::new();
+    let mut latitude = None;
+    let mut longitude = None;
+    for part in parts.into_iter().flatten()
+    {
+        match part
+        {
+            // Some clients split a long text into several parts.
+            UploadPart::Desc(s) => {
+                if !fields.desc.is_empty()
+                {
+                    fields.desc.push_str("\n\n");
+                }
+                fields.desc += &s;
+            },
+            UploadPart::Slug(s) => {fields.slug = s;},
+            UploadPart::Visibility(s) => {fields.visibility = s;},
+            UploadPart::Snippet(s) => {fields.snippet = s;},
+            UploadPart::PublishAt(t) => {
+                fields.publish_time = t.map(|t| t.unix_timestamp());
+            },
+            UploadPart::AltText(i, s) => {alt_texts.insert(i, s);},
+            UploadPart::Caption(i, s) => {captions.insert(i, s);},
+            UploadPart::Latitude(c) => {latitude = c;},
+            UploadPart::Longitude(c) => {longitude = c;},
+            UploadPart::Tags(t) => {fields.tags = t;},
+            UploadPart::Split(s) => {fields.split = s;},
+            UploadPart::Queue(q) => {fields.queue = q;},
+            UploadPart::Sensitive(s) => {fields.sensitive = s;},
+            UploadPart::License(l) => {fields.license = l;},
+            // Expanded into the images above.
+            UploadPart::Zip(_) => {},
+            UploadPart::Image(index, img) => {raw_images.push((index, img));},
+            UploadPart::Unknown(name) => {
+                debug!("Ignoring unrecognized part {}.", name);
+            },
+        }
+    }
+    fields.coordinates = latitude.zip(longitude);
+    // An image that fails is quarantined with the fields, and the
+    // rest of the upload still makes its post.
+    let group = format!("{:016x}", rand::random::<u64>());
+    let mut images: Vec<Image> = Vec::new();
+    let mut failure = None;
+    for (position, (index, mut img)) in orderImages(raw_images).into_iter()
+        .enumerate()
+    {
+        let alt_text = alt_texts.remove(&index);
+        let caption = captions.remove(&index);
+        img.license = fields.license.clone();
+        img.upload = Some(Box::new(quarantine::Upload {
+            group: group.clone(),
+            position,
+            alt_text: alt_text.clone(),
+            caption: caption.clone(),
+            fields: fields.clone(),
+            post_id: None,
+        }));
+        match img.processBlocking(config).await
+        {
+            Ok(mut image) => {
+                fields.finishImage(&mut image, alt_text.as_deref(),
+                                   caption.as_deref());
+                images.push(image);
+            },
+            Err(e) => {failure.get_or_insert(e);},
+        }
+    }
+    // The images are processed by now, so a scheduled post only has
+    // to become visible at its time. Looking up the place and pinging
+    // the WebSub hub would block the executor.
+    let ids = if images.is_empty()
+    {
+        Vec::new()
+    }
+    else
+    {
+        tokio::task::block_in_place(
+            || makePosts(&fields, images, data_manager, config))
+            .map_err(error::reject)?
+    };
+    if let Some(e) = failure
+    {
+        if let (false, Some(id)) = (fields.split, ids.first())
+        {
+            quarantine::setPost(&group, *id, config).map_err(error::reject)?;
+        }
+        let message = format!("An image failed, and is quarantined for retry \
+                               on the admin page: {}", e);
+        warn!("{}", message);
+        return Ok(uploadErrorResponse(StatusCode::INTERNAL_SERVER_ERROR,
+                                      &message, accept.as_deref()));
+    }
+    let mut created = Vec::new();
+    for id in ids
+    {
+        let post = data_manager.findPostByID(id).map_err(error::reject)?
+            .ok_or_else(|| error::reject(
+                rterr!("Post {} is gone after it is created", id)))?;
+        created.push(webhook::postLink(&post, config));
+    }
+    uploadResponse(created, accept.as_deref()).map_err(error::reject)
+}
+
+/// The response of an upload that made the posts in `created`, which
+/// are the links of `webhook::postLink`. A client that wants JSON gets
+/// the first post, and all of them in `posts` if the upload was split.
+/// A form is redirected to the first post.
+fn uploadResponse(created: Vec<serde_json::Value>, accept: Option<&str>) ->
+    Result<Response, Error>
+{
+    let first = created.first().cloned().ok_or_else(
+        || rterr!("Upload made no posts"))?;
+    if error::wantsJson(accept)
+    {
+        let mut body = first;
+        body["posts"] = serde_json::Value::Array(created);
+        Ok(warp::reply::with_status(warp::reply::json(&body),
+                                    StatusCode::CREATED).into_response())
+    }
+    else
+    {
+        let url = first["url"].as_str().ok_or_else(
+            || rterr!("Invalid post link: {}", first))?;
+        Ok(warp::redirect::see_other(uriFromStr(url)?).into_response())
+    }
+}
+
+pub fn urlFor(name: &str, arg: &str) -> String
+{
+    match name
+    {
+        "index" => String::from("/"),
         "upload" => String::from("/upload"),
-        "post" => String::from("/p/") + arg,
+        "admin" => String::from("/admin"),
+        "publish" => String::from("/admin/publish/") + arg,
+        "quarantine_retry" => format!("/admin/quarantine/{}/retry", arg),
+        "quarantine_discard" => format!("/admin/quarantine/{}/discard", arg),
+        "tag_approve" => format!("/admin/suggested-tags/{}/approve", arg),
+        "tag_reject" => format!("/admin/suggested-tags/{}/reject", arg),
+        "api_token_create" => String::from("/admin/tokens"),
+        "webhook_test" => String::from("/admin/webhook-test"),
+        "logout" => String::from("/logout"),
+        "sessions" => String::from("/sessions"),
+        "api_token_revoke" => format!("/admin/tokens/{}/revoke", arg),
+        "duplicates" => String::from("/admin/duplicates"),
+        "trash" => String::from("/trash"),
+        "trash_restore" => format!("/trash/{}/restore", arg),
+        "trash_purge" => format!("/trash/{}/purge", arg),
+        "post" => String::from("/") + arg,
         "feed" => String::from("/feed.xml"),
+        "sitemap" => String::from("/sitemap.xml"),
+        "map" => String::from("/map"),
+        "albums" => String::from("/albums"),
+        "album" => String::from("/?album=") + arg,
+        "album_cover" => format!("/albums/{}/cover", arg),
+        "map_geojson" => String::from("/map.geojson"),
+        "lang" => String::from("/lang/") + arg,
+        "layout" => String::from("/layout/") + arg,
+        "prefs" => String::from("/prefs/") + arg,
+        "print" => String::from("/archive/print"),
         "delete_confirm" => String::from("/delete-confirm/") + arg,
         "delete" => String::from("/delete/") + arg,
+        "redact" => String::from("/redact/") + arg,
+        "setup" => String::from("/setup"),
+        "like" => String::from("/p/") + arg + "/like",
+        "post_album" => String::from("/p/") + arg + "/album",
+        "post_sensitive" => String::from("/p/") + arg + "/sensitive",
+        "post_license" => String::from("/p/") + arg + "/license",
+        "post_download" => String::from("/p/") + arg + "/download.zip",
+        "album_download" => format!("/album/{}/download.zip", arg),
+        "shared" => String::from("/s/") + arg,
+        "share_create" => String::from("/share/") + arg,
+        "share_revoke" => String::from("/unshare/") + arg,
         "login" => String::from("/login/"),
         "static" => String::from("/static/") + arg,
         "image_file" => String::from("/image/") + arg,
+        "api_manifest" => String::from("/api/v1/manifest"),
+        "api_posts" => String::from("/api/v1/posts"),
+        "api_library" => String::from("/api/v1/library"),
         _ => String::from("/"),
     }
 }
@@ -309,26 +1789,93 @@ fn getTeraFuncArgs(args: &HashMap<String, tera::Value>, arg_name: &str) ->
     Ok(value)
 }
 
+/// The part of the URL path before the result of `urlFor()`.
+pub fn pathPrefix(serve_path: &str) -> String
+{
+    if serve_path == "" || serve_path == "/"
+    {
+        String::new()
+    }
+    else if serve_path.starts_with("/")
+    {
+        serve_path.to_owned()
+    }
+    else
+    {
+        String::from("/") + serve_path
+    }
+}
+
+/// The absolute URL of `urlFor(name, arg)`, for use outside of the
+/// site.
+pub fn absoluteUrl(name: &str, arg: &str, config: &Configuration) -> String
+{
+    config.site_info.url_domain.clone() + &pathPrefix(&config.serve_under_path)
+        + &urlFor(name, arg)
+}
+
 fn makeURLFor(serve_path: String) -> impl tera::Function
 {
     move |args: &HashMap<String, tera::Value>| ->
         tera::Result<tera::Value> {
-            let path_prefix: String = if serve_path == "" || serve_path == "/"
+            let name = getTeraFuncArgs(args, "name")?;
+            let arg = getTeraFuncArgs(args, "arg")?;
+            Ok(tera::to_value(pathPrefix(&serve_path) + &urlFor(&name, &arg))
+               .unwrap())
+    }
+}
+
+/// Fill in the renditions of the images of the posts, so that
+/// templates could make `<picture>` elements. The alternate encodings
+/// that exist come first, and the image in `image_encoding` is the
+/// fallback.
+pub fn fillImageSources(posts: &mut [Post], config: &Configuration)
+{
+    let url_prefix = pathPrefix(&config.serve_under_path);
+    let url = |path: &Path| url_prefix.clone() + &urlFor(
+        "image_file", path.to_str().unwrap_or(""));
+    for post in posts
+    {
+        for image in &mut post.images
+        {
+            let (thumb_width, _) = image.thumbnailSize(config.thumb_pixel_size);
+            let source_set = |path: &Path, thumb: &Path| {
+                let mut sources = Vec::new();
+                if thumb_width < image.width
+                {
+                    sources.push(ImageSource {
+                        width: thumb_width,
+                        url: url(thumb),
+                    });
+                }
+                sources.push(ImageSource {
+                    width: image.width,
+                    url: url(path),
+                });
+                SourceSet {
+                    mime_type: mimeTypeFromPath(path).to_owned(),
+                    sources,
+                }
+            };
+            let mut sets = Vec::new();
+            for encoding in &config.alternate_encodings
             {
-                String::new()
+                if let Ok((path, thumb)) = image.alternate(encoding.extension())
+                {
+                    let image_dir = Path::new(&config.image_dir);
+                    if image_dir.join(&path).exists() &&
+                        image_dir.join(&thumb).exists()
+                    {
+                        sets.push(source_set(&path, &thumb));
+                    }
+                }
             }
-            else if serve_path.starts_with("/")
+            if let Ok(thumb) = image.thumbnail()
             {
-                serve_path.to_owned()
+                sets.push(source_set(&image.path, &thumb));
             }
-            else
-            {
-                String::from("/") + &serve_path
-            };
-
-            let name = getTeraFuncArgs(args, "name")?;
-            let arg = getTeraFuncArgs(args, "arg")?;
-            Ok(tera::to_value(path_prefix + &urlFor(&name, &arg)).unwrap())
+            image.sources = sets;
+        }
     }
 }
 
@@ -337,17 +1884,22 @@ pub struct App
     templates: Tera,
     data_manager: data::Manager,
     config: Configuration,
+    views: Arc<ViewCounter>,
+    catalogs: Arc<Catalogs>,
+    page_cache: Arc<PageCache>,
 }
 
 impl App
 {
     pub fn new(config: Configuration) -> Result<Self, Error>
     {
-        let db_path = Path::new(&config.data_dir).join("db.sqlite");
         let mut result = Self {
             templates: Tera::default(),
-            data_manager: data::Manager::newWithFilename(&db_path),
+            data_manager: data::Manager::fromConfig(&config)?,
+            catalogs: Arc::new(Catalogs::fromConfig(&config)?),
+            page_cache: Arc::new(PageCache::new(&config)),
             config,
+            views: Arc::new(ViewCounter::new()),
         };
         result.init()?;
         Ok(result)
@@ -365,8 +1917,19 @@ impl App
             std::fs::create_dir_all(&self.config.image_dir)
                 .map_err(|e| rterr!("Failed to create image dir: {}", e))?;
         }
+        if !self.config.password.is_empty()
+        {
+            warn!("The plain text password in the config is deprecated. \
+                   Use password_hash from `nspic hash-password` instead.");
+        }
+        if self.config.like_key.is_none()
+        {
+            self.config.like_key = Some(loadLikeKey(&self.config)?);
+        }
+        cleanup::removeStaleTempFiles(&self.config)?;
         self.data_manager.connect()?;
         self.data_manager.init()?;
+        self.recordImageSizes()?;
         let template_path = PathBuf::from(&self.config.data_dir)
             .join("templates").canonicalize()
             .map_err(|_| rterr!("Invalid template dir"))?
@@ -378,98 +1941,767 @@ impl App
             |e| rterr!("Failed to compile templates: {}", e))?;
         self.templates.register_function(
             "url_for", makeURLFor(self.config.serve_under_path.clone()));
+        self.templates.register_function("t", self.catalogs.teraFunction());
+        Ok(())
+    }
+
+    /// Record the file sizes of images that were added before sizes
+    /// were tracked.
+    fn recordImageSizes(&self) -> Result<(), Error>
+    {
+        let paths = self.data_manager.imagesWithoutSize()?;
+        if paths.is_empty()
+        {
+            return Ok(());
+        }
+        info!("Recording sizes of {} images...", paths.len());
+        let size = |path: &Path| std::fs::metadata(
+            Path::new(&self.config.image_dir).join(path))
+            .map(|m| m.len()).unwrap_or(0);
+        for path in paths
+        {
+            let image = Image { path, ..Default::default() };
+            self.data_manager.setImageSizes(
+                &image.path, size(&image.path), size(&image.thumbnail()?))?;
+        }
         Ok(())
     }
 
     pub async fn serve(self) -> Result<(), Error>
     {
+        let proxies = Arc::new(TrustedProxies::new(&self.config));
         let static_dir = PathBuf::from(&self.config.static_dir);
         info!("Static dir is {}", static_dir.display());
         let statics = warp::get().and(warp::path("static"))
             .and(warp::fs::dir(static_dir));
-        let statics = statics.or(warp::get().and(warp::path("image")).and(
-            warp::fs::dir(PathBuf::from(&self.config.image_dir))));
+        let statics = statics.or(image_files::imageFiles(self.config.clone()));
+        let config = self.config.clone();
+        let statics = statics.or(warp::get().and(warp::path("image"))
+            .and(warp::path::tail()).and_then(move |tail: warp::path::Tail| {
+                let config = config.clone();
+                async move { handleMissingThumbnail(tail, config).await }
+            }));
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let statics = statics.or(warp::get().and(warp::path("image"))
+            .and(warp::path(card::CARDS_DIR)).and(warp::path::param())
+            .and(warp::path::end()).and_then(move |name: String| {
+                let config = config.clone();
+                let data_manager = data_manager.clone();
+                async move { handleMissingCard(name, data_manager, config).await }
+            }));
+
+        let temp = self.templates.clone();
+        let data_manager = self.data_manager.clone();
+        let page_cache = self.page_cache.clone();
+        let index = warp::get().and(warp::query::<HashMap<String, String>>())
+            .and(warp::path::end())
+            .and(proxy::siteConfig(self.config.clone(), proxies.clone()))
+            .and(warp::filters::cookie::optional(LIKER_COOKIE))
+            .and(i18n::locale(self.catalogs.clone()))
+            .and(prefs::prefs())
+            .and(layout::layout())
+            .and(warp::header::optional::<String>("if-none-match"))
+            .map(move |query: HashMap<String, String>, config: Arc<Configuration>,
+                 liker: Option<String>, catalog: Arc<Catalog>, prefs: Prefs,
+                 layout: Layout,
+                 if_none_match: Option<String>| {
+            withLikerCookie(
+                handleIndex(&temp, &query, &data_manager, &config, &catalog,
+                            &prefs, layout, &page_cache, if_none_match)
+                    .toResponse(),
+                &liker)
+        });
+
+        // A post under each URL scheme. The path and the reference of
+        // the post are extracted.
+        let post_page = |location: BoxedFilter<(String, String)>| {
+            let temp = self.templates.clone();
+            let data_manager = self.data_manager.clone();
+            let views = self.views.clone();
+            warp::get().and(location)
+                .and(proxy::siteConfig(self.config.clone(), proxies.clone()))
+                .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+                .and(warp::filters::cookie::optional(LIKER_COOKIE))
+                .and(i18n::locale(self.catalogs.clone()))
+                .and(prefs::prefs())
+                .and(warp::header::optional::<String>("if-none-match"))
+                .map(move |path: String, post_ref: String,
+                     config: Arc<Configuration>, token: Option<String>,
+                     liker: Option<String>, catalog: Arc<Catalog>, prefs: Prefs,
+                     if_none_match: Option<String>| {
+                withLikerCookie(
+                    handlePost(&temp, &path, &post_ref, &data_manager, &views,
+                               &config, token, &catalog, &prefs, if_none_match)
+                        .toResponse(),
+                    &liker)
+            })
+        };
+        let post_by_id = warp::path("p").and(warp::path::param())
+            .and(warp::path::end())
+            .map(|post_ref: String| (format!("p/{}", post_ref), post_ref));
+        let post_by_date = warp::path::param().and(warp::path::param())
+            .and(warp::path::param()).and(warp::path::end())
+            .map(|year: u32, month: u32, post_ref: String|
+                 (format!("{}/{:02}/{}", year, month, post_ref), post_ref));
+        let post = post_page(post_by_id.or(post_by_date).unify()
+                             .untuple_one().boxed());
+        // This takes any path of one segment, so it goes after all the
+        // other routes.
+        let post_by_slug = post_page(
+            warp::path::param().and(warp::path::end())
+                .map(|post_ref: String| (post_ref.clone(), post_ref))
+                .untuple_one().boxed());
+
+        let temp = self.templates.clone();
+        let data_manager = self.data_manager.clone();
+        let page_cache = self.page_cache.clone();
+        let feed = warp::get().and(warp::path("feed.xml"))
+            .and(warp::path::end())
+            .and(proxy::siteConfig(self.config.clone(), proxies.clone()))
+            .map(move |config: Arc<Configuration>| {
+            handleFeed(&temp, &data_manager, &config, &page_cache).toResponse()
+        });
+
+        let data_manager = self.data_manager.clone();
+        let sitemap = warp::get().and(warp::path("sitemap.xml"))
+            .and(warp::path::end())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(proxy::siteConfig(self.config.clone(), proxies.clone()))
+            .map(move |params: HashMap<String, String>,
+                 config: Arc<Configuration>| {
+            sitemap::handleSitemap(&params, &data_manager, &config).toResponse()
+        });
+
+        let robots = warp::get().and(warp::path("robots.txt"))
+            .and(warp::path::end())
+            .and(proxy::siteConfig(self.config.clone(), proxies.clone()))
+            .map(|config: Arc<Configuration>| robots::handleRobots(&config));
+
+        let temp = self.templates.clone();
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let map = warp::get().and(warp::path("map")).and(warp::path::end())
+            .and(i18n::locale(self.catalogs.clone())).and(prefs::prefs())
+            .map(move |catalog: Arc<Catalog>, prefs: Prefs| {
+                geo::handleMap(&temp, &catalog, &prefs, &data_manager, &config)
+                    .toResponse()
+            });
+
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let map_geojson = warp::get().and(warp::path("map.geojson"))
+            .and(warp::path::end()).map(move || {
+                geo::handleGeoJson(&data_manager, &config).toResponse()
+            });
+
+        let temp = self.templates.clone();
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let print_archive = warp::get().and(warp::path("archive"))
+            .and(warp::path("print")).and(warp::path::end())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .map(move |params: HashMap<String, String>, token: Option<String>| {
+            print::handlePrint(temp.clone(), params, data_manager.clone(),
+                               config.clone(), token).toResponse()
+        });
+
+        let temp = self.templates.clone();
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let delete_confirm = warp::get().and(warp::path("delete-confirm"))
+            .and(warp::path::param()).and(warp::path::end())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .and(i18n::locale(self.catalogs.clone())).and(prefs::prefs())
+            .map(move |id: i64, token: Option<String>, catalog: Arc<Catalog>,
+                 prefs: Prefs| {
+                handleDeleteConfirm(&temp, id, &data_manager, &config, token,
+                                    &catalog, &prefs).toResponse()
+            });
+
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let delete = warp::post().and(warp::path("delete"))
+            .and(warp::path::param()).and(warp::path::end())
+            .and(auth::credential())
+            .map(move |id: i64, credential: Option<Credential>| {
+                handleDelete(id, &data_manager, &config, credential).toResponse()
+            });
+
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let redact = warp::post().and(warp::path("redact"))
+            .and(warp::path::param()).and(warp::path::end())
+            .and(auth::credential())
+            .map(move |id: i64, credential: Option<Credential>| {
+                handleRedact(id, &data_manager, &config, credential).toResponse()
+            });
+
+        let temp = self.templates.clone();
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let setup_page = warp::get().and(warp::path("setup"))
+            .and(warp::path::end()).and(i18n::locale(self.catalogs.clone()))
+            .and(prefs::prefs())
+            .map(move |catalog: Arc<Catalog>, prefs: Prefs| {
+                setup::handleSetupPage(&temp, &data_manager, &config, &catalog,
+                                       &prefs).toResponse()
+            });
+
+        let temp = self.templates.clone();
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let setup = warp::post().and(warp::path("setup"))
+            .and(warp::path::end()).and(warp::body::form())
+            .and(i18n::locale(self.catalogs.clone())).and(prefs::prefs())
+            .map(move |form: HashMap<String, String>, catalog: Arc<Catalog>,
+                 prefs: Prefs| {
+                setup::handleSetup(&temp, &form, &data_manager, &config,
+                                   &catalog, &prefs).toResponse()
+            });
+
+        let temp = self.templates.clone();
+        let data_manager = self.data_manager.clone();
+        let shared = warp::get().and(warp::path("s")).and(warp::path::param())
+            .and(warp::path::end())
+            .and(proxy::siteConfig(self.config.clone(), proxies.clone()))
+            .and(i18n::locale(self.catalogs.clone()))
+            .and(prefs::prefs())
+            .and(warp::header::optional::<String>("if-none-match"))
+            .map(move |share_token: String, config: Arc<Configuration>,
+                 catalog: Arc<Catalog>, prefs: Prefs,
+                 if_none_match: Option<String>| {
+                handleShared(&temp, &share_token, &data_manager, &config,
+                             &catalog, &prefs)
+                    .map(|html| http_cache::page(html, &if_none_match, None))
+                    .toResponse()
+            });
+
+        let temp = self.templates.clone();
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let albums = warp::get().and(warp::path("albums")).and(warp::path::end())
+            .and(i18n::locale(self.catalogs.clone())).and(prefs::prefs())
+            .map(move |catalog: Arc<Catalog>, prefs: Prefs| {
+                handleAlbums(&temp, &data_manager, &config, &catalog, &prefs)
+                    .toResponse()
+            });
+
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let post_album = warp::post().and(warp::path("p"))
+            .and(warp::path::param()).and(warp::path("album"))
+            .and(warp::path::end())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .and(warp::body::form())
+            .map(move |id: i64, token: Option<String>,
+                 form: HashMap<String, String>| {
+                handlePostAlbum(id, &form, &data_manager, &config, token)
+                    .toResponse()
+            });
+
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let post_sensitive = warp::post().and(warp::path("p"))
+            .and(warp::path::param()).and(warp::path("sensitive"))
+            .and(warp::path::end())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .and(warp::body::form())
+            .map(move |id: i64, token: Option<String>,
+                 form: HashMap<String, String>| {
+                handlePostSensitive(id, &form, &data_manager, &config, token)
+                    .toResponse()
+            });
+
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let post_license = warp::post().and(warp::path("p"))
+            .and(warp::path::param()).and(warp::path("license"))
+            .and(warp::path::end())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .and(warp::body::form())
+            .map(move |id: i64, token: Option<String>,
+                 form: HashMap<String, String>| {
+                handlePostLicense(id, &form, &data_manager, &config, token)
+                    .toResponse()
+            });
+
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let album_cover = warp::post().and(warp::path("albums"))
+            .and(warp::path::param()).and(warp::path("cover"))
+            .and(warp::path::end())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .and(warp::body::form())
+            .map(move |id: i64, token: Option<String>,
+                 form: HashMap<String, String>| {
+                handleAlbumCover(id, &form, &data_manager, &config, token)
+                    .toResponse()
+            });
+
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let share_create = warp::post().and(warp::path("share"))
+            .and(warp::path::param()).and(warp::path::end())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .and(warp::body::form())
+            .map(move |id: i64, token: Option<String>,
+                 form: HashMap<String, String>| {
+                handleShareCreate(id, &form, &data_manager, &config, token)
+                    .toResponse()
+            });
+
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let share_revoke = warp::post().and(warp::path("unshare"))
+            .and(warp::path::param()).and(warp::path::end())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .map(move |share_token: String, token: Option<String>| {
+                handleShareRevoke(&share_token, &data_manager, &config, token)
+                    .toResponse()
+            });
 
         let temp = self.templates.clone();
         let config = self.config.clone();
         let data_manager = self.data_manager.clone();
-        let index = warp::get().and(warp::query::<HashMap<String, String>>())
-            .and(warp::path::end()).map(move |query: HashMap<String, String>| {
-            handleIndex(&temp, &query, &data_manager, &config).toResponse()
-        });
+        let upload_page = warp::get().and(warp::path("upload"))
+            .and(warp::path::end())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .and(i18n::locale(self.catalogs.clone())).and(prefs::prefs()).map(
+                move |token: Option<String>, catalog: Arc<Catalog>, prefs: Prefs|
+                handleUploadPage(&data_manager, &temp, &config, token,
+                                 &catalog, &prefs).toResponse());
 
-        let temp = self.templates.clone();
         let config = self.config.clone();
         let data_manager = self.data_manager.clone();
-        let post = warp::get().and(warp::path("p")).and(warp::path::param())
-            .and(warp::path::end()).map(move |id: i64| {
-            handlePost(&temp, id, &data_manager, &config).toResponse()
-        });
+        let upload = warp::post().and(warp::path("upload"))
+            .and(warp::path::end())
+            .and(auth::credential())
+            .and(warp::multipart::form()
+                 .max_length(self.config.upload_bytes_max))
+            .and(warp::header::optional::<String>("accept"))
+            .and_then(
+                move |credential: Option<Credential>,
+                      data: warp::multipart::FormData, accept: Option<String>| {
+                let config = config.clone();
+                let data_manager = data_manager.clone();
+                async move {
+                    handleUpload(credential, data, accept, &data_manager,
+                                 &config).await
+                }
+            });
 
         let temp = self.templates.clone();
         let config = self.config.clone();
         let data_manager = self.data_manager.clone();
-        let feed = warp::get().and(warp::path("feed.xml"))
-            .and(warp::path::end()).map(move || {
-            handleFeed(&temp, &data_manager, &config).toResponse()
-        });
+        let admin = warp::get().and(warp::path("admin")).and(warp::path::end())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .and(i18n::locale(self.catalogs.clone())).and(prefs::prefs()).map(
+                move |token: Option<String>, catalog: Arc<Catalog>, prefs: Prefs|
+                handleAdmin(&data_manager, &temp, &config, token, &catalog,
+                            &prefs, None, None).toResponse());
 
         let temp = self.templates.clone();
         let config = self.config.clone();
         let data_manager = self.data_manager.clone();
-        let delete_confirm = warp::get().and(warp::path("delete-confirm"))
+        let webhook_test = warp::post().and(warp::path("admin"))
+            .and(warp::path("webhook-test")).and(warp::path::end())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .and(i18n::locale(self.catalogs.clone())).and(prefs::prefs()).map(
+                move |token: Option<String>, catalog: Arc<Catalog>, prefs: Prefs|
+                handleWebhookTestAction(&data_manager, &temp, &config, token,
+                                        &catalog, &prefs).toResponse());
+
+        let config = self.config.clone();
+        let catalogs = self.catalogs.clone();
+        let set_language = warp::get().and(warp::path("lang"))
+            .and(warp::path::param()).and(warp::path::end())
+            .and(warp::header::optional::<String>("referer"))
+            .map(move |code: String, referer: Option<String>| {
+                i18n::handleSetLanguage(&code, referer, &catalogs, &config)
+                    .toResponse()
+            });
+
+        let config = self.config.clone();
+        let set_layout = warp::get().and(warp::path("layout"))
             .and(warp::path::param()).and(warp::path::end())
+            .and(warp::header::optional::<String>("referer"))
+            .map(move |value: String, referer: Option<String>| {
+                layout::handleSetLayout(&value, referer, &config).toResponse()
+            });
+
+        let config = self.config.clone();
+        let set_pref = warp::get().and(warp::path("prefs"))
+            .and(warp::path::param()).and(warp::path::param())
+            .and(warp::path::end())
+            .and(warp::filters::cookie::optional(prefs::PREFS_COOKIE))
+            .and(warp::header::optional::<String>("referer"))
+            .map(move |name: String, value: String, cookie: Option<String>,
+                 referer: Option<String>| {
+                prefs::handleSetPref(&name, &value, cookie, referer, &config)
+                    .toResponse()
+            });
+
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let publish = warp::post().and(warp::path("admin"))
+            .and(warp::path("publish")).and(warp::path::param())
+            .and(warp::path::end())
             .and(warp::filters::cookie::optional(TOKEN_COOKIE))
-            .map(move |id: i64, token: Option<String>| {
-                handleDeleteConfirm(&temp, id, &data_manager, &config, token)
+            .and(warp::body::form())
+            .map(move |id: i64, token: Option<String>,
+                 form: HashMap<String, String>| {
+                handlePublish(id, &form, &data_manager, &config, token)
                     .toResponse()
             });
 
         let config = self.config.clone();
         let data_manager = self.data_manager.clone();
-        let delete = warp::post().and(warp::path("delete"))
+        let suggestion_action = warp::post().and(warp::path("admin"))
+            .and(warp::path("suggested-tags")).and(warp::path::param())
+            .and(warp::path::param()).and(warp::path::end())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .and(warp::body::form())
+            .map(move |id: i64, action: String, token: Option<String>,
+                 form: HashMap<String, String>| {
+                let tag = form.get("Tag").map(String::as_str).unwrap_or("");
+                classifier::handleSuggestionAction(id, &action, tag, &data_manager,
+                                                   &config, token).toResponse()
+            });
+
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let quarantine_action = warp::post().and(warp::path("admin"))
+            .and(warp::path("quarantine")).and(warp::path::param())
             .and(warp::path::param()).and(warp::path::end())
             .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .and_then(move |id: String, action: String, token: Option<String>| {
+                let config = config.clone();
+                let data_manager = data_manager.clone();
+                // Retrying runs the image pipeline.
+                async move {
+                    tokio::task::spawn_blocking(move || {
+                        handleQuarantineAction(&id, &action, &data_manager,
+                                               &config, token).toResponse()
+                    }).await.map_err(|e| error::reject(
+                        rterr!("Quarantine task failed: {}", e)))
+                }
+            });
+
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let temp = self.templates.clone();
+        let api_token_create = warp::post().and(warp::path("admin"))
+            .and(warp::path("tokens")).and(warp::path::end())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .and(i18n::locale(self.catalogs.clone())).and(prefs::prefs())
+            .and(warp::body::form())
+            .map(move |token: Option<String>, catalog: Arc<Catalog>,
+                 prefs: Prefs, form: HashMap<String, String>| {
+                handleApiTokenCreate(&form, &data_manager, &temp, &config, token,
+                                     &catalog, &prefs).toResponse()
+            });
+
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let api_token_revoke = warp::post().and(warp::path("admin"))
+            .and(warp::path("tokens")).and(warp::path::param())
+            .and(warp::path("revoke")).and(warp::path::end())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
             .map(move |id: i64, token: Option<String>| {
-                handleDelete(id, &data_manager, &config, token).toResponse()
+                handleApiTokenRevoke(id, &data_manager, &config, token)
+                    .toResponse()
+            });
+
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let temp = self.templates.clone();
+        let login = warp::get().and(warp::path("login")).and(warp::path::end())
+            .and(warp::header::optional::<String>("Authorization"))
+            .and(proxy::remote(proxies.clone()))
+            .and(warp::header::optional::<String>("user-agent"))
+            .and(i18n::locale(self.catalogs.clone())).and(prefs::prefs())
+            .map(move |auth_value: Option<String>, remote: Option<SocketAddr>,
+                       user_agent: Option<String>, catalog: Arc<Catalog>,
+                       prefs: Prefs| {
+                handleLogin(auth_value, remote, user_agent, &temp, &catalog,
+                            &prefs, &data_manager, &config).toResponse()
             });
 
         let temp = self.templates.clone();
         let config = self.config.clone();
         let data_manager = self.data_manager.clone();
-        let upload_page = warp::get().and(warp::path("upload"))
+        let login_form = warp::post().and(warp::path("login"))
+            .and(warp::path::end()).and(warp::body::form())
+            .and(proxy::remote(proxies.clone()))
+            .and(warp::header::optional::<String>("user-agent"))
+            .and(i18n::locale(self.catalogs.clone())).and(prefs::prefs())
+            .map(move |form: HashMap<String, String>, remote: Option<SocketAddr>,
+                       user_agent: Option<String>, catalog: Arc<Catalog>,
+                       prefs: Prefs| {
+                handleLoginForm(&form, remote, user_agent, &temp, &catalog,
+                                &prefs, &data_manager, &config).toResponse()
+            });
+
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let logout = warp::post().and(warp::path("logout"))
             .and(warp::path::end())
-            .and(warp::filters::cookie::optional(TOKEN_COOKIE)).map(
-                move |token: Option<String>|
-                handleUploadPage(&data_manager, &temp, &config, token)
-                    .toResponse());
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .map(move |token: Option<String>| {
+                auth::handleLogout(token, &data_manager, &config).toResponse()
+            });
 
+        let temp = self.templates.clone();
         let config = self.config.clone();
         let data_manager = self.data_manager.clone();
-        let upload = warp::post().and(warp::path("upload"))
+        let sessions = warp::get().and(warp::path("sessions"))
             .and(warp::path::end())
             .and(warp::filters::cookie::optional(TOKEN_COOKIE))
-            .and(warp::multipart::form()
-                 .max_length(self.config.upload_bytes_max))
-            .and_then(
-                move |token: Option<String>, data: warp::multipart::FormData| {
+            .and(i18n::locale(self.catalogs.clone())).and(prefs::prefs())
+            .map(move |token: Option<String>, catalog: Arc<Catalog>,
+                 prefs: Prefs| {
+                auth::handleSessions(token, &temp, &catalog, &prefs,
+                                     &data_manager, &config).toResponse()
+            });
+
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let delete_sessions = warp::delete().and(warp::path("sessions"))
+            .and(warp::path::end())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .map(move |token: Option<String>| {
+                auth::handleDeleteSessions(token, &data_manager, &config)
+                    .toResponse()
+            });
+
+        let temp = self.templates.clone();
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let trash_page = warp::get().and(warp::path("trash"))
+            .and(warp::path::end())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .and(i18n::locale(self.catalogs.clone())).and(prefs::prefs())
+            .map(move |token: Option<String>, catalog: Arc<Catalog>,
+                 prefs: Prefs| {
+                trash::handleTrash(token, &temp, &catalog, &prefs,
+                                   &data_manager, &config).toResponse()
+            });
+
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let post_download = warp::get().and(warp::path("p"))
+            .and(warp::path::param()).and(warp::path("download.zip"))
+            .and(warp::path::end())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .map(move |id: i64, token: Option<String>| {
+                download::handlePostDownload(id, &data_manager, &config, token)
+                    .toResponse()
+            });
+
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let album_download = warp::get().and(warp::path("album"))
+            .and(warp::path::param()).and(warp::path("download.zip"))
+            .and(warp::path::end())
+            .map(move |id: i64| {
+                download::handleAlbumDownload(id, &data_manager, &config)
+                    .toResponse()
+            });
+
+        let temp = self.templates.clone();
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let duplicates_page = warp::get().and(warp::path("admin"))
+            .and(warp::path("duplicates")).and(warp::path::end())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .and(i18n::locale(self.catalogs.clone())).and(prefs::prefs())
+            .map(move |token: Option<String>, catalog: Arc<Catalog>,
+                 prefs: Prefs| {
+                duplicates::handleDuplicates(token, &temp, &catalog, &prefs,
+                                             &data_manager, &config).toResponse()
+            });
+
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let trash_action = warp::post().and(warp::path("trash"))
+            .and(warp::path::param()).and(warp::path::param())
+            .and(warp::path::end())
+            .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+            .map(move |id: i64, action: String, token: Option<String>| {
+                trash::handleTrashAction(id, &action, &data_manager, &config,
+                                         token).toResponse()
+            });
+
+        let api_client = proxy::remote(proxies.clone())
+            .and(warp::header::optional::<String>("X-API-Key"))
+            .map(|remote, api_key| api::ApiClient { remote, api_key });
+        let limiter = Arc::new(RateLimiter::new());
+
+        let temp = self.templates.clone();
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let limiter_clone = limiter.clone();
+        let api_posts = warp::get().and(warp::path("api")).and(warp::path("v1"))
+            .and(warp::path("posts")).and(warp::path::end())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(api_client.clone()).and(i18n::locale(self.catalogs.clone()))
+            .map(move |query: HashMap<String, String>, client: api::ApiClient,
+                 catalog: Arc<Catalog>| {
+                api::handlePosts(&query, &client, &limiter_clone, &temp, &catalog,
+                                 &data_manager, &config).toResponse()
+            });
+
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let limiter_clone = limiter.clone();
+        let api_post = warp::get().and(warp::path("api")).and(warp::path("v1"))
+            .and(warp::path("posts")).and(warp::path::param())
+            .and(warp::path::end()).and(api_client.clone())
+            .map(move |id: i64, client: api::ApiClient| {
+                api::handlePost(id, &client, &limiter_clone, &data_manager,
+                                &config).toResponse()
+            });
+
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let api_arrange_images = warp::put().and(warp::path("api"))
+            .and(warp::path("v1")).and(warp::path("posts"))
+            .and(warp::path::param()).and(warp::path("images"))
+            .and(warp::path::end()).and(auth::credential())
+            .and(warp::body::content_length_limit(API_BODY_BYTES_MAX))
+            .and(warp::body::json())
+            .map(move |id: i64, credential: Option<Credential>,
+                 images: Vec<api::ImageArrangement>| {
+                api::handleArrangeImages(id, &images, credential,
+                                         &data_manager, &config).toResponse()
+            });
+
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let api_move_post = warp::post().and(warp::path("api"))
+            .and(warp::path("v1")).and(warp::path("posts"))
+            .and(warp::path::param()).and(warp::path("album"))
+            .and(warp::path::end()).and(auth::credential())
+            .and(warp::body::content_length_limit(API_BODY_BYTES_MAX))
+            .and(warp::body::json())
+            .map(move |id: i64, credential: Option<Credential>,
+                 request: api::AlbumAssignment| {
+                api::handleMovePost(id, &request, credential, &data_manager,
+                                    &config).toResponse()
+            });
+
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let api_move_posts = warp::post().and(warp::path("api"))
+            .and(warp::path("v1")).and(warp::path("posts"))
+            .and(warp::path("album")).and(warp::path::end())
+            .and(auth::credential())
+            .and(warp::body::content_length_limit(API_BODY_BYTES_MAX))
+            .and(warp::body::json())
+            .map(move |credential: Option<Credential>, request: api::AlbumMove| {
+                api::handleMovePosts(&request, credential, &data_manager, &config)
+                    .toResponse()
+            });
+
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let api_upload = warp::put().and(warp::path("api"))
+            .and(warp::path("v1")).and(warp::path("upload"))
+            .and(warp::path::end()).and(auth::credential())
+            .and(warp::header::optional::<String>("content-type"))
+            .and(warp::header::optional::<String>("x-desc"))
+            .and(warp::header::optional::<String>("x-tags"))
+            .and(warp::body::content_length_limit(self.config.image_bytes_max))
+            .and(warp::body::bytes())
+            .then(move |credential: Option<Credential>,
+                  content_type: Option<String>, desc: Option<String>,
+                  tags: Option<String>, body: bytes::Bytes| {
                 let config = config.clone();
                 let data_manager = data_manager.clone();
                 async move {
-                    handleUpload(token, data, &data_manager, &config).await
+                    api::handleRawUpload(body, content_type, desc, tags,
+                                         credential, &data_manager, &config)
+                        .await
+                        .toResponse()
                 }
             });
 
         let config = self.config.clone();
         let data_manager = self.data_manager.clone();
-        let login = warp::get().and(warp::path("login")).and(warp::path::end())
-            .and(warp::header::optional::<String>("Authorization"))
-            .map(move |auth_value: Option<String>| {
-                handleLogin(auth_value, &data_manager, &config).toResponse()
+        let api_webhook_test = warp::post().and(warp::path("api"))
+            .and(warp::path("v1")).and(warp::path("webhooks"))
+            .and(warp::path("test")).and(warp::path::end())
+            .and(auth::credential())
+            .map(move |credential: Option<Credential>| {
+                api::handleWebhookTest(credential, &data_manager, &config)
+                    .toResponse()
+            });
+
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let api_library = warp::get().and(warp::path("api"))
+            .and(warp::path("v1")).and(warp::path("library"))
+            .and(warp::path::end()).and(auth::credential())
+            .map(move |credential: Option<Credential>| {
+                api::handleLibrary(credential, &data_manager, &config)
+                    .toResponse()
+            });
+
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let limiter_clone = limiter.clone();
+        let like = warp::post().and(warp::path("p")).and(warp::path::param())
+            .and(warp::path("like")).and(warp::path::end())
+            .and(warp::filters::cookie::optional(LIKER_COOKIE))
+            .and(proxy::remote(proxies.clone()))
+            .map(move |post_ref: String, liker: Option<String>,
+                 remote: Option<SocketAddr>| {
+                handleLike(&post_ref, liker, remote, &limiter_clone,
+                           &data_manager, &config).toResponse()
+            });
+
+        let config = self.config.clone();
+        let data_manager = self.data_manager.clone();
+        let limiter_clone = limiter.clone();
+        let api_manifest = warp::get().and(warp::path("api"))
+            .and(warp::path("v1")).and(warp::path("manifest"))
+            .and(warp::path::end())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(api_client.clone())
+            .map(move |query: HashMap<String, String>, client: api::ApiClient| {
+                api::handleManifest(&query, &client, &limiter_clone,
+                                    &data_manager, &config).toResponse()
             });
 
-        let bare_route = statics.or(index).or(post).or(feed).or(delete_confirm)
-            .or(delete).or(upload_page).or(upload).or(login);
+        // Long chains of `or` make deeply nested futures, which can
+        // overflow the stack. So the routes are boxed in groups.
+        let page_routes = statics.or(index).or(post).or(feed).or(sitemap)
+            .or(robots).or(print_archive).or(delete_confirm).or(map)
+            .or(map_geojson).or(albums)
+            .map(Reply::into_response).boxed();
+        let action_routes = delete.or(redact).or(setup_page).or(setup)
+            .or(shared).or(share_create).or(share_revoke).or(album_cover)
+            .or(post_album).or(post_sensitive).or(post_license)
+            .map(Reply::into_response).boxed();
+        let admin_routes = upload_page.or(upload).or(admin).or(set_language)
+            .or(set_layout).or(set_pref)
+            .or(publish).or(quarantine_action).or(login).or(login_form)
+            .or(api_token_create).or(api_token_revoke).or(logout).or(sessions)
+            .or(delete_sessions)
+            .map(Reply::into_response).boxed();
+        let library_routes = trash_page.or(trash_action).or(duplicates_page)
+            .or(suggestion_action).or(post_download).or(album_download)
+            .or(webhook_test)
+            .map(Reply::into_response).boxed();
+        let api_routes = api_posts.or(api_post).or(api_manifest).or(like)
+            .or(api_arrange_images).or(api_library).or(api_upload)
+            .or(api_move_post).or(api_move_posts).or(api_webhook_test)
+            .map(Reply::into_response).boxed();
+        let bare_route = page_routes.or(action_routes).or(admin_routes)
+            .or(library_routes).or(api_routes)
+            .or(post_by_slug.map(Reply::into_response).boxed());
         let route = if self.config.serve_under_path == String::from("/") ||
             self.config.serve_under_path.is_empty()
         {
@@ -491,15 +2723,335 @@ impl App
             r.and(bare_route).boxed()
         };
 
+        if let Some(mail_config) = &self.config.mail_in
+        {
+            mail::spawn(mail_config.clone(), self.data_manager.clone(),
+                        self.config.clone())?;
+        }
+        views::spawnFlusher(self.views.clone(), self.data_manager.clone());
+        schedule::spawn(self.data_manager.clone(), self.config.clone());
+        delivery::spawn(self.data_manager.clone(), self.config.clone());
+        cleanup::spawn(self.config.clone());
+        trash::spawn(self.data_manager.clone(), self.config.clone());
+        backup::spawn(self.data_manager.clone(), self.config.clone());
+        if let Some(watch_config) = &self.config.watch_folder
+        {
+            watch::spawn(watch_config.clone(), self.data_manager.clone(),
+                         self.config.clone());
+        }
+        if let Some(matrix_config) = &self.config.matrix
+        {
+            matrix::spawn(matrix_config.clone(), self.data_manager.clone(),
+                          self.config.clone());
+        }
+
+        let addr = std::net::SocketAddr::new(
+            self.config.listen_address.parse().map_err(
+                |_| rterr!("Invalid listen address: {}",
+                           self.config.listen_address))?,
+            self.config.listen_port);
+        let mut route = error::negotiate(route.map(Reply::into_response).boxed());
+        if self.config.security_headers.enabled
+        {
+            route = security_headers::wrap(
+                route, self.config.security_headers.clone());
+        }
+        if let Some(log_config) = &self.config.access_log
+        {
+            route = access_log::wrap(route, log_config.clone(), proxies.clone());
+        }
+        if let Some(tls_config) = &self.config.tls
+        {
+            return tls::serve(route, addr, tls_config, &self.config).await;
+        }
+
         info!("Listening at {}:{}...", self.config.listen_address,
               self.config.listen_port);
+        warp::serve(route).run(addr).await;
+        Ok(())
+    }
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::config::ImageEncoding;
+
+    #[test]
+    fn pageWindowHasGaps()
+    {
+        assert_eq!(pageWindow(1, 1, 2), vec![1]);
+        assert_eq!(pageWindow(2, 4, 2), vec![1, 2, 3, 4]);
+        assert_eq!(pageWindow(1, 10, 2), vec![1, 2, 3, 0, 10]);
+        assert_eq!(pageWindow(6, 10, 2), vec![1, 0, 4, 5, 6, 7, 8, 0, 10]);
+        assert_eq!(pageWindow(10, 10, 2), vec![1, 0, 8, 9, 10]);
+    }
+
+    #[test]
+    fn likeIpsAreHashedWithSavedKey() -> Result<(), Error>
+    {
+        let dir = std::env::temp_dir().join(
+            "nspic-test-".to_owned() + &rand::random::<u64>().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut config = Configuration {
+            data_dir: dir.to_str().unwrap().to_owned(),
+            ..Default::default()
+        };
+        assert!(likeIpHash("192.0.2.1", &config).is_err());
+        let key = loadLikeKey(&config)?;
+        assert_eq!(key.len(), 64);
+        assert_eq!(loadLikeKey(&config)?, key);
+        config.like_key = Some(key);
+        let hash = likeIpHash("192.0.2.1", &config)?;
+        assert_eq!(hash, likeIpHash("192.0.2.1", &config)?);
+        assert_ne!(hash, likeIpHash("192.0.2.2", &config)?);
+        config.like_key = Some(String::from("another key"));
+        assert_ne!(hash, likeIpHash("192.0.2.1", &config)?);
+        std::fs::remove_dir_all(&dir).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn existingAlternatesComeFirst() -> Result<(), Error>
+    {
+        let dir = std::env::temp_dir().join(
+            "nspic-test-".to_owned() + &rand::random::<u64>().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = Configuration {
+            image_dir: dir.to_str().unwrap().to_owned(),
+            alternate_encodings: vec![ImageEncoding::Avif, ImageEncoding::WebP],
+            ..Default::default()
+        };
+        for name in ["a.avif", "a_t.avif", "a.webp"]
+        {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let mut post = Post::new();
+        post.images.push(Image {
+            path: PathBuf::from("a.jpg"),
+            width: 1000,
+            height: 500,
+            ..Default::default()
+        });
+        let mut posts = [post];
+        fillImageSources(&mut posts, &config);
+        std::fs::remove_dir_all(&dir).ok();
+
+        // The WebP thumbnail is missing, so WebP is not offered.
+        let sources = &posts[0].images[0].sources;
+        assert_eq!(sources.len(), 2);
+        assert_eq!(sources[0].mime_type, "image/avif");
+        assert_eq!(sources[0].srcset(), "/image/a_t.avif 256w, /image/a.avif 1000w");
+        assert_eq!(sources[1].mime_type, "image/jpeg");
+        Ok(())
+    }
+
+    fn readParts(body: &str, config: Configuration) ->
+        Vec<Result<UploadPart, Error>>
+    {
+        let filter = warp::multipart::form().then(
+            move |form: warp::multipart::FormData| {
+                let config = config.clone();
+                async move { readUploadParts(form, &config).await }
+            });
+        tokio::runtime::Runtime::new().unwrap().block_on(
+            warp::test::request().method("POST")
+                .header("content-type", "multipart/form-data; boundary=X")
+                .body(body).filter(&filter)).ok().unwrap()
+    }
+
+    #[test]
+    fn truncatedUploadIsBadRequest()
+    {
+        let dir = std::env::temp_dir().join(
+            "nspic-test-".to_owned() + &rand::random::<u64>().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = Configuration {
+            image_dir: dir.to_str().unwrap().to_owned(),
+            ..Default::default()
+        };
+        // The file part ends before its boundary.
+        let body = "--X\r\nContent-Disposition: form-data; name=\"Desc\"\r\n\r\n\
+                    A cat\r\n--X\r\nContent-Disposition: form-data; \
+                    name=\"FileToUpload\"; filename=\"a.jpg\"\r\n\
+                    Content-Type: image/jpeg\r\n\r\nabcd";
+        let parts = readParts(body, config);
+        let leftovers = std::fs::read_dir(&dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).ok();
+
+        assert!(matches!(&parts[0], Ok(UploadPart::Desc(desc)) if desc == "A cat"));
+        assert!(parts.len() > 1);
+        assert!(parts[1..].iter().all(
+            |p| matches!(p, Err(Error::HTTPStatus(StatusCode::BAD_REQUEST, _)))));
+        assert_eq!(leftovers, 0);
+    }
+
+    #[test]
+    fn zipImagesAreLimited()
+    {
+        let dir = std::env::temp_dir().join(
+            "nspic-test-".to_owned() + &rand::random::<u64>().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = Configuration {
+            image_dir: dir.to_str().unwrap().to_owned(),
+            ..Default::default()
+        };
+        let mut data = Vec::new();
+        let mut headers = Vec::new();
+        for name in ["a.jpg", "b.txt", "c.jpg", "d.jpg"]
+        {
+            let content: &[u8] = if name.ends_with(".jpg")
+            {
+                b"\xff\xd8\xff\xe0 image"
+            }
+            else
+            {
+                b"not an image"
+            };
+            let header = zip::EntryHeader {
+                name: name.to_owned(),
+                crc: 0,
+                size: content.len() as u32,
+                offset: data.len() as u32,
+                modified: OffsetDateTime::UNIX_EPOCH,
+            };
+            data.extend(zip::localHeader(&header));
+            data.extend(content);
+            headers.push(header);
+        }
+        let offset = data.len() as u32;
+        data.extend(zip::centralDirectory(&headers, offset));
+
+        let budget = |images, bytes| ZipBudget { images, bytes };
+        let all = zipImages(&data, budget(None, 100), &config);
+        let fitting = zipImages(&data, budget(Some(3), 100), &config);
+        let too_many = zipImages(&data, budget(Some(2), 100), &config);
+        let too_large = zipImages(&data, budget(None, 40), &config);
+        let (images, left) = all.unwrap();
+        for img in images.iter().chain(fitting.as_ref().unwrap().0.iter())
+        {
+            std::fs::remove_file(&img.path).unwrap();
+        }
+        let leftovers = std::fs::read_dir(&dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(images.len(), 3);
+        assert_eq!(left.images, None);
+        // Three 10 byte images and the 12 byte text file.
+        assert_eq!(left.bytes, 100 - 42);
+        assert_eq!(fitting.unwrap().1.images, Some(0));
+        assert!(matches!(too_many,
+                         Err(Error::HTTPStatus(StatusCode::PAYLOAD_TOO_LARGE, _))));
+        assert!(matches!(too_large,
+                         Err(Error::HTTPStatus(StatusCode::BAD_REQUEST, _))));
+        assert_eq!(leftovers, 0);
+    }
+
+    #[test]
+    fn unknownPartsAreIgnored()
+    {
+        let body = "--X\r\nContent-Disposition: form-data; name=\"Extra\"\r\n\r\n\
+                    1\r\n--X\r\nContent-Disposition: form-data; name=\"Alt-1\"\r\n\
+                    \r\nA cat\r\n--X--\r\n";
+        let parts = readParts(body, Configuration::default());
+        assert!(matches!(&parts[0], Ok(UploadPart::Unknown(name)) if name == "Extra"));
+        assert!(matches!(&parts[1], Ok(UploadPart::AltText(1, text)) if text == "A cat"));
+        let strict = Configuration {
+            upload_ignore_unknown_parts: false,
+            ..Default::default()
+        };
+        assert!(readParts(body, strict)[0].is_err());
+    }
+
+    #[test]
+    fn uploadTagsAreSplitByCommas()
+    {
+        assert_eq!(tagsFromForm(" cat, old sofa,,Cat "), vec!["cat", "old sofa"]);
+        assert!(tagsFromForm("").is_empty());
+    }
+
+    #[test]
+    fn uploadRespondsWithPostLinks() -> Result<(), Error>
+    {
+        let created = vec![
+            serde_json::json!({"id": 3, "url": "http://example.org/p/3"}),
+            serde_json::json!({"id": 4, "url": "http://example.org/p/4"})];
+        let response = uploadResponse(created.clone(), None)?;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(response.headers()["location"], "http://example.org/p/3");
+        let response = uploadResponse(created, Some("application/json"))?;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert!(uploadResponse(Vec::new(), None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn imagesAreOrderedByIndex()
+    {
+        let image = |name: &str| RawImage {
+            path: PathBuf::from(name),
+            hash: String::new(),
+            original_filename: name.to_owned(),
+            license: None,
+            upload: None,
+        };
+        let ordered = orderImages(vec![(Some(2), image("a")), (None, image("b")),
+                                       (Some(0), image("c"))]);
+        let names: Vec<(usize, &str)> = ordered.iter()
+            .map(|(i, img)| (*i, img.original_filename.as_str())).collect();
+        assert_eq!(names, vec![(0, "c"), (1, "b"), (2, "a")]);
+    }
+
+    #[test]
+    fn pagesNeedNoInlineStyles() -> Result<(), Error>
+    {
+        let config = Configuration {
+            password: String::from("pw"),
+            ..Default::default()
+        };
+        // The default CSP blocks inline styles and scripts.
+        let csp = &config.security_headers.content_security_policy;
+        assert!(csp.contains("style-src 'self';"));
+        assert!(!csp.contains("unsafe-inline"));
+
+        let catalogs = Arc::new(Catalogs::fromConfig(&config)?);
+        let mut templates = Tera::new(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/templates/**/*")).map_err(
+            |e| rterr!("Failed to compile templates: {}", e))?;
+        templates.register_function("url_for", makeURLFor(String::new()));
+        templates.register_function("t", catalogs.teraFunction());
+        let dir = std::env::temp_dir().join(
+            "nspic-test-".to_owned() + &rand::random::<u64>().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut data_manager = data::Manager::newWithFilename(dir.join("db.sqlite"));
+        data_manager.connect()?;
+        data_manager.init()?;
+        let post = Post {
+            images: vec![Image {
+                path: PathBuf::from("a.jpg"),
+                color: String::from("#336699"),
+                ..Default::default()
+            }],
+            ..Post::new()
+        };
+        data_manager.addPost(&post, None)?;
 
-        warp::serve(route).run(
-            std::net::SocketAddr::new(
-                self.config.listen_address.parse().map_err(
-                    |_| rterr!("Invalid listen address: {}",
-                               self.config.listen_address))?,
-                self.config.listen_port)).await;
+        let response = handleIndex(
+            &templates, &HashMap::new(), &data_manager, &config,
+            &catalogs.select(None, None), &Prefs::default(),
+            Layout::parse("grid-4").unwrap(), &PageCache::new(&config), None);
+        std::fs::remove_dir_all(&dir).ok();
+        let response = response?;
+        let body = tokio::runtime::Runtime::new().unwrap().block_on(
+            warp::hyper::body::to_bytes(response.into_body())).unwrap();
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains(r##"data-color="#336699""##));
+        assert!(body.contains("Columns4"));
+        assert!(!body.contains(" style="));
+        assert!(!body.contains("<script>"));
         Ok(())
     }
 }
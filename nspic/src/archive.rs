@@ -0,0 +1,347 @@
+// Export the library into a self-contained tar.gz archive, and
+// import it back. The archive contains a `manifest.json` with the
+// database content, and the image files under `images/`, with the
+// same relative paths as in the image dir.
+
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::error::Error;
+use crate::config::Configuration;
+use crate::data;
+use crate::post::{Album, Image, Location, Post, Visibility};
+
+static MANIFEST_NAME: &str = "manifest.json";
+static IMAGES_DIR: &str = "images";
+static ARCHIVE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct ArchivedImage
+{
+    path: String,
+    width: u32,
+    height: u32,
+    #[serde(default)]
+    alt_text: String,
+    #[serde(default)]
+    caption: String,
+    #[serde(default)]
+    color: String,
+    #[serde(default)]
+    blurhash: String,
+    #[serde(default)]
+    phash: Option<u64>,
+    /// Byte sizes of the image and the thumbnail. Older archives
+    /// don’t have them, and the imported files are measured instead.
+    #[serde(default)]
+    size: Option<u64>,
+    #[serde(default)]
+    thumbnail_size: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ArchivedPost
+{
+    id: i64,
+    desc: String,
+    upload_time: i64,
+    album_id: Option<i64>,
+    #[serde(default)]
+    redacted: bool,
+    #[serde(default)]
+    slug: Option<String>,
+    #[serde(default)]
+    draft: bool,
+    #[serde(default)]
+    visibility: Visibility,
+    #[serde(default)]
+    scheduled: bool,
+    #[serde(default)]
+    location: Option<Location>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    sensitive: bool,
+    #[serde(default)]
+    license: Option<String>,
+    images: Vec<ArchivedImage>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ArchivedAlbum
+{
+    id: i64,
+    title: String,
+    #[serde(default)]
+    cover: Option<String>,
+    #[serde(default)]
+    parent: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Manifest
+{
+    version: u32,
+    albums: Vec<ArchivedAlbum>,
+    posts: Vec<ArchivedPost>,
+}
+
+impl ArchivedPost
+{
+    fn fromPost(post: &Post) -> Result<Self, Error>
+    {
+        let images: Result<Vec<ArchivedImage>, Error> = post.images.iter()
+            .map(|img| Ok(ArchivedImage {
+                path: img.path.to_str().ok_or_else(
+                    || rterr!("Invalid image path: {:?}", img.path))?
+                    .to_owned(),
+                width: img.width,
+                height: img.height,
+                alt_text: img.alt_text.clone(),
+                caption: img.caption.clone(),
+                color: img.color.clone(),
+                blurhash: img.blurhash.clone(),
+                phash: img.phash,
+                size: Some(img.size),
+                thumbnail_size: Some(img.thumbnail_size),
+            })).collect();
+        Ok(Self {
+            id: post.id,
+            desc: post.desc.clone(),
+            upload_time: post.upload_time.unix_timestamp(),
+            album_id: post.album_id,
+            redacted: post.redacted,
+            slug: post.slug.clone(),
+            draft: post.draft,
+            visibility: post.visibility,
+            scheduled: post.scheduled,
+            location: post.location.clone(),
+            tags: post.tags.clone(),
+            sensitive: post.sensitive,
+            license: post.license.clone(),
+            images: images?,
+        })
+    }
+
+    /// The post, with its images already extracted to `image_dir`.
+    fn toPost(self, image_dir: &Path) -> Result<Post, Error>
+    {
+        let mut post = Post::new();
+        post.id = self.id;
+        post.desc = self.desc;
+        post.upload_time = OffsetDateTime::from_unix_timestamp(self.upload_time)
+            .map_err(|_| rterr!("Invalid upload time in post {}", self.id))?;
+        post.album_id = self.album_id;
+        post.redacted = self.redacted;
+        post.slug = self.slug;
+        post.draft = self.draft;
+        post.visibility = self.visibility;
+        post.scheduled = self.scheduled;
+        post.location = self.location;
+        post.tags = self.tags;
+        post.sensitive = self.sensitive;
+        post.license = self.license;
+        let size = |path: &Path| std::fs::metadata(image_dir.join(path))
+            .map(|m| m.len()).unwrap_or(0);
+        let images: Result<Vec<Image>, Error> = self.images.into_iter().map(|img| {
+            let mut image = Image {
+                path: PathBuf::from(img.path),
+                width: img.width,
+                height: img.height,
+                alt_text: img.alt_text,
+                caption: img.caption,
+                color: img.color,
+                blurhash: img.blurhash,
+                phash: img.phash,
+                ..Default::default()
+            };
+            image.size = img.size.unwrap_or_else(|| size(&image.path));
+            image.thumbnail_size = match img.thumbnail_size
+            {
+                Some(thumbnail_size) => thumbnail_size,
+                None => size(&image.thumbnail()?),
+            };
+            Ok(image)
+        }).collect();
+        post.images = images?;
+        Ok(post)
+    }
+}
+
+fn appendFile<W: Write>(builder: &mut tar::Builder<W>, image_dir: &Path,
+                        rel_path: &Path) -> Result<(), Error>
+{
+    let full_path = image_dir.join(rel_path);
+    if !full_path.exists()
+    {
+        warn!("Image file {:?} is missing. Skipping...", full_path);
+        return Ok(());
+    }
+    builder.append_path_with_name(&full_path, Path::new(IMAGES_DIR)
+                                  .join(rel_path))
+        .map_err(|e| rterr!("Failed to add {:?} to archive: {}", full_path, e))
+}
+
+/// Write the whole library into a tar.gz file at `path`.
+pub fn export(path: &Path, data_manager: &data::Manager,
+              config: &Configuration) -> Result<(), Error>
+{
+    let posts = data_manager.getAllPosts()?;
+    let albums = data_manager.getAlbums()?;
+    let manifest = Manifest {
+        version: ARCHIVE_VERSION,
+        albums: albums.iter().map(|a| ArchivedAlbum {
+            id: a.id,
+            title: a.title.clone(),
+            cover: a.cover.clone(),
+            parent: a.parent,
+        }).collect(),
+        posts: posts.iter().map(ArchivedPost::fromPost)
+            .collect::<Result<Vec<_>, Error>>()?,
+    };
+    let manifest = serde_json::to_vec_pretty(&manifest).map_err(
+        |e| rterr!("Failed to serialize manifest: {}", e))?;
+
+    let f = File::create(path).map_err(
+        |e| rterr!("Failed to create archive at {:?}: {}", path, e))?;
+    let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+        f, flate2::Compression::default()));
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(OffsetDateTime::now_utc().unix_timestamp() as u64);
+    header.set_cksum();
+    builder.append_data(&mut header, MANIFEST_NAME, manifest.as_slice())
+        .map_err(|e| rterr!("Failed to add manifest to archive: {}", e))?;
+
+    let image_dir = Path::new(&config.image_dir);
+    for post in &posts
+    {
+        for img in &post.images
+        {
+            appendFile(&mut builder, image_dir, &img.path)?;
+            appendFile(&mut builder, image_dir, &img.thumbnail()?)?;
+        }
+    }
+    builder.into_inner().and_then(|gz| gz.finish()).map_err(
+        |e| rterr!("Failed to finish archive: {}", e))?;
+    info!("Exported {} posts to {:?}.", posts.len(), path);
+    Ok(())
+}
+
+/// Import a tar.gz file created by `export()`. The library has to be
+/// empty, also of posts in the trash. Post IDs are kept, so that the
+/// permalinks still work.
+pub fn import(path: &Path, data_manager: &data::Manager,
+              config: &Configuration) -> Result<(), Error>
+{
+    if data_manager.hasPosts()?
+    {
+        return Err(rterr!("Refusing to import into a non-empty library"));
+    }
+    let f = File::open(path).map_err(
+        |e| rterr!("Failed to open archive at {:?}: {}", path, e))?;
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(f));
+    let image_dir = Path::new(&config.image_dir);
+    let mut manifest: Option<Manifest> = None;
+    for entry in archive.entries().map_err(
+        |e| rterr!("Failed to read archive: {}", e))?
+    {
+        let mut entry = entry.map_err(
+            |e| rterr!("Failed to read archive entry: {}", e))?;
+        let entry_path = entry.path().map_err(
+            |e| rterr!("Invalid path in archive: {}", e))?.into_owned();
+        if entry_path == Path::new(MANIFEST_NAME)
+        {
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content).map_err(
+                |e| rterr!("Failed to read manifest: {}", e))?;
+            manifest = Some(serde_json::from_slice(&content).map_err(
+                |e| rterr!("Invalid manifest: {}", e))?);
+        }
+        else if let Ok(rel_path) = entry_path.strip_prefix(IMAGES_DIR)
+        {
+            if !rel_path.components().all(
+                |c| matches!(c, std::path::Component::Normal(_)))
+            {
+                return Err(rterr!("Invalid path in archive: {:?}", entry_path));
+            }
+            let target = image_dir.join(rel_path);
+            if let Some(parent) = target.parent()
+            {
+                std::fs::create_dir_all(parent).map_err(
+                    |e| rterr!("Failed to create dir {:?}: {}", parent, e))?;
+            }
+            entry.unpack(&target).map_err(
+                |e| rterr!("Failed to extract {:?}: {}", entry_path, e))?;
+        }
+        else
+        {
+            warn!("Unknown entry {:?} in archive. Ignoring...", entry_path);
+        }
+    }
+
+    let manifest = manifest.ok_or_else(|| rterr!("No manifest in archive"))?;
+    if manifest.version != ARCHIVE_VERSION
+    {
+        return Err(rterr!("Unsupported archive version {}", manifest.version));
+    }
+    for album in manifest.albums
+    {
+        data_manager.importAlbum(&Album {
+            id: album.id, title: album.title, cover: album.cover,
+            parent: album.parent })?;
+    }
+    let count = manifest.posts.len();
+    for post in manifest.posts
+    {
+        data_manager.importPost(&post.toPost(image_dir)?)?;
+    }
+    info!("Imported {} posts from {:?}.", count, path);
+    Ok(())
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn missingSizesAreMeasured() -> Result<(), Error>
+    {
+        let dir = std::env::temp_dir().join(
+            "nspic-test-".to_owned() + &rand::random::<u64>().to_string());
+        std::fs::create_dir_all(dir.join("a")).unwrap();
+        std::fs::write(dir.join("a/abc.jpg"), b"image").unwrap();
+        std::fs::write(dir.join("a/abc_t.jpg"), b"thumb!").unwrap();
+        // From an archive before the sizes were exported.
+        let old: ArchivedPost = serde_json::from_str(
+            r#"{"id": 1, "desc": "", "upload_time": 0, "album_id": null,
+                "images": [{"path": "a/abc.jpg", "width": 1, "height": 1}]}"#)
+            .unwrap();
+        let measured = old.toPost(&dir);
+        std::fs::remove_dir_all(&dir).ok();
+        let image = &measured?.images[0];
+        assert_eq!((image.size, image.thumbnail_size), (5, 6));
+
+        let post = Post {
+            images: vec![Image {
+                path: PathBuf::from("a/abc.jpg"),
+                size: 100,
+                thumbnail_size: 10,
+                ..Default::default()
+            }],
+            ..Post::new()
+        };
+        let image = &ArchivedPost::fromPost(&post)?.toPost(&dir)?.images[0];
+        assert_eq!((image.size, image.thumbnail_size), (100, 10));
+        Ok(())
+    }
+}
@@ -1,27 +1,241 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use warp::Filter;
 use warp::http::status::StatusCode;
 use warp::Reply;
 use warp::reply::Response;
 use base64::engine::Engine;
+use argon2::Argon2;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier,
+                            SaltString};
+use log::{info, warn};
+use log::error as log_error;
+use sha2::{Digest, Sha256};
+use tera::Tera;
+use time::{Duration, OffsetDateTime};
 
 use crate::error::Error;
 use crate::config::Configuration;
 use crate::data;
 use crate::utils::uriFromStr;
+use crate::i18n::Catalog;
+use crate::prefs::Prefs;
 
 static BASE64: &base64::engine::general_purpose::GeneralPurpose =
     &base64::engine::general_purpose::STANDARD;
 static BASE64_NO_PAD: &base64::engine::general_purpose::GeneralPurpose =
     &base64::engine::general_purpose::STANDARD_NO_PAD;
 pub static TOKEN_COOKIE: &str = "nspic-token";
+/// The username used with the `password` in the config.
+pub static DEFAULT_USERNAME: &str = "user";
+/// Failed logins are forgotten after this long without another one.
+const LOGIN_FAILURE_MEMORY: Duration = Duration::days(1);
+const LOGIN_LOCKOUT_MAX: Duration = Duration::days(1);
 
 fn createToken() -> String
 {
     BASE64_NO_PAD.encode(rand::random::<i128>().to_ne_bytes())
 }
 
-fn makeCookie(token: String, session_life_time: u64) -> String
+/// A long-lived token for scripts, which is sent in an `Authorization:
+/// Bearer` header. Only a digest of the token itself is stored.
+pub struct ApiToken
+{
+    pub id: i64,
+    pub name: String,
+    pub created: OffsetDateTime,
+    pub last_used: Option<OffsetDateTime>,
+}
+
+impl Serialize for ApiToken
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let format = time::format_description::parse_borrowed::<2>(
+            "[year]-[month]-[day] [hour]:[minute]:[second] UTC").unwrap();
+        let format_time = |t: &OffsetDateTime| t.format(&format).map_err(
+            |_| serde::ser::Error::custom("Invalid time"));
+        let mut state = serializer.serialize_struct("ApiToken", 4)?;
+        state.serialize_field("id", &self.id)?;
+        state.serialize_field("name", &self.name)?;
+        state.serialize_field("created_utc_str", &format_time(&self.created)?)?;
+        state.serialize_field("last_used_utc_str",
+                              &self.last_used.as_ref().map(format_time)
+                              .transpose()?)?;
+        state.end()
+    }
+}
+
+pub struct Session
+{
+    pub token: String,
+    pub auth_time: OffsetDateTime,
+    pub user_agent: Option<String>,
+}
+
+/// A session as it is shown on the sessions page, without the token.
+#[derive(serde::Serialize)]
+struct SessionInfo<'a>
 {
-    format!("{}={}; Max-Age={}; Path=/", TOKEN_COOKIE, token, session_life_time)
+    auth_time_utc_str: String,
+    user_agent: Option<&'a str>,
+    /// Whether this is the session of the request.
+    current: bool,
+}
+
+/// How a request is authenticated.
+pub enum Credential
+{
+    /// The token in the session cookie.
+    Session(String),
+    /// An API token from the Authorization header.
+    ApiToken(String),
+}
+
+/// A random token that can be put in a URL.
+pub fn createUrlToken() -> String
+{
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(
+        rand::random::<[u8; 16]>())
+}
+
+/// The session cookie with `value`, which lives for `max_age` seconds.
+fn sessionCookie(value: &str, max_age: u64, config: &Configuration) -> String
+{
+    let mut cookie = format!("{}={}; Max-Age={}; Path=/; SameSite={}",
+                             TOKEN_COOKIE, value, max_age,
+                             config.cookie.same_site.name());
+    if config.cookieIsSecure()
+    {
+        cookie.push_str("; Secure");
+    }
+    if config.cookie.http_only
+    {
+        cookie.push_str("; HttpOnly");
+    }
+    cookie
+}
+
+fn makeCookie(token: String, config: &Configuration) -> String
+{
+    sessionCookie(&token, config.session_life_time_sec, config)
+}
+
+fn clearCookie(config: &Configuration) -> String
+{
+    sessionCookie("", 0, config)
+}
+
+/// Hash a password into a PHC string with Argon2.
+pub fn hashPassword(password: &str) -> Result<String, Error>
+{
+    let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>())
+        .map_err(|e| rterr!("Failed to generate salt: {}", e))?;
+    Argon2::default().hash_password(password.as_bytes(), &salt)
+        .map(|h| h.to_string())
+        .map_err(|e| rterr!("Failed to hash password: {}", e))
+}
+
+fn verifyPassword(password: &str, hash: &str) -> bool
+{
+    match PasswordHash::new(hash)
+    {
+        Ok(parsed) => Argon2::default().verify_password(
+            password.as_bytes(), &parsed).is_ok(),
+        Err(e) => {
+            log_error!("Invalid password hash: {}", e);
+            false
+        },
+    }
+}
+
+/// Decode the credential of Basic authentication into the username
+/// and the password.
+fn decodeBasic(auth_value: &str) -> Result<(String, String), Error>
+{
+    let decoded = BASE64.decode(auth_value).ok()
+        .and_then(|d| String::from_utf8(d).ok())
+        .ok_or_else(|| Error::HTTPStatus(StatusCode::BAD_REQUEST,
+                                         "Invalid credential".to_owned()))?;
+    let (username, password) = decoded.split_once(':').ok_or_else(
+        || Error::HTTPStatus(StatusCode::BAD_REQUEST,
+                             "Invalid credential".to_owned()))?;
+    Ok((username.to_owned(), password.to_owned()))
+}
+
+/// Check a username and password. A user in the database is checked
+/// against its password hash. Otherwise the password in the config is
+/// used.
+fn checkPassword(username: &str, password: &str, data_manager: &data::Manager,
+                 config: &Configuration) -> Result<bool, Error>
+{
+    if let Some(hash) = data_manager.findPasswordHash(username)?
+    {
+        Ok(verifyPassword(password, &hash))
+    }
+    else if username != DEFAULT_USERNAME
+    {
+        Ok(false)
+    }
+    else if !config.password_hash.is_empty()
+    {
+        Ok(verifyPassword(password, &config.password_hash))
+    }
+    else
+    {
+        Ok(!config.password.is_empty() &&
+           constantTimeEq(password.as_bytes(), config.password.as_bytes()))
+    }
+}
+
+/// Compare the digests of `a` and `b`, which takes the same time
+/// wherever they differ.
+fn constantTimeEq(a: &[u8], b: &[u8]) -> bool
+{
+    let (a, b) = (Sha256::digest(a), Sha256::digest(b));
+    a.iter().zip(b.iter()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Ask for a new password twice on the terminal.
+fn promptNewPassword(prompt: &str) -> Result<String, Error>
+{
+    let password = rpassword::prompt_password(prompt)
+        .map_err(|e| rterr!("Failed to read password: {}", e))?;
+    if password.is_empty()
+    {
+        return Err(rterr!("Password cannot be empty"));
+    }
+    let again = rpassword::prompt_password("Again: ")
+        .map_err(|e| rterr!("Failed to read password: {}", e))?;
+    if password != again
+    {
+        return Err(rterr!("Passwords do not match"));
+    }
+    Ok(password)
+}
+
+/// Interactively set the password of a user in the database. All
+/// sessions are removed afterwards.
+pub fn setPasswordInteractively(username: &str, data_manager: &data::Manager) ->
+    Result<(), Error>
+{
+    let password = promptNewPassword(&format!("New password for {}: ", username))?;
+    data_manager.setPasswordHash(username, &hashPassword(&password)?)?;
+    data_manager.deleteAllSessions()?;
+    println!("Password of {} is set.", username);
+    Ok(())
+}
+
+/// Interactively hash a password for `password_hash` in the config.
+pub fn printPasswordHash() -> Result<(), Error>
+{
+    let password = promptNewPassword("Password: ")?;
+    println!("{}", hashPassword(&password)?);
+    Ok(())
 }
 
 pub fn validateSession(token: &Option<String>, data_manager: &data::Manager,
@@ -30,8 +244,7 @@ pub fn validateSession(token: &Option<String>, data_manager: &data::Manager,
     if let Some(token) = token
     {
         data_manager.expireSessions(config.session_life_time_sec)?;
-        data_manager.hasSession(&token)?;
-        Ok(true)
+        Ok(data_manager.hasSession(token)?.is_some())
     }
     else
     {
@@ -39,39 +252,445 @@ pub fn validateSession(token: &Option<String>, data_manager: &data::Manager,
     }
 }
 
-pub fn handleLogin(
-    auth_value_maybe: Option<String>, data_manager: &data::Manager,
-    config: &Configuration) -> Result<Response, Error>
+/// A filter that extracts the credential of the request. An API token
+/// takes precedence over the session cookie.
+pub fn credential() ->
+    impl Filter<Extract = (Option<Credential>,), Error = warp::Rejection> + Clone
+{
+    warp::header::optional::<String>("authorization")
+        .and(warp::filters::cookie::optional(TOKEN_COOKIE))
+        .map(|auth_value: Option<String>, token: Option<String>| {
+            auth_value.as_deref().and_then(|v| v.strip_prefix("Bearer "))
+                .map(|t| Credential::ApiToken(t.trim().to_owned()))
+                .or(token.map(Credential::Session))
+        })
+}
+
+fn hashApiToken(token: &str) -> String
+{
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Like `validateSession()`, but an API token is also accepted.
+pub fn validateCredential(credential: &Option<Credential>,
+                          data_manager: &data::Manager, config: &Configuration) ->
+    Result<bool, Error>
+{
+    match credential
+    {
+        Some(Credential::Session(token)) =>
+            validateSession(&Some(token.clone()), data_manager, config),
+        Some(Credential::ApiToken(token)) =>
+            data_manager.useApiToken(&hashApiToken(token)),
+        None => Ok(false),
+    }
+}
+
+/// Create an API token named `name`. Return its ID and the token,
+/// which can not be retrieved later.
+pub fn createApiToken(name: &str, data_manager: &data::Manager) ->
+    Result<(i64, String), Error>
+{
+    let token = createUrlToken();
+    let id = data_manager.addApiToken(name, &hashApiToken(&token))?;
+    info!("Created API token {} ({}).", id, name);
+    Ok((id, token))
+}
+
+pub fn revokeApiToken(id: i64, data_manager: &data::Manager) -> Result<(), Error>
+{
+    if !data_manager.deleteApiToken(id)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::NOT_FOUND,
+                                     format!("No API token {}", id)));
+    }
+    info!("Revoked API token {}.", id);
+    Ok(())
+}
+
+/// Print the API tokens in the database, for the CLI.
+pub fn printApiTokens(data_manager: &data::Manager) -> Result<(), Error>
+{
+    for token in data_manager.getApiTokens()?
+    {
+        let used = token.last_used.map(|t| t.unix_timestamp().to_string())
+            .unwrap_or_else(|| String::from("never"));
+        println!("{}\t{}\tcreated {}\tlast used {}", token.id, token.name,
+                 token.created.unix_timestamp(), used);
+    }
+    Ok(())
+}
+
+/// How long a client is locked out after `failures` failed logins in
+/// a row.
+fn lockoutDuration(failures: u32, config: &Configuration) -> Duration
+{
+    if failures < config.login_attempts_max
+    {
+        return Duration::ZERO;
+    }
+    let doublings = (failures - config.login_attempts_max).min(20);
+    let seconds = config.login_lockout_sec.saturating_mul(1 << doublings);
+    Duration::seconds(i64::try_from(seconds).unwrap_or(i64::MAX))
+        .min(LOGIN_LOCKOUT_MAX)
+}
+
+/// If `client` is locked out, the time when the lockout ends.
+fn lockedUntil(client: &str, now: OffsetDateTime, data_manager: &data::Manager,
+               config: &Configuration) -> Result<Option<OffsetDateTime>, Error>
 {
-    if let Some(auth_value) = auth_value_maybe
+    Ok(data_manager.loginFailures(client)?.map(
+        |(count, last_time)| last_time + lockoutDuration(count, config))
+       .filter(|until| *until > now))
+}
+
+enum LoginResult
+{
+    /// The response that sets the session cookie.
+    LoggedIn(Response),
+    /// Try again after this long.
+    LockedOut(Duration),
+    Failed,
+}
+
+/// Log in with `username` and `password` from `remote`, unless it is
+/// locked out. `user_agent` is recorded with the session.
+fn attemptLogin(username: &str, password: &str, remote: Option<SocketAddr>,
+                user_agent: Option<&str>, data_manager: &data::Manager,
+                config: &Configuration) -> Result<LoginResult, Error>
+{
+    let client = remote.map(|addr| addr.ip().to_string()).unwrap_or_default();
+    let now = OffsetDateTime::now_utc();
+    if let Some(until) = lockedUntil(&client, now, data_manager, config)?
+    {
+        return Ok(LoginResult::LockedOut(until - now));
+    }
+    if checkPassword(username, password, data_manager, config)?
+    {
+        data_manager.clearLoginFailures(&client)?;
+        let token = createToken();
+        data_manager.createSession(&token, user_agent)?;
+        Ok(LoginResult::LoggedIn(warp::reply::with_header(
+            warp::redirect::see_other(uriFromStr(&config.serve_under_path)?),
+            "Set-Cookie", makeCookie(token, config))
+                                 .into_response()))
+    }
+    else
     {
-        if !auth_value.starts_with("Basic ")
+        let count = data_manager.recordLoginFailure(
+            &client, now, now - LOGIN_FAILURE_MEMORY)?;
+        warn!("Failed login from {} ({} in a row).", client, count);
+        let lockout = lockoutDuration(count, config);
+        if lockout > Duration::ZERO
         {
-            return Err(Error::HTTPStatus(
-                StatusCode::UNAUTHORIZED,
-                "Not using basic authentication".to_owned()));
+            info!("Locking out {} for {} seconds.", client,
+                  lockout.whole_seconds());
         }
-        let expeced = BASE64.encode(format!("user:{}", config.password));
-        if expeced.as_str() == &auth_value[6..]
+        Ok(LoginResult::Failed)
+    }
+}
+
+fn retryAfter(wait: Duration) -> String
+{
+    wait.whole_seconds().max(1).to_string()
+}
+
+/// Log in with HTTP Basic authentication, for scripts.
+fn handleBasicLogin(auth_value: &str, remote: Option<SocketAddr>,
+                    user_agent: Option<&str>, data_manager: &data::Manager,
+                    config: &Configuration) -> Result<Response, Error>
+{
+    let credential = auth_value.strip_prefix("Basic ").ok_or_else(
+        || Error::HTTPStatus(StatusCode::UNAUTHORIZED,
+                             "Not using basic authentication".to_owned()))?;
+    let (username, password) = decodeBasic(credential)?;
+    match attemptLogin(&username, &password, remote, user_agent, data_manager,
+                       config)?
+    {
+        LoginResult::LoggedIn(response) => Ok(response),
+        LoginResult::LockedOut(wait) => Ok(warp::reply::with_header(
+            warp::reply::with_status(
+                "Too many failed logins", StatusCode::TOO_MANY_REQUESTS),
+            "Retry-After", retryAfter(wait)).into_response()),
+        LoginResult::Failed => Err(Error::HTTPStatus(
+            StatusCode::UNAUTHORIZED, "Invalid credential".to_owned())),
+    }
+}
+
+fn renderLoginForm(templates: &Tera, username: &str, error_msg: Option<&str>,
+                   status: StatusCode, catalog: &Catalog, prefs: &Prefs,
+                   config: &Configuration) -> Result<Response, Error>
+{
+    let mut context = tera::Context::new();
+    context.insert("site_info", &config.site_info);
+    catalog.fillContext(&mut context);
+    prefs.fillContext(&mut context);
+    context.insert("username", username);
+    context.insert("error", &error_msg);
+    let html = templates.render("login.html", &context).map_err(
+        |e| rterr!("Failed to render template: {}", e))?;
+    Ok(warp::reply::with_status(warp::reply::html(html), status)
+       .into_response())
+}
+
+/// The login form, or a Basic login if the request has the
+/// Authorization header.
+#[allow(clippy::too_many_arguments)]
+pub fn handleLogin(
+    auth_value_maybe: Option<String>, remote: Option<SocketAddr>,
+    user_agent: Option<String>, templates: &Tera, catalog: &Catalog,
+    prefs: &Prefs, data_manager: &data::Manager, config: &Configuration) ->
+    Result<Response, Error>
+{
+    match auth_value_maybe
+    {
+        Some(auth_value) => handleBasicLogin(
+            &auth_value, remote, user_agent.as_deref(), data_manager, config),
+        None => renderLoginForm(templates, DEFAULT_USERNAME, None,
+                                StatusCode::OK, catalog, prefs, config),
+    }
+}
+
+/// Log in with the posted login form.
+#[allow(clippy::too_many_arguments)]
+pub fn handleLoginForm(
+    form: &HashMap<String, String>, remote: Option<SocketAddr>,
+    user_agent: Option<String>, templates: &Tera, catalog: &Catalog,
+    prefs: &Prefs, data_manager: &data::Manager, config: &Configuration) ->
+    Result<Response, Error>
+{
+    let field = |name: &str| form.get(name).map(|s| s.as_str()).unwrap_or("");
+    let username = field("Username");
+    match attemptLogin(username, field("Password"), remote,
+                       user_agent.as_deref(), data_manager, config)?
+    {
+        LoginResult::LoggedIn(response) => Ok(response),
+        LoginResult::LockedOut(wait) => {
+            let mut response = renderLoginForm(
+                templates, username, Some(catalog.get("login_locked_out")),
+                StatusCode::TOO_MANY_REQUESTS, catalog, prefs, config)?;
+            if let Ok(value) = retryAfter(wait).parse()
+            {
+                response.headers_mut().insert("Retry-After", value);
+            }
+            Ok(response)
+        },
+        LoginResult::Failed => renderLoginForm(
+            templates, username, Some(catalog.get("login_failed")),
+            StatusCode::UNAUTHORIZED, catalog, prefs, config),
+    }
+}
+
+/// Delete the session of the request, and go to the index page.
+pub fn handleLogout(token: Option<String>, data_manager: &data::Manager,
+                    config: &Configuration) -> Result<Response, Error>
+{
+    if let Some(token) = token
+    {
+        data_manager.deleteSession(&token)?;
+    }
+    Ok(warp::reply::with_header(
+        warp::redirect::see_other(uriFromStr(&config.serve_under_path)?),
+        "Set-Cookie", clearCookie(config)).into_response())
+}
+
+/// Delete all sessions, including the one of the request.
+pub fn handleDeleteSessions(token: Option<String>, data_manager: &data::Manager,
+                            config: &Configuration) -> Result<Response, Error>
+{
+    if !validateSession(&token, data_manager, config)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    data_manager.deleteAllSessions()?;
+    Ok(warp::reply::with_header(StatusCode::NO_CONTENT, "Set-Cookie",
+                                clearCookie(config)).into_response())
+}
+
+/// The page that lists the active sessions.
+pub fn handleSessions(token: Option<String>, templates: &Tera,
+                      catalog: &Catalog, prefs: &Prefs,
+                      data_manager: &data::Manager, config: &Configuration) -> Result<Response, Error>
+{
+    if !validateSession(&token, data_manager, config)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    let format = time::format_description::parse_borrowed::<2>(
+        "[year]-[month]-[day] [hour]:[minute]:[second] UTC").unwrap();
+    let sessions = data_manager.getSessions()?;
+    let infos = sessions.iter().map(|session| Ok(SessionInfo {
+        auth_time_utc_str: session.auth_time.format(&format).map_err(
+            |e| rterr!("Invalid auth time: {}", e))?,
+        user_agent: session.user_agent.as_deref(),
+        current: token.as_ref() == Some(&session.token),
+    })).collect::<Result<Vec<_>, Error>>()?;
+    let mut context = tera::Context::new();
+    context.insert("site_info", &config.site_info);
+    catalog.fillContext(&mut context);
+    prefs.fillContext(&mut context);
+    context.insert("sessions", &infos);
+    let html = templates.render("sessions.html", &context).map_err(
+        |e| rterr!("Failed to render template: {}", e))?;
+    Ok(warp::reply::html(html).into_response())
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn hashAndVerifyPassword() -> Result<(), Error>
+    {
+        let hash = hashPassword("abc")?;
+        assert!(verifyPassword("abc", &hash));
+        assert!(!verifyPassword("abd", &hash));
+        assert!(!verifyPassword("abc", "not a hash"));
+        Ok(())
+    }
+
+    #[test]
+    fn failedLoginsLockOut() -> Result<(), Error>
+    {
+        let mut data_manager = data::Manager::new(
+            crate::sqlite_connection::Source::Memory);
+        data_manager.connect()?;
+        data_manager.init()?;
+        let config = Configuration {
+            password: String::from("pw"),
+            ..Default::default()
+        };
+        let remote = "192.0.2.1:1234".parse().ok();
+        let login = |password: &str| handleBasicLogin(
+            &format!("Basic {}", BASE64.encode(format!("user:{}", password))),
+            remote, None, &data_manager, &config);
+
+        for _ in 0..4
         {
-            // Authentication is good.
-            let token = createToken();
-            data_manager.createSession(&token)?;
-            return Ok(warp::reply::with_header(
-                warp::redirect::found(uriFromStr(&config.serve_under_path)?),
-                "Set-Cookie", makeCookie(token, config.session_life_time_sec))
-                      .into_response());
+            assert!(login("wrong").is_err());
         }
-        else
+        assert_eq!(lockoutDuration(4, &config), Duration::ZERO);
+        assert_eq!(login("pw")?.status(), StatusCode::SEE_OTHER);
+        // A good login starts over.
+        for _ in 0..5
         {
-            return Err(Error::HTTPStatus(
-                StatusCode::UNAUTHORIZED,
-                "Invalid credential".to_owned()));
+            assert!(login("wrong").is_err());
         }
+        assert_eq!(login("pw")?.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        assert_eq!(lockoutDuration(5, &config), Duration::seconds(60));
+        assert_eq!(lockoutDuration(7, &config), Duration::seconds(240));
+        assert_eq!(lockoutDuration(100, &config), LOGIN_LOCKOUT_MAX);
+        let config = Configuration {
+            login_lockout_sec: u64::MAX / 2,
+            ..config
+        };
+        assert_eq!(lockoutDuration(5, &config), LOGIN_LOCKOUT_MAX);
+        assert_eq!(lockoutDuration(100, &config), LOGIN_LOCKOUT_MAX);
+        Ok(())
     }
 
-    Ok(warp::reply::with_header(
-        warp::reply::with_status(warp::reply::reply(), StatusCode::UNAUTHORIZED),
-        "WWW-Authenticate",
-        r#"Basic realm="nspic", charset="UTF-8""#).into_response())
+    #[test]
+    fn configPasswordHashIsChecked() -> Result<(), Error>
+    {
+        let mut data_manager = data::Manager::new(
+            crate::sqlite_connection::Source::Memory);
+        data_manager.connect()?;
+        data_manager.init()?;
+        let config = Configuration {
+            password_hash: hashPassword("pw")?,
+            ..Default::default()
+        };
+        assert!(checkPassword("user", "pw", &data_manager, &config)?);
+        assert!(!checkPassword("user", "px", &data_manager, &config)?);
+        assert!(!checkPassword("other", "pw", &data_manager, &config)?);
+        assert_eq!(decodeBasic(&BASE64.encode("user:a:b"))?,
+                   (String::from("user"), String::from("a:b")));
+        assert!(constantTimeEq(b"abc", b"abc"));
+        assert!(!constantTimeEq(b"abc", b"abcd"));
+        Ok(())
+    }
+
+    #[test]
+    fn apiTokensAreChecked() -> Result<(), Error>
+    {
+        let mut data_manager = data::Manager::new(
+            crate::sqlite_connection::Source::Memory);
+        data_manager.connect()?;
+        data_manager.init()?;
+        let config = Configuration::default();
+        let (id, token) = createApiToken("cron", &data_manager)?;
+        let valid = |c: Credential| validateCredential(&Some(c), &data_manager,
+                                                       &config);
+        assert!(valid(Credential::ApiToken(token.clone()))?);
+        assert!(!valid(Credential::ApiToken(String::from("x")))?);
+        // The token is only stored as its digest.
+        assert!(data_manager.useApiToken(&hashApiToken(&token))?);
+        assert!(!data_manager.useApiToken(&token)?);
+        let tokens = data_manager.getApiTokens()?;
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].name, "cron");
+        assert!(tokens[0].last_used.is_some());
+
+        revokeApiToken(id, &data_manager)?;
+        assert!(!valid(Credential::ApiToken(token))?);
+        assert!(revokeApiToken(id, &data_manager).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn cookieHasSecurityAttributes()
+    {
+        let mut config = Configuration::default();
+        assert_eq!(makeCookie(String::from("t"), &config),
+                   "nspic-token=t; Max-Age=2592000; Path=/; SameSite=Lax; \
+                    HttpOnly");
+        config.site_info.url_domain = String::from("https://example.org");
+        assert_eq!(clearCookie(&config),
+                   "nspic-token=; Max-Age=0; Path=/; SameSite=Lax; Secure; \
+                    HttpOnly");
+        let config = Configuration {
+            cookie: crate::config::CookieConfig {
+                secure: Some(false),
+                http_only: false,
+                same_site: crate::config::SameSite::None,
+            },
+            ..config
+        };
+        assert_eq!(clearCookie(&config),
+                   "nspic-token=; Max-Age=0; Path=/; SameSite=None");
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn logoutDeletesSession() -> Result<(), Error>
+    {
+        let mut data_manager = data::Manager::new(
+            crate::sqlite_connection::Source::Memory);
+        data_manager.connect()?;
+        data_manager.init()?;
+        let config = Configuration::default();
+        data_manager.createSession("a", Some("curl/8.0"))?;
+        data_manager.createSession("b", None)?;
+        let sessions = data_manager.getSessions()?;
+        assert_eq!(sessions.len(), 2);
+        assert!(sessions.iter().any(
+            |s| s.token == "a" && s.user_agent.as_deref() == Some("curl/8.0")));
+
+        let response = handleLogout(Some(String::from("a")), &data_manager,
+                                    &config)?;
+        assert_eq!(response.headers()["Set-Cookie"], clearCookie(&config));
+        assert!(!validateSession(&Some(String::from("a")), &data_manager,
+                                 &config)?);
+        assert!(validateSession(&Some(String::from("b")), &data_manager,
+                                &config)?);
+
+        assert!(handleDeleteSessions(None, &data_manager, &config).is_err());
+        handleDeleteSessions(Some(String::from("b")), &data_manager, &config)?;
+        assert!(data_manager.getSessions()?.is_empty());
+        Ok(())
+    }
 }
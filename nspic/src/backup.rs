@@ -0,0 +1,227 @@
+// Backups of the library into the dir of the `[backup]` config, which
+// can be on another disk or a mounted remote storage. A backup is a
+// snapshot of the database, made with the SQLite backup API so that
+// it is consistent while the server is running. The image dir is
+// copied into `images/` of the backup dir incrementally: only the
+// files that are new or changed are copied. Only the last `keep`
+// snapshots are kept. Image files are never removed from the backup,
+// because the older snapshots can still refer to them.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::{debug, info};
+use log::error as log_error;
+use time::OffsetDateTime;
+
+use crate::error::Error;
+use crate::config::{BackupConfig, Configuration};
+use crate::data;
+
+static SNAPSHOT_PREFIX: &str = "db-";
+static SNAPSHOT_SUFFIX: &str = ".sqlite";
+static IMAGES_DIR: &str = "images";
+/// The longest time to wait before checking whether a backup is due.
+const CHECK_INTERVAL_SEC: u64 = 3600;
+
+fn backupConfig(config: &Configuration) -> Result<&BackupConfig, Error>
+{
+    config.backup.as_ref().ok_or_else(|| rterr!("Backup is not configured"))
+}
+
+/// The database snapshots in `dir`, oldest first.
+fn snapshots(dir: &Path) -> Result<Vec<PathBuf>, Error>
+{
+    let entries = std::fs::read_dir(dir).map_err(
+        |e| rterr!("Failed to read {:?}: {}", dir, e))?;
+    let mut result: Vec<PathBuf> = entries.flatten().map(|entry| entry.path())
+        .filter(|path| path.file_name().and_then(|n| n.to_str()).map(
+            |n| n.starts_with(SNAPSHOT_PREFIX) && n.ends_with(SNAPSHOT_SUFFIX))
+                .unwrap_or(false))
+        .collect();
+    // The names have the time in a sortable format.
+    result.sort();
+    Ok(result)
+}
+
+/// Copy the files in `src` that are missing or changed in `dest`,
+/// recursively. Return the number of copied files.
+fn copyNewFiles(src: &Path, dest: &Path) -> Result<usize, Error>
+{
+    std::fs::create_dir_all(dest).map_err(
+        |e| rterr!("Failed to create {:?}: {}", dest, e))?;
+    let entries = std::fs::read_dir(src).map_err(
+        |e| rterr!("Failed to read {:?}: {}", src, e))?;
+    let mut count = 0;
+    for entry in entries.flatten()
+    {
+        let name = entry.file_name();
+        // Images that are being processed.
+        if name.to_str().map(|n| n.starts_with("temp-")).unwrap_or(false)
+        {
+            continue;
+        }
+        let src_path = entry.path();
+        let dest_path = dest.join(&name);
+        let meta = entry.metadata().map_err(
+            |e| rterr!("Failed to read {:?}: {}", src_path, e))?;
+        if meta.is_dir()
+        {
+            count += copyNewFiles(&src_path, &dest_path)?;
+            continue;
+        }
+        // Copies are always newer than the files they copy.
+        let is_new = match std::fs::metadata(&dest_path)
+        {
+            Ok(dest_meta) => dest_meta.len() != meta.len() ||
+                match (meta.modified(), dest_meta.modified())
+                {
+                    (Ok(src_time), Ok(dest_time)) => src_time > dest_time,
+                    _ => true,
+                },
+            Err(_) => true,
+        };
+        if is_new
+        {
+            debug!("Backing up {:?}...", src_path);
+            std::fs::copy(&src_path, &dest_path).map_err(
+                |e| rterr!("Failed to copy {:?}: {}", src_path, e))?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Remove the snapshots in `dir` except the last `keep` ones. Return
+/// the number of removed snapshots.
+fn prune(dir: &Path, keep: usize) -> Result<usize, Error>
+{
+    let all = snapshots(dir)?;
+    let count = all.len().saturating_sub(keep);
+    for path in &all[..count]
+    {
+        std::fs::remove_file(path).map_err(
+            |e| rterr!("Failed to remove {:?}: {}", path, e))?;
+    }
+    Ok(count)
+}
+
+/// Back up the library now. Return the path of the database
+/// snapshot.
+pub fn backup(data_manager: &data::Manager, config: &Configuration) ->
+    Result<PathBuf, Error>
+{
+    let backup_config = backupConfig(config)?;
+    let dir = Path::new(&backup_config.dir);
+    std::fs::create_dir_all(dir).map_err(
+        |e| rterr!("Failed to create backup dir: {}", e))?;
+    let format = time::format_description::parse_borrowed::<2>(
+        "[year][month][day]-[hour][minute][second]").unwrap();
+    let name = OffsetDateTime::now_utc().format(&format)
+        .map_err(|e| rterr!("Failed to format time: {}", e))?;
+    let path = dir.join(format!("{}{}{}", SNAPSHOT_PREFIX, name,
+                                SNAPSHOT_SUFFIX));
+    // An unfinished snapshot should not look like a backup.
+    let temp_path = dir.join(format!("temp-{}{}", name, SNAPSHOT_SUFFIX));
+    data_manager.backupTo(&temp_path)?;
+    std::fs::rename(&temp_path, &path).map_err(
+        |e| rterr!("Failed to move {:?}: {}", temp_path, e))?;
+    let copied = copyNewFiles(Path::new(&config.image_dir),
+                              &dir.join(IMAGES_DIR))?;
+    let removed = prune(dir, backup_config.keep)?;
+    info!("Backed up the database into {:?} and {} new image files, and \
+           removed {} old snapshots.", path, copied, removed);
+    Ok(path)
+}
+
+/// Whether the last snapshot in `dir` is at least `interval` old.
+fn isDue(dir: &Path, interval: Duration) -> bool
+{
+    snapshots(dir).ok().and_then(|all| all.last().cloned())
+        .and_then(|path| std::fs::metadata(path).ok())
+        .and_then(|meta| meta.modified().ok())
+        .and_then(|mtime| mtime.elapsed().ok())
+        .map(|age| age >= interval).unwrap_or(true)
+}
+
+/// Back up the library periodically in the background, if backup is
+/// configured.
+pub fn spawn(data_manager: data::Manager, config: Configuration)
+{
+    let backup_config = match &config.backup
+    {
+        Some(c) => c.clone(),
+        None => return,
+    };
+    let interval = Duration::from_secs(backup_config.interval_sec);
+    let check_interval = Duration::from_secs(
+        std::cmp::min(backup_config.interval_sec, CHECK_INTERVAL_SEC));
+    std::thread::spawn(move || {
+        loop
+        {
+            if isDue(Path::new(&backup_config.dir), interval)
+            {
+                if let Err(e) = backup(&data_manager, &config)
+                {
+                    log_error!("Failed to back up: {}", e);
+                }
+            }
+            std::thread::sleep(check_interval);
+        }
+    });
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn onlyNewFilesAreCopied() -> Result<(), Error>
+    {
+        let dir = std::env::temp_dir().join(
+            "nspic-test-".to_owned() + &rand::random::<u64>().to_string());
+        let src = dir.join("src");
+        let dest = dir.join("dest");
+        std::fs::create_dir_all(src.join("ab")).unwrap();
+        std::fs::write(src.join("ab/abcd.jpg"), "abcd").unwrap();
+        std::fs::write(src.join("temp-1.jpg"), "").unwrap();
+        let first = copyNewFiles(&src, &dest);
+        let second = copyNewFiles(&src, &dest);
+        std::fs::write(src.join("ab/abcd.jpg"), "changed").unwrap();
+        let third = copyNewFiles(&src, &dest);
+        let content = std::fs::read_to_string(dest.join("ab/abcd.jpg"));
+        let temp_exists = dest.join("temp-1.jpg").exists();
+        std::fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(first?, 1);
+        assert_eq!(second?, 0);
+        assert_eq!(third?, 1);
+        assert_eq!(content.unwrap(), "changed");
+        assert!(!temp_exists);
+        Ok(())
+    }
+
+    #[test]
+    fn oldSnapshotsArePruned() -> Result<(), Error>
+    {
+        let dir = std::env::temp_dir().join(
+            "nspic-test-".to_owned() + &rand::random::<u64>().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["db-20240103-000000.sqlite", "db-20240101-000000.sqlite",
+                     "db-20240102-000000.sqlite", "temp-20240104-000000.sqlite"]
+        {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let removed = prune(&dir, 2);
+        let remaining = snapshots(&dir);
+        std::fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(removed?, 1);
+        assert_eq!(remaining?, vec![dir.join("db-20240102-000000.sqlite"),
+                                    dir.join("db-20240103-000000.sqlite")]);
+        Ok(())
+    }
+}
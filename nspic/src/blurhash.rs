@@ -0,0 +1,159 @@
+// Placeholders that pages show while an image loads: the average color
+// of the image, and its BlurHash (https://blurha.sh), which is a short
+// string of a few cosine components that a page can decode into a
+// blurred version of the image. Both are computed from a small copy of
+// the image, in 8-bit RGB.
+
+use std::f64::consts::PI;
+
+const BASE83_CHARS: &[u8] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+fn srgbToLinear(value: u8) -> f64
+{
+    let v = value as f64 / 255.0;
+    if v <= 0.04045
+    {
+        v / 12.92
+    }
+    else
+    {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linearToSrgb(value: f64) -> u32
+{
+    let v = value.clamp(0.0, 1.0);
+    if v <= 0.0031308
+    {
+        (v * 12.92 * 255.0 + 0.5) as u32
+    }
+    else
+    {
+        ((1.055 * v.powf(1.0 / 2.4) - 0.055) * 255.0 + 0.5) as u32
+    }
+}
+
+fn base83(value: u32, length: u32, output: &mut String)
+{
+    for i in 1..=length
+    {
+        let digit = (value / 83u32.pow(length - i)) % 83;
+        output.push(BASE83_CHARS[digit as usize] as char);
+    }
+}
+
+/// The component (`i`, `j`) of the image in linear RGB.
+fn component(pixels: &[u8], width: usize, height: usize, i: usize, j: usize) ->
+    [f64; 3]
+{
+    let mut sum = [0.0; 3];
+    for y in 0..height
+    {
+        for x in 0..width
+        {
+            let basis = (PI * (i * x) as f64 / width as f64).cos() *
+                (PI * (j * y) as f64 / height as f64).cos();
+            let pixel = &pixels[(y * width + x) * 3..][..3];
+            for (s, p) in sum.iter_mut().zip(pixel)
+            {
+                *s += basis * srgbToLinear(*p);
+            }
+        }
+    }
+    let scale = if i == 0 && j == 0 { 1.0 } else { 2.0 } /
+        (width * height) as f64;
+    sum.map(|s| s * scale)
+}
+
+/// The average color of the `width` by `height` RGB `pixels`, like
+/// `#8a9bb0`.
+pub fn averageColor(pixels: &[u8], width: usize, height: usize) -> String
+{
+    let [r, g, b] = component(pixels, width, height, 0, 0).map(linearToSrgb);
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+/// The BlurHash of the `width` by `height` RGB `pixels`, with
+/// `x_components` by `y_components` components, each of which is 1
+/// to 9.
+pub fn encode(pixels: &[u8], width: usize, height: usize, x_components: usize,
+              y_components: usize) -> String
+{
+    let mut factors = Vec::new();
+    for j in 0..y_components
+    {
+        for i in 0..x_components
+        {
+            factors.push(component(pixels, width, height, i, j));
+        }
+    }
+    let mut hash = String::new();
+    base83(((x_components - 1) + (y_components - 1) * 9) as u32, 1, &mut hash);
+
+    let ac = &factors[1..];
+    let max_value = if ac.is_empty()
+    {
+        base83(0, 1, &mut hash);
+        1.0
+    }
+    else
+    {
+        let actual_max = ac.iter().flatten().fold(0.0f64, |m, v| m.max(v.abs()));
+        let quantised_max = (actual_max * 166.0 - 0.5).floor().clamp(0.0, 82.0);
+        base83(quantised_max as u32, 1, &mut hash);
+        (quantised_max + 1.0) / 166.0
+    };
+
+    let [r, g, b] = factors[0].map(linearToSrgb);
+    base83((r << 16) + (g << 8) + b, 4, &mut hash);
+    for factor in ac
+    {
+        let [r, g, b] = factor.map(|v| {
+            let v = v / max_value;
+            (v.signum() * v.abs().sqrt() * 9.0 + 9.5).floor().clamp(0.0, 18.0) as u32
+        });
+        base83(r * 19 * 19 + g * 19 + b, 2, &mut hash);
+    }
+    hash
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn hashStartsWithAverageColor()
+    {
+        let pixels = [255, 0, 0].repeat(8 * 8);
+        assert_eq!(averageColor(&pixels, 8, 8), "#ff0000");
+        // The size flag, the maximal AC value, and the DC, which is the
+        // average color.
+        let hash = encode(&pixels, 8, 8, 4, 3);
+        assert_eq!(hash.len(), 4 + 2 * 4 * 3);
+        assert_eq!(&hash[..1], "L");
+        assert_eq!(&hash[2..6], "TI:j");
+        assert_eq!(encode(&pixels, 8, 8, 1, 1), "00TI:j");
+    }
+
+    #[test]
+    fn gradientsHaveComponents()
+    {
+        let mut pixels = Vec::new();
+        for _ in 0..4
+        {
+            for x in 0..8u8
+            {
+                pixels.extend([x * 32, x * 32, x * 32]);
+            }
+        }
+        let solid = encode(&[128].repeat(8 * 4 * 3), 8, 4, 4, 3);
+        let hash = encode(&pixels, 8, 4, 4, 3);
+        assert_eq!(hash.len(), solid.len());
+        assert_ne!(hash[6..], solid[6..]);
+    }
+}
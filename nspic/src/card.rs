@@ -0,0 +1,130 @@
+// Social cards, which are the images in the link previews of posts
+// that are mostly text. Such a post has no image, or its images
+// don’t show what it is about. A card shows the description of the
+// post and the site title. Cards are rendered when they are first
+// requested, and kept in `cards/` of the image dir. The name of a
+// card has a hash of its text, so that an edited post gets a new
+// card, and that a card can’t be requested without knowing the text.
+
+use std::path::{Path, PathBuf};
+
+use log::info;
+use sha2::{Digest, Sha256};
+
+use crate::error::Error;
+use crate::config::Configuration;
+use crate::data;
+use crate::post::Post;
+use crate::meta::{MetaImage, truncate};
+use crate::app::absoluteUrl;
+use crate::post_pipeline::renderCard;
+
+pub static CARDS_DIR: &str = "cards";
+/// The size that the big previews of most sites use.
+const CARD_WIDTH: u32 = 1200;
+const CARD_HEIGHT: u32 = 630;
+/// A post with images needs a description this long to be mostly
+/// text.
+const TEXT_HEAVY_CHARS_MIN: usize = 280;
+/// Maximal number of characters of the description on a card.
+const CARD_TEXT_MAX_CHARS: usize = 500;
+
+/// Whether the description is the main content of `post`.
+pub fn isTextHeavy(post: &Post) -> bool
+{
+    !post.desc.trim().is_empty() &&
+        (post.images.is_empty() ||
+         post.desc.chars().count() >= TEXT_HEAVY_CHARS_MIN)
+}
+
+fn cardText(post: &Post) -> String
+{
+    truncate(&post.desc, CARD_TEXT_MAX_CHARS)
+}
+
+/// The path of the card of `post` in the image dir.
+fn cardPath(post: &Post, config: &Configuration) -> PathBuf
+{
+    let mut hasher = Sha256::new();
+    hasher.update(config.site_info.site_title.as_bytes());
+    hasher.update([0]);
+    hasher.update(cardText(post).as_bytes());
+    let digest = format!("{:x}", hasher.finalize());
+    Path::new(CARDS_DIR).join(format!("{}-{}.png", post.id, &digest[..16]))
+}
+
+/// The card of `post` as the image of its page.
+pub fn cardImage(post: &Post, config: &Configuration) -> Option<MetaImage>
+{
+    Some(MetaImage {
+        url: absoluteUrl("image_file", cardPath(post, config).to_str()?, config),
+        mime_type: "image/png",
+        width: CARD_WIDTH,
+        height: CARD_HEIGHT,
+    })
+}
+
+/// Render the card `name` in the cards dir, which is missing. Return
+/// the path of the card accessible from the CWD, or None if no post
+/// has that card.
+pub fn renderMissingCard(name: &str, data_manager: &data::Manager,
+                         config: &Configuration) -> Result<Option<PathBuf>, Error>
+{
+    if !config.social_cards
+    {
+        return Ok(None);
+    }
+    let id: i64 = match name.split_once('-').and_then(|(id, _)| id.parse().ok())
+    {
+        Some(id) => id,
+        None => return Ok(None),
+    };
+    let post = match data_manager.findPostByID(id)?
+    {
+        Some(post) if isTextHeavy(&post) => post,
+        _ => return Ok(None),
+    };
+    let path = cardPath(&post, config);
+    if path != Path::new(CARDS_DIR).join(name)
+    {
+        return Ok(None);
+    }
+    let card_file = Path::new(&config.image_dir).join(path);
+    if !card_file.exists()
+    {
+        info!("Rendering card {:?}...", card_file);
+        renderCard(&cardText(&post), &config.site_info.site_title, CARD_WIDTH,
+                   CARD_HEIGHT, &card_file, config)?;
+    }
+    Ok(Some(card_file))
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::post::Image;
+
+    #[test]
+    fn cardsFollowTheText()
+    {
+        let config = Configuration::default();
+        let mut post = Post::new();
+        post.id = 3;
+        assert!(!isTextHeavy(&post));
+        post.desc = String::from("A cat");
+        assert!(isTextHeavy(&post));
+        post.images.push(Image::default());
+        assert!(!isTextHeavy(&post));
+        post.desc = "A cat. ".repeat(50);
+        assert!(isTextHeavy(&post));
+
+        let path = cardPath(&post, &config);
+        assert!(path.starts_with(CARDS_DIR));
+        assert!(path.file_name().unwrap().to_str().unwrap().starts_with("3-"));
+        post.desc += "And a dog.";
+        assert_ne!(cardPath(&post, &config), path);
+    }
+}
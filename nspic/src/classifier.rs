@@ -0,0 +1,165 @@
+// Tag suggestions from an external image classifier, like a local
+// CLIP service. When a post or draft is created, a classification is
+// queued with the deliveries, so that a slow or unavailable service
+// doesn’t hold up uploads. The images are POSTed to the service one
+// by one, and the labels that it returns are saved as suggested tags
+// of the post, which only become tags when the admin approves them on
+// the admin page.
+
+use std::time::Duration;
+
+use log::error as log_error;
+use serde::Serialize;
+use serde_json::Value;
+use warp::Reply;
+use warp::http::status::StatusCode;
+use warp::reply::Response;
+
+use crate::error::Error;
+use crate::config::{ClassifierConfig, Configuration};
+use crate::data;
+use crate::delivery::KIND_CLASSIFIER;
+use crate::post::{Post, cleanTags, mimeTypeFromPath};
+use crate::auth::validateSession;
+use crate::post_pipeline::imagePath;
+use crate::utils::uriFromStr;
+use crate::app::{pathPrefix, urlFor};
+
+const TIMEOUT_SEC: u64 = 60;
+
+/// The tags suggested for a post, for the admin page.
+#[derive(Serialize)]
+pub struct SuggestedTags
+{
+    pub post: Post,
+    pub tags: Vec<String>,
+}
+
+/// Queue the classification of the images of a new post, if there is
+/// a classifier. Failures are only logged.
+pub fn enqueue(post_id: i64, data_manager: &data::Manager, config: &Configuration)
+{
+    if config.classifier.is_none()
+    {
+        return;
+    }
+    if let Err(e) = data_manager.addDelivery(KIND_CLASSIFIER, post_id)
+    {
+        log_error!("Failed to queue classification of post {}: {}", post_id, e);
+    }
+}
+
+/// The labels in a `response` of the classifier, which is an array of
+/// labels, or of objects with a label and a score. The labels that
+/// score less than `score_min` are dropped, and at most `tags_max` of
+/// the highest scoring ones are kept.
+fn parseLabels(response: &Value, classifier: &ClassifierConfig) ->
+    Result<Vec<String>, Error>
+{
+    let items = response.as_array().ok_or_else(
+        || rterr!("Classifier response is not an array"))?;
+    let mut labels = Vec::new();
+    for item in items
+    {
+        let (label, score) = match item
+        {
+            Value::String(label) => (label.as_str(), 1.0),
+            Value::Object(object) => (
+                object.get("label").and_then(Value::as_str).ok_or_else(
+                    || rterr!("Classifier label without a label: {}", item))?,
+                object.get("score").and_then(Value::as_f64).unwrap_or(1.0)),
+            _ => return Err(rterr!("Invalid classifier label: {}", item)),
+        };
+        if score >= classifier.score_min
+        {
+            labels.push((label.to_owned(), score));
+        }
+    }
+    labels.sort_by(|a, b| b.1.total_cmp(&a.1));
+    labels.truncate(classifier.tags_max);
+    Ok(labels.into_iter().map(|(label, _)| label).collect())
+}
+
+/// Send the images of the post to the classifier, and save the labels
+/// as suggested tags. This is called by the delivery queue, which
+/// retries on errors.
+pub fn send(post: &Post, data_manager: &data::Manager, config: &Configuration) ->
+    Result<(), Error>
+{
+    let classifier = config.classifier.as_ref().ok_or_else(
+        || rterr!("Classifier is not configured"))?;
+    let agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(TIMEOUT_SEC)).build();
+    let mut labels = Vec::new();
+    for image in &post.images
+    {
+        let file = imagePath(image, config);
+        let body = std::fs::read(&file).map_err(
+            |e| rterr!("Failed to read {:?}: {}", file, e))?;
+        let response: Value = agent.post(&classifier.url)
+            .set("Content-Type", mimeTypeFromPath(&image.path))
+            .send_bytes(&body).map_err(|e| rterr!("Classifier failed: {}", e))?
+            .into_json().map_err(|e| rterr!("Invalid classifier response: {}", e))?;
+        labels.extend(parseLabels(&response, classifier)?);
+    }
+    let tags: Vec<String> = cleanTags(labels).into_iter().filter(
+        |tag| !post.tags.iter().any(|t| t.to_lowercase() == tag.to_lowercase()))
+        .collect();
+    data_manager.addSuggestedTags(post.id, &tags)
+}
+
+/// Approve or reject a suggested tag of a post, and go back to the
+/// admin page.
+pub fn handleSuggestionAction(post_id: i64, action: &str, tag: &str,
+                              data_manager: &data::Manager, config: &Configuration,
+                              token: Option<String>) -> Result<Response, Error>
+{
+    if !validateSession(&token, data_manager, config)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    let found = match action
+    {
+        "approve" => data_manager.approveSuggestedTag(post_id, tag)?,
+        "reject" => data_manager.rejectSuggestedTag(post_id, tag)?,
+        _ => return Err(Error::HTTPStatus(StatusCode::NOT_FOUND, String::new())),
+    };
+    if !found
+    {
+        return Err(Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()));
+    }
+    Ok(warp::redirect::see_other(uriFromStr(
+        &(pathPrefix(&config.serve_under_path) + &urlFor("admin", "")))?)
+       .into_response())
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn labelsAreFilteredByScore() -> Result<(), Error>
+    {
+        let classifier = ClassifierConfig {
+            url: String::from("http://localhost:5000/classify"),
+            score_min: 0.5,
+            tags_max: 2,
+        };
+        assert_eq!(parseLabels(&json!(["cat", "sofa"]), &classifier)?,
+                   vec!["cat", "sofa"]);
+        let response = json!([
+            {"label": "sofa", "score": 0.6},
+            {"label": "dog", "score": 0.1},
+            {"label": "cat", "score": 0.9},
+            {"label": "cushion", "score": 0.55},
+        ]);
+        assert_eq!(parseLabels(&response, &classifier)?, vec!["cat", "sofa"]);
+        assert!(parseLabels(&json!({"labels": []}), &classifier).is_err());
+        assert!(parseLabels(&json!([1]), &classifier).is_err());
+        Ok(())
+    }
+}
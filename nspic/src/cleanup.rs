@@ -0,0 +1,103 @@
+// Removing the temp files that a crash left in the image dir. Images
+// are written to `temp-*` files first while they are processed, and
+// normally renamed or removed after. Files older than
+// `temp_file_max_age_sec` can’t belong to a running upload anymore.
+
+use std::path::Path;
+use std::time::Duration;
+
+use log::{debug, info};
+use log::error as log_error;
+
+use crate::error::Error;
+use crate::config::Configuration;
+
+/// How often to look for stale temp files while running.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Remove the temp files in `dir` that are at least `max_age` old.
+/// Return the number of removed files.
+fn removeStaleFiles(dir: &Path, max_age: Duration) -> Result<usize, Error>
+{
+    let entries = std::fs::read_dir(dir).map_err(
+        |e| rterr!("Failed to read {:?}: {}", dir, e))?;
+    let mut count = 0;
+    for entry in entries.flatten()
+    {
+        let is_temp = entry.file_name().to_str()
+            .map(|name| name.starts_with("temp-")).unwrap_or(false);
+        let stale = entry.metadata().ok().filter(|m| m.is_file())
+            .and_then(|m| m.modified().ok())
+            .and_then(|mtime| mtime.elapsed().ok())
+            .map(|age| age >= max_age).unwrap_or(false);
+        if !is_temp || !stale
+        {
+            continue;
+        }
+        debug!("Removing stale temp file {:?}...", entry.path());
+        match std::fs::remove_file(entry.path())
+        {
+            Ok(_) => count += 1,
+            Err(e) => log_error!("Failed to remove {:?}: {}", entry.path(), e),
+        }
+    }
+    Ok(count)
+}
+
+/// Remove the stale temp files in the image dir.
+pub fn removeStaleTempFiles(config: &Configuration) -> Result<(), Error>
+{
+    let count = removeStaleFiles(
+        Path::new(&config.image_dir),
+        Duration::from_secs(config.temp_file_max_age_sec))?;
+    if count > 0
+    {
+        info!("Removed {} stale temp files.", count);
+    }
+    Ok(())
+}
+
+/// Remove stale temp files periodically in the background.
+pub fn spawn(config: Configuration)
+{
+    std::thread::spawn(move || {
+        loop
+        {
+            std::thread::sleep(CLEANUP_INTERVAL);
+            if let Err(e) = removeStaleTempFiles(&config)
+            {
+                log_error!("Failed to remove stale temp files: {}", e);
+            }
+        }
+    });
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn onlyStaleTempFilesAreRemoved() -> Result<(), Error>
+    {
+        let dir = std::env::temp_dir().join(
+            "nspic-test-".to_owned() + &rand::random::<u64>().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["temp-1.jpg", "temp-2-processed.jpg", "abcd.jpg"]
+        {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        std::fs::create_dir(dir.join("temp-dir")).unwrap();
+        let fresh = removeStaleFiles(&dir, Duration::from_secs(3600));
+        let stale = removeStaleFiles(&dir, Duration::ZERO);
+        let remaining = std::fs::read_dir(&dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(fresh?, 0);
+        assert_eq!(stale?, 2);
+        assert_eq!(remaining, 2);
+        Ok(())
+    }
+}
@@ -1,11 +1,14 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
 use serde::{Deserialize, Serialize};
 
 use crate::error::Error;
 
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
 pub enum ImageEncoding
 {
-    Jpeg, Png, Avif, JpegXl,
+    Jpeg, Png, Avif, JpegXl, WebP,
 }
 
 impl ImageEncoding
@@ -18,6 +21,7 @@ impl ImageEncoding
             Self::Png => "png",
             Self::Avif => "avif",
             Self::JpegXl => "jxl",
+            Self::WebP => "webp",
         }
     }
 }
@@ -36,11 +40,32 @@ fn defaultListenPort() -> u16 { 8080 }
 fn defaultDataDir() -> String { String::from(".") }
 fn defaultImageDir() -> String { String::from("test") }
 fn defaultUploadBytesMax() -> u64 { 1024 * 1024 * 100 }
+fn defaultImageBytesMax() -> u64 { 1024 * 1024 * 50 }
+fn defaultImagesPerPostMax() -> usize { 32 }
 fn defaultImagePixelSize() -> u32 { 1280 }
 fn defaultThumbPixelSize() -> u32 { 256 }
 fn defaultImageEncoding() -> ImageEncoding { ImageEncoding::Jpeg }
 fn defaultImageEncodingQuality() -> i32 { 90 }
 fn defaultSessionLiftTimeSec() -> u64 { 2592000 }
+fn defaultPageSize() -> u64 { 16 }
+fn defaultLocale() -> String { String::from("en") }
+fn defaultShardLevels() -> usize { 1 }
+fn defaultShardWidth() -> usize { 1 }
+fn defaultPipelineQueueMax() -> usize { 16 }
+fn defaultMagickTimeoutSec() -> u64 { 120 }
+fn defaultUrlFetchTimeoutSec() -> u64 { 30 }
+fn defaultWebhookTimeoutSec() -> u64 { 30 }
+fn defaultMagickMemoryMax() -> String { String::from("1GiB") }
+fn defaultMagickDiskMax() -> String { String::from("4GiB") }
+fn defaultQueueIntervalSec() -> u64 { 24 * 3600 }
+fn defaultRelatedPostCount() -> u64 { 4 }
+fn defaultDuplicateCheck() -> DuplicateCheck { DuplicateCheck::Warn }
+fn defaultDuplicateDistanceMax() -> u32 { 4 }
+fn defaultFeedSize() -> u64 { 10 }
+fn defaultTempFileMaxAgeSec() -> u64 { 24 * 3600 }
+fn defaultTrashDays() -> u64 { 30 }
+fn defaultLoginAttemptsMax() -> u32 { 5 }
+fn defaultLoginLockoutSec() -> u64 { 60 }
 
 fn defaultSiteTitle() -> String { String::from("NSPic") }
 fn defaultFootnote() -> String { String::new() }
@@ -80,7 +105,796 @@ impl Default for SiteInfo
     }
 }
 
-#[derive(Deserialize, Clone)]
+fn defaultMailInListenPort() -> u16 { 2525 }
+
+/// Configuration of mail-in posting. NSPic runs a minimal SMTP (or
+/// LMTP) server, which is supposed to be behind a real MTA. Each
+/// email from an allowed sender becomes a post, with the subject line
+/// and the text body as the description, and the image attachments as
+/// the images.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct MailInConfig
+{
+    #[serde(default = "defaultListenAddr")]
+    pub listen_address: String,
+    #[serde(default = "defaultMailInListenPort")]
+    pub listen_port: u16,
+    /// Email addresses that are allowed to post. Matching is case
+    /// insensitive.
+    pub allowed_senders: Vec<String>,
+    /// If set, the subject has to contain this word, because sender
+    /// addresses are easy to forge. It is removed from the
+    /// description.
+    pub secret: Option<String>,
+    /// Speak LMTP instead of SMTP, for delivery from an MTA like
+    /// Postfix or Dovecot.
+    #[serde(default)]
+    pub lmtp: bool,
+}
+
+impl MailInConfig
+{
+    fn validate(&self) -> Result<(), Error>
+    {
+        if self.allowed_senders.is_empty()
+        {
+            return Err(rterr!("[mail_in] allowed_senders is empty, so nobody \
+                               can post"));
+        }
+        if let Some(secret) = &self.secret
+        {
+            if secret.is_empty() || secret.contains(char::is_whitespace)
+            {
+                return Err(rterr!("[mail_in] secret should be one word"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Configuration of the Matrix bot. Allowed users can send images to
+/// the bot in a DM, and each image becomes a post. The bot replies
+/// with the URL of the post.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct MatrixConfig
+{
+    /// Example: https://matrix.org
+    pub homeserver: String,
+    /// Full user ID of the bot account. Example: @nspic:matrix.org
+    pub user_id: String,
+    pub access_token: String,
+    /// Full user IDs that are allowed to post.
+    pub allowed_users: Vec<String>,
+}
+
+impl MatrixConfig
+{
+    fn validate(&self) -> Result<(), Error>
+    {
+        if !self.homeserver.starts_with("https://") &&
+            !self.homeserver.starts_with("http://")
+        {
+            return Err(rterr!("[matrix] homeserver should be a URL, not {}",
+                              self.homeserver));
+        }
+        if !self.user_id.starts_with('@') || !self.user_id.contains(':')
+        {
+            return Err(rterr!("[matrix] user_id should be a full user ID like \
+                               @nspic:matrix.org, not {}", self.user_id));
+        }
+        Ok(())
+    }
+}
+
+fn defaultTelegramApiUrl() -> String { String::from("https://api.telegram.org") }
+
+/// Configuration of cross-posting to a Telegram channel. New public
+/// posts are sent to the channel by a bot that is an admin of it.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct TelegramConfig
+{
+    /// The token from @BotFather.
+    pub bot_token: String,
+    /// The @username of a public channel, or the numeric ID of a
+    /// private one. Example: @my_photos
+    pub chat_id: String,
+    #[serde(default = "defaultTelegramApiUrl")]
+    pub api_url: String,
+}
+
+impl TelegramConfig
+{
+    fn validate(&self) -> Result<(), Error>
+    {
+        if !self.bot_token.contains(':')
+        {
+            return Err(rterr!("[telegram] bot_token should look like \
+                               123456:ABC-DEF..."));
+        }
+        if !self.chat_id.starts_with('@') && self.chat_id.parse::<i64>().is_err()
+        {
+            return Err(rterr!("[telegram] chat_id should be a @username or a \
+                               numeric ID, not {}", self.chat_id));
+        }
+        Ok(())
+    }
+}
+
+/// A caption that can be chosen at upload time, for recurring posts.
+/// These placeholders in the text are replaced: `{date}` (e.g.
+/// 2024-03-01), `{year}`, `{week}` (the ISO week number), and
+/// `{count}` (the number of images).
+#[derive(Deserialize, Serialize, Clone)]
+pub struct CaptionSnippet
+{
+    pub name: String,
+    pub text: String,
+}
+
+fn defaultPageCacheTtlSec() -> u64 { 60 }
+fn defaultPageCacheEntriesMax() -> usize { 256 }
+
+/// Configuration of the in-memory cache of the index and the feed.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct PageCacheConfig
+{
+    /// A cached page is rendered again after this long, even if no
+    /// post changed, e.g. for the like counts.
+    #[serde(default = "defaultPageCacheTtlSec")]
+    pub ttl_sec: u64,
+    #[serde(default = "defaultPageCacheEntriesMax")]
+    pub entries_max: usize,
+}
+
+impl PageCacheConfig
+{
+    fn validate(&self) -> Result<(), Error>
+    {
+        if self.ttl_sec == 0 || self.entries_max == 0
+        {
+            return Err(rterr!("[page_cache] ttl_sec and entries_max cannot be 0"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+pub enum SendfileHeader
+{
+    /// For nginx.
+    XAccelRedirect,
+    /// For Apache with mod_xsendfile, and lighttpd.
+    XSendfile,
+}
+
+fn defaultSendfileHeader() -> SendfileHeader { SendfileHeader::XAccelRedirect }
+
+/// Letting the reverse proxy send the image files.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct SendfileConfig
+{
+    #[serde(default = "defaultSendfileHeader")]
+    pub header: SendfileHeader,
+    /// The path of an image in the image dir is appended to this. For
+    /// X-Accel-Redirect, this is an internal location of nginx, like
+    /// `/nspic-images/`. For X-Sendfile, this is the image dir as the
+    /// proxy sees it, and the absolute path of `image_dir` if it is
+    /// not set.
+    pub prefix: Option<String>,
+}
+
+impl SendfileConfig
+{
+    fn validate(&self) -> Result<(), Error>
+    {
+        match (self.header, &self.prefix)
+        {
+            (SendfileHeader::XAccelRedirect, Some(prefix))
+                if prefix.starts_with('/') => Ok(()),
+            (SendfileHeader::XAccelRedirect, _) => Err(rterr!(
+                "[sendfile] prefix should be a location like /nspic-images/")),
+            (SendfileHeader::XSendfile, _) => Ok(()),
+        }
+    }
+}
+
+/// What to do with an upload that looks the same as an image in the
+/// library.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+pub enum DuplicateCheck
+{
+    Off,
+    /// Log a warning, and post it.
+    Warn,
+    /// Reject the upload.
+    Block,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+pub enum WatermarkPosition
+{
+    TopLeft, Top, TopRight, Left, Center, Right, BottomLeft, Bottom,
+    BottomRight,
+}
+
+impl WatermarkPosition
+{
+    /// The gravity of ImageMagick for this position.
+    pub fn gravity(&self) -> &'static str
+    {
+        match self
+        {
+            Self::TopLeft => "northwest",
+            Self::Top => "north",
+            Self::TopRight => "northeast",
+            Self::Left => "west",
+            Self::Center => "center",
+            Self::Right => "east",
+            Self::BottomLeft => "southwest",
+            Self::Bottom => "south",
+            Self::BottomRight => "southeast",
+        }
+    }
+}
+
+fn defaultWatermarkPosition() -> WatermarkPosition { WatermarkPosition::BottomRight }
+fn defaultWatermarkOpacity() -> f64 { 0.5 }
+fn defaultWatermarkWidthPercent() -> u32 { 20 }
+fn defaultWatermarkColor() -> String { String::from("#ffffff") }
+
+/// A watermark on the full-size images that are served. The images in
+/// the library are kept without it, and a watermarked copy of each is
+/// served in its place.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct WatermarkConfig
+{
+    /// A PNG file to overlay, which can be transparent.
+    pub image: Option<String>,
+    /// A text to overlay instead of an image.
+    pub text: Option<String>,
+    #[serde(default = "defaultWatermarkPosition")]
+    pub position: WatermarkPosition,
+    /// From 0 for invisible to 1 for opaque.
+    #[serde(default = "defaultWatermarkOpacity")]
+    pub opacity: f64,
+    /// The width of the watermark in percent of the width of the
+    /// image.
+    #[serde(default = "defaultWatermarkWidthPercent")]
+    pub width_percent: u32,
+    /// The color of `text`.
+    #[serde(default = "defaultWatermarkColor")]
+    pub color: String,
+}
+
+impl WatermarkConfig
+{
+    fn validate(&self) -> Result<(), Error>
+    {
+        match (&self.image, &self.text)
+        {
+            (Some(image), None) => if !Path::new(image).is_file()
+            {
+                return Err(rterr!("[watermark] image not found: {}", image));
+            },
+            (None, Some(text)) => if text.trim().is_empty()
+            {
+                return Err(rterr!("[watermark] text is empty"));
+            },
+            _ => return Err(rterr!(
+                "[watermark] should have either image or text")),
+        }
+        if !(self.opacity > 0.0 && self.opacity <= 1.0)
+        {
+            return Err(rterr!("[watermark] opacity should be in (0, 1]"));
+        }
+        if self.width_percent == 0 || self.width_percent > 100
+        {
+            return Err(rterr!("[watermark] width_percent should be in 1..=100"));
+        }
+        Ok(())
+    }
+}
+
+fn defaultClassifierScoreMin() -> f64 { 0.5 }
+fn defaultClassifierTagsMax() -> usize { 5 }
+
+/// An HTTP service that labels images, like a local CLIP model. Each
+/// processed image is POSTed to `url`, and the labels that come back
+/// are suggested as tags of its post, which the admin approves or
+/// rejects.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ClassifierConfig
+{
+    /// Receives the image file as the body, and responds with a JSON
+    /// array of labels, like `["cat", "sofa"]`, or of objects like
+    /// `{"label": "cat", "score": 0.93}`.
+    pub url: String,
+    /// Labels with a lower score are not suggested.
+    #[serde(default = "defaultClassifierScoreMin")]
+    pub score_min: f64,
+    /// The most labels suggested for each image.
+    #[serde(default = "defaultClassifierTagsMax")]
+    pub tags_max: usize,
+}
+
+impl ClassifierConfig
+{
+    fn validate(&self) -> Result<(), Error>
+    {
+        if !self.url.starts_with("http://") && !self.url.starts_with("https://")
+        {
+            return Err(rterr!("[classifier] url should be an HTTP URL, not {}",
+                              self.url));
+        }
+        if !(0.0..=1.0).contains(&self.score_min)
+        {
+            return Err(rterr!("[classifier] score_min should be in [0, 1]"));
+        }
+        Ok(())
+    }
+}
+
+fn defaultBackupIntervalSec() -> u64 { 86400 }
+fn defaultBackupKeep() -> usize { 7 }
+
+/// Configuration of the automatic backups. Each backup is a snapshot
+/// of the database in `dir`, and the image files are copied into
+/// `dir` as they are added.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct BackupConfig
+{
+    /// E.g. on another disk, or a mounted remote storage.
+    pub dir: String,
+    #[serde(default = "defaultBackupIntervalSec")]
+    pub interval_sec: u64,
+    /// How many database snapshots to keep.
+    #[serde(default = "defaultBackupKeep")]
+    pub keep: usize,
+}
+
+impl BackupConfig
+{
+    fn validate(&self) -> Result<(), Error>
+    {
+        if self.interval_sec == 0
+        {
+            return Err(rterr!("[backup] interval_sec cannot be 0"));
+        }
+        if self.keep == 0
+        {
+            return Err(rterr!("[backup] keep cannot be 0"));
+        }
+        Ok(())
+    }
+}
+
+fn defaultWatchIntervalSec() -> u64 { 30 }
+
+/// Configuration of the watch folder. Image files put in the folder
+/// become drafts, which can be published from the admin page. The
+/// files are removed from the folder once they are picked up.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct WatchFolderConfig
+{
+    pub path: String,
+    /// How often to scan the folder. A file is only picked up if it
+    /// didn’t change in this period.
+    #[serde(default = "defaultWatchIntervalSec")]
+    pub interval_sec: u64,
+}
+
+impl WatchFolderConfig
+{
+    fn validate(&self) -> Result<(), Error>
+    {
+        if self.interval_sec == 0
+        {
+            return Err(rterr!("[watch_folder] interval_sec cannot be 0"));
+        }
+        Ok(())
+    }
+}
+
+fn defaultAcmeDirectory() -> String
+{
+    String::from("https://acme-v02.api.letsencrypt.org/directory")
+}
+
+/// Serve HTTPS directly. The certificate files are reloaded when they
+/// change, e.g. when renewed by an ACME client.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct TlsConfig
+{
+    /// PEM file of the certificate chain.
+    pub cert_path: String,
+    /// PEM file of the private key.
+    pub key_path: String,
+    /// If set, listen for plain HTTP at this port, e.g. 80, to serve
+    /// ACME HTTP-01 challenges from `acme-challenge` under the data
+    /// dir, and redirect everything else to HTTPS. This lets an ACME
+    /// client in webroot mode get certificates without a proxy.
+    pub http_port: Option<u16>,
+    /// Get the certificate of the domain in `site_info.url_domain`
+    /// from an ACME CA, and renew it before it expires. It is written
+    /// to `cert_path` and `key_path`. This needs `http_port`, which
+    /// has to be reachable at port 80 from the internet.
+    #[serde(default)]
+    pub acme: bool,
+    /// The directory URL of the ACME CA.
+    #[serde(default = "defaultAcmeDirectory")]
+    pub acme_directory: String,
+    /// The contact address of the ACME account.
+    pub acme_email: Option<String>,
+}
+
+impl TlsConfig
+{
+    fn validate(&self, listen_port: u16) -> Result<(), Error>
+    {
+        if self.cert_path.is_empty() || self.key_path.is_empty()
+        {
+            return Err(rterr!("[tls] cert_path and key_path are required"));
+        }
+        if self.http_port == Some(listen_port)
+        {
+            return Err(rterr!("[tls] http_port is the same as listen_port"));
+        }
+        if self.acme && self.http_port.is_none()
+        {
+            return Err(rterr!("[tls] acme needs http_port"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+pub enum AccessLogFormat
+{
+    Text, Json,
+}
+
+fn defaultAccessLogFormat() -> AccessLogFormat { AccessLogFormat::Text }
+
+/// Logging a line for each request. The lines are logged at info
+/// level with the target `access`, e.g. `RUST_LOG=access=info`.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct AccessLogConfig
+{
+    #[serde(default = "defaultAccessLogFormat")]
+    pub format: AccessLogFormat,
+    /// Log the client address from the X-Forwarded-For header if
+    /// there is one, which is what a reverse proxy sets.
+    #[serde(default = "defaultTrue")]
+    pub trust_forwarded_for: bool,
+}
+
+fn defaultAnonymousRequestsPerMinute() -> u32 { 30 }
+fn defaultKeyedRequestsPerMinute() -> u32 { 600 }
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ApiKey
+{
+    pub key: String,
+    /// Only used to identify the key in the logs, and to share the
+    /// rate limit between requests with the same key.
+    pub name: String,
+    /// Overrides `keyed_requests_per_minute` for this key.
+    pub requests_per_minute: Option<u32>,
+}
+
+/// Configuration of the JSON API. Anyone can read the API, but
+/// requests without an API key (given in the `X-API-Key` header) are
+/// limited per IP address with a lower rate.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ApiConfig
+{
+    #[serde(default = "defaultAnonymousRequestsPerMinute")]
+    pub anonymous_requests_per_minute: u32,
+    #[serde(default = "defaultKeyedRequestsPerMinute")]
+    pub keyed_requests_per_minute: u32,
+    #[serde(default)]
+    pub keys: Vec<ApiKey>,
+}
+
+impl Default for ApiConfig
+{
+    fn default() -> Self
+    {
+        Self {
+            anonymous_requests_per_minute: defaultAnonymousRequestsPerMinute(),
+            keyed_requests_per_minute: defaultKeyedRequestsPerMinute(),
+            keys: Vec::new(),
+        }
+    }
+}
+
+impl ApiConfig
+{
+    fn validate(&self) -> Result<(), Error>
+    {
+        let mut seen = std::collections::HashSet::new();
+        for key in &self.keys
+        {
+            if key.key.is_empty()
+            {
+                return Err(rterr!("[api] key {} is empty", key.name));
+            }
+            if !seen.insert(&key.key)
+            {
+                return Err(rterr!("[api] key {} is used more than once",
+                                  key.name));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn defaultTrue() -> bool { true }
+fn defaultRobotsDisallow() -> Vec<String>
+{
+    ["/admin", "/upload", "/login", "/delete-confirm", "/s/"].iter()
+        .map(|p| p.to_string()).collect()
+}
+
+/// How the URLs of posts look. A post without a slug has its ID in
+/// place of the slug.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+pub enum PostUrlScheme
+{
+    /// `/p/<slug>`
+    Id,
+    /// `/<year>/<month>/<slug>`, by the upload time in UTC.
+    Date,
+    /// `/<slug>`. Posts with a slug that is taken by a page of NSPic,
+    /// and posts without a slug, are at `/p/<id>`.
+    Slug,
+}
+
+fn defaultPostUrlScheme() -> PostUrlScheme { PostUrlScheme::Id }
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+pub enum SameSite
+{
+    Strict, Lax, None,
+}
+
+impl SameSite
+{
+    pub fn name(&self) -> &str
+    {
+        match self
+        {
+            Self::Strict => "Strict",
+            Self::Lax => "Lax",
+            Self::None => "None",
+        }
+    }
+}
+
+fn defaultSameSite() -> SameSite { SameSite::Lax }
+
+/// Attributes of the session cookie.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct CookieConfig
+{
+    /// Only send the cookie over HTTPS. If this is not set, it is
+    /// true when `url_domain` is https.
+    pub secure: Option<bool>,
+    /// Keep the cookie away from JavaScript.
+    #[serde(default = "defaultTrue")]
+    pub http_only: bool,
+    /// Whether the cookie is sent with requests from other sites.
+    #[serde(default = "defaultSameSite")]
+    pub same_site: SameSite,
+}
+
+impl Default for CookieConfig
+{
+    fn default() -> Self
+    {
+        Self {
+            secure: None,
+            http_only: true,
+            same_site: defaultSameSite(),
+        }
+    }
+}
+
+/// Inline styles and scripts are not allowed, so the templates must
+/// not use `style` attributes or inline `<script>`s.
+fn defaultContentSecurityPolicy() -> String
+{
+    String::from("default-src 'self'; style-src 'self'; img-src 'self' data:; \
+                  object-src 'none'; base-uri 'self'; form-action 'self'; \
+                  frame-ancestors 'none'")
+}
+fn defaultReferrerPolicy() -> String
+{
+    String::from("strict-origin-when-cross-origin")
+}
+fn defaultFrameOptions() -> String { String::from("DENY") }
+
+/// Security headers of the HTML pages. A header with an empty value
+/// is not sent. The default policy allows everything the built-in
+/// templates use, which is only from the site itself.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct SecurityHeadersConfig
+{
+    #[serde(default = "defaultTrue")]
+    pub enabled: bool,
+    #[serde(default = "defaultContentSecurityPolicy")]
+    pub content_security_policy: String,
+    #[serde(default = "defaultReferrerPolicy")]
+    pub referrer_policy: String,
+    /// The X-Frame-Options header.
+    #[serde(default = "defaultFrameOptions")]
+    pub frame_options: String,
+    /// Send `X-Content-Type-Options: nosniff`.
+    #[serde(default = "defaultTrue")]
+    pub no_sniff: bool,
+}
+
+impl Default for SecurityHeadersConfig
+{
+    fn default() -> Self
+    {
+        Self {
+            enabled: true,
+            content_security_policy: defaultContentSecurityPolicy(),
+            referrer_policy: defaultReferrerPolicy(),
+            frame_options: defaultFrameOptions(),
+            no_sniff: true,
+        }
+    }
+}
+
+/// What /robots.txt tells crawlers.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct RobotsConfig
+{
+    /// If false, all crawlers are asked to stay away.
+    #[serde(default = "defaultTrue")]
+    pub allow_crawling: bool,
+    /// Paths that crawlers should not visit, relative to
+    /// `serve_under_path`.
+    #[serde(default = "defaultRobotsDisallow")]
+    pub disallow: Vec<String>,
+    /// Ask the crawlers of AI companies to stay away.
+    #[serde(default)]
+    pub block_ai_crawlers: bool,
+}
+
+impl Default for RobotsConfig
+{
+    fn default() -> Self
+    {
+        Self {
+            allow_crawling: true,
+            disallow: defaultRobotsDisallow(),
+            block_ai_crawlers: false,
+        }
+    }
+}
+
+fn defaultJournalMode() -> String { String::from("wal") }
+fn defaultBusyTimeoutMs() -> u64 { 5000 }
+fn defaultSynchronous() -> String { String::from("normal") }
+
+/// The pragmas of the SQLite connections.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct DatabaseConfig
+{
+    /// With WAL, reading doesn’t block writing.
+    #[serde(default = "defaultJournalMode")]
+    pub journal_mode: String,
+    /// How long to wait for a locked database before failing.
+    #[serde(default = "defaultBusyTimeoutMs")]
+    pub busy_timeout_ms: u64,
+    #[serde(default = "defaultTrue")]
+    pub foreign_keys: bool,
+    #[serde(default = "defaultSynchronous")]
+    pub synchronous: String,
+}
+
+impl DatabaseConfig
+{
+    fn validate(&self) -> Result<(), Error>
+    {
+        // These are put into the pragmas as they are.
+        if !["delete", "truncate", "persist", "memory", "wal", "off"]
+            .contains(&self.journal_mode.as_str())
+        {
+            return Err(rterr!("[database] Invalid journal_mode: {}",
+                              self.journal_mode));
+        }
+        if !["off", "normal", "full", "extra"].contains(&self.synchronous.as_str())
+        {
+            return Err(rterr!("[database] Invalid synchronous: {}",
+                              self.synchronous));
+        }
+        Ok(())
+    }
+}
+
+impl Default for DatabaseConfig
+{
+    fn default() -> Self
+    {
+        Self {
+            journal_mode: defaultJournalMode(),
+            busy_timeout_ms: defaultBusyTimeoutMs(),
+            foreign_keys: true,
+            synchronous: defaultSynchronous(),
+        }
+    }
+}
+
+fn defaultTileAttribution() -> String
+{
+    String::from("© OpenStreetMap contributors")
+}
+
+/// Locations of posts, and the map of them.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct GeoConfig
+{
+    /// Take the location of a post from the GPS data in the EXIF of
+    /// its images, if the uploader didn’t give one.
+    #[serde(default = "defaultTrue")]
+    pub read_exif: bool,
+    /// A URL to look up the name of a place, with `{lat}` and
+    /// `{lon}` in it, which returns JSON with the name in
+    /// `display_name`, e.g.
+    /// `https://nominatim.openstreetmap.org/reverse?format=jsonv2&lat={lat}&lon={lon}`.
+    /// Places are not looked up if this is not set.
+    pub reverse_geocode_url: Option<String>,
+    /// Map tiles with `{z}`, `{x}`, and `{y}` in it, e.g.
+    /// `https://tile.openstreetmap.org/{z}/{x}/{y}.png`. The map has
+    /// no background if this is not set. The host of the tiles has to
+    /// be allowed in the `img-src` of the Content-Security-Policy.
+    pub tile_url: Option<String>,
+    #[serde(default = "defaultTileAttribution")]
+    pub tile_attribution: String,
+}
+
+impl GeoConfig
+{
+    fn validate(&self) -> Result<(), Error>
+    {
+        if let Some(url) = &self.reverse_geocode_url
+        {
+            if !url.contains("{lat}") || !url.contains("{lon}")
+            {
+                return Err(rterr!("[geo] reverse_geocode_url should have \
+                                   {{lat}} and {{lon}}"));
+            }
+        }
+        if let Some(url) = &self.tile_url
+        {
+            if !url.contains("{z}") || !url.contains("{x}") || !url.contains("{y}")
+            {
+                return Err(rterr!("[geo] tile_url should have {{z}}, {{x}}, \
+                                   and {{y}}"));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for GeoConfig
+{
+    fn default() -> Self
+    {
+        Self {
+            read_exif: true,
+            reverse_geocode_url: None,
+            tile_url: None,
+            tile_attribution: defaultTileAttribution(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
 pub struct Configuration
 {
     #[serde(default = "defaultListenAddr")]
@@ -94,10 +908,36 @@ pub struct Configuration
     pub static_dir: String,
     #[serde(default = "defaultDataDir")]
     pub data_dir: String,
+    /// Example: `sqlite:///var/lib/nspic/db.sqlite`, or
+    /// `postgres://nspic@localhost/nspic` if NSPic is built with the
+    /// `postgres` feature. Default is `db.sqlite` under `data_dir`.
+    pub database_url: Option<String>,
+    #[serde(default)]
+    pub database: DatabaseConfig,
+    /// Maximal size of a whole upload request.
     #[serde(default = "defaultUploadBytesMax")]
     pub upload_bytes_max: u64,
+    /// Maximal size of one image file. Default is 50 MiB, or
+    /// `upload_bytes_max` if that is smaller.
+    #[serde(default = "defaultImageBytesMax")]
+    pub image_bytes_max: u64,
+    #[serde(default = "defaultImagesPerPostMax")]
+    pub images_per_post_max: usize,
+    /// Maximal total size of the images and thumbnails in the
+    /// library. There is no limit if this is not set.
+    pub library_bytes_max: Option<u64>,
     #[serde(default = "defaultImageDir")]
     pub image_dir: String,
+    /// Images are put in nested sub-directories of the image dir
+    /// named by prefixes of their hashes. There are `shard_levels`
+    /// levels of sub-directories, each named by `shard_width` hex
+    /// characters. For example with 2 levels of width 2, an image is
+    /// at `ab/cd/abcd1234....jpg`. Run `nspic reshard` after changing
+    /// these.
+    #[serde(default = "defaultShardLevels")]
+    pub shard_levels: usize,
+    #[serde(default = "defaultShardWidth")]
+    pub shard_width: usize,
     #[serde(default = "defaultImagePixelSize")]
     pub image_pixel_size: u32,
     #[serde(default = "defaultThumbPixelSize")]
@@ -106,23 +946,585 @@ pub struct Configuration
     pub image_encoding: ImageEncoding,
     #[serde(default = "defaultImageEncodingQuality")]
     pub image_encoding_quality: i32,
+    /// Each image is also encoded in these formats, and browsers that
+    /// support one of them get it instead of `image_encoding`. The
+    /// preferred format goes first, e.g. `["Avif", "WebP"]`. Run
+    /// `nspic reencode` after changing this.
+    #[serde(default)]
+    pub alternate_encodings: Vec<ImageEncoding>,
+    /// Tag new posts with the IPTC keywords and XMP subjects of their
+    /// images, which photo editors like Lightroom and darktable
+    /// write.
+    #[serde(default)]
+    pub import_keyword_tags: bool,
+    #[serde(default = "defaultDuplicateCheck")]
+    pub duplicate_check: DuplicateCheck,
+    /// Images whose perceptual hashes differ in at most this many of
+    /// the 64 bits are probably the same.
+    #[serde(default = "defaultDuplicateDistanceMax")]
+    pub duplicate_distance_max: u32,
+    /// The license of posts that don’t have their own, like
+    /// `"CC-BY-4.0"`, `"CC0-1.0"`, `"All rights reserved"`, or any
+    /// other text. Posts have no license if this is not set.
+    pub default_license: Option<String>,
+    /// Ignore the parts of an upload that NSPic doesn’t know, which
+    /// some HTTP clients add. If this is false, such an upload is
+    /// rejected.
+    #[serde(default = "defaultTrue")]
+    pub upload_ignore_unknown_parts: bool,
+    /// How long to wait for the download of an image uploaded by URL.
+    #[serde(default = "defaultUrlFetchTimeoutSec")]
+    pub url_fetch_timeout_sec: u64,
+    /// Allow uploading by URLs of loopback and private addresses. This
+    /// lets anyone who can upload make the server request its local
+    /// network.
+    #[serde(default)]
+    pub url_fetch_allow_private: bool,
+    /// How many images can be processed at the same time, which
+    /// limits the ImageMagick processes. Default is the number of
+    /// CPUs.
+    #[serde(alias = "max_concurrent_encodes")]
+    pub pipeline_jobs_max: Option<usize>,
+    /// How many images can wait for processing. Uploads beyond this
+    /// are rejected with 503 until the queue drains.
+    #[serde(default = "defaultPipelineQueueMax")]
+    pub pipeline_queue_max: usize,
+    /// An ImageMagick command that runs longer than this is killed,
+    /// and the image fails to process.
+    #[serde(default = "defaultMagickTimeoutSec")]
+    pub magick_timeout_sec: u64,
+    /// How much memory an ImageMagick command can use for the pixels,
+    /// in the units of ImageMagick, like `512MiB`. Beyond this it
+    /// caches the pixels on disk, up to `magick_disk_max`, and fails
+    /// after that.
+    #[serde(default = "defaultMagickMemoryMax")]
+    pub magick_memory_max: String,
+    #[serde(default = "defaultMagickDiskMax")]
+    pub magick_disk_max: String,
+    /// Temp files in the image dir that are older than this are left
+    /// by crashes, and are removed.
+    #[serde(default = "defaultTempFileMaxAgeSec")]
+    pub temp_file_max_age_sec: u64,
+    /// Deleted posts stay in the trash for this many days, where they
+    /// can be restored, and are purged after. With 0 they are purged
+    /// right away.
+    #[serde(default = "defaultTrashDays")]
+    pub trash_days: u64,
     #[serde(default = "defaultSessionLiftTimeSec")]
     pub session_life_time_sec: u64,
+    /// A client that fails to log in this many times is locked out
+    /// for `login_lockout_sec`, which doubles with each further
+    /// failure.
+    #[serde(default = "defaultLoginAttemptsMax")]
+    pub login_attempts_max: u32,
+    #[serde(default = "defaultLoginLockoutSec")]
+    pub login_lockout_sec: u64,
+    /// The password of the default user in plain text. Deprecated:
+    /// use `password_hash` instead.
+    #[serde(default)]
     pub password: String,
+    /// The Argon2 hash of the password of the default user, from
+    /// `nspic hash-password`. If neither this nor `password` is set
+    /// and there is no user in the database, the first visitor of
+    /// /setup can create one.
+    #[serde(default)]
+    pub password_hash: String,
+    /// The key of the HMAC that the IPs of likes are hashed with, so
+    /// that they can’t be recovered from the database. Default is a
+    /// random key that is made at the first start, and saved as
+    /// `like-key` under `data_dir`.
+    pub like_key: Option<String>,
+    /// Number of posts on each page of the index.
+    #[serde(default = "defaultPageSize")]
+    pub page_size: u64,
+    /// A post added to the queue in the upload form goes live this
+    /// long after the newest post, e.g. one post a day.
+    #[serde(default = "defaultQueueIntervalSec")]
+    pub queue_interval_sec: u64,
+    /// How many related posts to suggest on a post page. 0 turns
+    /// the suggestions off.
+    #[serde(default = "defaultRelatedPostCount")]
+    pub related_post_count: u64,
+    /// Make a card image from the description of a post that is
+    /// mostly text, for the link previews of social media sites.
+    #[serde(default = "defaultTrue")]
+    pub social_cards: bool,
+    /// The URL scheme of posts. The URLs of the other schemes
+    /// redirect to the ones of this scheme, so that it can be changed
+    /// without breaking links.
+    #[serde(default = "defaultPostUrlScheme")]
+    pub post_url: PostUrlScheme,
+    /// Number of the newest posts in the Atom feed.
+    #[serde(default = "defaultFeedSize")]
+    pub feed_size: u64,
+    /// The UI language if the browser doesn’t ask for one that NSPic
+    /// has.
+    #[serde(default = "defaultLocale")]
+    pub default_locale: String,
+    /// The UI language, instead of the one that the browser asks for.
+    /// A language picked with the language switcher still wins.
+    pub locale: Option<String>,
     /// NSPic will POST to this URI with a JSON payload when a post is
-    /// created.
+    /// created. The payload has the `event` and the `version` of its
+    /// format, and the details of the post and its images.
     pub webhook_url: Option<String>,
+    /// How long to wait for the webhook to respond, including
+    /// connecting. A delivery that times out is retried later.
+    #[serde(default = "defaultWebhookTimeoutSec")]
+    pub webhook_timeout_sec: u64,
+    /// The WebSub hub that is pinged when a post is added to the
+    /// feed. The feed advertises it to feed readers.
+    pub websub_hub_url: Option<String>,
+    #[serde(default)]
     pub site_info: SiteInfo,
+    #[serde(default)]
+    pub snippets: Vec<CaptionSnippet>,
+    /// Mail-in posting is disabled if this is not set.
+    pub mail_in: Option<MailInConfig>,
+    /// The Matrix bot is disabled if this is not set.
+    pub matrix: Option<MatrixConfig>,
+    /// Cross-posting to Telegram is disabled if this is not set.
+    pub telegram: Option<TelegramConfig>,
+    #[serde(default)]
+    pub api: ApiConfig,
+    /// Addresses and networks of the reverse proxies in front of
+    /// NSPic, like `127.0.0.1` or `10.0.0.0/8`. Requests from them can
+    /// tell the address of the client, and the protocol and host that
+    /// it asked for, with the Forwarded or X-Forwarded-* headers.
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    #[serde(default)]
+    pub robots: RobotsConfig,
+    #[serde(default)]
+    pub cookie: CookieConfig,
+    #[serde(default)]
+    pub security_headers: SecurityHeadersConfig,
+    #[serde(default)]
+    pub geo: GeoConfig,
+    /// Requests are not logged if this is not set.
+    pub access_log: Option<AccessLogConfig>,
+    /// Serve plain HTTP if this is not set.
+    pub tls: Option<TlsConfig>,
+    /// The watch folder is disabled if this is not set.
+    pub watch_folder: Option<WatchFolderConfig>,
+    /// There are no automatic backups if this is not set.
+    pub backup: Option<BackupConfig>,
+    /// Pages are not cached in memory if this is not set.
+    pub page_cache: Option<PageCacheConfig>,
+    /// Image files are sent by NSPic if this is not set.
+    pub sendfile: Option<SendfileConfig>,
+    /// Images are served as they are in the library if this is not
+    /// set.
+    pub watermark: Option<WatermarkConfig>,
+    /// Tags are not suggested if this is not set.
+    pub classifier: Option<ClassifierConfig>,
+    /// A directory of more config files, e.g. one for each feature,
+    /// which are merged into this one in the order of their names.
+    pub include_dir: Option<String>,
 }
 
 impl Configuration
 {
-    pub fn fromFile(path: &str) -> Result<Self, Error>
+    pub fn pipelineJobsMax(&self) -> usize
     {
-        let content = std::fs::read_to_string(path).map_err(
-            |_| rterr!("Failed to read config file at {}", path))?;
-        toml::from_str(&content).map_err(
-            |_| rterr!("Failed to parse config file"))
+        self.pipeline_jobs_max.unwrap_or_else(
+            || std::thread::available_parallelism().map(|n| n.get())
+                .unwrap_or(1)).max(1)
+    }
+
+    /// Whether the session cookie is only sent over HTTPS.
+    pub fn cookieIsSecure(&self) -> bool
+    {
+        self.cookie.secure.unwrap_or_else(
+            || self.site_info.url_domain.starts_with("https://"))
+    }
+
+    /// Check the values that the serde types can’t, section by
+    /// section.
+    pub fn validate(&self) -> Result<(), Error>
+    {
+        if self.image_encoding_quality < 0 || self.image_encoding_quality > 100
+        {
+            return Err(rterr!("image_encoding_quality should be 0 to 100"));
+        }
+        if self.thumb_pixel_size == 0 || self.image_pixel_size == 0
+        {
+            return Err(rterr!("Image sizes cannot be 0"));
+        }
+        if self.thumb_pixel_size > self.image_pixel_size
+        {
+            return Err(rterr!("thumb_pixel_size ({}) should not be larger than \
+                               image_pixel_size ({})", self.thumb_pixel_size,
+                              self.image_pixel_size));
+        }
+        // The routes are made of the segments of the path, so an
+        // empty segment matches nothing.
+        if self.serve_under_path != "/" && !self.serve_under_path.is_empty() &&
+            (self.serve_under_path.trim_start_matches('/').split('/')
+             .any(|seg| seg.is_empty()) ||
+             self.serve_under_path.contains(|c: char| c.is_whitespace() ||
+                                            "?#%".contains(c)))
+        {
+            return Err(rterr!("Invalid serve_under_path: {:?}. It should be \
+                               like \"/pic\", without a trailing /.",
+                              self.serve_under_path));
+        }
+        // Image names are SHA-256 hashes, which has 64 hex digits.
+        if self.shard_levels * self.shard_width > 64 ||
+            (self.shard_levels > 0 && self.shard_width == 0)
+        {
+            return Err(rterr!("Invalid shard_levels and shard_width"));
+        }
+        for (i, encoding) in self.alternate_encodings.iter().enumerate()
+        {
+            if *encoding == self.image_encoding ||
+                self.alternate_encodings[..i].contains(encoding)
+            {
+                return Err(rterr!("Encoding {} is listed more than once",
+                                  encoding.extension()));
+            }
+        }
+        if !self.password_hash.is_empty() &&
+            argon2::password_hash::PasswordHash::new(&self.password_hash).is_err()
+        {
+            return Err(rterr!("Invalid password_hash"));
+        }
+        if self.pipeline_jobs_max == Some(0)
+        {
+            return Err(rterr!("pipeline_jobs_max cannot be 0"));
+        }
+        if self.magick_timeout_sec == 0 || self.url_fetch_timeout_sec == 0 ||
+            self.webhook_timeout_sec == 0
+        {
+            return Err(rterr!("Timeouts cannot be 0"));
+        }
+        crate::proxy::validate(&self.trusted_proxies)?;
+        let size = regex::Regex::new(r"^[0-9]+(\.[0-9]+)?([KMGTP]i?B?)?$").unwrap();
+        for (name, value) in [("magick_memory_max", &self.magick_memory_max),
+                              ("magick_disk_max", &self.magick_disk_max)]
+        {
+            if !size.is_match(value)
+            {
+                return Err(rterr!("Invalid {}: {}", name, value));
+            }
+        }
+        if self.image_bytes_max == 0 || self.images_per_post_max == 0
+        {
+            return Err(rterr!("Upload limits cannot be 0"));
+        }
+        if self.image_bytes_max > self.upload_bytes_max
+        {
+            return Err(rterr!("image_bytes_max ({}) should not be larger than \
+                               upload_bytes_max ({})", self.image_bytes_max,
+                              self.upload_bytes_max));
+        }
+        if let Some(url) = &self.webhook_url
+        {
+            if !url.starts_with("http://") && !url.starts_with("https://")
+            {
+                return Err(rterr!("webhook_url should be an HTTP URL: {}", url));
+            }
+        }
+        if self.feed_size == 0
+        {
+            return Err(rterr!("feed_size cannot be 0"));
+        }
+        if self.queue_interval_sec == 0
+        {
+            return Err(rterr!("queue_interval_sec cannot be 0"));
+        }
+        if self.temp_file_max_age_sec == 0
+        {
+            return Err(rterr!("temp_file_max_age_sec cannot be 0"));
+        }
+        // Browsers drop SameSite=None cookies without Secure.
+        if self.cookie.same_site == SameSite::None && !self.cookieIsSecure()
+        {
+            return Err(rterr!("[cookie] same_site = \"None\" needs secure"));
+        }
+        let mut snippet_names = std::collections::HashSet::new();
+        for snippet in &self.snippets
+        {
+            if !snippet_names.insert(&snippet.name)
+            {
+                return Err(rterr!("Snippet {} is defined more than once",
+                                  snippet.name));
+            }
+        }
+        if let Some(mail_in) = &self.mail_in
+        {
+            mail_in.validate()?;
+        }
+        if let Some(matrix) = &self.matrix
+        {
+            matrix.validate()?;
+        }
+        if let Some(telegram) = &self.telegram
+        {
+            telegram.validate()?;
+        }
+        self.database.validate()?;
+        self.api.validate()?;
+        self.geo.validate()?;
+        if let Some(tls) = &self.tls
+        {
+            tls.validate(self.listen_port)?;
+        }
+        if let Some(watch_folder) = &self.watch_folder
+        {
+            watch_folder.validate()?;
+        }
+        if let Some(backup) = &self.backup
+        {
+            backup.validate()?;
+        }
+        if let Some(page_cache) = &self.page_cache
+        {
+            page_cache.validate()?;
+        }
+        if let Some(sendfile) = &self.sendfile
+        {
+            sendfile.validate()?;
+        }
+        if let Some(watermark) = &self.watermark
+        {
+            watermark.validate()?;
+        }
+        if let Some(classifier) = &self.classifier
+        {
+            classifier.validate()?;
+        }
+        Ok(())
+    }
+
+    /// Check what the server needs from the system, so that a missing
+    /// program or a read-only dir is found at startup instead of at
+    /// the first upload.
+    pub fn checkEnvironment(&self) -> Result<(), Error>
+    {
+        for (name, dir) in [("data_dir", &self.data_dir),
+                            ("image_dir", &self.image_dir)]
+        {
+            std::fs::create_dir_all(dir).map_err(
+                |e| rterr!("Failed to create {} {:?}: {}", name, dir, e))?;
+            let probe = Path::new(dir).join(
+                format!(".nspic-probe-{}", rand::random::<u64>()));
+            std::fs::write(&probe, b"").map_err(
+                |e| rterr!("{} {:?} is not writable: {}", name, dir, e))?;
+            std::fs::remove_file(&probe).ok();
+        }
+        if !Path::new(&self.static_dir).is_dir()
+        {
+            return Err(rterr!("static_dir {:?} is not a directory",
+                              self.static_dir));
+        }
+        let output = std::process::Command::new("magick").arg("-version")
+            .output().map_err(|e| rterr!(
+                "Failed to run magick, which is needed to process images. \
+                 Is ImageMagick 7 installed and in PATH? {}", e))?;
+        if !output.status.success()
+        {
+            return Err(rterr!("magick -version failed: {}",
+                              String::from_utf8_lossy(&output.stderr).trim()));
+        }
+        Ok(())
+    }
+}
+
+/// Keys whose values are hidden in the config dump.
+const SECRET_KEYS: &[&str] = &["password", "password_hash", "access_token",
+                               "key", "like_key"];
+
+/// The config file merged with the files in its `include_dir`, with
+/// the file that each value came from.
+pub struct ConfigFiles
+{
+    pub config: Configuration,
+    /// The file of each value set by a file, by the dotted path of
+    /// the key. Arrays are one value.
+    sources: BTreeMap<String, String>,
+}
+
+/// Merge `other` from `file` into `base`. Tables are merged key by
+/// key, and other values replace the ones in `base`.
+fn mergeTable(base: &mut toml::Table, other: toml::Table, file: &str,
+              prefix: &str, sources: &mut BTreeMap<String, String>)
+{
+    for (key, value) in other
+    {
+        let path = if prefix.is_empty()
+        {
+            key.clone()
+        }
+        else
+        {
+            format!("{}.{}", prefix, key)
+        };
+        match (base.get_mut(&key), value)
+        {
+            (Some(toml::Value::Table(base_table)), toml::Value::Table(table)) =>
+                mergeTable(base_table, table, file, &path, sources),
+            (_, toml::Value::Table(table)) => {
+                let mut new_table = toml::Table::new();
+                mergeTable(&mut new_table, table, file, &path, sources);
+                base.insert(key, toml::Value::Table(new_table));
+            },
+            (_, value) => {
+                sources.insert(path, file.to_owned());
+                base.insert(key, value);
+            },
+        }
+    }
+}
+
+fn readConfigFile(path: &Path) -> Result<String, Error>
+{
+    std::fs::read_to_string(path).map_err(
+        |e| rterr!("Failed to read config file at {:?}: {}", path, e))
+}
+
+fn parseToml(content: &str, path: &Path) -> Result<toml::Table, Error>
+{
+    toml::from_str(content).map_err(
+        |e| rterr!("Failed to parse config file {:?}: {}", path, e))
+}
+
+fn readToml(path: &Path) -> Result<toml::Table, Error>
+{
+    parseToml(&readConfigFile(path)?, path)
+}
+
+impl ConfigFiles
+{
+    /// `config` that didn’t come from any file.
+    pub fn fromConfig(config: Configuration) -> Self
+    {
+        Self { config, sources: BTreeMap::new() }
+    }
+
+    /// Load the config file at `path`. If it has `include_dir`, the
+    /// `.toml` files in that directory are merged on top of it in the
+    /// order of their names. A relative `include_dir` is relative to
+    /// the directory of the config file.
+    pub fn load(path: &str) -> Result<Self, Error>
+    {
+        let mut sources = BTreeMap::new();
+        let mut table = toml::Table::new();
+        let content = readConfigFile(Path::new(path))?;
+        mergeTable(&mut table, parseToml(&content, Path::new(path))?, path, "",
+                   &mut sources);
+        let mut included_any = false;
+        if let Some(include_dir) = table.get("include_dir").and_then(|v| v.as_str())
+        {
+            let dir = Path::new(path).parent().unwrap_or(Path::new(""))
+                .join(include_dir);
+            let mut files: Vec<PathBuf> = std::fs::read_dir(&dir).map_err(
+                |e| rterr!("Failed to read include_dir {:?}: {}", dir, e))?
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|p| p.extension().map(|e| e == "toml").unwrap_or(false))
+                .collect();
+            files.sort();
+            for file in files
+            {
+                let mut included = readToml(&file)?;
+                // Includes don’t nest.
+                included.remove("include_dir");
+                mergeTable(&mut table, included, &file.to_string_lossy(), "",
+                           &mut sources);
+                included_any = true;
+            }
+        }
+        let mut config: Configuration = if included_any
+        {
+            toml::Value::Table(table).try_into().map_err(|e| {
+                // The merged table has no lines, so point to the file
+                // of the key instead.
+                let message = e.to_string();
+                match sources.iter().find(
+                    |(key, _)| message.contains(&format!("`{}`", key)))
+                {
+                    Some((_, file)) => rterr!("Invalid config in {:?}: {}",
+                                              file, message.trim()),
+                    None => rterr!("Invalid config: {}", message.trim()),
+                }
+            })?
+        }
+        else
+        {
+            // Parsed from the text, the error has the line of the
+            // value.
+            toml::from_str(&content).map_err(
+                |e| rterr!("Invalid config file {:?}: {}", path, e))?
+        };
+        // The default image_bytes_max is capped by upload_bytes_max,
+        // so that a small upload_bytes_max alone is a valid config.
+        if !sources.contains_key("image_bytes_max")
+        {
+            config.image_bytes_max = config.image_bytes_max.min(
+                config.upload_bytes_max);
+        }
+        config.validate()?;
+        Ok(Self { config, sources })
+    }
+
+    /// The effective config as TOML, with the file of each value in
+    /// a comment. Secrets are masked.
+    pub fn dump(&self) -> Result<String, Error>
+    {
+        let value = toml::Value::try_from(&self.config).map_err(
+            |e| rterr!("Failed to serialize config: {}", e))?;
+        let mut result = String::new();
+        if let toml::Value::Table(table) = value
+        {
+            self.dumpTable(&table, "", &mut result);
+        }
+        Ok(result)
+    }
+
+    fn dumpTable(&self, table: &toml::Table, prefix: &str, result: &mut String)
+    {
+        let path = |key: &str| if prefix.is_empty()
+        {
+            key.to_owned()
+        }
+        else
+        {
+            format!("{}.{}", prefix, key)
+        };
+        for (key, value) in table
+        {
+            if value.is_table()
+            {
+                continue;
+            }
+            let source = self.sources.get(&path(key)).map(|s| s.as_str())
+                .unwrap_or("default");
+            result.push_str(&format!("{} = {}  # {}\n", key, maskSecrets(key, value),
+                                     source));
+        }
+        for (key, value) in table
+        {
+            if let toml::Value::Table(sub_table) = value
+            {
+                result.push_str(&format!("\n[{}]\n", path(key)));
+                self.dumpTable(sub_table, &path(key), result);
+            }
+        }
+    }
+}
+
+/// `value` of `key` with the secrets in it replaced.
+fn maskSecrets(key: &str, value: &toml::Value) -> toml::Value
+{
+    match value
+    {
+        toml::Value::String(s) if SECRET_KEYS.contains(&key) && !s.is_empty() =>
+            toml::Value::String(String::from("********")),
+        toml::Value::Array(items) => toml::Value::Array(
+            items.iter().map(|v| maskSecrets(key, v)).collect()),
+        toml::Value::Table(table) => toml::Value::Table(
+            table.iter().map(|(k, v)| (k.clone(), maskSecrets(k, v))).collect()),
+        _ => value.clone(),
     }
 }
 
@@ -136,16 +1538,158 @@ impl Default for Configuration
             serve_under_path: defaultServePath(),
             static_dir: String::from("static"),
             data_dir: defaultDataDir(),
+            database_url: None,
+            database: DatabaseConfig::default(),
             upload_bytes_max: defaultUploadBytesMax(),
+            image_bytes_max: defaultImageBytesMax(),
+            library_bytes_max: None,
+            images_per_post_max: defaultImagesPerPostMax(),
             image_dir: defaultImageDir(),
+            shard_levels: defaultShardLevels(),
+            shard_width: defaultShardWidth(),
             image_pixel_size: defaultImagePixelSize(),
             thumb_pixel_size: defaultThumbPixelSize(),
             image_encoding: defaultImageEncoding(),
             image_encoding_quality: defaultImageEncodingQuality(),
+            alternate_encodings: Vec::new(),
+            import_keyword_tags: false,
+            duplicate_check: defaultDuplicateCheck(),
+            duplicate_distance_max: defaultDuplicateDistanceMax(),
+            default_license: None,
+            upload_ignore_unknown_parts: true,
+            url_fetch_timeout_sec: defaultUrlFetchTimeoutSec(),
+            url_fetch_allow_private: false,
+            pipeline_jobs_max: None,
+            pipeline_queue_max: defaultPipelineQueueMax(),
+            magick_timeout_sec: defaultMagickTimeoutSec(),
+            magick_memory_max: defaultMagickMemoryMax(),
+            magick_disk_max: defaultMagickDiskMax(),
+            temp_file_max_age_sec: defaultTempFileMaxAgeSec(),
+            trash_days: defaultTrashDays(),
             session_life_time_sec: defaultSessionLiftTimeSec(),
+            login_attempts_max: defaultLoginAttemptsMax(),
+            login_lockout_sec: defaultLoginLockoutSec(),
             password: String::from("nspic"),
+            password_hash: String::new(),
+            like_key: None,
+            page_size: defaultPageSize(),
+            queue_interval_sec: defaultQueueIntervalSec(),
+            related_post_count: defaultRelatedPostCount(),
+            social_cards: true,
+            post_url: defaultPostUrlScheme(),
+            feed_size: defaultFeedSize(),
+            default_locale: defaultLocale(),
+            locale: None,
             webhook_url: None,
+            webhook_timeout_sec: defaultWebhookTimeoutSec(),
+            websub_hub_url: None,
             site_info: SiteInfo::default(),
+            snippets: Vec::new(),
+            mail_in: None,
+            matrix: None,
+            telegram: None,
+            api: ApiConfig::default(),
+            trusted_proxies: Vec::new(),
+            robots: RobotsConfig::default(),
+            cookie: CookieConfig::default(),
+            security_headers: SecurityHeadersConfig::default(),
+            geo: GeoConfig::default(),
+            access_log: None,
+            tls: None,
+            watch_folder: None,
+            backup: None,
+            page_cache: None,
+            sendfile: None,
+            watermark: None,
+            classifier: None,
+            include_dir: None,
+        }
+    }
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn includedFilesAreMerged() -> Result<(), Error>
+    {
+        let dir = std::env::temp_dir().join(
+            "nspic-test-".to_owned() + &rand::random::<u64>().to_string());
+        std::fs::create_dir_all(dir.join("conf.d")).unwrap();
+        let main_file = dir.join("nspic.toml");
+        std::fs::write(&main_file, "static_dir = \"static\"\n\
+                                    include_dir = \"conf.d\"\n\
+                                    password = \"secret\"\n\
+                                    [site_info]\n\
+                                    site_title = \"Main\"\n").unwrap();
+        std::fs::write(dir.join("conf.d").join("20-b.toml"),
+                       "page_size = 3\n").unwrap();
+        std::fs::write(dir.join("conf.d").join("10-a.toml"),
+                       "page_size = 2\n[site_info]\nfootnote = \"Hi\"\n").unwrap();
+        let result = ConfigFiles::load(main_file.to_str().unwrap());
+        std::fs::write(dir.join("conf.d").join("30-c.toml"),
+                       "image_encoding_quality = 101\n").unwrap();
+        let invalid = ConfigFiles::load(main_file.to_str().unwrap());
+        std::fs::write(dir.join("conf.d").join("30-c.toml"),
+                       "upload_bytes_max = 1000\n").unwrap();
+        let small_upload = ConfigFiles::load(main_file.to_str().unwrap());
+        std::fs::write(dir.join("conf.d").join("30-c.toml"),
+                       "upload_bytes_max = 1000\nimage_bytes_max = 2000\n").unwrap();
+        let large_image = ConfigFiles::load(main_file.to_str().unwrap());
+        std::fs::remove_dir_all(&dir).ok();
+
+        let files = result?;
+        assert_eq!(files.config.page_size, 3);
+        assert_eq!(files.config.site_info.site_title, "Main");
+        assert_eq!(files.config.site_info.footnote, "Hi");
+        let dump = files.dump()?;
+        assert!(dump.contains("page_size = 3  # "));
+        assert!(dump.contains("20-b.toml\n"));
+        assert!(dump.contains("password = \"********\"  # "));
+        assert!(dump.contains("thumb_pixel_size = 256  # default\n"));
+        assert!(invalid.is_err());
+        assert_eq!(small_upload?.config.image_bytes_max, 1000);
+        assert!(large_image.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn invalidConfigsAreExplained()
+    {
+        let dir = std::env::temp_dir().join(
+            "nspic-test-".to_owned() + &rand::random::<u64>().to_string());
+        std::fs::create_dir_all(dir.join("conf.d")).unwrap();
+        let main_file = dir.join("nspic.toml");
+        std::fs::write(&main_file, "static_dir = \"static\"\n\
+                                    listen_port = \"abc\"\n").unwrap();
+        let single = ConfigFiles::load(main_file.to_str().unwrap());
+        std::fs::write(&main_file, "static_dir = \"static\"\n\
+                                    include_dir = \"conf.d\"\n").unwrap();
+        std::fs::write(dir.join("conf.d").join("10-a.toml"),
+                       "page_size = \"many\"\n").unwrap();
+        let included = ConfigFiles::load(main_file.to_str().unwrap());
+        std::fs::remove_dir_all(&dir).ok();
+
+        let message = single.err().unwrap().to_string();
+        assert!(message.contains("line 2"), "{}", message);
+        let message = included.err().unwrap().to_string();
+        assert!(message.contains("10-a.toml"), "{}", message);
+
+        let mut config = Configuration::default();
+        assert!(config.validate().is_ok());
+        config.serve_under_path = String::from("/pic");
+        assert!(config.validate().is_ok());
+        for path in ["/pic/", "/a//b", "/p c"]
+        {
+            config.serve_under_path = String::from(path);
+            assert!(config.validate().is_err(), "{}", path);
         }
+        config.serve_under_path = String::from("/");
+        config.thumb_pixel_size = config.image_pixel_size + 1;
+        assert!(config.validate().is_err());
     }
 }
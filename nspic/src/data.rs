@@ -1,44 +1,236 @@
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 use log::info;
 use rusqlite as sql;
-use rusqlite::OptionalExtension;
 use time::OffsetDateTime;
 
 use crate::error;
 use crate::error::Error as Error;
-use crate::post::{Image, Post};
+use crate::config::{Configuration, DatabaseConfig};
+use crate::auth::{ApiToken, Session};
+use crate::classifier::SuggestedTags;
+use crate::delivery::Delivery;
+use crate::duplicates::ImageHash;
+use crate::post::{Album, AlbumSummary, Image, Location, Post, ShareLink,
+                  Visibility};
+use crate::post::urlArgOf;
+use crate::database;
+use crate::database::OptionalExtension;
 use crate::sqlite_connection;
 
-pub enum PostOrder { NewFirst, }
+pub enum PostOrder
+{
+    NewFirst,
+    OldFirst,
+    /// A shuffled order that is stable for the same seed, so that
+    /// pagination works.
+    Random(u32),
+    /// Most likes first, and new first among the same number of likes.
+    MostLiked,
+    /// Most views first, and new first among the same number of views.
+    MostViewed,
+}
+
+impl PostOrder
+{
+    /// Parse the value of an `order` query parameter. The seed is
+    /// only used by the random order.
+    pub fn fromQuery(order: &str, seed: u32) -> Option<Self>
+    {
+        match order
+        {
+            "new" => Some(Self::NewFirst),
+            "old" => Some(Self::OldFirst),
+            "random" => Some(Self::Random(seed)),
+            "liked" => Some(Self::MostLiked),
+            "viewed" => Some(Self::MostViewed),
+            _ => None,
+        }
+    }
+}
+
+/// The columns of posts that `row2Post` reads, in order.
+const POST_COLUMNS: &str = "id, \"desc\", upload_time, album, redacted, slug, draft,
+     visibility, (SELECT COUNT(*) FROM likes WHERE post = posts.id), views,
+     scheduled, latitude, longitude, place, sensitive, license";
+
+/// The tables of a PostgreSQL database, which are the same as the
+/// ones of SQLite after all the columns are added. PostgreSQL has no
+/// rowid, so the tables that are listed in the order their rows were
+/// added have a column of that name. The notices of the tables that
+/// exist already are not logged.
+#[cfg(feature = "postgres")]
+const POSTGRES_SCHEMA: &str = "
+    SET client_min_messages = WARNING;
+    CREATE TABLE IF NOT EXISTS albums (
+    id BIGSERIAL PRIMARY KEY,
+    title TEXT,
+    cover TEXT,
+    parent BIGINT
+    );
+    CREATE TABLE IF NOT EXISTS posts (
+    id BIGSERIAL PRIMARY KEY,
+    \"desc\" TEXT,
+    upload_time BIGINT,
+    album BIGINT REFERENCES albums(id),
+    redacted BIGINT NOT NULL DEFAULT 0,
+    slug TEXT,
+    draft BIGINT NOT NULL DEFAULT 0,
+    visibility TEXT NOT NULL DEFAULT 'public',
+    views BIGINT NOT NULL DEFAULT 0,
+    scheduled BIGINT NOT NULL DEFAULT 0,
+    latitude DOUBLE PRECISION,
+    longitude DOUBLE PRECISION,
+    place TEXT,
+    sensitive BIGINT NOT NULL DEFAULT 0,
+    license TEXT,
+    trashed BIGINT
+    );
+    CREATE UNIQUE INDEX IF NOT EXISTS posts_slug ON posts (slug);
+    CREATE TABLE IF NOT EXISTS images (
+    id BIGSERIAL PRIMARY KEY,
+    path TEXT,
+    width BIGINT,
+    height BIGINT,
+    post BIGINT REFERENCES posts(id),
+    size BIGINT,
+    thumbnail_size BIGINT,
+    alt_text TEXT,
+    caption TEXT,
+    color TEXT,
+    blurhash TEXT,
+    phash BIGINT,
+    position BIGINT
+    );
+    CREATE INDEX IF NOT EXISTS images_post ON images (post);
+    CREATE TABLE IF NOT EXISTS tags (
+    rowid BIGSERIAL,
+    post BIGINT REFERENCES posts(id),
+    tag TEXT,
+    PRIMARY KEY(post, tag)
+    );
+    CREATE INDEX IF NOT EXISTS tags_tag ON tags (tag);
+    CREATE TABLE IF NOT EXISTS suggested_tags (
+    rowid BIGSERIAL,
+    post BIGINT REFERENCES posts(id),
+    tag TEXT,
+    PRIMARY KEY(post, tag)
+    );
+    CREATE TABLE IF NOT EXISTS sessions (
+    token TEXT PRIMARY KEY,
+    auth_time BIGINT,
+    user_agent TEXT
+    );
+    CREATE TABLE IF NOT EXISTS share_links (
+    token TEXT PRIMARY KEY,
+    post BIGINT REFERENCES posts(id),
+    expire_time BIGINT
+    );
+    CREATE TABLE IF NOT EXISTS likes (
+    post BIGINT REFERENCES posts(id),
+    liker TEXT,
+    ip_hash TEXT,
+    time BIGINT,
+    PRIMARY KEY(post, liker)
+    );
+    CREATE TABLE IF NOT EXISTS users (
+    username TEXT PRIMARY KEY,
+    password_hash TEXT
+    );
+    CREATE TABLE IF NOT EXISTS login_failures (
+    client TEXT PRIMARY KEY,
+    count BIGINT,
+    last_time BIGINT
+    );
+    CREATE TABLE IF NOT EXISTS tokens (
+    id BIGSERIAL PRIMARY KEY,
+    name TEXT,
+    hash TEXT UNIQUE,
+    created BIGINT,
+    last_used BIGINT
+    );
+    CREATE TABLE IF NOT EXISTS deliveries (
+    id BIGSERIAL PRIMARY KEY,
+    kind TEXT NOT NULL,
+    post BIGINT NOT NULL REFERENCES posts(id),
+    attempts BIGINT NOT NULL DEFAULT 0,
+    next_time BIGINT NOT NULL
+    );";
+
+/// How long a post count is cached.
+const COUNT_CACHE_TTL: Duration = Duration::from_secs(10);
 
 #[derive(Clone)]
 pub struct Manager
 {
-    filename: sqlite_connection::Source,
-    connection: Option<r2d2::Pool<sqlite_connection::Manager>>,
+    source: database::Source,
+    connection: Option<database::Pool>,
+    pragmas: DatabaseConfig,
+    /// Post counts by what is counted, so that paging doesn’t count
+    /// the posts every time. They are forgotten when posts change.
+    counts: Arc<Mutex<HashMap<String, (Instant, u64)>>>,
+    generation: Arc<AtomicU64>,
 }
 
 impl Manager
 {
+    fn withSource(source: database::Source) -> Self
+    {
+        Self {
+            source,
+            connection: None,
+            pragmas: DatabaseConfig::default(),
+            counts: Arc::new(Mutex::new(HashMap::new())),
+            generation: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
     #[allow(dead_code)]
     pub fn new(f: sqlite_connection::Source) -> Self
     {
-        Self { filename: f, connection: None }
+        Self::withSource(database::Source::Sqlite(f))
     }
 
     pub fn newWithFilename<P: AsRef<Path>>(f: P) -> Self
     {
-        Self {
-            filename: sqlite_connection::Source::File(
-                std::path::PathBuf::from(f.as_ref())),
-            connection: None,
-        }
+        Self::new(sqlite_connection::Source::File(
+            std::path::PathBuf::from(f.as_ref())))
+    }
+
+    /// Create a manager from a database URL. Supported forms are
+    /// `sqlite://<path>`, and `postgres://...` if NSPic is built with
+    /// the `postgres` feature. Without a URL, the database is
+    /// `db.sqlite` under the data dir.
+    pub fn fromConfig(config: &Configuration) -> Result<Self, Error>
+    {
+        let mut manager = match &config.database_url
+        {
+            None => Self::newWithFilename(
+                Path::new(&config.data_dir).join("db.sqlite")),
+            Some(url) if url.starts_with("sqlite://") =>
+                Self::newWithFilename(&url["sqlite://".len()..]),
+            #[cfg(feature = "postgres")]
+            Some(url) if url.starts_with("postgres://") ||
+                url.starts_with("postgresql://") =>
+                Self::withSource(database::Source::Postgres(url.clone())),
+            #[cfg(not(feature = "postgres"))]
+            Some(url) if url.starts_with("postgres://") ||
+                url.starts_with("postgresql://") =>
+                return Err(rterr!("PostgreSQL is not supported by this build \
+                                   of NSPic. Build it with the postgres \
+                                   feature, or use a sqlite:// database URL.")),
+            Some(url) => return Err(rterr!("Unsupported database URL: {}", url)),
+        };
+        manager.pragmas = config.database.clone();
+        Ok(manager)
     }
 
-    fn confirmConnection(&self) ->
-        Result<r2d2::PooledConnection<sqlite_connection::Manager>, Error>
+    fn confirmConnection(&self) -> Result<database::Connection, Error>
     {
         if let Some(pool) = &self.connection
         {
@@ -46,35 +238,76 @@ impl Manager
         }
         else
         {
-            Err(error!(DataError, "Sqlite database not connected"))
+            Err(error!(DataError, "Database not connected"))
         }
     }
 
     /// Connect to the database. Create database file if not exist.
     pub fn connect(&mut self) -> Result<(), Error>
     {
-        let manager = match &self.filename
+        let manager = match &self.source
         {
-            sqlite_connection::Source::File(path) => {
+            database::Source::Sqlite(sqlite_connection::Source::File(path)) => {
                 info!("Opening database at {:?}...", path);
                 sqlite_connection::Manager::file(path)
             },
-            sqlite_connection::Source::Memory =>
+            database::Source::Sqlite(sqlite_connection::Source::Memory) =>
                 sqlite_connection::Manager::memory(),
+            #[cfg(feature = "postgres")]
+            database::Source::Postgres(url) => {
+                info!("Connecting to PostgreSQL...");
+                self.connection = Some(database::Pool::postgres(url).map_err(
+                    |e| rterr!("Failed to create connection pool: {}", e))?);
+                return Ok(());
+            },
         };
-        self.connection = Some(r2d2::Pool::new(manager).map_err(
-            |e| rterr!("Failed to create connection pool: {}", e))?);
+        let pragmas = self.pragmas.clone();
+        let manager = manager.with_init(move |conn| {
+            conn.busy_timeout(Duration::from_millis(pragmas.busy_timeout_ms))?;
+            // The values are checked when the config is loaded.
+            conn.execute_batch(&format!(
+                "PRAGMA journal_mode = {}; PRAGMA synchronous = {};
+                 PRAGMA foreign_keys = {};", pragmas.journal_mode,
+                pragmas.synchronous, if pragmas.foreign_keys { "ON" } else { "OFF" }))
+        });
+        let pool = r2d2::Pool::new(manager).map_err(
+            |e| rterr!("Failed to create connection pool: {}", e))?;
+        self.connection = Some(database::Pool::Sqlite(pool));
+        Ok(())
+    }
+
+    /// Add a column to a table if it doesn’t exist, so that databases
+    /// created by older versions keep working.
+    fn addColumnIfMissing(conn: &database::Connection, table: &str, column: &str,
+                          decl: &str) -> Result<(), Error>
+    {
+        let columns: Vec<String> = conn.queryRows(
+            &format!("PRAGMA table_info({});", table), &[], |row| row.get(1))
+            .map_err(|e| error!(DataError, "Failed to get table info: {}", e))?;
+        if !columns.iter().any(|c| c == column)
+        {
+            info!("Adding column {} to table {}...", column, table);
+            conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {};",
+                                  table, column, decl), &[])
+                .map_err(|e| error!(DataError, "Failed to add column: {}", e))?;
+        }
         Ok(())
     }
 
     pub fn init(&self) -> Result<(), Error>
     {
         let conn = self.confirmConnection()?;
+        #[cfg(feature = "postgres")]
+        if conn.sqlite().is_none()
+        {
+            return conn.executeBatch(POSTGRES_SCHEMA).map_err(
+                |e| error!(DataError, "Failed to create tables: {}", e));
+        }
         conn.execute(
             "CREATE TABLE IF NOT EXISTS albums (
              id INTEGER PRIMARY KEY ASC,
              title TEXT
-             );", []).map_err(
+             );", &[]).map_err(
             |e| error!(DataError, "Failed to create table: {}", e))?;
         conn.execute(
             "CREATE TABLE IF NOT EXISTS posts (
@@ -83,7 +316,7 @@ impl Manager
              upload_time INTEGER,
              album INTEGER,
              FOREIGN KEY(album) REFERENCES albums(id)
-             );", []).map_err(
+             );", &[]).map_err(
             |e| error!(DataError, "Failed to create table: {}", e))?;
         conn.execute(
             "CREATE TABLE IF NOT EXISTS images (
@@ -93,13 +326,136 @@ impl Manager
              height INTEGER,
              post id,
              FOREIGN KEY(post) REFERENCES posts(id)
-             );", []).map_err(
+             );", &[]).map_err(
+            |e| error!(DataError, "Failed to create table: {}", e))?;
+        // Byte sizes of the image file and the thumbnail file.
+        Self::addColumnIfMissing(&conn, "images", "size", "INTEGER")?;
+        Self::addColumnIfMissing(&conn, "images", "thumbnail_size", "INTEGER")?;
+        Self::addColumnIfMissing(&conn, "images", "alt_text", "TEXT")?;
+        Self::addColumnIfMissing(&conn, "images", "caption", "TEXT")?;
+        Self::addColumnIfMissing(&conn, "images", "color", "TEXT")?;
+        Self::addColumnIfMissing(&conn, "images", "blurhash", "TEXT")?;
+        // The perceptual hash, with the bits of the u64.
+        Self::addColumnIfMissing(&conn, "images", "phash", "INTEGER")?;
+        // The order of the images in a post. Images from before this
+        // column are in the order they were added.
+        Self::addColumnIfMissing(&conn, "images", "position", "INTEGER")?;
+        conn.execute("UPDATE images SET position = id WHERE position IS NULL;",
+                     &[]).map_err(
+            |e| error!(DataError, "Failed to set image positions: {}", e))?;
+        conn.execute("CREATE INDEX IF NOT EXISTS images_post ON images (post);",
+                     &[]).map_err(
+            |e| error!(DataError, "Failed to create index: {}", e))?;
+        // The path of the image chosen as the cover of an album.
+        Self::addColumnIfMissing(&conn, "albums", "cover", "TEXT")?;
+        // The album that an album is in, e.g. a collection of albums.
+        Self::addColumnIfMissing(&conn, "albums", "parent", "INTEGER")?;
+        Self::addColumnIfMissing(&conn, "posts", "redacted",
+                                 "INTEGER NOT NULL DEFAULT 0")?;
+        Self::addColumnIfMissing(&conn, "posts", "slug", "TEXT")?;
+        Self::addColumnIfMissing(&conn, "posts", "draft",
+                                 "INTEGER NOT NULL DEFAULT 0")?;
+        Self::addColumnIfMissing(&conn, "posts", "visibility",
+                                 "TEXT NOT NULL DEFAULT 'public'")?;
+        Self::addColumnIfMissing(&conn, "posts", "views",
+                                 "INTEGER NOT NULL DEFAULT 0")?;
+        Self::addColumnIfMissing(&conn, "posts", "scheduled",
+                                 "INTEGER NOT NULL DEFAULT 0")?;
+        Self::addColumnIfMissing(&conn, "posts", "latitude", "REAL")?;
+        Self::addColumnIfMissing(&conn, "posts", "longitude", "REAL")?;
+        Self::addColumnIfMissing(&conn, "posts", "place", "TEXT")?;
+        Self::addColumnIfMissing(&conn, "posts", "sensitive",
+                                 "INTEGER NOT NULL DEFAULT 0")?;
+        Self::addColumnIfMissing(&conn, "posts", "license", "TEXT")?;
+        // When a deleted post was moved to the trash, or NULL.
+        Self::addColumnIfMissing(&conn, "posts", "trashed", "INTEGER")?;
+        conn.execute("CREATE UNIQUE INDEX IF NOT EXISTS posts_slug ON posts (slug);",
+                     &[]).map_err(
+            |e| error!(DataError, "Failed to create index: {}", e))?;
+        // Tags are listed in the order they were added.
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS tags (
+             post INTEGER,
+             tag TEXT,
+             PRIMARY KEY(post, tag),
+             FOREIGN KEY(post) REFERENCES posts(id)
+             );", &[]).map_err(
+            |e| error!(DataError, "Failed to create table: {}", e))?;
+        conn.execute("CREATE INDEX IF NOT EXISTS tags_tag ON tags (tag);",
+                     &[]).map_err(
+            |e| error!(DataError, "Failed to create index: {}", e))?;
+        // Tags from the classifier that the admin hasn’t approved or
+        // rejected yet.
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS suggested_tags (
+             post INTEGER,
+             tag TEXT,
+             PRIMARY KEY(post, tag),
+             FOREIGN KEY(post) REFERENCES posts(id)
+             );", &[]).map_err(
             |e| error!(DataError, "Failed to create table: {}", e))?;
         conn.execute(
             "CREATE TABLE IF NOT EXISTS sessions (
              token TEXT PRIMARY KEY,
              auth_time INTEGER
-             );", []).map_err(
+             );", &[]).map_err(
+            |e| error!(DataError, "Failed to create table: {}", e))?;
+        Self::addColumnIfMissing(&conn, "sessions", "user_agent", "TEXT")?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS share_links (
+             token TEXT PRIMARY KEY,
+             post INTEGER,
+             expire_time INTEGER,
+             FOREIGN KEY(post) REFERENCES posts(id)
+             );", &[]).map_err(
+            |e| error!(DataError, "Failed to create table: {}", e))?;
+        // A liker is a cookie, or a hashed IP address for clients
+        // without the cookie.
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS likes (
+             post INTEGER,
+             liker TEXT,
+             ip_hash TEXT,
+             time INTEGER,
+             PRIMARY KEY(post, liker),
+             FOREIGN KEY(post) REFERENCES posts(id)
+             );", &[]).map_err(
+            |e| error!(DataError, "Failed to create table: {}", e))?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS users (
+             username TEXT PRIMARY KEY,
+             password_hash TEXT
+             );", &[]).map_err(
+            |e| error!(DataError, "Failed to create table: {}", e))?;
+        // Failed logins by client address, for the lockout.
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS login_failures (
+             client TEXT PRIMARY KEY,
+             count INTEGER,
+             last_time INTEGER
+             );", &[]).map_err(
+            |e| error!(DataError, "Failed to create table: {}", e))?;
+        // API tokens are stored as their SHA-256 digests.
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS tokens (
+             id INTEGER PRIMARY KEY AUTOINCREMENT,
+             name TEXT,
+             hash TEXT UNIQUE,
+             created INTEGER,
+             last_used INTEGER
+             );", &[]).map_err(
+            |e| error!(DataError, "Failed to create table: {}", e))?;
+        // Outbound deliveries of posts that are not done yet. The
+        // kind is the service, e.g. “webhook”.
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS deliveries (
+             id INTEGER PRIMARY KEY AUTOINCREMENT,
+             kind TEXT NOT NULL,
+             post INTEGER NOT NULL,
+             attempts INTEGER NOT NULL DEFAULT 0,
+             next_time INTEGER NOT NULL,
+             FOREIGN KEY(post) REFERENCES posts(id)
+             );", &[]).map_err(
             |e| error!(DataError, "Failed to create table: {}", e))?;
         Ok(())
     }
@@ -108,35 +464,112 @@ impl Manager
     {
         let conn = self.confirmConnection()?;
         let row_count = conn.execute(
-            "INSERT INTO posts (desc, upload_time, album)
-             VALUES (?, ?, ?);", sql::params![
+            "INSERT INTO posts (\"desc\", upload_time, album, slug, draft,
+                                visibility, scheduled, latitude, longitude,
+                                place, sensitive, license)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);", sql::params![
                  &post.desc,
                  post.upload_time.unix_timestamp(),
                  album_id,
+                 post.slug,
+                 post.draft,
+                 post.visibility.asStr(),
+                 post.scheduled,
+                 post.location.as_ref().map(|l| l.latitude),
+                 post.location.as_ref().map(|l| l.longitude),
+                 post.location.as_ref().and_then(|l| l.place.as_ref()),
+                 post.sensitive,
+                 post.license,
              ]).map_err(|e| error!(DataError, "Failed to add image: {}", e))?;
         if row_count != 1
         {
             return Err(error!(DataError, "Invalid insert happened"));
         }
-        let id = conn.last_insert_rowid();
-        for img in &post.images
+        let id = conn.lastInsertId().map_err(
+            |e| error!(DataError, "Failed to add post: {}", e))?;
+        for (position, img) in post.images.iter().enumerate()
         {
-            self.addImage(&img, id)?;
+            self.addImage(img, id, position)?;
         }
+        self.addTags(id, &post.tags)?;
         Ok(id)
     }
 
-    fn addImage(&self, img: &Image, post_id: i64) -> Result<(), Error>
+    /// Add a post with the ID in `post`, instead of letting the
+    /// database assign one. This is used when importing from an
+    /// archive.
+    pub fn importPost(&self, post: &Post) -> Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        let row_count = conn.execute(
+            "INSERT INTO posts (id, \"desc\", upload_time, album, redacted, slug,
+                                draft, visibility, scheduled, latitude,
+                                longitude, place, sensitive, license)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);", sql::params![
+                 post.id,
+                 &post.desc,
+                 post.upload_time.unix_timestamp(),
+                 post.album_id,
+                 post.redacted,
+                 post.slug,
+                 post.draft,
+                 post.visibility.asStr(),
+                 post.scheduled,
+                 post.location.as_ref().map(|l| l.latitude),
+                 post.location.as_ref().map(|l| l.longitude),
+                 post.location.as_ref().and_then(|l| l.place.as_ref()),
+                 post.sensitive,
+                 post.license,
+             ]).map_err(|e| error!(DataError, "Failed to import post: {}", e))?;
+        if row_count != 1
+        {
+            return Err(error!(DataError, "Invalid insert happened"));
+        }
+        conn.syncIds("posts").map_err(
+            |e| error!(DataError, "Failed to import post: {}", e))?;
+        for (position, img) in post.images.iter().enumerate()
+        {
+            self.addImage(img, post.id, position)?;
+        }
+        self.addTags(post.id, &post.tags)
+    }
+
+    fn addTags(&self, post_id: i64, tags: &[String]) -> Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        for tag in tags
+        {
+            conn.execute("INSERT OR IGNORE INTO tags (post, tag) VALUES (?, ?);",
+                         sql::params![post_id, tag])
+                .map_err(|e| error!(DataError, "Failed to add tag: {}", e))?;
+        }
+        // This is the last step of adding a post.
+        self.postsChanged();
+        Ok(())
+    }
+
+    fn addImage(&self, img: &Image, post_id: i64, position: usize) ->
+        Result<(), Error>
     {
         let conn = self.confirmConnection()?;
         let row_count = conn.execute(
-            "INSERT INTO images (path, width, height, post)
-             VALUES (?, ?, ?, ?);", sql::params![
+            "INSERT INTO images (path, width, height, post, size, thumbnail_size,
+                                 alt_text, caption, position, color, blurhash,
+                                 phash)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);", sql::params![
                  &img.path.to_str().ok_or_else(
                      || rterr!("Invalid image path: {:?}", img.path))?,
                  img.width,
                  img.height,
                  post_id,
+                 img.size,
+                 img.thumbnail_size,
+                 Some(&img.alt_text).filter(|t| !t.is_empty()),
+                 Some(&img.caption).filter(|t| !t.is_empty()),
+                 position,
+                 Some(&img.color).filter(|t| !t.is_empty()),
+                 Some(&img.blurhash).filter(|t| !t.is_empty()),
+                 img.phash.map(|h| h as i64),
              ]).map_err(|e| error!(DataError, "Failed to add image: {}", e))?;
         if row_count != 1
         {
@@ -148,226 +581,1968 @@ impl Manager
     pub fn deletePost(&self, post_id: i64) -> Result<(), Error>
     {
         let conn = self.confirmConnection()?;
-        let row_count = conn.execute("DELETE FROM images WHERE post = ?;",
-                                     sql::params![post_id,]).map_err(
+        // A redacted post has no images.
+        conn.execute("DELETE FROM images WHERE post = ?;",
+                     sql::params![post_id,]).map_err(
             |e| error!(DataError, "Failed to delete images: {}", e))?;
-        if row_count == 0
-        {
-            return Err(error!(DataError, "Post not found"));
-        }
+        conn.execute("DELETE FROM share_links WHERE post = ?;",
+                     sql::params![post_id,]).map_err(
+            |e| error!(DataError, "Failed to delete share links: {}", e))?;
+        conn.execute("DELETE FROM likes WHERE post = ?;",
+                     sql::params![post_id,]).map_err(
+            |e| error!(DataError, "Failed to delete likes: {}", e))?;
+        conn.execute("DELETE FROM tags WHERE post = ?;",
+                     sql::params![post_id,]).map_err(
+            |e| error!(DataError, "Failed to delete tags: {}", e))?;
+        conn.execute("DELETE FROM suggested_tags WHERE post = ?;",
+                     sql::params![post_id,]).map_err(
+            |e| error!(DataError, "Failed to delete suggested tags: {}", e))?;
+        conn.execute("DELETE FROM deliveries WHERE post = ?;",
+                     sql::params![post_id,]).map_err(
+            |e| error!(DataError, "Failed to delete deliveries: {}", e))?;
         let row_count = conn.execute("DELETE FROM posts WHERE id = ?;",
                                      sql::params![post_id,]).map_err(
             |e| error!(DataError, "Failed to delete post: {}", e))?;
+        self.postsChanged();
+        if row_count != 1
+        {
+            return Err(error!(DataError, "Post not found"));
+        }
+        Ok(())
+    }
+
+    /// Remove the images of a post from the database, but keep the
+    /// post, marked as redacted.
+    pub fn redactPost(&self, post_id: i64) -> Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        let row_count = conn.execute(
+            "UPDATE posts SET redacted = 1 WHERE id = ?;",
+            sql::params![post_id,]).map_err(
+            |e| error!(DataError, "Failed to redact post: {}", e))?;
         if row_count != 1
         {
-            return Err(error!(DataError, "Invalid deletion happened."));
+            return Err(error!(DataError, "Post not found"));
         }
+        conn.execute("DELETE FROM images WHERE post = ?;",
+                     sql::params![post_id,]).map_err(
+            |e| error!(DataError, "Failed to delete images: {}", e))?;
+        self.postsChanged();
         Ok(())
     }
 
-    fn row2Post(row: &sql::Row, images: Vec<Image>) -> sql::Result<Post>
+    /// A post from a row of `POST_COLUMNS`, without its images and
+    /// tags, which are filled in by `fillImagesAndTags`.
+    fn row2Post(row: &database::Row) -> sql::Result<Post>
     {
         let time_value = row.get(2)?;
+        let visibility: String = row.get(7)?;
+        let latitude: Option<f64> = row.get(11)?;
+        let longitude: Option<f64> = row.get(12)?;
         Ok(Post {
             id: row.get(0)?,
-            images,
+            images: Vec::new(),
             desc: row.get(1)?,
             upload_time: time::OffsetDateTime::from_unix_timestamp(
                 time_value).map_err(
                 |_| sql::Error::IntegralValueOutOfRange(
                     2, time_value))?,
             album_id: row.get(3)?,
+            redacted: row.get(4)?,
+            slug: row.get(5)?,
+            draft: row.get(6)?,
+            visibility: Visibility::fromStr(&visibility).ok_or_else(
+                || sql::Error::InvalidColumnType(
+                    7, String::from("visibility"), sql::types::Type::Text))?,
+            likes: row.get(8)?,
+            views: row.get(9)?,
+            scheduled: row.get(10)?,
+            location: match (latitude, longitude)
+            {
+                (Some(latitude), Some(longitude)) => Some(Location {
+                    latitude, longitude, place: row.get(13)?,
+                }),
+                _ => None,
+            },
+            tags: Vec::new(),
+            sensitive: row.get(14)?,
+            license: row.get(15)?,
         })
     }
 
-    fn row2Image(row: &sql::Row) -> sql::Result<Image>
+    fn row2Image(row: &database::Row) -> sql::Result<Image>
     {
         let path: String = row.get(0)?;
         Ok(Image {
             path: PathBuf::from_str(&path).unwrap(),
             width: row.get(1)?,
             height: row.get(2)?,
+            size: row.get::<Option<u64>>(3)?.unwrap_or(0),
+            thumbnail_size: row.get::<Option<u64>>(4)?.unwrap_or(0),
+            alt_text: row.get::<Option<String>>(5)?.unwrap_or_default(),
+            caption: row.get::<Option<String>>(6)?.unwrap_or_default(),
+            color: row.get::<Option<String>>(7)?.unwrap_or_default(),
+            blurhash: row.get::<Option<String>>(8)?.unwrap_or_default(),
+            ..Default::default()
         })
     }
 
+    /// Set the images and tags of `posts`, with one query each.
+    fn fillImagesAndTags(conn: &database::Connection, posts: &mut [Post]) ->
+        Result<(), Error>
+    {
+        if posts.is_empty()
+        {
+            return Ok(());
+        }
+        let ids = posts.iter().map(|p| p.id.to_string()).collect::<Vec<_>>()
+            .join(",");
+        let mut images: HashMap<i64, Vec<Image>> = HashMap::new();
+        let rows = conn.queryRows(&format!(
+            "SELECT path, width, height, size, thumbnail_size, alt_text, caption,
+             color, blurhash, post FROM images WHERE post IN ({}) ORDER BY position, id;", ids),
+            &[], |row| Ok((row.get(9)?, Self::row2Image(row)?)))
+            .map_err(|e| error!(DataError, "Failed to retrieve image: {}", e))?;
+        for (post_id, image) in rows
+        {
+            images.entry(post_id).or_default().push(image);
+        }
+        let mut tags: HashMap<i64, Vec<String>> = HashMap::new();
+        let rows = conn.queryRows(&format!(
+            "SELECT post, tag FROM tags WHERE post IN ({}) ORDER BY rowid;", ids),
+            &[], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|e| error!(DataError, "Failed to retrieve tags: {}", e))?;
+        for (post_id, tag) in rows
+        {
+            tags.entry(post_id).or_default().push(tag);
+        }
+        for post in posts
+        {
+            post.images = images.remove(&post.id).unwrap_or_default();
+            post.tags = tags.remove(&post.id).unwrap_or_default();
+        }
+        Ok(())
+    }
+
     pub fn findPostByID(&self, post_id: i64) -> Result<Option<Post>, Error>
     {
         let conn = self.confirmConnection()?;
-        let mut cmd = conn.prepare(
-            "SELECT path, width, height FROM images WHERE post = ?;")
-            .map_err(|e| error!(
-                DataError,
-                "Failed to compare statement to get images: {}", e))?;
-        let images: Result<Vec<Image>, Error> =
-            cmd.query_map([post_id,], Self::row2Image)
-            .map_err(|e| error!(DataError, "Failed to retrieve image: {}", e))?
-            .map(|row| row.map_err(|e| error!(DataError, "{}", e)))
-            .collect();
-        let images = images?;
-        conn.query_row(
-            "SELECT id, desc, upload_time, album FROM posts WHERE id=?;",
-            sql::params![post_id], |row| Self::row2Post(row, images))
+        let post = conn.queryRow(
+            &format!("SELECT {} FROM posts WHERE id=? AND trashed IS NULL;",
+                     POST_COLUMNS),
+            sql::params![post_id], Self::row2Post)
             .optional().map_err(
-                |e| error!(DataError, "Failed to look up post {}: {}", post_id, e))
+                |e| error!(DataError, "Failed to look up post {}: {}", post_id, e))?;
+        match post
+        {
+            Some(mut post) => {
+                Self::fillImagesAndTags(&conn, std::slice::from_mut(&mut post))?;
+                Ok(Some(post))
+            },
+            None => Ok(None),
+        }
     }
 
-    /// Retrieve “count” number of posts, starting from the entry at
-    /// index “start_index”. Index is 0-based. Returned entries are
-    /// sorted from new to old.
-    pub fn getPosts(&self, start_index: u64, count: u64, order: PostOrder) ->
-        Result<Vec<Post>, Error>
+    pub fn findPostBySlug(&self, slug: &str) -> Result<Option<Post>, Error>
     {
         let conn = self.confirmConnection()?;
-
-        let order_expr = match order
+        let id: Option<i64> = conn.queryRow(
+            "SELECT id FROM posts WHERE slug = ?;", sql::params![slug], |row| row.get(0))
+            .optional().map_err(
+                |e| error!(DataError, "Failed to look up post {}: {}", slug, e))?;
+        match id
         {
-            PostOrder::NewFirst => "ORDER BY upload_time DESC",
-        };
+            Some(id) => self.findPostByID(id),
+            None => Ok(None),
+        }
+    }
 
-        let mut cmd = conn.prepare(
-            &format!("SELECT id FROM posts {} LIMIT ? OFFSET ?;", order_expr))
-            .map_err(|e| error!(
-                DataError,
-                "Failed to compare statement to get posts: {}", e))?;
-        let ids = cmd.query_map([count, start_index], |row| row.get(0))
-            .map_err(|e| error!(DataError, "Failed to retrieve videos: {}", e))?
-            .map(|row| row.map_err(|e| error!(DataError, "{}", e)));
-        let mut result: Vec<Post> = Vec::new();
-        for id in ids
-        {
-            let id = id?;
-            if let Some(p) = self.findPostByID(id)?
-            {
-                result.push(p);
-            }
-            else
+    /// Return `slug` if no post has it, otherwise `slug` with a
+    /// number appended that makes it unique.
+    pub fn uniqueSlug(&self, slug: &str) -> Result<String, Error>
+    {
+        let conn = self.confirmConnection()?;
+        let exists = |s: &str| -> Result<bool, Error> {
+            conn.queryRow("SELECT COUNT(*) FROM posts WHERE slug = ?;", sql::params![s],
+                           |row| row.get::<i64>(0))
+                .map(|count| count > 0)
+                .map_err(|e| error!(DataError, "Failed to look up slug: {}", e))
+        };
+        if !exists(slug)?
+        {
+            return Ok(slug.to_owned());
+        }
+        let mut n = 2;
+        loop
+        {
+            let candidate = format!("{}-{}", slug, n);
+            if !exists(&candidate)?
             {
-                return Err(error!(
-                    DataError, "Failed to retrieve post with id {}.", id));
+                return Ok(candidate);
             }
+            n += 1;
         }
-        Ok(result)
     }
 
-    pub fn countPosts(&self) -> Result<u64, Error>
+    /// The SQL condition of posts that are published and live. A
+    /// scheduled post is live as soon as its time comes, whether it
+    /// is announced or not.
+    fn liveCondition() -> String
+    {
+        format!("draft = 0 AND trashed IS NULL AND
+                 (scheduled = 0 OR upload_time <= {})",
+                time::OffsetDateTime::now_utc().unix_timestamp())
+    }
+
+    /// Retrieve “count” number of published public posts, starting
+    /// from the entry at index “start_index”. Index is 0-based.
+    pub fn getPosts(&self, start_index: u64, count: u64, order: PostOrder) ->
+        Result<Vec<Post>, Error>
+    {
+        self.queryPosts(&format!("WHERE {} AND visibility = 'public'",
+                                 Self::liveCondition()),
+                        start_index, count, order)
+    }
+
+    /// The condition of posts in an album, or in the albums in it,
+    /// recursively. The album is the only parameter.
+    fn inAlbumCondition() -> &'static str
+    {
+        // UNION drops the albums that were already visited, so this
+        // ends even if the parents make a loop. PostgreSQL needs the
+        // type of the parameter.
+        "album IN (WITH RECURSIVE tree(id) AS
+                   (SELECT CAST(? AS BIGINT) UNION
+                    SELECT albums.id FROM albums JOIN tree ON albums.parent = tree.id)
+                   SELECT id FROM tree)"
+    }
+
+    /// Like `getPosts`, but only the posts in the album, and in the
+    /// albums in it.
+    pub fn getPostsInAlbum(&self, album_id: i64, start_index: u64, count: u64,
+                           order: PostOrder) -> Result<Vec<Post>, Error>
+    {
+        self.queryPostsWithParams(
+            &format!("WHERE {} AND visibility = 'public' AND {}",
+                     Self::liveCondition(), Self::inAlbumCondition()),
+            &[&album_id], start_index, count, order)
+    }
+
+    /// Like `getPosts`, but only the posts with the tag.
+    pub fn getPostsWithTag(&self, tag: &str, start_index: u64, count: u64,
+                           order: PostOrder) -> Result<Vec<Post>, Error>
+    {
+        self.queryPostsWithParams(
+            &format!("WHERE {} AND visibility = 'public' AND
+                      id IN (SELECT post FROM tags WHERE tag = ?)",
+                     Self::liveCondition()),
+            &[&tag], start_index, count, order)
+    }
+
+    /// Retrieve “count” number of live posts uploaded in the Unix
+    /// time range [`from`, `to`), old first, starting from index
+    /// `start_index`. Unlisted and private posts are included if
+    /// `include_hidden` is true.
+    pub fn getPostsInRange(&self, from: i64, to: i64, include_hidden: bool,
+                           start_index: u64, count: u64) ->
+        Result<Vec<Post>, Error>
+    {
+        let visibility = if include_hidden
+        {
+            ""
+        }
+        else
+        {
+            " AND visibility = 'public'"
+        };
+        self.queryPosts(&format!(
+            "WHERE {}{} AND upload_time >= {} AND upload_time < {}",
+            Self::liveCondition(), visibility, from, to),
+                        start_index, count, PostOrder::OldFirst)
+    }
+
+    /// The `count` public posts with the most views.
+    pub fn getMostViewed(&self, count: u64) -> Result<Vec<Post>, Error>
+    {
+        self.getPosts(0, count, PostOrder::MostViewed)
+    }
+
+    /// All published posts that are unlisted or private, new first.
+    pub fn getHiddenPosts(&self) -> Result<Vec<Post>, Error>
+    {
+        self.queryPosts(&format!("WHERE {} AND visibility != 'public'",
+                                 Self::liveCondition()),
+                        0, i64::MAX as u64, PostOrder::NewFirst)
+    }
+
+    /// Scheduled posts that are not live yet, the next one first.
+    pub fn getPendingPosts(&self) -> Result<Vec<Post>, Error>
+    {
+        self.queryPosts(&format!(
+            "WHERE draft = 0 AND trashed IS NULL AND scheduled = 1 AND
+             upload_time > {}",
+            time::OffsetDateTime::now_utc().unix_timestamp()),
+                        0, i64::MAX as u64, PostOrder::OldFirst)
+    }
+
+    /// Scheduled posts that are live but not announced yet.
+    pub fn getDueScheduledPosts(&self) -> Result<Vec<Post>, Error>
+    {
+        self.queryPosts(&format!(
+            "WHERE draft = 0 AND trashed IS NULL AND scheduled = 1 AND
+             upload_time <= {}",
+            time::OffsetDateTime::now_utc().unix_timestamp()),
+                        0, i64::MAX as u64, PostOrder::OldFirst)
+    }
+
+    /// The upload time of the newest published post, including the
+    /// scheduled ones that are not live yet.
+    pub fn latestPostTime(&self) -> Result<Option<time::OffsetDateTime>, Error>
     {
         let conn = self.confirmConnection()?;
-        conn.query_row("SELECT COUNT(*) FROM posts;", [], |row| row.get(0))
-            .map_err(|e| error!(DataError, "Failed to count posts: {}", e))
+        let time: Option<i64> = conn.queryRow(
+            "SELECT MAX(upload_time) FROM posts WHERE draft = 0 AND trashed IS NULL;",
+            &[],
+            |row| row.get(0))
+            .map_err(|e| error!(DataError, "Failed to query posts: {}", e))?;
+        time.map(|t| time::OffsetDateTime::from_unix_timestamp(t).map_err(
+            |_| error!(DataError, "Invalid upload time: {}", t))).transpose()
     }
 
-    pub fn createSession(&self, token: &str) -> Result<(), Error>
+    /// Mark a scheduled post as announced. Return false if it was
+    /// already, so that it is announced only once.
+    pub fn clearScheduled(&self, post_id: i64) -> Result<bool, Error>
     {
         let conn = self.confirmConnection()?;
         let row_count = conn.execute(
-            "INSERT INTO sessions (token, auth_time)
-             VALUES (?, ?);", sql::params![
-                 token,
-                 OffsetDateTime::now_utc().unix_timestamp(),
-             ]).map_err(|e| error!(DataError, "Failed to create session: {}", e))?;
-        if row_count != 1
-        {
-            return Err(error!(DataError, "Invalid insert happened"));
-        }
-        Ok(())
+            "UPDATE posts SET scheduled = 0 WHERE id = ? AND scheduled = 1;",
+            sql::params![post_id]).map_err(
+            |e| error!(DataError, "Failed to update scheduled post: {}", e))?;
+        // The post went live a moment ago.
+        self.postsChanged();
+        Ok(row_count == 1)
+    }
+
+    /// All drafts, new first.
+    pub fn getDrafts(&self) -> Result<Vec<Post>, Error>
+    {
+        self.queryPosts("WHERE draft = 1 AND trashed IS NULL", 0, i64::MAX as u64,
+                        PostOrder::NewFirst)
+    }
+
+    /// All posts including drafts, new first. Posts in the trash are
+    /// not included.
+    pub fn getAllPosts(&self) -> Result<Vec<Post>, Error>
+    {
+        self.queryPosts("WHERE trashed IS NULL", 0, i64::MAX as u64,
+                        PostOrder::NewFirst)
+    }
+
+    /// The posts in the trash, and when they were moved there, the
+    /// last one first.
+    pub fn getTrashedPosts(&self) -> Result<Vec<(Post, OffsetDateTime)>, Error>
+    {
+        let conn = self.confirmConnection()?;
+        let rows: Vec<(Post, i64)> = conn.queryRows(&format!(
+            "SELECT {}, trashed FROM posts WHERE trashed IS NOT NULL
+             ORDER BY trashed DESC, id DESC;", POST_COLUMNS),
+            &[], |row| Ok((Self::row2Post(row)?, row.get(16)?)))
+            .map_err(|e| error!(DataError, "Failed to retrieve posts: {}", e))?;
+        let (mut posts, times): (Vec<Post>, Vec<i64>) = rows.into_iter().unzip();
+        Self::fillImagesAndTags(&conn, &mut posts)?;
+        Ok(posts.into_iter().zip(times.into_iter().map(
+            |t| OffsetDateTime::from_unix_timestamp(t)
+                .unwrap_or(OffsetDateTime::UNIX_EPOCH))).collect())
     }
 
-    /// Return time of authentication of the token.
-    pub fn hasSession(&self, token: &str) -> Result<OffsetDateTime, Error>
+    /// The post with ID `post_id` if it is in the trash.
+    pub fn findTrashedPost(&self, post_id: i64) -> Result<Option<Post>, Error>
     {
         let conn = self.confirmConnection()?;
-        let mut cmd = conn.prepare(
-            "SELECT auth_time FROM sessions WHERE token=?;")
-            .map_err(|e| error!(
-                DataError,
-                "Failed to prepare statement to lookup session: {}", e))?;
-        if let Some(auth_time_sec) = cmd.query_row([token,], |row| row.get(0))
+        let post = conn.queryRow(
+            &format!("SELECT {} FROM posts WHERE id=? AND trashed IS NOT NULL;",
+                     POST_COLUMNS),
+            sql::params![post_id], Self::row2Post)
             .optional().map_err(
-                |e| error!(DataError, "Failed to look up session: {}", e))?
+                |e| error!(DataError, "Failed to look up post {}: {}", post_id, e))?;
+        match post
         {
-            OffsetDateTime::from_unix_timestamp(auth_time_sec).map_err(
-                |_| rterr!("Invalid auth time"))
-        }
-        else
-        {
-            Err(rterr!("Session not found"))
+            Some(mut post) => {
+                Self::fillImagesAndTags(&conn, std::slice::from_mut(&mut post))?;
+                Ok(Some(post))
+            },
+            None => Ok(None),
         }
     }
 
-    pub fn expireSessions(&self, life_time_sec: u64) -> Result<(), Error>
+    /// Move a post into the trash, after which it is hidden
+    /// everywhere but on the trash page. Return false if there is no
+    /// such post outside of the trash.
+    pub fn trashPost(&self, post_id: i64) -> Result<bool, Error>
     {
         let conn = self.confirmConnection()?;
-        let now = OffsetDateTime::now_utc().unix_timestamp();
         let row_count = conn.execute(
-            "DELETE FROM sessions WHERE auth_time < ?;",
-            sql::params![now as u64 - life_time_sec])
-            .map_err(|e| error!(DataError, "Failed to expire sessions: {}", e))?;
-        if row_count > 0
-        {
-            info!("Expired {} sessions.", row_count);
-        }
-        Ok(())
+            "UPDATE posts SET trashed = ? WHERE id = ? AND trashed IS NULL;",
+            sql::params![OffsetDateTime::now_utc().unix_timestamp(), post_id])
+            .map_err(|e| error!(DataError, "Failed to trash post: {}", e))?;
+        self.postsChanged();
+        Ok(row_count == 1)
     }
-}
 
-// ========== Unit tests ============================================>
+    /// Take a post out of the trash. Return false if it is not there.
+    pub fn restorePost(&self, post_id: i64) -> Result<bool, Error>
+    {
+        let conn = self.confirmConnection()?;
+        let row_count = conn.execute(
+            "UPDATE posts SET trashed = NULL WHERE id = ? AND trashed IS NOT NULL;",
+            sql::params![post_id])
+            .map_err(|e| error!(DataError, "Failed to restore post: {}", e))?;
+        self.postsChanged();
+        Ok(row_count == 1)
+    }
 
-#[cfg(test)]
-mod tests
-{
-    use super::*;
+    fn queryPosts(&self, condition: &str, start_index: u64, count: u64,
+                  order: PostOrder) -> Result<Vec<Post>, Error>
+    {
+        self.queryPostsWithParams(condition, &[], start_index, count, order)
+    }
 
-    fn tempFile() -> PathBuf
+    /// Like `queryPosts`, with `params` for the placeholders in
+    /// `condition`.
+    fn queryPostsWithParams(&self, condition: &str, params: &[&dyn sql::ToSql],
+                            start_index: u64, count: u64, order: PostOrder) ->
+        Result<Vec<Post>, Error>
     {
-        std::env::temp_dir().join(OffsetDateTime::now_utc().unix_timestamp_nanos().to_string())
+        let conn = self.confirmConnection()?;
+
+        let order_expr = match order
+        {
+            PostOrder::NewFirst => String::from("ORDER BY upload_time DESC"),
+            PostOrder::OldFirst => String::from("ORDER BY upload_time ASC"),
+            // Multiplying by an odd number modulo a prime larger than
+            // any ID is a permutation of the IDs.
+            PostOrder::Random(seed) => format!(
+                "ORDER BY (id * {}) % 4294967291, id", seed as u64 * 2 + 1),
+            PostOrder::MostLiked => String::from(
+                "ORDER BY (SELECT COUNT(*) FROM likes WHERE post = posts.id) DESC,
+                 upload_time DESC"),
+            PostOrder::MostViewed => String::from(
+                "ORDER BY views DESC, upload_time DESC"),
+        };
+
+        let mut all_params = params.to_vec();
+        all_params.push(&count);
+        all_params.push(&start_index);
+        let mut result: Vec<Post> = conn.queryRows(
+            &format!("SELECT {} FROM posts {} {} LIMIT ? OFFSET ?;", POST_COLUMNS,
+                     condition, order_expr),
+            all_params.as_slice(), Self::row2Post)
+            .map_err(|e| error!(DataError, "Failed to retrieve posts: {}", e))?;
+        Self::fillImagesAndTags(&conn, &mut result)?;
+        Ok(result)
     }
 
-    struct FileDeleter
+    /// The URL arguments and upload times of live public posts, new
+    /// first, without loading the posts.
+    pub fn getPostUrlArgs(&self, start_index: u64, count: u64) ->
+        Result<Vec<(String, i64)>, Error>
     {
-        files: Vec<PathBuf>,
+        let conn = self.confirmConnection()?;
+        conn.queryRows(&format!(
+            "SELECT id, slug, upload_time FROM posts
+             WHERE {} AND visibility = 'public'
+             ORDER BY upload_time DESC LIMIT ? OFFSET ?;", Self::liveCondition()),
+            sql::params![count, start_index], |row| {
+            let id: i64 = row.get(0)?;
+            let slug: Option<String> = row.get(1)?;
+            let time: i64 = row.get(2)?;
+            let upload_time = OffsetDateTime::from_unix_timestamp(time)
+                .unwrap_or(OffsetDateTime::UNIX_EPOCH);
+            Ok((urlArgOf(id, slug.as_deref(), upload_time), time))
+        }).map_err(|e| error!(DataError, "Failed to retrieve posts: {}", e))
     }
 
-    impl FileDeleter
+    /// Up to `count` live public posts related to `post`. Posts in the
+    /// same album come first, then the ones sharing more tags, and the
+    /// ties go to the posts uploaded closer in time.
+    pub fn getRelatedPosts(&self, post: &Post, count: u64) ->
+        Result<Vec<Post>, Error>
     {
-        fn new() -> Self
-        {
-            Self { files: Vec::new() }
-        }
+        let conn = self.confirmConnection()?;
+        // The album comparison is a CASE, because PostgreSQL puts NULL
+        // first in a descending order.
+        let mut result: Vec<Post> = conn.queryRows(&format!(
+            "SELECT {} FROM posts
+             WHERE {} AND visibility = 'public' AND id != ?1
+             ORDER BY CASE WHEN album = ?2 THEN 1 ELSE 0 END DESC,
+                      (SELECT COUNT(*) FROM tags WHERE post = posts.id AND tag IN
+                       (SELECT tag FROM tags WHERE post = ?1)) DESC,
+                      ABS(upload_time - ?3) ASC
+             LIMIT ?4;", POST_COLUMNS, Self::liveCondition()),
+            sql::params![post.id, post.album_id, post.upload_time.unix_timestamp(),
+                         count],
+            Self::row2Post)
+            .map_err(|e| error!(DataError, "Failed to retrieve posts: {}", e))?;
+        Self::fillImagesAndTags(&conn, &mut result)?;
+        Ok(result)
+    }
 
-        fn register<P: AsRef<Path>>(&mut self, f: P)
+    /// The live public posts uploaded right before and right after
+    /// the post with ID `post_id`.
+    pub fn adjacentPosts(&self, post_id: i64) ->
+        Result<(Option<Post>, Option<Post>), Error>
+    {
+        let conn = self.confirmConnection()?;
+        let time: Option<i64> = conn.queryRow(
+            "SELECT upload_time FROM posts WHERE id = ?;", sql::params![post_id],
+            |row| row.get(0)).optional()
+            .map_err(|e| error!(DataError, "Failed to query post: {}", e))?;
+        let time = match time
         {
-            let p: &Path = f.as_ref();
-            self.files.push(p.to_owned());
-        }
+            Some(t) => t,
+            None => return Ok((None, None)),
+        };
+        // Posts uploaded at the same time are ordered by ID.
+        let neighbor = |compare: &str, order: &str| -> Result<Option<Post>, Error> {
+            let id: Option<i64> = conn.queryRow(&format!(
+                "SELECT id FROM posts
+                 WHERE {} AND visibility = 'public' AND (upload_time, id) {} (?, ?)
+                 ORDER BY upload_time {order}, id {order} LIMIT 1;",
+                Self::liveCondition(), compare, order = order),
+                sql::params![time, post_id], |row| row.get(0)).optional()
+                .map_err(|e| error!(DataError, "Failed to query posts: {}", e))?;
+            id.map(|id| self.findPostByID(id)).transpose().map(Option::flatten)
+        };
+        Ok((neighbor("<", "DESC")?, neighbor(">", "ASC")?))
     }
 
-    impl Drop for FileDeleter
+    /// Live public posts with a location, new first.
+    pub fn getLocatedPosts(&self) -> Result<Vec<Post>, Error>
     {
-        fn drop(&mut self)
+        self.queryPosts(&format!("WHERE {} AND visibility = 'public' AND
+                                  latitude IS NOT NULL", Self::liveCondition()),
+                        0, i64::MAX as u64, PostOrder::NewFirst)
+    }
+
+    /// Count the posts of `condition` with `params` in it, or return
+    /// the count cached as `key`.
+    fn cachedCount(&self, key: &str, condition: &str,
+                   params: &[&dyn sql::ToSql]) -> Result<u64, Error>
+    {
+        if let Some((time, count)) = self.counts.lock().unwrap().get(key)
         {
-            for f in &self.files
+            if time.elapsed() < COUNT_CACHE_TTL
             {
-                std::fs::remove_file(&f).ok();
+                return Ok(*count);
             }
         }
+        let conn = self.confirmConnection()?;
+        let count: u64 = conn.queryRow(
+            &format!("SELECT COUNT(*) FROM posts {};", condition), params,
+            |row| row.get(0))
+            .map_err(|e| error!(DataError, "Failed to count posts: {}", e))?;
+        self.counts.lock().unwrap().insert(key.to_owned(), (Instant::now(), count));
+        Ok(count)
     }
 
-    #[test]
-    fn addEmptyPostAndQuery() -> Result<(), Error>
+    /// Forget the cached counts, and count a change of posts.
+    fn postsChanged(&self)
     {
-        let mut manager = Manager::new(sqlite_connection::Source::Memory);
-        manager.connect()?;
+        self.counts.lock().unwrap().clear();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// A number that changes whenever posts are added, deleted, or
+    /// published, for caches of pages.
+    pub fn generation(&self) -> u64
+    {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Whether there are any posts at all, including the drafts and
+    /// the ones in the trash.
+    pub fn hasPosts(&self) -> Result<bool, Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.queryRow("SELECT EXISTS (SELECT 1 FROM posts);", &[], |row| row.get(0))
+            .map_err(|e| error!(DataError, "Failed to count posts: {}", e))
+    }
+
+    /// The number of live public posts.
+    pub fn countPosts(&self) -> Result<u64, Error>
+    {
+        self.cachedCount("all", &format!("WHERE {} AND visibility = 'public'",
+                                         Self::liveCondition()), &[])
+    }
+
+    /// The number of live public posts in the album, and in the
+    /// albums in it.
+    pub fn countPostsInAlbum(&self, album_id: i64) -> Result<u64, Error>
+    {
+        self.cachedCount(&format!("album:{}", album_id), &format!(
+            "WHERE {} AND visibility = 'public' AND {}",
+            Self::liveCondition(), Self::inAlbumCondition()), &[&album_id])
+    }
+
+    /// The number of live public posts with the tag.
+    pub fn countPostsWithTag(&self, tag: &str) -> Result<u64, Error>
+    {
+        self.cachedCount(&format!("tag:{}", tag), &format!(
+            "WHERE {} AND visibility = 'public' AND
+             id IN (SELECT post FROM tags WHERE tag = ?)",
+            Self::liveCondition()), &[&tag])
+    }
+
+    /// Make a draft a published post, with the description, slug,
+    /// visibility, license, and upload time in `post`.
+    pub fn publishDraft(&self, post: &Post) -> Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        let row_count = conn.execute(
+            "UPDATE posts SET \"desc\" = ?, slug = ?, visibility = ?, upload_time = ?,
+             sensitive = ?, license = ?, draft = 0 WHERE id = ? AND draft = 1;",
+            sql::params![
+                 &post.desc,
+                 post.slug,
+                 post.visibility.asStr(),
+                 post.upload_time.unix_timestamp(),
+                 post.sensitive,
+                 post.license,
+                 post.id,
+             ]).map_err(|e| error!(DataError, "Failed to publish draft: {}", e))?;
+        self.postsChanged();
+        if row_count != 1
+        {
+            return Err(error!(DataError, "Draft not found"));
+        }
+        Ok(())
+    }
+
+    /// Flag or unflag a post as sensitive. Return false if there is no
+    /// such post.
+    pub fn setPostSensitive(&self, post_id: i64, sensitive: bool) ->
+        Result<bool, Error>
+    {
+        let conn = self.confirmConnection()?;
+        let row_count = conn.execute(
+            "UPDATE posts SET sensitive = ? WHERE id = ? AND trashed IS NULL;",
+            sql::params![sensitive, post_id]).map_err(
+            |e| error!(DataError, "Failed to flag post: {}", e))?;
+        self.postsChanged();
+        Ok(row_count > 0)
+    }
+
+    /// Set the license of a post, or clear it with None. Return false
+    /// if there is no such post.
+    pub fn setPostLicense(&self, post_id: i64, license: Option<&str>) ->
+        Result<bool, Error>
+    {
+        let conn = self.confirmConnection()?;
+        let row_count = conn.execute(
+            "UPDATE posts SET license = ? WHERE id = ? AND trashed IS NULL;",
+            sql::params![license, post_id]).map_err(
+            |e| error!(DataError, "Failed to set license: {}", e))?;
+        self.postsChanged();
+        Ok(row_count > 0)
+    }
+
+    /// The total size of the images and their thumbnails.
+    pub fn libraryBytes(&self) -> Result<u64, Error>
+    {
+        let conn = self.confirmConnection()?;
+        // PostgreSQL sums integers into a larger type.
+        conn.queryRow(
+            "SELECT CAST(COALESCE(SUM(COALESCE(size, 0) + COALESCE(thumbnail_size, 0)),
+             0) AS BIGINT) FROM images;", &[], |row| row.get::<i64>(0))
+            .map(|n| n as u64)
+            .map_err(|e| error!(DataError, "Failed to sum image sizes: {}", e))
+    }
+
+    /// Write a consistent copy of the database to `path`, while it
+    /// can still be used. Only SQLite databases are copied like this.
+    pub fn backupTo(&self, path: &Path) -> Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        let conn = conn.sqlite().ok_or_else(
+            || rterr!("A PostgreSQL database is backed up with pg_dump"))?;
+        conn.backup(sql::DatabaseName::Main, path, None).map_err(
+            |e| error!(DataError, "Failed to back up database: {}", e))
+    }
+
+    /// Paths of images whose sizes are not recorded.
+    pub fn imagesWithoutSize(&self) -> Result<Vec<PathBuf>, Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.queryRows(
+            "SELECT path FROM images WHERE size IS NULL OR thumbnail_size IS NULL;",
+            &[], |row| row.get::<String>(0).map(PathBuf::from))
+            .map_err(|e| error!(DataError, "Failed to retrieve images: {}", e))
+    }
+
+    pub fn addSuggestedTags(&self, post_id: i64, tags: &[String]) ->
+        Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        for tag in tags
+        {
+            conn.execute("INSERT OR IGNORE INTO suggested_tags (post, tag)
+                          VALUES (?, ?);", sql::params![post_id, tag])
+                .map_err(|e| error!(DataError, "Failed to add suggested tag: {}", e))?;
+        }
+        Ok(())
+    }
+
+    /// The posts with suggested tags, newest first, excluding the ones
+    /// in the trash.
+    pub fn getSuggestedTags(&self) -> Result<Vec<SuggestedTags>, Error>
+    {
+        let conn = self.confirmConnection()?;
+        let rows = conn.queryRows(
+            "SELECT post, tag FROM suggested_tags JOIN posts ON post = posts.id
+             WHERE trashed IS NULL ORDER BY upload_time DESC, post, suggested_tags.rowid;",
+            &[], |row| Ok((row.get::<i64>(0)?, row.get::<String>(1)?)))
+            .map_err(|e| error!(DataError, "Failed to retrieve suggested tags: {}", e))?;
+        let mut result: Vec<SuggestedTags> = Vec::new();
+        for (post_id, tag) in rows
+        {
+            match result.last_mut()
+            {
+                Some(last) if last.post.id == post_id => last.tags.push(tag),
+                _ => if let Some(post) = self.findPostByID(post_id)?
+                {
+                    result.push(SuggestedTags { post, tags: vec![tag] });
+                },
+            }
+        }
+        Ok(result)
+    }
+
+    /// Make a suggested tag a tag of the post. Return false if there is
+    /// no such suggestion.
+    pub fn approveSuggestedTag(&self, post_id: i64, tag: &str) -> Result<bool, Error>
+    {
+        let conn = self.confirmConnection()?;
+        let trans = conn.transaction().map_err(
+            |e| error!(DataError, "Failed to start transaction: {}", e))?;
+        let found = trans.execute(
+            "DELETE FROM suggested_tags WHERE post = ? AND tag = ?;",
+            sql::params![post_id, tag]).map_err(
+            |e| error!(DataError, "Failed to delete suggested tag: {}", e))? > 0;
+        if found
+        {
+            trans.execute("INSERT OR IGNORE INTO tags (post, tag) VALUES (?, ?);",
+                          sql::params![post_id, tag]).map_err(
+                |e| error!(DataError, "Failed to add tag: {}", e))?;
+        }
+        trans.commit().map_err(
+            |e| error!(DataError, "Failed to commit transaction: {}", e))?;
+        if found
+        {
+            self.postsChanged();
+        }
+        Ok(found)
+    }
+
+    /// Drop a suggested tag. Return false if there is no such
+    /// suggestion.
+    pub fn rejectSuggestedTag(&self, post_id: i64, tag: &str) -> Result<bool, Error>
+    {
+        let conn = self.confirmConnection()?;
+        let row_count = conn.execute(
+            "DELETE FROM suggested_tags WHERE post = ? AND tag = ?;",
+            sql::params![post_id, tag]).map_err(
+            |e| error!(DataError, "Failed to delete suggested tag: {}", e))?;
+        Ok(row_count > 0)
+    }
+
+    /// Paths of images whose perceptual hashes are not recorded.
+    pub fn imagesWithoutPHash(&self) -> Result<Vec<PathBuf>, Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.queryRows("SELECT path FROM images WHERE phash IS NULL;",
+            &[], |row| row.get::<String>(0).map(PathBuf::from))
+            .map_err(|e| error!(DataError, "Failed to retrieve images: {}", e))
+    }
+
+    pub fn setImagePHash(&self, path: &Path, phash: u64) -> Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.execute(
+            "UPDATE images SET phash = ? WHERE path = ?;",
+            sql::params![phash as i64, path.to_str().ok_or_else(
+                || rterr!("Invalid image path: {:?}", path))?])
+            .map_err(|e| error!(DataError, "Failed to set image hash: {}", e))?;
+        Ok(())
+    }
+
+    /// The perceptual hashes of the images in posts that are not in the
+    /// trash, in the order of the images.
+    pub fn getImageHashes(&self) -> Result<Vec<ImageHash>, Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.queryRows(
+            "SELECT images.path, images.phash, posts.id, posts.slug, upload_time
+             FROM images JOIN posts ON images.post = posts.id
+             WHERE images.phash IS NOT NULL AND posts.trashed IS NULL
+             ORDER BY images.id;", &[], |row| {
+            let path: String = row.get(0)?;
+            let phash: i64 = row.get(1)?;
+            let slug: Option<String> = row.get(3)?;
+            let upload_time = OffsetDateTime::from_unix_timestamp(row.get(4)?)
+                .unwrap_or(OffsetDateTime::UNIX_EPOCH);
+            let path = PathBuf::from(path);
+            let thumbnail = Image { path: path.clone(), ..Default::default() }
+                .thumbnail().unwrap_or_default();
+            Ok(ImageHash {
+                path,
+                thumbnail,
+                phash: phash as u64,
+                post_id: row.get(2)?,
+                post_url_arg: urlArgOf(row.get(2)?, slug.as_deref(), upload_time),
+            })
+        }).map_err(|e| error!(DataError, "Failed to retrieve images: {}", e))
+    }
+
+    /// Paths of all images in the library. The images of the posts in
+    /// the trash are not in the image dir, and are left out.
+    pub fn allImagePaths(&self) -> Result<Vec<PathBuf>, Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.queryRows(
+            "SELECT images.path FROM images JOIN posts ON images.post = posts.id
+             WHERE posts.trashed IS NULL ORDER BY images.id;",
+            &[], |row| row.get::<String>(0).map(PathBuf::from))
+            .map_err(|e| error!(DataError, "Failed to retrieve images: {}", e))
+    }
+
+    pub fn setImagePath(&self, old_path: &Path, new_path: &Path) ->
+        Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.execute(
+            "UPDATE images SET path = ? WHERE path = ?;",
+            sql::params![
+                new_path.to_str().ok_or_else(
+                    || rterr!("Invalid image path: {:?}", new_path))?,
+                old_path.to_str().ok_or_else(
+                    || rterr!("Invalid image path: {:?}", old_path))?])
+            .map_err(|e| error!(DataError, "Failed to set image path: {}", e))?;
+        Ok(())
+    }
+
+    /// Add `images` to the end of a post.
+    pub fn addImagesToPost(&self, post_id: i64, images: &[Image]) ->
+        Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        let next: i64 = conn.queryRow(
+            "SELECT COALESCE(MAX(position) + 1, 0) FROM images WHERE post = ?;",
+            sql::params![post_id], |row| row.get(0))
+            .map_err(|e| error!(DataError, "Failed to count images: {}", e))?;
+        for (i, img) in images.iter().enumerate()
+        {
+            self.addImage(img, post_id, next as usize + i)?;
+        }
+        self.postsChanged();
+        Ok(())
+    }
+
+    /// Put the images of a post in the order of `images`, and set
+    /// their captions. `images` has the paths and the captions.
+    pub fn arrangeImages(&self, post_id: i64, images: &[(&str, &str)]) ->
+        Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        let trans = conn.transaction().map_err(
+            |e| error!(DataError, "Failed to start transaction: {}", e))?;
+        for (position, (path, caption)) in images.iter().enumerate()
+        {
+            trans.execute(
+                "UPDATE images SET position = ?, caption = ?
+                 WHERE post = ? AND path = ?;",
+                sql::params![position, Some(caption).filter(|c| !c.is_empty()),
+                             post_id, path])
+                .map_err(|e| error!(DataError, "Failed to arrange images: {}", e))?;
+        }
+        trans.commit().map_err(
+            |e| error!(DataError, "Failed to commit image order: {}", e))
+    }
+
+    /// Put the posts in the album, or in no album if `album_id` is
+    /// None. Return false and change nothing if the album or one of
+    /// the posts doesn’t exist.
+    pub fn movePostsToAlbum(&self, post_ids: &[i64], album_id: Option<i64>) ->
+        Result<bool, Error>
+    {
+        let conn = self.confirmConnection()?;
+        let trans = conn.transaction().map_err(
+            |e| error!(DataError, "Failed to start transaction: {}", e))?;
+        if let Some(album_id) = album_id
+        {
+            let exists: bool = trans.queryRow(
+                "SELECT EXISTS (SELECT 1 FROM albums WHERE id = ?);",
+                sql::params![album_id], |row| row.get(0))
+                .map_err(|e| error!(DataError, "Failed to find album: {}", e))?;
+            if !exists
+            {
+                return Ok(false);
+            }
+        }
+        for post_id in post_ids
+        {
+            let row_count = trans.execute(
+                "UPDATE posts SET album = ? WHERE id = ? AND trashed IS NULL;",
+                sql::params![album_id, post_id])
+                .map_err(|e| error!(DataError, "Failed to move post: {}", e))?;
+            // The transaction is rolled back when it is dropped.
+            if row_count != 1
+            {
+                return Ok(false);
+            }
+        }
+        trans.commit().map_err(
+            |e| error!(DataError, "Failed to commit album move: {}", e))?;
+        self.postsChanged();
+        Ok(true)
+    }
+
+    pub fn setImageSizes(&self, path: &Path, size: u64, thumbnail_size: u64) ->
+        Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.execute(
+            "UPDATE images SET size = ?, thumbnail_size = ? WHERE path = ?;",
+            sql::params![size, thumbnail_size, path.to_str().ok_or_else(
+                || rterr!("Invalid image path: {:?}", path))?])
+            .map_err(|e| error!(DataError, "Failed to set image size: {}", e))?;
+        Ok(())
+    }
+
+    pub fn getAlbums(&self) -> Result<Vec<Album>, Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.queryRows(
+            "SELECT id, title, cover, parent FROM albums ORDER BY id;", &[],
+            |row| Ok(Album {
+                id: row.get(0)?,
+                title: row.get(1)?,
+                cover: row.get(2)?,
+                parent: row.get(3)?,
+            })).map_err(|e| error!(DataError, "Failed to retrieve albums: {}", e))
+    }
+
+    /// The albums in the album `parent`, or the top level albums if
+    /// it is None, with the number of live public posts in them, and
+    /// their covers. A chosen cover that is no longer in a live
+    /// public post of the album is replaced by the first image of the
+    /// newest post. Posts in the albums in an album count as in it.
+    pub fn getAlbumSummaries(&self, parent: Option<i64>) ->
+        Result<Vec<AlbumSummary>, Error>
+    {
+        let albums = self.getAlbums()?.into_iter().filter(|a| a.parent == parent);
+        let conn = self.confirmConnection()?;
+        let query = format!(
+            "SELECT images.path, width, height, size, thumbnail_size, alt_text,
+             caption, color, blurhash FROM images JOIN posts ON images.post = posts.id
+             WHERE {} AND visibility = 'public' AND {}
+             ORDER BY images.path = ? DESC, upload_time DESC, posts.id DESC,
+             images.position, images.id LIMIT 1;",
+            Self::liveCondition(), Self::inAlbumCondition());
+        let mut summaries = Vec::new();
+        for album in albums
+        {
+            let cover = conn.queryRow(&query, sql::params![album.id, &album.cover],
+                                      Self::row2Image)
+                .optional().map_err(
+                    |e| error!(DataError, "Failed to get album cover: {}", e))?;
+            summaries.push(AlbumSummary {
+                post_count: self.countPostsInAlbum(album.id)?,
+                id: album.id,
+                title: album.title,
+                cover,
+            });
+        }
+        Ok(summaries)
+    }
+
+    /// The album `album_id` and the albums that it is in, from the top
+    /// level one to itself. This is empty if there is no such album.
+    pub fn getAlbumPath(&self, album_id: i64) -> Result<Vec<Album>, Error>
+    {
+        let albums: HashMap<i64, Album> = self.getAlbums()?.into_iter()
+            .map(|a| (a.id, a)).collect();
+        let mut path: Vec<Album> = Vec::new();
+        let mut next = Some(album_id);
+        while let Some(album) = next.and_then(|id| albums.get(&id))
+        {
+            if path.iter().any(|a| a.id == album.id)
+            {
+                break;
+            }
+            next = album.parent;
+            path.push(album.clone());
+        }
+        path.reverse();
+        Ok(path)
+    }
+
+    /// Choose the image at `path` as the cover of the album, or go
+    /// back to the default cover if `path` is None. Return false if
+    /// there is no such album, or the image is not in it or in the
+    /// albums in it.
+    pub fn setAlbumCover(&self, album_id: i64, path: Option<&str>) ->
+        Result<bool, Error>
+    {
+        let conn = self.confirmConnection()?;
+        let row_count = conn.execute(&format!(
+            "UPDATE albums SET cover = ?1 WHERE id = ?2 AND
+             (CAST(?1 AS TEXT) IS NULL OR EXISTS
+             (SELECT 1 FROM images JOIN posts ON images.post = posts.id
+              WHERE images.path = ?1 AND {}));",
+            Self::inAlbumCondition().replace('?', "?2")),
+            sql::params![path, album_id])
+            .map_err(|e| error!(DataError, "Failed to set album cover: {}", e))?;
+        Ok(row_count == 1)
+    }
+
+    /// Add an album with the ID in `album`. This is used when
+    /// importing from an archive.
+    pub fn importAlbum(&self, album: &Album) -> Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        let row_count = conn.execute(
+            "INSERT INTO albums (id, title, cover, parent) VALUES (?, ?, ?, ?);",
+            sql::params![album.id, &album.title, &album.cover, album.parent])
+            .map_err(|e| error!(DataError, "Failed to import album: {}", e))?;
+        if row_count != 1
+        {
+            return Err(error!(DataError, "Invalid insert happened"));
+        }
+        conn.syncIds("albums").map_err(
+            |e| error!(DataError, "Failed to import album: {}", e))
+    }
+
+    pub fn createSession(&self, token: &str, user_agent: Option<&str>) ->
+        Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        let row_count = conn.execute(
+            "INSERT INTO sessions (token, auth_time, user_agent)
+             VALUES (?, ?, ?);", sql::params![
+                 token,
+                 OffsetDateTime::now_utc().unix_timestamp(),
+                 user_agent,
+             ]).map_err(|e| error!(DataError, "Failed to create session: {}", e))?;
+        if row_count != 1
+        {
+            return Err(error!(DataError, "Invalid insert happened"));
+        }
+        Ok(())
+    }
+
+    /// Return time of authentication of the token, or `None` if there
+    /// is no such session.
+    pub fn hasSession(&self, token: &str) ->
+        Result<Option<OffsetDateTime>, Error>
+    {
+        let conn = self.confirmConnection()?;
+        if let Some(auth_time_sec) = conn.queryRow(
+            "SELECT auth_time FROM sessions WHERE token=?;", sql::params![token],
+            |row| row.get(0)).optional().map_err(
+                |e| error!(DataError, "Failed to look up session: {}", e))?
+        {
+            OffsetDateTime::from_unix_timestamp(auth_time_sec).map(Some)
+                .map_err(|_| rterr!("Invalid auth time"))
+        }
+        else
+        {
+            Ok(None)
+        }
+    }
+
+    /// All sessions, the newest first.
+    pub fn getSessions(&self) -> Result<Vec<Session>, Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.queryRows(
+            "SELECT token, auth_time, user_agent FROM sessions
+             ORDER BY auth_time DESC;",
+            &[], |row| {
+            let time_value = row.get(1)?;
+            Ok(Session {
+                token: row.get(0)?,
+                auth_time: OffsetDateTime::from_unix_timestamp(time_value)
+                    .map_err(|_| sql::Error::IntegralValueOutOfRange(1, time_value))?,
+                user_agent: row.get(2)?,
+            })
+        }).map_err(|e| error!(DataError, "Failed to get sessions: {}", e))
+    }
+
+    pub fn deleteSession(&self, token: &str) -> Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.execute("DELETE FROM sessions WHERE token=?;", sql::params![token])
+            .map_err(|e| error!(DataError, "Failed to delete session: {}", e))?;
+        Ok(())
+    }
+
+    /// Remove all sessions, i.e. log out everywhere.
+    pub fn deleteAllSessions(&self) -> Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        let row_count = conn.execute("DELETE FROM sessions;", &[]).map_err(
+            |e| error!(DataError, "Failed to delete sessions: {}", e))?;
+        info!("Deleted {} sessions.", row_count);
+        Ok(())
+    }
+
+    /// Set the password hash of a user. The user is created if it
+    /// does not exist.
+    pub fn setPasswordHash(&self, username: &str, hash: &str) ->
+        Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.execute(
+            "INSERT INTO users (username, password_hash) VALUES (?, ?)
+             ON CONFLICT(username) DO UPDATE SET password_hash=excluded.password_hash;",
+            sql::params![username, hash])
+            .map_err(|e| error!(DataError, "Failed to set password: {}", e))?;
+        Ok(())
+    }
+
+    /// The number of failed logins from `client`, and the time of the
+    /// last one.
+    pub fn loginFailures(&self, client: &str) ->
+        Result<Option<(u32, OffsetDateTime)>, Error>
+    {
+        let conn = self.confirmConnection()?;
+        let row: Option<(u32, i64)> = conn.queryRow(
+            "SELECT count, last_time FROM login_failures WHERE client=?;",
+            sql::params![client], |row| Ok((row.get(0)?, row.get(1)?))).optional()
+            .map_err(|e| error!(DataError, "Failed to look up login failures: {}",
+                                e))?;
+        row.map(|(count, time)| Ok((count, OffsetDateTime::from_unix_timestamp(
+            time).map_err(|_| rterr!("Invalid login failure time"))?)))
+            .transpose()
+    }
+
+    /// Count a failed login from `client` at `time`. Failures before
+    /// `forget_before` are not counted. Return the new count.
+    pub fn recordLoginFailure(&self, client: &str, time: OffsetDateTime,
+                              forget_before: OffsetDateTime) ->
+        Result<u32, Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.execute(
+            "INSERT INTO login_failures (client, count, last_time) VALUES (?, 1, ?)
+             ON CONFLICT(client) DO UPDATE SET
+             count = CASE WHEN login_failures.last_time < ? THEN 1
+                     ELSE login_failures.count + 1 END,
+             last_time = excluded.last_time;",
+            sql::params![client, time.unix_timestamp(),
+                         forget_before.unix_timestamp()])
+            .map_err(|e| error!(DataError, "Failed to record login failure: {}",
+                                e))?;
+        conn.queryRow("SELECT count FROM login_failures WHERE client=?;",
+                       sql::params![client], |row| row.get(0))
+            .map_err(|e| error!(DataError, "Failed to count login failures: {}",
+                                e))
+    }
+
+    pub fn clearLoginFailures(&self, client: &str) -> Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.execute("DELETE FROM login_failures WHERE client=?;", sql::params![client])
+            .map_err(|e| error!(DataError, "Failed to clear login failures: {}",
+                                e))?;
+        Ok(())
+    }
+
+    /// Add an API token with the digest `hash`. Return the ID of the
+    /// token.
+    pub fn addApiToken(&self, name: &str, hash: &str) -> Result<i64, Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.execute(
+            "INSERT INTO tokens (name, hash, created) VALUES (?, ?, ?);",
+            sql::params![name, hash, OffsetDateTime::now_utc().unix_timestamp()])
+            .map_err(|e| error!(DataError, "Failed to add API token: {}", e))?;
+        conn.lastInsertId().map_err(
+            |e| error!(DataError, "Failed to add API token: {}", e))
+    }
+
+    fn row2ApiToken(row: &database::Row) -> sql::Result<ApiToken>
+    {
+        let time = |index: usize, value: i64| OffsetDateTime::from_unix_timestamp(
+            value).map_err(|_| sql::Error::IntegralValueOutOfRange(index, value));
+        Ok(ApiToken {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            created: time(2, row.get(2)?)?,
+            last_used: row.get::<Option<i64>>(3)?
+                .map(|t| time(3, t)).transpose()?,
+        })
+    }
+
+    pub fn getApiTokens(&self) -> Result<Vec<ApiToken>, Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.queryRows(
+            "SELECT id, name, created, last_used FROM tokens ORDER BY id;",
+            &[], Self::row2ApiToken)
+            .map_err(|e| error!(DataError, "Failed to get API tokens: {}", e))
+    }
+
+    /// If there is an API token with the digest `hash`, record its use
+    /// and return true.
+    pub fn useApiToken(&self, hash: &str) -> Result<bool, Error>
+    {
+        let conn = self.confirmConnection()?;
+        let row_count = conn.execute(
+            "UPDATE tokens SET last_used = ? WHERE hash = ?;",
+            sql::params![OffsetDateTime::now_utc().unix_timestamp(), hash])
+            .map_err(|e| error!(DataError, "Failed to look up API token: {}", e))?;
+        Ok(row_count == 1)
+    }
+
+    /// Delete an API token. Return false if there is no such token.
+    pub fn deleteApiToken(&self, id: i64) -> Result<bool, Error>
+    {
+        let conn = self.confirmConnection()?;
+        let row_count = conn.execute("DELETE FROM tokens WHERE id = ?;", sql::params![id])
+            .map_err(|e| error!(DataError, "Failed to delete API token: {}", e))?;
+        Ok(row_count == 1)
+    }
+
+    /// Queue a delivery of the post with ID `post_id` to the service
+    /// `kind`, due now.
+    pub fn addDelivery(&self, kind: &str, post_id: i64) -> Result<i64, Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.execute(
+            "INSERT INTO deliveries (kind, post, next_time) VALUES (?, ?, ?);",
+            sql::params![kind, post_id, OffsetDateTime::now_utc().unix_timestamp()])
+            .map_err(|e| error!(DataError, "Failed to add delivery: {}", e))?;
+        conn.lastInsertId().map_err(
+            |e| error!(DataError, "Failed to add delivery: {}", e))
+    }
+
+    /// Deliveries that are due at `now`, the oldest first.
+    pub fn getDueDeliveries(&self, now: OffsetDateTime) ->
+        Result<Vec<Delivery>, Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.queryRows(
+            "SELECT id, kind, post, attempts FROM deliveries
+             WHERE next_time <= ? ORDER BY id;",
+            sql::params![now.unix_timestamp()], |row| Ok(Delivery {
+            id: row.get(0)?,
+            kind: row.get(1)?,
+            post_id: row.get(2)?,
+            attempts: row.get(3)?,
+        })).map_err(|e| error!(DataError, "Failed to retrieve deliveries: {}", e))
+    }
+
+    /// Count a failed attempt of a delivery, and try it again at
+    /// `next_time`.
+    pub fn postponeDelivery(&self, id: i64, next_time: OffsetDateTime) ->
+        Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.execute(
+            "UPDATE deliveries SET attempts = attempts + 1, next_time = ?
+             WHERE id = ?;", sql::params![next_time.unix_timestamp(), id])
+            .map_err(|e| error!(DataError, "Failed to update delivery: {}", e))?;
+        Ok(())
+    }
+
+    pub fn deleteDelivery(&self, id: i64) -> Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.execute("DELETE FROM deliveries WHERE id = ?;", sql::params![id])
+            .map_err(|e| error!(DataError, "Failed to delete delivery: {}", e))?;
+        Ok(())
+    }
+
+    pub fn hasUsers(&self) -> Result<bool, Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.queryRow("SELECT COUNT(*) FROM users;", &[],
+                       |row| row.get::<i64>(0))
+            .map(|count| count > 0)
+            .map_err(|e| error!(DataError, "Failed to count users: {}", e))
+    }
+
+    /// Create a user only if there is no user yet. Return whether the
+    /// user was created.
+    pub fn createFirstUser(&self, username: &str, hash: &str) ->
+        Result<bool, Error>
+    {
+        let conn = self.confirmConnection()?;
+        let row_count = conn.execute(
+            "INSERT INTO users (username, password_hash) SELECT ?, ?
+             WHERE NOT EXISTS (SELECT 1 FROM users);",
+            sql::params![username, hash])
+            .map_err(|e| error!(DataError, "Failed to create user: {}", e))?;
+        Ok(row_count == 1)
+    }
+
+    pub fn findPasswordHash(&self, username: &str) ->
+        Result<Option<String>, Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.queryRow("SELECT password_hash FROM users WHERE username=?;",
+                       sql::params![username], |row| row.get(0))
+            .optional().map_err(
+                |e| error!(DataError, "Failed to look up user {}: {}",
+                           username, e))
+    }
+
+    pub fn expireSessions(&self, life_time_sec: u64) -> Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let row_count = conn.execute(
+            "DELETE FROM sessions WHERE auth_time < ?;",
+            sql::params![now as u64 - life_time_sec])
+            .map_err(|e| error!(DataError, "Failed to expire sessions: {}", e))?;
+        if row_count > 0
+        {
+            info!("Expired {} sessions.", row_count);
+        }
+        Ok(())
+    }
+
+    /// Add to the view counts of posts. `views` is a list of post
+    /// IDs and the numbers of views to add.
+    pub fn addViews(&self, views: &[(i64, u64)]) -> Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        let trans = conn.transaction().map_err(
+            |e| error!(DataError, "Failed to start transaction: {}", e))?;
+        for (post_id, count) in views
+        {
+            trans.execute("UPDATE posts SET views = views + ? WHERE id = ?;",
+                          sql::params![count, post_id])
+                .map_err(|e| error!(DataError, "Failed to add views: {}", e))?;
+        }
+        trans.commit().map_err(
+            |e| error!(DataError, "Failed to commit views: {}", e))
+    }
+
+    /// Record a like of a post from the client with the liker cookie
+    /// `liker` and the hashed IP `ip_hash`. A post can be liked once
+    /// per IP, and once per cookie, so that neither a new cookie nor
+    /// a new IP makes another like. Browsers behind the same NAT
+    /// share a like. Return false if the like is a duplicate.
+    pub fn addLike(&self, post_id: i64, liker: Option<&str>, ip_hash: &str) ->
+        Result<bool, Error>
+    {
+        let conn = self.confirmConnection()?;
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let liker = match liker
+        {
+            Some(liker) => format!("cookie:{}", liker),
+            None => format!("ip:{}", ip_hash),
+        };
+        let row_count = conn.execute(
+            "INSERT OR IGNORE INTO likes (post, liker, ip_hash, time)
+             SELECT CAST(? AS BIGINT), ?, ?, CAST(? AS BIGINT) WHERE NOT EXISTS
+             (SELECT 1 FROM likes WHERE post = ? AND ip_hash = ?);",
+            sql::params![post_id, liker, ip_hash, now, post_id, ip_hash])
+            .map_err(|e| error!(DataError, "Failed to add like: {}", e))?;
+        Ok(row_count == 1)
+    }
+
+    pub fn addShareLink(&self, link: &ShareLink) -> Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.execute(
+            "INSERT INTO share_links (token, post, expire_time) VALUES (?, ?, ?);",
+            sql::params![&link.token, link.post_id,
+                         link.expire_time.unix_timestamp()])
+            .map_err(|e| error!(DataError, "Failed to add share link: {}", e))?;
+        Ok(())
+    }
+
+    fn row2ShareLink(row: &database::Row) -> sql::Result<ShareLink>
+    {
+        let time_value = row.get(2)?;
+        Ok(ShareLink {
+            token: row.get(0)?,
+            post_id: row.get(1)?,
+            expire_time: OffsetDateTime::from_unix_timestamp(time_value)
+                .map_err(|_| sql::Error::IntegralValueOutOfRange(2, time_value))?,
+        })
+    }
+
+    /// Find a share link that has not expired.
+    pub fn findShareLink(&self, token: &str) -> Result<Option<ShareLink>, Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.queryRow(
+            "SELECT token, post, expire_time FROM share_links
+             WHERE token = ? AND expire_time > ?;",
+            sql::params![token, OffsetDateTime::now_utc().unix_timestamp()],
+            Self::row2ShareLink).optional().map_err(
+            |e| error!(DataError, "Failed to look up share link: {}", e))
+    }
+
+    /// Share links of a post that have not expired, the ones that
+    /// expire last first.
+    pub fn getShareLinks(&self, post_id: i64) -> Result<Vec<ShareLink>, Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.queryRows(
+            "SELECT token, post, expire_time FROM share_links
+             WHERE post = ? AND expire_time > ? ORDER BY expire_time DESC;",
+            sql::params![post_id, OffsetDateTime::now_utc().unix_timestamp()],
+            Self::row2ShareLink)
+            .map_err(|e| error!(DataError, "Failed to get share links: {}", e))
+    }
+
+    pub fn deleteShareLink(&self, token: &str) -> Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        conn.execute("DELETE FROM share_links WHERE token = ?;", sql::params![token])
+            .map_err(|e| error!(DataError, "Failed to delete share link: {}", e))?;
+        Ok(())
+    }
+
+    pub fn expireShareLinks(&self) -> Result<(), Error>
+    {
+        let conn = self.confirmConnection()?;
+        let row_count = conn.execute(
+            "DELETE FROM share_links WHERE expire_time <= ?;",
+            sql::params![OffsetDateTime::now_utc().unix_timestamp()])
+            .map_err(|e| error!(DataError, "Failed to expire share links: {}", e))?;
+        if row_count > 0
+        {
+            info!("Expired {} share links.", row_count);
+        }
+        Ok(())
+    }
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn tempFile() -> PathBuf
+    {
+        std::env::temp_dir().join(OffsetDateTime::now_utc().unix_timestamp_nanos().to_string())
+    }
+
+    struct FileDeleter
+    {
+        files: Vec<PathBuf>,
+    }
+
+    impl FileDeleter
+    {
+        fn new() -> Self
+        {
+            Self { files: Vec::new() }
+        }
+
+        fn register<P: AsRef<Path>>(&mut self, f: P)
+        {
+            let p: &Path = f.as_ref();
+            self.files.push(p.to_owned());
+        }
+    }
+
+    impl Drop for FileDeleter
+    {
+        fn drop(&mut self)
+        {
+            for f in &self.files
+            {
+                std::fs::remove_file(&f).ok();
+                // Left by the WAL journal mode.
+                for suffix in ["-wal", "-shm"]
+                {
+                    let mut name = f.clone().into_os_string();
+                    name.push(suffix);
+                    std::fs::remove_file(name).ok();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn managerFromDatabaseUrl()
+    {
+        let mut config = Configuration::default();
+        assert!(Manager::fromConfig(&config).is_ok());
+        config.database_url = Some(String::from("sqlite:///tmp/a.sqlite"));
+        assert!(matches!(Manager::fromConfig(&config).unwrap().source,
+                         database::Source::Sqlite(sqlite_connection::Source::File(p))
+                         if p == Path::new("/tmp/a.sqlite")));
+        // Each connection of the pool would have its own in-memory
+        // database.
+        config.database_url = Some(String::from("sqlite::memory:"));
+        assert!(Manager::fromConfig(&config).is_err());
+        config.database_url = Some(String::from("postgres://localhost/nspic"));
+        assert_eq!(Manager::fromConfig(&config).is_ok(), cfg!(feature = "postgres"));
+    }
+
+    #[test]
+    fn connectionsHavePragmas() -> Result<(), Error>
+    {
+        let mut deleter = FileDeleter::new();
+        let db = tempFile();
+        deleter.register(&db);
+
+        let config = Configuration {
+            database_url: Some(format!("sqlite://{}", db.display())),
+            database: DatabaseConfig {
+                synchronous: String::from("full"),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut manager = Manager::fromConfig(&config)?;
+        manager.connect()?;
+        let conn = manager.confirmConnection()?;
+        let pragma = |name: &str| conn.queryRow(
+            &format!("PRAGMA {};", name), &[], |row| row.get::<sql::types::Value>(0))
+            .unwrap();
+        assert_eq!(pragma("journal_mode"), sql::types::Value::Text(String::from("wal")));
+        assert_eq!(pragma("foreign_keys"), sql::types::Value::Integer(1));
+        // FULL is 2.
+        assert_eq!(pragma("synchronous"), sql::types::Value::Integer(2));
+        assert_eq!(pragma("busy_timeout"), sql::types::Value::Integer(5000));
+        Ok(())
+    }
+
+    #[test]
+    fn addEmptyPostAndQuery() -> Result<(), Error>
+    {
+        let mut manager = Manager::new(sqlite_connection::Source::Memory);
+        manager.connect()?;
+        manager.init()?;
+
+        let p = Post::new();
+        let id = manager.addPost(&p, None)?;
+        let post_maybe = manager.findPostByID(id)?;
+        assert!(post_maybe.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn addPostWithImageAndQueryAndDelete() -> Result<(), Error>
+    {
+        let mut deleter = FileDeleter::new();
+        let db = tempFile();
+        deleter.register(&db);
+
+        let mut manager = Manager::new(sqlite_connection::Source::File(db));
+        manager.connect()?;
+        manager.init()?;
+
+        let image1 = Image {
+            path: PathBuf::from("aaa"),
+            width: 1,
+            height: 2,
+            ..Default::default()
+        };
+        let image2 = Image {
+            path: PathBuf::from("bbb"),
+            width: 3,
+            height: 4,
+            ..Default::default()
+        };
+        let mut p = Post::new();
+        p.images = vec![image1, image2];
+
+        let id = manager.addPost(&p, None)?;
+        let post_maybe = manager.findPostByID(id)?;
+        assert!(post_maybe.is_some());
+        let post = post_maybe.unwrap();
+        assert_eq!(post.id, id);
+        assert_eq!(post.images.len(), 2);
+
+        manager.deletePost(id)?;
+        assert!(manager.findPostByID(id)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn imageSizesAreSaved() -> Result<(), Error>
+    {
+        let mut deleter = FileDeleter::new();
+        let db = tempFile();
+        deleter.register(&db);
+
+        let mut manager = Manager::new(sqlite_connection::Source::File(db));
+        manager.connect()?;
+        manager.init()?;
+
+        let mut p = Post::new();
+        p.images = vec![Image {
+            path: PathBuf::from("aaa"),
+            size: 5,
+            thumbnail_size: 6,
+            ..Default::default()
+        }];
+        let id = manager.addPost(&p, None)?;
+        let post = manager.findPostByID(id)?.unwrap();
+        assert_eq!(post.images[0].size, 5);
+        assert_eq!(post.images[0].thumbnail_size, 6);
+        assert_eq!(manager.imagesWithoutSize()?.len(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn altTextIsSaved() -> Result<(), Error>
+    {
+        let mut deleter = FileDeleter::new();
+        let db = tempFile();
+        deleter.register(&db);
+
+        let mut manager = Manager::new(sqlite_connection::Source::File(db));
+        manager.connect()?;
+        manager.init()?;
+
+        let mut p = Post::new();
+        p.images = vec![Image {
+            path: PathBuf::from("aaa"),
+            ..Default::default()
+        }, Image {
+            path: PathBuf::from("bbb"),
+            alt_text: String::from("A cat"),
+            ..Default::default()
+        }];
+        let id = manager.addPost(&p, None)?;
+        let post = manager.findPostByID(id)?.unwrap();
+        assert_eq!(post.images[0].alt_text, "");
+        assert_eq!(post.images[1].alt_text, "A cat");
+        Ok(())
+    }
+
+    #[test]
+    fn tagsAreSavedInOrder() -> Result<(), Error>
+    {
+        let mut deleter = FileDeleter::new();
+        let db = tempFile();
+        deleter.register(&db);
+
+        let mut manager = Manager::new(sqlite_connection::Source::File(db));
+        manager.connect()?;
+        manager.init()?;
+
+        let mut p = Post::new();
+        p.tags = vec![String::from("cat"), String::from("Cat toys")];
+        let id = manager.addPost(&p, None)?;
+        assert_eq!(manager.findPostByID(id)?.unwrap().tags, p.tags);
+        Ok(())
+    }
+
+    #[test]
+    fn postsAreFoundBySlug() -> Result<(), Error>
+    {
+        let mut deleter = FileDeleter::new();
+        let db = tempFile();
+        deleter.register(&db);
+
+        let mut manager = Manager::new(sqlite_connection::Source::File(db));
+        manager.connect()?;
+        manager.init()?;
+
+        assert_eq!(manager.uniqueSlug("abc")?, "abc");
+        let mut p = Post::new();
+        p.slug = Some(String::from("abc"));
+        let id = manager.addPost(&p, None)?;
+        assert_eq!(manager.findPostBySlug("abc")?.unwrap().id, id);
+        assert!(manager.findPostBySlug("abd")?.is_none());
+        assert_eq!(manager.uniqueSlug("abc")?, "abc-2");
+        Ok(())
+    }
+
+    #[test]
+    fn redactedPostsLoseTheirImages() -> Result<(), Error>
+    {
+        let mut deleter = FileDeleter::new();
+        let db = tempFile();
+        deleter.register(&db);
+
+        let mut manager = Manager::new(sqlite_connection::Source::File(db));
+        manager.connect()?;
+        manager.init()?;
+
+        let mut p = Post::new();
+        p.images = vec![Image {
+            path: PathBuf::from("aaa"),
+            ..Default::default()
+        }];
+        let id = manager.addPost(&p, None)?;
+        manager.redactPost(id)?;
+        let post = manager.findPostByID(id)?.unwrap();
+        assert!(post.redacted);
+        assert!(post.images.is_empty());
+        assert_eq!(manager.allImagePaths()?.len(), 0);
+
+        // Without images, it can still be deleted, once.
+        manager.deletePost(id)?;
+        assert!(manager.findPostByID(id)?.is_none());
+        assert!(manager.deletePost(id).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn imagesAreArranged() -> Result<(), Error>
+    {
+        let mut deleter = FileDeleter::new();
+        let db = tempFile();
+        deleter.register(&db);
+        let mut manager = Manager::new(sqlite_connection::Source::File(db));
+        manager.connect()?;
+        manager.init()?;
+        let mut p = Post::new();
+        p.images = ["aaa", "bbb", "ccc"].iter().map(|path| Image {
+            path: PathBuf::from(path),
+            ..Default::default()
+        }).collect();
+        p.images[0].caption = String::from("First");
+        let id = manager.addPost(&p, None)?;
+        let post = manager.findPostByID(id)?.unwrap();
+        assert_eq!(post.images[0].path, PathBuf::from("aaa"));
+        assert_eq!(post.images[0].caption, "First");
+
+        manager.arrangeImages(id, &[("ccc", "Last"), ("aaa", ""), ("bbb", "")])?;
+        let post = manager.findPostByID(id)?.unwrap();
+        let paths: Vec<_> = post.images.iter()
+            .map(|img| img.path.to_str().unwrap()).collect();
+        assert_eq!(paths, vec!["ccc", "aaa", "bbb"]);
+        assert_eq!(post.images[0].caption, "Last");
+        assert_eq!(post.images[1].caption, "");
+        Ok(())
+    }
+
+    #[test]
+    fn locatedPostsAreListed() -> Result<(), Error>
+    {
+        let mut deleter = FileDeleter::new();
+        let db = tempFile();
+        deleter.register(&db);
+        let mut manager = Manager::new(sqlite_connection::Source::File(db));
+        manager.connect()?;
+        manager.init()?;
+        let mut p = Post::new();
+        manager.addPost(&p, None)?;
+        p.location = Location::new(-33.9, 151.2);
+        p.location.as_mut().unwrap().place = Some(String::from("Sydney"));
+        let id = manager.addPost(&p, None)?;
+        p.visibility = Visibility::Private;
+        manager.addPost(&p, None)?;
+
+        let posts = manager.getLocatedPosts()?;
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].id, id);
+        assert_eq!(posts[0].location, p.location);
+        Ok(())
+    }
+
+    #[test]
+    fn deliveriesAreQueued() -> Result<(), Error>
+    {
+        let mut deleter = FileDeleter::new();
+        let db = tempFile();
+        deleter.register(&db);
+        let mut manager = Manager::new(sqlite_connection::Source::File(db));
+        manager.connect()?;
+        manager.init()?;
+        let post_id = manager.addPost(&Post::new(), None)?;
+        let first = manager.addDelivery("webhook", post_id)?;
+        let second = manager.addDelivery("telegram", post_id)?;
+        let now = OffsetDateTime::now_utc();
+        let due = manager.getDueDeliveries(now)?;
+        assert_eq!(due.iter().map(|d| d.id).collect::<Vec<_>>(), vec![first, second]);
+        assert_eq!(due[1].kind, "telegram");
+        assert_eq!(due[1].post_id, post_id);
+        assert_eq!(due[1].attempts, 0);
+
+        manager.postponeDelivery(first, now + time::Duration::minutes(1))?;
+        manager.deleteDelivery(second)?;
+        assert!(manager.getDueDeliveries(now)?.is_empty());
+        let due = manager.getDueDeliveries(now + time::Duration::minutes(1))?;
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].attempts, 1);
+        manager.deletePost(post_id)?;
+        assert!(manager.getDueDeliveries(now + time::Duration::minutes(1))?
+                .is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn adjacentPostsAreFound() -> Result<(), Error>
+    {
+        let mut deleter = FileDeleter::new();
+        let db = tempFile();
+        deleter.register(&db);
+        let mut manager = Manager::new(sqlite_connection::Source::File(db));
+        manager.connect()?;
+        manager.init()?;
+        let mut ids = Vec::new();
+        for t in [10, 20, 20, 30]
+        {
+            let mut p = Post::new();
+            p.upload_time = OffsetDateTime::from_unix_timestamp(t).unwrap();
+            ids.push(manager.addPost(&p, None)?);
+        }
+        let mut hidden = Post::new();
+        hidden.upload_time = OffsetDateTime::from_unix_timestamp(15).unwrap();
+        hidden.visibility = Visibility::Unlisted;
+        manager.addPost(&hidden, None)?;
+
+        let id = |p: Option<Post>| p.map(|p| p.id);
+        let (prev, next) = manager.adjacentPosts(ids[0])?;
+        assert_eq!((id(prev), id(next)), (None, Some(ids[1])));
+        let (prev, next) = manager.adjacentPosts(ids[1])?;
+        assert_eq!((id(prev), id(next)), (Some(ids[0]), Some(ids[2])));
+        let (prev, next) = manager.adjacentPosts(ids[3])?;
+        assert_eq!((id(prev), id(next)), (Some(ids[2]), None));
+        let (prev, next) = manager.adjacentPosts(100)?;
+        assert!(prev.is_none() && next.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn relatedPostsAreRanked() -> Result<(), Error>
+    {
+        let mut deleter = FileDeleter::new();
+        let db = tempFile();
+        deleter.register(&db);
+        let mut manager = Manager::new(sqlite_connection::Source::File(db));
+        manager.connect()?;
         manager.init()?;
+        manager.importAlbum(&Album {
+            id: 1, title: String::from("Trip"), cover: None, parent: None })?;
+        let post_at = |t: i64, tags: &[&str]| {
+            let mut p = Post::new();
+            p.upload_time = OffsetDateTime::from_unix_timestamp(t).unwrap();
+            p.tags = tags.iter().map(|t| t.to_string()).collect();
+            p
+        };
+        let post = post_at(1000, &["cat", "snow"]);
+        let id = manager.addPost(&post, Some(1))?;
+        let near = manager.addPost(&post_at(1010, &[]), None)?;
+        let far = manager.addPost(&post_at(5000, &[]), None)?;
+        let one_tag = manager.addPost(&post_at(9000, &["cat"]), None)?;
+        let two_tags = manager.addPost(&post_at(9000, &["snow", "cat"]), None)?;
+        let album = manager.addPost(&post_at(9000, &[]), Some(1))?;
+        let mut private = post_at(1000, &["cat", "snow"]);
+        private.visibility = Visibility::Private;
+        manager.addPost(&private, Some(1))?;
 
-        let p = Post::new();
+        let post = manager.findPostByID(id)?.unwrap();
+        let related: Vec<i64> = manager.getRelatedPosts(&post, 10)?.iter()
+            .map(|p| p.id).collect();
+        assert_eq!(related, vec![album, two_tags, one_tag, near, far]);
+        assert_eq!(manager.getRelatedPosts(&post, 2)?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn getPostsInOrder() -> Result<(), Error>
+    {
+        let mut deleter = FileDeleter::new();
+        let db = tempFile();
+        deleter.register(&db);
+
+        let mut manager = Manager::new(sqlite_connection::Source::File(db));
+        manager.connect()?;
+        manager.init()?;
+
+        let mut ids = Vec::new();
+        for i in 0..5
+        {
+            let mut p = Post::new();
+            p.upload_time = OffsetDateTime::from_unix_timestamp(i).unwrap();
+            ids.push(manager.addPost(&p, None)?);
+        }
+        let postIDs = |order| -> Result<Vec<i64>, Error> {
+            Ok(manager.getPosts(0, 10, order)?.iter().map(|p| p.id).collect())
+        };
+        assert_eq!(postIDs(PostOrder::OldFirst)?, ids);
+        ids.reverse();
+        assert_eq!(postIDs(PostOrder::NewFirst)?, ids);
+        let shuffled = postIDs(PostOrder::Random(42))?;
+        assert_eq!(shuffled, postIDs(PostOrder::Random(42))?);
+        let mut sorted = shuffled.clone();
+        sorted.sort();
+        ids.reverse();
+        assert_eq!(sorted, ids);
+        Ok(())
+    }
+
+    #[test]
+    fn listedPostsHaveTheirImagesAndTags() -> Result<(), Error>
+    {
+        let mut deleter = FileDeleter::new();
+        let db = tempFile();
+        deleter.register(&db);
+
+        let mut manager = Manager::new(sqlite_connection::Source::File(db));
+        manager.connect()?;
+        manager.init()?;
+
+        for i in 0..3
+        {
+            let mut p = Post::new();
+            p.upload_time = OffsetDateTime::from_unix_timestamp(i).unwrap();
+            p.images = (0..i).map(|j| Image {
+                path: PathBuf::from(format!("{}-{}", i, j)),
+                ..Default::default()
+            }).collect();
+            p.tags = vec![format!("tag{}", i), String::from("all")];
+            manager.addPost(&p, None)?;
+        }
+        let posts = manager.getPosts(0, 10, PostOrder::OldFirst)?;
+        let paths: Vec<Vec<PathBuf>> = posts.iter()
+            .map(|p| p.images.iter().map(|img| img.path.clone()).collect())
+            .collect();
+        assert_eq!(paths, vec![vec![], vec![PathBuf::from("1-0")],
+                               vec![PathBuf::from("2-0"), PathBuf::from("2-1")]]);
+        assert_eq!(posts[2].tags, vec!["tag2", "all"]);
+        Ok(())
+    }
+
+    #[test]
+    fn suggestedTagsAreApprovedOrRejected() -> Result<(), Error>
+    {
+        let mut deleter = FileDeleter::new();
+        let db = tempFile();
+        deleter.register(&db);
+
+        let mut manager = Manager::new(sqlite_connection::Source::File(db));
+        manager.connect()?;
+        manager.init()?;
+
+        let mut p = Post::new();
+        p.tags = vec![String::from("cat")];
         let id = manager.addPost(&p, None)?;
-        let post_maybe = manager.findPostByID(id)?;
-        assert!(post_maybe.is_some());
+        manager.addSuggestedTags(id, &[String::from("sofa"), String::from("dog")])?;
+        let suggestions = manager.getSuggestedTags()?;
+        assert_eq!(suggestions.len(), 1);
+        assert_eq!(suggestions[0].post.id, id);
+        assert_eq!(suggestions[0].tags, vec!["sofa", "dog"]);
+
+        assert!(manager.approveSuggestedTag(id, "sofa")?);
+        assert!(!manager.approveSuggestedTag(id, "sofa")?);
+        assert!(manager.rejectSuggestedTag(id, "dog")?);
+        assert!(manager.getSuggestedTags()?.is_empty());
+        assert_eq!(manager.findPostByID(id)?.unwrap().tags, vec!["cat", "sofa"]);
+        assert_eq!(manager.countPostsWithTag("sofa")?, 1);
         Ok(())
     }
 
     #[test]
-    fn addPostWithImageAndQueryAndDelete() -> Result<(), Error>
+    fn postsAreCountedByAlbumAndTag() -> Result<(), Error>
     {
         let mut deleter = FileDeleter::new();
         let db = tempFile();
@@ -377,28 +2552,469 @@ mod tests
         manager.connect()?;
         manager.init()?;
 
-        let image1 = Image {
+        manager.importAlbum(&Album {
+            id: 1, title: String::from("Trip"), cover: None, parent: None })?;
+        let mut p = Post::new();
+        p.tags = vec![String::from("cat")];
+        manager.addPost(&p, Some(1))?;
+        p.tags = vec![String::from("dog")];
+        let dog = manager.addPost(&p, None)?;
+        assert_eq!(manager.countPosts()?, 2);
+        assert_eq!(manager.countPostsInAlbum(1)?, 1);
+        assert_eq!(manager.countPostsWithTag("cat")?, 1);
+        assert_eq!(manager.countPostsWithTag("bird")?, 0);
+        assert_eq!(manager.getPostsWithTag("dog", 0, 10, PostOrder::NewFirst)?
+                   .iter().map(|p| p.id).collect::<Vec<_>>(), vec![dog]);
+        assert_eq!(manager.getPostsInAlbum(1, 0, 10, PostOrder::NewFirst)?.len(), 1);
+        // The cached counts are forgotten.
+        manager.addPost(&p, Some(1))?;
+        assert_eq!(manager.countPostsInAlbum(1)?, 2);
+        manager.deletePost(dog)?;
+        assert_eq!(manager.countPostsWithTag("dog")?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn albumCoversDefaultToNewestPost() -> Result<(), Error>
+    {
+        let mut deleter = FileDeleter::new();
+        let db = tempFile();
+        deleter.register(&db);
+
+        let mut manager = Manager::new(sqlite_connection::Source::File(db));
+        manager.connect()?;
+        manager.init()?;
+
+        manager.importAlbum(&Album {
+            id: 1, title: String::from("Trip"), cover: None, parent: None })?;
+        manager.importAlbum(&Album {
+            id: 2, title: String::from("Empty"), cover: None, parent: None })?;
+        let post_with = |t: i64, paths: &[&str]| {
+            let mut p = Post::new();
+            p.upload_time = OffsetDateTime::from_unix_timestamp(t).unwrap();
+            p.images = paths.iter().map(|path| Image {
+                path: PathBuf::from(path), ..Default::default() }).collect();
+            p
+        };
+        manager.addPost(&post_with(1000, &["a/old.jpg"]), Some(1))?;
+        let new = manager.addPost(&post_with(2000, &["a/new1.jpg", "a/new2.jpg"]),
+                                  Some(1))?;
+        manager.addPost(&post_with(3000, &["a/other.jpg"]), None)?;
+        let cover = |manager: &Manager| -> Result<Vec<Option<PathBuf>>, Error> {
+            Ok(manager.getAlbumSummaries(None)?.into_iter()
+               .map(|a| a.cover.map(|c| c.path)).collect())
+        };
+        assert_eq!(cover(&manager)?, vec![Some(PathBuf::from("a/new1.jpg")), None]);
+        assert_eq!(manager.getAlbumSummaries(None)?[0].post_count, 2);
+
+        assert!(manager.setAlbumCover(1, Some("a/old.jpg"))?);
+        assert_eq!(cover(&manager)?[0], Some(PathBuf::from("a/old.jpg")));
+        // Only images in the album can be its cover.
+        assert!(!manager.setAlbumCover(1, Some("a/other.jpg"))?);
+        assert!(!manager.setAlbumCover(3, None)?);
+        assert!(manager.setAlbumCover(1, Some("a/new2.jpg"))?);
+        assert_eq!(cover(&manager)?[0], Some(PathBuf::from("a/new2.jpg")));
+        // The chosen cover is gone with its post.
+        manager.deletePost(new)?;
+        assert_eq!(cover(&manager)?[0], Some(PathBuf::from("a/old.jpg")));
+        Ok(())
+    }
+
+    #[test]
+    fn albumsAreNested() -> Result<(), Error>
+    {
+        let mut deleter = FileDeleter::new();
+        let db = tempFile();
+        deleter.register(&db);
+
+        let mut manager = Manager::new(sqlite_connection::Source::File(db));
+        manager.connect()?;
+        manager.init()?;
+
+        let album = |id: i64, title: &str, parent: Option<i64>| Album {
+            id, title: title.to_owned(), cover: None, parent };
+        manager.importAlbum(&album(1, "Travel", None))?;
+        manager.importAlbum(&album(2, "Japan 2024", Some(1)))?;
+        manager.importAlbum(&album(3, "Kyoto", Some(2)))?;
+        manager.importAlbum(&album(4, "Cats", None))?;
+        let mut p = Post::new();
+        p.images = vec![Image { path: PathBuf::from("a/kyoto.jpg"),
+                                ..Default::default() }];
+        manager.addPost(&p, Some(3))?;
+        p.images.clear();
+        manager.addPost(&p, Some(1))?;
+        manager.addPost(&p, Some(4))?;
+
+        assert_eq!(manager.countPostsInAlbum(1)?, 2);
+        assert_eq!(manager.countPostsInAlbum(2)?, 1);
+        assert_eq!(manager.getPostsInAlbum(1, 0, 10, PostOrder::NewFirst)?.len(), 2);
+        let top: Vec<(i64, u64)> = manager.getAlbumSummaries(None)?.iter()
+            .map(|a| (a.id, a.post_count)).collect();
+        assert_eq!(top, vec![(1, 2), (4, 1)]);
+        // The cover comes from the albums in it.
+        assert_eq!(manager.getAlbumSummaries(Some(1))?[0].cover.as_ref()
+                   .map(|c| c.path.clone()), Some(PathBuf::from("a/kyoto.jpg")));
+        assert!(manager.setAlbumCover(1, Some("a/kyoto.jpg"))?);
+        assert_eq!(manager.getAlbumPath(3)?.iter().map(|a| a.id).collect::<Vec<_>>(),
+                   vec![1, 2, 3]);
+        assert!(manager.getAlbumPath(9)?.is_empty());
+
+        // A loop of parents doesn’t hang.
+        manager.importAlbum(&album(5, "A", Some(6)))?;
+        manager.importAlbum(&album(6, "B", Some(5)))?;
+        assert_eq!(manager.countPostsInAlbum(5)?, 0);
+        assert_eq!(manager.getAlbumPath(5)?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn postsAreMovedBetweenAlbums() -> Result<(), Error>
+    {
+        let mut deleter = FileDeleter::new();
+        let db = tempFile();
+        deleter.register(&db);
+
+        let mut manager = Manager::new(sqlite_connection::Source::File(db));
+        manager.connect()?;
+        manager.init()?;
+
+        manager.importAlbum(&Album {
+            id: 1, title: String::from("Trip"), cover: None, parent: None })?;
+        let p = Post::new();
+        let a = manager.addPost(&p, None)?;
+        let b = manager.addPost(&p, Some(1))?;
+        assert_eq!(manager.countPostsInAlbum(1)?, 1);
+        assert!(manager.movePostsToAlbum(&[a, b], Some(1))?);
+        assert_eq!(manager.countPostsInAlbum(1)?, 2);
+        assert!(manager.movePostsToAlbum(&[b], None)?);
+        assert_eq!(manager.findPostByID(b)?.unwrap().album_id, None);
+        // Nothing is moved with a missing post or album.
+        assert!(!manager.movePostsToAlbum(&[b, 999], Some(1))?);
+        assert!(!manager.movePostsToAlbum(&[b], Some(2))?);
+        assert_eq!(manager.findPostByID(b)?.unwrap().album_id, None);
+        Ok(())
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn benchmarkGetPosts() -> Result<(), Error>
+    {
+        let mut deleter = FileDeleter::new();
+        let db = tempFile();
+        deleter.register(&db);
+
+        let mut manager = Manager::new(sqlite_connection::Source::File(db));
+        manager.connect()?;
+        manager.init()?;
+
+        let mut p = Post::new();
+        p.images = (0..4).map(|_| Image::default()).collect();
+        p.tags = vec![String::from("cat"), String::from("dog")];
+        for _ in 0..10000
+        {
+            manager.addPost(&p, None)?;
+        }
+        let start = std::time::Instant::now();
+        for page in 0..100
+        {
+            assert_eq!(manager.getPosts(page * 100, 100, PostOrder::NewFirst)?
+                       .len(), 100);
+        }
+        println!("100 pages of 100 posts in {:?}", start.elapsed());
+        Ok(())
+    }
+
+    #[test]
+    fn draftsAreNotListed() -> Result<(), Error>
+    {
+        let mut deleter = FileDeleter::new();
+        let db = tempFile();
+        deleter.register(&db);
+
+        let mut manager = Manager::new(sqlite_connection::Source::File(db));
+        manager.connect()?;
+        manager.init()?;
+
+        let mut p = Post::new();
+        manager.addPost(&p, None)?;
+        p.draft = true;
+        p.id = manager.addPost(&p, None)?;
+        assert_eq!(manager.countPosts()?, 1);
+        assert_eq!(manager.getPosts(0, 10, PostOrder::NewFirst)?.len(), 1);
+        assert_eq!(manager.getDrafts()?.len(), 1);
+        assert_eq!(manager.getAllPosts()?.len(), 2);
+
+        p.desc = String::from("Now with a description");
+        manager.publishDraft(&p)?;
+        assert!(manager.publishDraft(&p).is_err());
+        assert_eq!(manager.countPosts()?, 2);
+        assert!(manager.getDrafts()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn shareLinksExpire() -> Result<(), Error>
+    {
+        let mut deleter = FileDeleter::new();
+        let db = tempFile();
+        deleter.register(&db);
+
+        let mut manager = Manager::new(sqlite_connection::Source::File(db));
+        manager.connect()?;
+        manager.init()?;
+
+        let post_id = manager.addPost(&Post::new(), None)?;
+        let now = OffsetDateTime::now_utc();
+        manager.addShareLink(&ShareLink {
+            token: String::from("valid"),
+            post_id,
+            expire_time: now + time::Duration::days(1),
+        })?;
+        manager.addShareLink(&ShareLink {
+            token: String::from("expired"),
+            post_id,
+            expire_time: now - time::Duration::days(1),
+        })?;
+        assert_eq!(manager.findShareLink("valid")?.unwrap().post_id, post_id);
+        assert!(manager.findShareLink("expired")?.is_none());
+        assert_eq!(manager.getShareLinks(post_id)?.len(), 1);
+        manager.deleteShareLink("valid")?;
+        assert!(manager.findShareLink("valid")?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn onlyOneFirstUser() -> Result<(), Error>
+    {
+        let mut manager = Manager::new(sqlite_connection::Source::Memory);
+        manager.connect()?;
+        manager.init()?;
+        assert!(!manager.hasUsers()?);
+        assert!(manager.createFirstUser("a", "hash")?);
+        assert!(!manager.createFirstUser("b", "hash")?);
+        assert!(manager.hasUsers()?);
+        assert!(manager.findPasswordHash("b")?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn likesAreCountedOnce() -> Result<(), Error>
+    {
+        let mut deleter = FileDeleter::new();
+        let db = tempFile();
+        deleter.register(&db);
+
+        let mut manager = Manager::new(sqlite_connection::Source::File(db));
+        manager.connect()?;
+        manager.init()?;
+
+        let mut p = Post::new();
+        let first = manager.addPost(&p, None)?;
+        p.upload_time = OffsetDateTime::from_unix_timestamp(1).unwrap();
+        let second = manager.addPost(&p, None)?;
+        assert!(manager.addLike(first, Some("a"), "ip1")?);
+        assert!(!manager.addLike(first, Some("a"), "ip2")?);
+        // A new cookie, or another browser behind the same NAT.
+        assert!(!manager.addLike(first, Some("b"), "ip1")?);
+        assert!(!manager.addLike(first, None, "ip1")?);
+        assert!(manager.addLike(first, None, "ip3")?);
+        assert!(!manager.addLike(first, None, "ip3")?);
+        assert!(!manager.addLike(first, Some("c"), "ip3")?);
+        assert_eq!(manager.findPostByID(first)?.unwrap().likes, 2);
+        let ids: Vec<i64> = manager.getPosts(0, 10, PostOrder::MostLiked)?
+            .iter().map(|p| p.id).collect();
+        assert_eq!(ids, vec![first, second]);
+        // The likes go with the post, or it couldn’t be deleted with
+        // the foreign keys.
+        manager.deletePost(first)?;
+        assert!(manager.addLike(first, Some("a"), "ip1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn hiddenPostsAreNotListed() -> Result<(), Error>
+    {
+        let mut deleter = FileDeleter::new();
+        let db = tempFile();
+        deleter.register(&db);
+
+        let mut manager = Manager::new(sqlite_connection::Source::File(db));
+        manager.connect()?;
+        manager.init()?;
+
+        let mut p = Post::new();
+        manager.addPost(&p, None)?;
+        p.visibility = Visibility::Unlisted;
+        let unlisted = manager.addPost(&p, None)?;
+        p.visibility = Visibility::Private;
+        manager.addPost(&p, None)?;
+        assert_eq!(manager.countPosts()?, 1);
+        assert_eq!(manager.getPosts(0, 10, PostOrder::NewFirst)?.len(), 1);
+        assert_eq!(manager.getHiddenPosts()?.len(), 2);
+        assert_eq!(manager.findPostByID(unlisted)?.unwrap().visibility,
+                   Visibility::Unlisted);
+        Ok(())
+    }
+
+    #[test]
+    fn scheduledPostsGoLiveOnTime() -> Result<(), Error>
+    {
+        let mut deleter = FileDeleter::new();
+        let db = tempFile();
+        deleter.register(&db);
+
+        let mut manager = Manager::new(sqlite_connection::Source::File(db));
+        manager.connect()?;
+        manager.init()?;
+
+        let now = time::OffsetDateTime::now_utc();
+        let mut p = Post::new();
+        p.scheduled = true;
+        p.upload_time = now + time::Duration::hours(1);
+        let pending = manager.addPost(&p, None)?;
+        p.upload_time = now - time::Duration::minutes(1);
+        let due = manager.addPost(&p, None)?;
+        assert_eq!(manager.countPosts()?, 1);
+        assert_eq!(manager.getPosts(0, 10, PostOrder::NewFirst)?[0].id, due);
+        assert_eq!(manager.getPendingPosts()?[0].id, pending);
+        assert!(manager.findPostByID(pending)?.unwrap().isPending());
+
+        let due_posts = manager.getDueScheduledPosts()?;
+        assert_eq!(due_posts.len(), 1);
+        assert!(manager.clearScheduled(due)?);
+        assert!(!manager.clearScheduled(due)?);
+        assert!(manager.getDueScheduledPosts()?.is_empty());
+        assert_eq!(manager.countPosts()?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn setAndFindPasswordHash() -> Result<(), Error>
+    {
+        let mut manager = Manager::new(sqlite_connection::Source::Memory);
+        manager.connect()?;
+        manager.init()?;
+
+        assert!(manager.findPasswordHash("user")?.is_none());
+        manager.setPasswordHash("user", "aaa")?;
+        manager.setPasswordHash("user", "bbb")?;
+        assert_eq!(manager.findPasswordHash("user")?, Some(String::from("bbb")));
+        Ok(())
+    }
+
+    #[test]
+    fn importPostKeepsID() -> Result<(), Error>
+    {
+        let mut deleter = FileDeleter::new();
+        let db = tempFile();
+        deleter.register(&db);
+
+        let mut manager = Manager::new(sqlite_connection::Source::File(db));
+        manager.connect()?;
+        manager.init()?;
+
+        manager.importAlbum(&Album {
+            id: 3, title: String::from("a"), cover: None, parent: None })?;
+        let mut p = Post::new();
+        p.id = 42;
+        p.album_id = Some(3);
+        p.images = vec![Image {
             path: PathBuf::from("aaa"),
             width: 1,
             height: 2,
+            ..Default::default()
+        }];
+        manager.importPost(&p)?;
+        let post = manager.findPostByID(42)?.unwrap();
+        assert_eq!(post.album_id, Some(3));
+        assert_eq!(post.images.len(), 1);
+        assert_eq!(manager.getAlbums()?.len(), 1);
+        Ok(())
+    }
+
+    /// This runs on the PostgreSQL database at the URL in
+    /// `NSPIC_TEST_POSTGRES_URL`, whose tables are dropped, and is
+    /// skipped without it.
+    #[cfg(feature = "postgres")]
+    #[test]
+    fn postgresDatabase() -> Result<(), Error>
+    {
+        let url = match std::env::var("NSPIC_TEST_POSTGRES_URL")
+        {
+            Ok(url) => url,
+            Err(_) => return Ok(()),
         };
-        let image2 = Image {
-            path: PathBuf::from("bbb"),
-            width: 3,
-            height: 4,
+        let config = Configuration {
+            database_url: Some(url),
+            ..Default::default()
         };
-        let mut p = Post::new();
-        p.images = vec![image1, image2];
+        let mut manager = Manager::fromConfig(&config)?;
+        manager.connect()?;
+        manager.confirmConnection()?.executeBatch(
+            "DROP TABLE IF EXISTS albums, posts, images, tags, suggested_tags,
+             sessions, share_links, likes, users, login_failures, tokens,
+             deliveries CASCADE;").unwrap();
+        manager.init()?;
+        manager.init()?;
 
+        // New IDs come after the imported ones.
+        manager.importAlbum(&Album {
+            id: 3, title: String::from("Trip"), cover: None, parent: None })?;
+        let mut p = Post::new();
+        p.id = 42;
+        p.desc = String::from("A cat");
+        p.album_id = Some(3);
+        p.images = vec![Image {
+            path: PathBuf::from("aaa"),
+            size: 10,
+            thumbnail_size: 2,
+            phash: Some(u64::MAX),
+            ..Default::default()
+        }];
+        p.tags = vec![String::from("cat"), String::from("animal")];
+        manager.importPost(&p)?;
+        p.desc = String::new();
+        p.tags = vec![String::from("dog"), String::from("animal")];
+        p.images[0].path = PathBuf::from("bbb");
         let id = manager.addPost(&p, None)?;
-        let post_maybe = manager.findPostByID(id)?;
-        assert!(post_maybe.is_some());
-        let post = post_maybe.unwrap();
-        assert_eq!(post.id, id);
-        assert_eq!(post.images.len(), 2);
+        assert_eq!(id, 43);
+        let post = manager.findPostByID(42)?.unwrap();
+        assert_eq!(post.desc, "A cat");
+        assert_eq!(post.tags, vec!["cat", "animal"]);
+        assert_eq!(manager.findPostByID(id)?.unwrap().tags, vec!["dog", "animal"]);
+        assert_eq!(manager.countPostsInAlbum(3)?, 1);
+        assert_eq!(manager.countPostsWithTag("animal")?, 2);
+        assert_eq!(manager.getRelatedPosts(&post, 5)?.len(), 1);
+        assert_eq!(manager.libraryBytes()?, 24);
+        assert_eq!(manager.getImageHashes()?[0].phash, u64::MAX);
+        assert!(manager.setAlbumCover(3, Some("aaa"))?);
+        assert!(!manager.setAlbumCover(3, Some("bbb"))?);
+        assert!(manager.setAlbumCover(3, None)?);
 
-        manager.deletePost(id)?;
-        assert!(manager.findPostByID(id)?.is_none());
+        assert!(manager.addLike(id, Some("a"), "ip1")?);
+        assert!(!manager.addLike(id, Some("a"), "ip2")?);
+        assert!(!manager.addLike(id, None, "ip1")?);
+        assert!(manager.addLike(id, None, "ip3")?);
+        assert_eq!(manager.findPostByID(id)?.unwrap().likes, 2);
+
+        // A failed move is rolled back.
+        assert!(!manager.movePostsToAlbum(&[id, 1000], Some(3))?);
+        assert_eq!(manager.findPostByID(id)?.unwrap().album_id, None);
+        assert!(manager.movePostsToAlbum(&[id], Some(3))?);
+        assert_eq!(manager.countPostsInAlbum(3)?, 2);
+
+        let now = OffsetDateTime::now_utc();
+        let long_ago = now - time::Duration::days(1);
+        assert_eq!(manager.recordLoginFailure("c", long_ago, long_ago)?, 1);
+        assert_eq!(manager.recordLoginFailure("c", long_ago, long_ago)?, 2);
+        assert_eq!(manager.recordLoginFailure("c", now, now)?, 1);
+
+        assert!(manager.createFirstUser("me", "hash")?);
+        assert!(!manager.createFirstUser("you", "hash")?);
+        assert_eq!(manager.addApiToken("t", "h")?, 1);
+        assert!(manager.hasSession("none")?.is_none());
+        assert!(manager.backupTo(Path::new("/nonexistent")).is_err());
         Ok(())
     }
 }
@@ -0,0 +1,500 @@
+// The connections to the database, which is SQLite by default, or
+// PostgreSQL with the `postgres` feature. The statements are written
+// for SQLite, with `?` placeholders, and are translated for
+// PostgreSQL. The rows of both are read as SQLite values, so that
+// the same code reads them.
+
+#[cfg(feature = "postgres")]
+use std::cell::RefCell;
+use std::fmt;
+use std::ops::Deref;
+use std::rc::Rc;
+
+use rusqlite as sql;
+use rusqlite::types::{FromSql, FromSqlError, Value, ValueRef};
+
+use crate::sqlite_connection;
+
+#[cfg(feature = "postgres")]
+type PostgresManager = r2d2_postgres::PostgresConnectionManager<postgres::NoTls>;
+
+/// Where the database is.
+#[derive(Debug, Clone)]
+pub enum Source
+{
+    Sqlite(sqlite_connection::Source),
+    /// A `postgres://` URL.
+    #[cfg(feature = "postgres")]
+    Postgres(String),
+}
+
+#[derive(Clone)]
+pub enum Pool
+{
+    Sqlite(r2d2::Pool<sqlite_connection::Manager>),
+    #[cfg(feature = "postgres")]
+    Postgres(r2d2::Pool<PostgresManager>),
+}
+
+impl Pool
+{
+    #[cfg(feature = "postgres")]
+    pub fn postgres(url: &str) -> Result<Self, String>
+    {
+        let config: postgres::Config = url.parse().map_err(
+            |e| format!("Invalid database URL: {}", e))?;
+        let manager = PostgresManager::new(config, postgres::NoTls);
+        blocking(|| r2d2::Pool::new(manager)).map(Self::Postgres)
+            .map_err(|e| e.to_string())
+    }
+
+    pub fn get(&self) -> Result<Connection, r2d2::Error>
+    {
+        match self
+        {
+            Self::Sqlite(pool) => pool.get().map(Connection::Sqlite),
+            #[cfg(feature = "postgres")]
+            Self::Postgres(pool) => blocking(|| pool.get())
+                .map(|conn| Connection::Postgres(Box::new(RefCell::new(conn)))),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error
+{
+    Sqlite(sql::Error),
+    #[cfg(feature = "postgres")]
+    Postgres(postgres::Error),
+}
+
+impl fmt::Display for Error
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            Self::Sqlite(e) => write!(f, "{}", e),
+            // The error of the server says what went wrong, instead of
+            // just “db error”.
+            #[cfg(feature = "postgres")]
+            Self::Postgres(e) => match e.as_db_error()
+            {
+                Some(db_error) => write!(f, "{}", db_error),
+                None => write!(f, "{}", e),
+            },
+        }
+    }
+}
+
+impl From<sql::Error> for Error
+{
+    fn from(e: sql::Error) -> Self
+    {
+        Self::Sqlite(e)
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl From<postgres::Error> for Error
+{
+    fn from(e: postgres::Error) -> Self
+    {
+        Self::Postgres(e)
+    }
+}
+
+/// Like `rusqlite::OptionalExtension`, for the results of
+/// `Connection::queryRow`.
+pub trait OptionalExtension<T>
+{
+    /// None instead of the error of a query without rows.
+    fn optional(self) -> Result<Option<T>, Error>;
+}
+
+impl<T> OptionalExtension<T> for Result<T, Error>
+{
+    fn optional(self) -> Result<Option<T>, Error>
+    {
+        match self
+        {
+            Ok(value) => Ok(Some(value)),
+            Err(Error::Sqlite(sql::Error::QueryReturnedNoRows)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// A row of a query, from either database.
+pub struct Row
+{
+    names: Rc<[String]>,
+    values: Vec<Value>,
+}
+
+impl Row
+{
+    pub fn get<T: FromSql>(&self, index: usize) -> sql::Result<T>
+    {
+        let value = self.values.get(index).ok_or(
+            sql::Error::InvalidColumnIndex(index))?;
+        T::column_result(ValueRef::from(value)).map_err(|e| match e
+        {
+            FromSqlError::InvalidType => sql::Error::InvalidColumnType(
+                index, self.names[index].clone(), value.data_type()),
+            FromSqlError::OutOfRange(i) =>
+                sql::Error::IntegralValueOutOfRange(index, i),
+            e => sql::Error::FromSqlConversionFailure(
+                index, value.data_type(), Box::new(e)),
+        })
+    }
+}
+
+pub enum Connection
+{
+    Sqlite(r2d2::PooledConnection<sqlite_connection::Manager>),
+    #[cfg(feature = "postgres")]
+    Postgres(Box<RefCell<r2d2::PooledConnection<PostgresManager>>>),
+}
+
+impl Connection
+{
+    /// The SQLite connection, for what only SQLite has.
+    pub fn sqlite(&self) -> Option<&sql::Connection>
+    {
+        match self
+        {
+            Self::Sqlite(conn) => Some(conn),
+            #[cfg(feature = "postgres")]
+            Self::Postgres(_) => None,
+        }
+    }
+
+    /// Run statements without parameters.
+    pub fn executeBatch(&self, statements: &str) -> Result<(), Error>
+    {
+        match self
+        {
+            Self::Sqlite(conn) => Ok(conn.execute_batch(statements)?),
+            #[cfg(feature = "postgres")]
+            Self::Postgres(client) => Ok(blocking(
+                || client.borrow_mut().batch_execute(statements))?),
+        }
+    }
+
+    /// Run a statement, and return the number of rows it changed.
+    pub fn execute(&self, statement: &str, params: &[&dyn sql::ToSql]) ->
+        Result<usize, Error>
+    {
+        match self
+        {
+            Self::Sqlite(conn) => Ok(conn.execute(statement, params)?),
+            #[cfg(feature = "postgres")]
+            Self::Postgres(client) => {
+                let values = postgresValues(params)?;
+                let count = blocking(|| client.borrow_mut().execute(
+                    &postgresStatement(statement), &postgresRefs(&values)))?;
+                Ok(count as usize)
+            },
+        }
+    }
+
+    /// The ID of the row added by the last `INSERT` on this
+    /// connection.
+    pub fn lastInsertId(&self) -> Result<i64, Error>
+    {
+        match self
+        {
+            Self::Sqlite(conn) => Ok(conn.last_insert_rowid()),
+            #[cfg(feature = "postgres")]
+            Self::Postgres(_) =>
+                self.queryRow("SELECT lastval();", &[], |row| row.get(0)),
+        }
+    }
+
+    /// Make the IDs that the database assigns in `table` come after
+    /// the ones that were inserted explicitly. SQLite does this by
+    /// itself.
+    #[cfg_attr(not(feature = "postgres"), allow(unused_variables))]
+    pub fn syncIds(&self, table: &str) -> Result<(), Error>
+    {
+        match self
+        {
+            Self::Sqlite(_) => Ok(()),
+            #[cfg(feature = "postgres")]
+            Self::Postgres(_) => self.executeBatch(&format!(
+                "SELECT setval(pg_get_serial_sequence('{0}', 'id'),
+                 (SELECT MAX(id) FROM {0}));", table)),
+        }
+    }
+
+    /// The first row of a query mapped by `f`. It is an error if
+    /// there is no row.
+    pub fn queryRow<T, F>(&self, query: &str, params: &[&dyn sql::ToSql], f: F) ->
+        Result<T, Error>
+        where F: FnOnce(&Row) -> sql::Result<T>
+    {
+        let row = self.rows(query, params)?.into_iter().next()
+            .ok_or(sql::Error::QueryReturnedNoRows)?;
+        Ok(f(&row)?)
+    }
+
+    /// All rows of a query mapped by `f`.
+    pub fn queryRows<T, F>(&self, query: &str, params: &[&dyn sql::ToSql],
+                           mut f: F) -> Result<Vec<T>, Error>
+        where F: FnMut(&Row) -> sql::Result<T>
+    {
+        self.rows(query, params)?.iter().map(|row| Ok(f(row)?)).collect()
+    }
+
+    fn rows(&self, query: &str, params: &[&dyn sql::ToSql]) ->
+        Result<Vec<Row>, Error>
+    {
+        match self
+        {
+            Self::Sqlite(conn) => {
+                let mut cmd = conn.prepare(query)?;
+                let names: Rc<[String]> = cmd.column_names().into_iter()
+                    .map(String::from).collect();
+                let mut rows = cmd.query(params)?;
+                let mut result = Vec::new();
+                while let Some(row) = rows.next()?
+                {
+                    result.push(Row {
+                        names: names.clone(),
+                        values: (0..names.len()).map(|i| row.get(i))
+                            .collect::<sql::Result<_>>()?,
+                    });
+                }
+                Ok(result)
+            },
+            #[cfg(feature = "postgres")]
+            Self::Postgres(client) => {
+                let values = postgresValues(params)?;
+                let rows = blocking(|| client.borrow_mut().query(
+                    &postgresStatement(query), &postgresRefs(&values)))?;
+                let names: Rc<[String]> = match rows.first()
+                {
+                    Some(row) => row.columns().iter()
+                        .map(|c| c.name().to_owned()).collect(),
+                    None => Rc::new([]),
+                };
+                rows.iter().map(|row| Ok(Row {
+                    names: names.clone(),
+                    values: (0..row.len()).map(|i| postgresValue(row, i))
+                        .collect::<Result<_, _>>()?,
+                })).collect()
+            },
+        }
+    }
+
+    /// Begin a transaction, which is rolled back if it is dropped
+    /// before it is committed.
+    pub fn transaction(&self) -> Result<Transaction<'_>, Error>
+    {
+        self.executeBatch("BEGIN;")?;
+        Ok(Transaction { conn: self, committed: false })
+    }
+}
+
+pub struct Transaction<'a>
+{
+    conn: &'a Connection,
+    committed: bool,
+}
+
+impl Transaction<'_>
+{
+    pub fn commit(mut self) -> Result<(), Error>
+    {
+        self.conn.executeBatch("COMMIT;")?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Deref for Transaction<'_>
+{
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection
+    {
+        self.conn
+    }
+}
+
+impl Drop for Transaction<'_>
+{
+    fn drop(&mut self)
+    {
+        if !self.committed
+        {
+            self.conn.executeBatch("ROLLBACK;").ok();
+        }
+    }
+}
+
+/// Run `f`, which waits for the PostgreSQL server, without holding up
+/// the other tasks of the async runtime. The client has a runtime of
+/// its own, which can't be started on a thread of the async runtime.
+#[cfg(feature = "postgres")]
+fn blocking<T>(f: impl FnOnce() -> T) -> T
+{
+    match tokio::runtime::Handle::try_current()
+    {
+        Ok(handle) if handle.runtime_flavor() ==
+            tokio::runtime::RuntimeFlavor::MultiThread =>
+            tokio::task::block_in_place(f),
+        _ => f(),
+    }
+}
+
+/// Translate a statement for SQLite into one for PostgreSQL. The
+/// placeholders are numbered, and `INSERT OR IGNORE` becomes an
+/// `INSERT` that does nothing on conflicts.
+#[cfg(feature = "postgres")]
+fn postgresStatement(statement: &str) -> String
+{
+    let mut result = String::with_capacity(statement.len());
+    let mut count = 0;
+    let mut quoted = false;
+    let mut chars = statement.chars().peekable();
+    while let Some(c) = chars.next()
+    {
+        if c == '\'' || c == '"'
+        {
+            quoted = !quoted;
+        }
+        if c != '?' || quoted
+        {
+            result.push(c);
+            continue;
+        }
+        let mut number = String::new();
+        while let Some(digit) = chars.next_if(char::is_ascii_digit)
+        {
+            number.push(digit);
+        }
+        if number.is_empty()
+        {
+            count += 1;
+            number = count.to_string();
+        }
+        result.push('$');
+        result.push_str(&number);
+    }
+    match result.trim_start().strip_prefix("INSERT OR IGNORE ")
+    {
+        Some(rest) => format!("INSERT {} ON CONFLICT DO NOTHING;",
+                              rest.trim_end().trim_end_matches(';')),
+        None => result,
+    }
+}
+
+/// A parameter of a statement, which is converted to the type of its
+/// placeholder, because PostgreSQL doesn’t convert them like SQLite.
+#[cfg(feature = "postgres")]
+#[derive(Debug)]
+struct PostgresValue(Value);
+
+#[cfg(feature = "postgres")]
+impl postgres::types::ToSql for PostgresValue
+{
+    fn to_sql(&self, ty: &postgres::types::Type, out: &mut bytes::BytesMut) ->
+        Result<postgres::types::IsNull, Box<dyn std::error::Error + Sync + Send>>
+    {
+        use postgres::types::Type;
+        match &self.0
+        {
+            Value::Null => Ok(postgres::types::IsNull::Yes),
+            Value::Integer(i) => match *ty
+            {
+                Type::BOOL => (*i != 0).to_sql(ty, out),
+                Type::INT2 => i16::try_from(*i)?.to_sql(ty, out),
+                Type::INT4 => i32::try_from(*i)?.to_sql(ty, out),
+                Type::FLOAT4 => (*i as f32).to_sql(ty, out),
+                Type::FLOAT8 => (*i as f64).to_sql(ty, out),
+                Type::TEXT | Type::VARCHAR => i.to_string().to_sql(ty, out),
+                _ => i.to_sql(ty, out),
+            },
+            Value::Real(f) => match *ty
+            {
+                Type::FLOAT4 => (*f as f32).to_sql(ty, out),
+                _ => f.to_sql(ty, out),
+            },
+            Value::Text(s) => s.to_sql(ty, out),
+            Value::Blob(b) => b.to_sql(ty, out),
+        }
+    }
+
+    fn accepts(_: &postgres::types::Type) -> bool
+    {
+        true
+    }
+
+    postgres::types::to_sql_checked!();
+}
+
+#[cfg(feature = "postgres")]
+fn postgresValues(params: &[&dyn sql::ToSql]) -> sql::Result<Vec<PostgresValue>>
+{
+    params.iter().map(|param| match param.to_sql()?
+    {
+        sql::types::ToSqlOutput::Borrowed(value) => Ok(PostgresValue(value.into())),
+        sql::types::ToSqlOutput::Owned(value) => Ok(PostgresValue(value)),
+        _ => Err(sql::Error::ToSqlConversionFailure(
+            "Unsupported parameter".into())),
+    }).collect()
+}
+
+#[cfg(feature = "postgres")]
+fn postgresRefs(values: &[PostgresValue]) ->
+    Vec<&(dyn postgres::types::ToSql + Sync)>
+{
+    values.iter().map(|v| v as &(dyn postgres::types::ToSql + Sync)).collect()
+}
+
+/// Column `index` of `row` as an SQLite value.
+#[cfg(feature = "postgres")]
+fn postgresValue(row: &postgres::Row, index: usize) ->
+    Result<Value, postgres::Error>
+{
+    use postgres::types::Type;
+    let value = match *row.columns()[index].type_()
+    {
+        Type::BOOL => row.try_get::<_, Option<bool>>(index)?
+            .map(|b| Value::Integer(b as i64)),
+        Type::INT2 => row.try_get::<_, Option<i16>>(index)?
+            .map(|i| Value::Integer(i as i64)),
+        Type::INT4 => row.try_get::<_, Option<i32>>(index)?
+            .map(|i| Value::Integer(i as i64)),
+        Type::INT8 => row.try_get::<_, Option<i64>>(index)?.map(Value::Integer),
+        Type::FLOAT4 => row.try_get::<_, Option<f32>>(index)?
+            .map(|f| Value::Real(f as f64)),
+        Type::FLOAT8 => row.try_get::<_, Option<f64>>(index)?.map(Value::Real),
+        Type::BYTEA => row.try_get::<_, Option<Vec<u8>>>(index)?.map(Value::Blob),
+        _ => row.try_get::<_, Option<String>>(index)?.map(Value::Text),
+    };
+    Ok(value.unwrap_or(Value::Null))
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(all(test, feature = "postgres"))]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn statementsAreTranslated()
+    {
+        assert_eq!(postgresStatement("SELECT a FROM t WHERE b = ? AND c = ?;"),
+                   "SELECT a FROM t WHERE b = $1 AND c = $2;");
+        assert_eq!(postgresStatement("UPDATE t SET a = ?1 WHERE b = ?2 OR c = ?1;"),
+                   "UPDATE t SET a = $1 WHERE b = $2 OR c = $1;");
+        assert_eq!(postgresStatement("SELECT '?' FROM t WHERE a = ?;"),
+                   "SELECT '?' FROM t WHERE a = $1;");
+        assert_eq!(postgresStatement("INSERT OR IGNORE INTO t (a) VALUES (?);"),
+                   "INSERT INTO t (a) VALUES ($1) ON CONFLICT DO NOTHING;");
+    }
+}
@@ -0,0 +1,159 @@
+// Outbound deliveries of new posts to other services: the webhook,
+// Telegram, the WebSub hub, and the image classifier. A delivery is
+// saved in the database when the post goes live, and a background
+// thread attempts it until it succeeds, backing off exponentially, so
+// that a service being down for a while doesn’t lose posts. The requests block that
+// thread instead of the handler that made the post.
+
+use std::time::Duration;
+
+use log::{info, warn};
+use log::error as log_error;
+use time::OffsetDateTime;
+
+use crate::error::Error;
+use crate::config::Configuration;
+use crate::data;
+use crate::post::Post;
+use crate::classifier;
+use crate::telegram;
+use crate::webhook;
+use crate::websub;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(15);
+const BACKOFF_BASE_SEC: i64 = 30;
+const BACKOFF_MAX_SEC: i64 = 6 * 3600;
+/// A delivery is given up after this many failed attempts, which is
+/// about 15 hours with the backoff above.
+const ATTEMPTS_MAX: u32 = 12;
+
+const KIND_WEBHOOK: &str = "webhook";
+const KIND_TELEGRAM: &str = "telegram";
+const KIND_WEBSUB: &str = "websub";
+/// Queued by `classifier::enqueue`, also for drafts.
+pub const KIND_CLASSIFIER: &str = "classifier";
+
+/// A delivery in the queue.
+pub struct Delivery
+{
+    pub id: i64,
+    /// The service to deliver to.
+    pub kind: String,
+    pub post_id: i64,
+    /// The number of failed attempts so far.
+    pub attempts: u32,
+}
+
+/// Queue the deliveries of a post that just went live, to the
+/// services that want it. Failures are only logged.
+pub fn enqueue(post: &Post, data_manager: &data::Manager,
+               config: &Configuration)
+{
+    let kinds = [(KIND_WEBHOOK, webhook::wants(post, config)),
+                 (KIND_TELEGRAM, telegram::wants(post, config)),
+                 (KIND_WEBSUB, websub::wants(post, config))];
+    for (kind, wanted) in kinds
+    {
+        if wanted
+        {
+            if let Err(e) = data_manager.addDelivery(kind, post.id)
+            {
+                log_error!("Failed to queue {} delivery of post {}: {}",
+                           kind, post.id, e);
+            }
+        }
+    }
+}
+
+/// How long to wait after the `attempts`th failed attempt.
+fn backoff(attempts: u32) -> time::Duration
+{
+    let factor = 1i64 << std::cmp::min(attempts.saturating_sub(1), 20);
+    time::Duration::seconds(std::cmp::min(BACKOFF_BASE_SEC * factor,
+                                          BACKOFF_MAX_SEC))
+}
+
+fn attempt(delivery: &Delivery, post: &Post, data_manager: &data::Manager,
+           config: &Configuration) -> Result<(), Error>
+{
+    match delivery.kind.as_str()
+    {
+        KIND_WEBHOOK => webhook::send(post, config),
+        KIND_TELEGRAM => telegram::send(post, config),
+        KIND_WEBSUB => websub::ping(config),
+        KIND_CLASSIFIER => classifier::send(post, data_manager, config),
+        _ => Err(rterr!("Unknown delivery kind: {}", delivery.kind)),
+    }
+}
+
+/// Attempt the deliveries that are due. Return the number of
+/// successful ones.
+pub fn deliverDue(data_manager: &data::Manager, config: &Configuration) ->
+    Result<usize, Error>
+{
+    let now = OffsetDateTime::now_utc();
+    let mut count = 0;
+    for delivery in data_manager.getDueDeliveries(now)?
+    {
+        let post = match data_manager.findPostByID(delivery.post_id)?
+        {
+            Some(post) => post,
+            None => {
+                data_manager.deleteDelivery(delivery.id)?;
+                continue;
+            },
+        };
+        match attempt(&delivery, &post, data_manager, config)
+        {
+            Ok(()) => {
+                info!("Delivered post {} to {}.", post.id, delivery.kind);
+                data_manager.deleteDelivery(delivery.id)?;
+                count += 1;
+            },
+            Err(e) if delivery.attempts + 1 >= ATTEMPTS_MAX => {
+                log_error!("Giving up delivering post {} to {}: {}",
+                           post.id, delivery.kind, e);
+                data_manager.deleteDelivery(delivery.id)?;
+            },
+            Err(e) => {
+                let wait = backoff(delivery.attempts + 1);
+                warn!("Failed to deliver post {} to {}, retrying in {}: {}",
+                      post.id, delivery.kind, wait, e);
+                data_manager.postponeDelivery(delivery.id, now + wait)?;
+            },
+        }
+    }
+    Ok(count)
+}
+
+/// Attempt the due deliveries periodically in the background.
+pub fn spawn(data_manager: data::Manager, config: Configuration)
+{
+    std::thread::spawn(move || {
+        loop
+        {
+            if let Err(e) = deliverDue(&data_manager, &config)
+            {
+                log_error!("Failed to deliver posts: {}", e);
+            }
+            std::thread::sleep(CHECK_INTERVAL);
+        }
+    });
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn backoffGrows()
+    {
+        assert_eq!(backoff(1), time::Duration::seconds(30));
+        assert_eq!(backoff(2), time::Duration::seconds(60));
+        assert_eq!(backoff(5), time::Duration::seconds(480));
+        assert_eq!(backoff(30), time::Duration::hours(6));
+    }
+}
@@ -0,0 +1,322 @@
+// Downloading the images of a post or an album as one ZIP archive.
+// The archive is written while it is sent, one chunk at a time, so
+// that a large album doesn’t have to fit in memory. The images are
+// stored without compression, because they are compressed already,
+// and each image is read once for its CRC before it is sent, because
+// the CRC goes in front of the data.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use futures_util::stream;
+use log::error as log_error;
+use time::OffsetDateTime;
+use warp::http::HeaderValue;
+use warp::http::status::StatusCode;
+use warp::hyper::Body;
+use warp::reply::Response;
+
+use crate::error::Error;
+use crate::config::Configuration;
+use crate::data;
+use crate::post::Post;
+use crate::auth::validateSession;
+use crate::image_files;
+use crate::zip::{self, EntryHeader};
+
+/// Number of bytes of an image sent at a time.
+const CHUNK_BYTES: usize = 64 * 1024;
+/// Number of posts of an album looked up at a time.
+const PAGE_SIZE: u64 = 100;
+
+/// An image to put in the archive.
+struct ZipFile
+{
+    name: String,
+    path: PathBuf,
+    modified: OffsetDateTime,
+}
+
+/// The images of `post`, named by the ID of the post and their place
+/// in it, like `12-1.jpg`. These are the files that are served, so
+/// the watermarked copies if there is a watermark.
+fn postFiles(post: &Post, config: &Configuration) -> Vec<ZipFile>
+{
+    post.images.iter().enumerate().map(|(i, image)| ZipFile {
+        name: match image.path.extension().and_then(|e| e.to_str())
+        {
+            Some(ext) => format!("{}-{}.{}", post.id, i + 1, ext),
+            None => format!("{}-{}", post.id, i + 1),
+        },
+        path: Path::new(&config.image_dir).join(
+            image_files::servedPath(image.path.clone(), config)),
+        modified: post.upload_time,
+    }).collect()
+}
+
+/// What is left to write of the archive.
+enum Part
+{
+    /// The local header of the file with this index.
+    Entry(usize),
+    /// The rest of the data of the file with this index.
+    Data { index: usize, file: File, remaining: u64 },
+    Central,
+    Done,
+}
+
+struct ZipState
+{
+    files: Vec<ZipFile>,
+    headers: Vec<EntryHeader>,
+    /// Number of bytes written so far.
+    offset: u64,
+}
+
+impl ZipState
+{
+    fn tooLarge() -> Error
+    {
+        Error::HTTPStatus(StatusCode::PAYLOAD_TOO_LARGE, String::from(
+            "Too large for a ZIP archive"))
+    }
+
+    /// Write the next chunk of the archive.
+    fn next(&mut self, part: Part) -> Result<(Vec<u8>, Part), Error>
+    {
+        let (chunk, next) = match part
+        {
+            Part::Entry(index) if index == self.files.len() =>
+                return self.next(Part::Central),
+            Part::Entry(index) => {
+                let zip_file = &self.files[index];
+                let open = || File::open(&zip_file.path).map_err(
+                    |e| rterr!("Failed to open {:?}: {}", zip_file.path, e));
+                let mut reader = flate2::CrcReader::new(open()?);
+                let size = std::io::copy(&mut reader, &mut std::io::sink()).map_err(
+                    |e| rterr!("Failed to read {:?}: {}", zip_file.path, e))?;
+                let header = EntryHeader {
+                    name: zip_file.name.clone(),
+                    crc: reader.crc().sum(),
+                    size: u32::try_from(size).map_err(|_| Self::tooLarge())?,
+                    offset: u32::try_from(self.offset)
+                        .map_err(|_| Self::tooLarge())?,
+                    modified: zip_file.modified,
+                };
+                let chunk = zip::localHeader(&header);
+                let remaining = header.size as u64;
+                self.headers.push(header);
+                (chunk, Part::Data { index, file: open()?, remaining })
+            },
+            Part::Data { index, mut file, remaining } => {
+                let mut chunk = Vec::with_capacity(CHUNK_BYTES);
+                let path = &self.files[index].path;
+                file.by_ref().take(remaining.min(CHUNK_BYTES as u64))
+                    .read_to_end(&mut chunk).map_err(
+                        |e| rterr!("Failed to read {:?}: {}", path, e))?;
+                if chunk.is_empty()
+                {
+                    return Err(rterr!("{:?} changed while it was sent", path));
+                }
+                let remaining = remaining - chunk.len() as u64;
+                let next = if remaining == 0
+                {
+                    Part::Entry(index + 1)
+                }
+                else
+                {
+                    Part::Data { index, file, remaining }
+                };
+                (chunk, next)
+            },
+            Part::Central => {
+                let offset = u32::try_from(self.offset)
+                    .map_err(|_| Self::tooLarge())?;
+                (zip::centralDirectory(&self.headers, offset), Part::Done)
+            },
+            Part::Done => unreachable!(),
+        };
+        self.offset += chunk.len() as u64;
+        Ok((chunk, next))
+    }
+}
+
+/// The response of an archive of `files`, which is saved as
+/// `filename`.
+fn zipResponse(files: Vec<ZipFile>, filename: &str) -> Result<Response, Error>
+{
+    if files.is_empty()
+    {
+        return Err(Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()));
+    }
+    if files.len() > u16::MAX as usize
+    {
+        return Err(ZipState::tooLarge());
+    }
+    let state = ZipState { files, headers: Vec::new(), offset: 0 };
+    let chunks = stream::unfold(
+        (state, Part::Entry(0)), |(mut state, part)| async move {
+            if let Part::Done = part
+            {
+                return None;
+            }
+            // Reading the files is blocking.
+            match tokio::task::block_in_place(|| state.next(part))
+            {
+                Ok((chunk, next)) => Some((Ok(chunk), (state, next))),
+                Err(e) => {
+                    log_error!("Failed to write ZIP archive: {}", e);
+                    Some((Err(e), (state, Part::Done)))
+                },
+            }
+        });
+    let mut response = Response::new(Body::wrap_stream(chunks));
+    let headers = response.headers_mut();
+    headers.insert("Content-Type", HeaderValue::from_static("application/zip"));
+    headers.insert("Content-Disposition", HeaderValue::from_str(
+        &format!("attachment; filename=\"{}\"", filename))
+                   .map_err(|_| rterr!("Invalid filename: {}", filename))?);
+    Ok(response)
+}
+
+/// Download the images of a post. Posts that need a session to be
+/// seen also need one to be downloaded.
+pub fn handlePostDownload(post_id: i64, data_manager: &data::Manager,
+                          config: &Configuration, token: Option<String>) ->
+    Result<Response, Error>
+{
+    let post = data_manager.findPostByID(post_id)?.ok_or_else(
+        || Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()))?;
+    if post.needsSession() && !validateSession(&token, data_manager, config)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()));
+    }
+    zipResponse(postFiles(&post, config), &format!("post-{}.zip", post.id))
+}
+
+/// Download the images of the public posts in an album and the albums
+/// in it, old first.
+pub fn handleAlbumDownload(album_id: i64, data_manager: &data::Manager,
+                           config: &Configuration) -> Result<Response, Error>
+{
+    if data_manager.getAlbumPath(album_id)?.is_empty()
+    {
+        return Err(Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()));
+    }
+    let mut files = Vec::new();
+    let mut start = 0;
+    loop
+    {
+        let posts = data_manager.getPostsInAlbum(
+            album_id, start, PAGE_SIZE, data::PostOrder::OldFirst)?;
+        files.extend(posts.iter().flat_map(|post| postFiles(post, config)));
+        if (posts.len() as u64) < PAGE_SIZE
+        {
+            break;
+        }
+        start += PAGE_SIZE;
+    }
+    zipResponse(files, &format!("album-{}.zip", album_id))
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn archiveHasAllFiles() -> Result<(), Error>
+    {
+        let dir = std::env::temp_dir().join(
+            "nspic-test-".to_owned() + &rand::random::<u64>().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let result = (|| -> Result<(), Error> {
+            // Larger than a chunk.
+            let large: Vec<u8> = (0..CHUNK_BYTES * 2 + 10).map(|i| i as u8).collect();
+            std::fs::write(dir.join("a.jpg"), &large).unwrap();
+            std::fs::write(dir.join("b.png"), b"small").unwrap();
+            let file = |name: &str, path: &str| ZipFile {
+                name: name.to_owned(),
+                path: dir.join(path),
+                modified: OffsetDateTime::UNIX_EPOCH,
+            };
+            let mut state = ZipState {
+                files: vec![file("1-1.jpg", "a.jpg"), file("1-2.png", "b.png")],
+                headers: Vec::new(),
+                offset: 0,
+            };
+            let mut data = Vec::new();
+            let mut part = Part::Entry(0);
+            while !matches!(part, Part::Done)
+            {
+                let (chunk, next) = state.next(part)?;
+                assert!(chunk.len() <= CHUNK_BYTES);
+                data.extend(chunk);
+                part = next;
+            }
+            assert_eq!(state.offset, data.len() as u64);
+            let mut entries = Vec::new();
+            let limits = zip::Limits {
+                entry_bytes_max: u64::MAX,
+                total_bytes_max: u64::MAX,
+            };
+            zip::readEntries(&data, &limits, |name, content| {
+                let mut data = Vec::new();
+                content.read_to_end(&mut data).unwrap();
+                entries.push((name.to_owned(), data));
+                Ok(())
+            })?;
+            assert_eq!(entries.len(), 2);
+            assert_eq!(entries[0].0, "1-1.jpg");
+            assert_eq!(entries[0].1, large);
+            assert_eq!(entries[1].1, b"small");
+            Ok(())
+        })();
+        std::fs::remove_dir_all(&dir).ok();
+        result
+    }
+
+    #[test]
+    fn watermarkedCopiesAreDownloaded()
+    {
+        let dir = std::env::temp_dir().join(
+            "nspic-test-".to_owned() + &rand::random::<u64>().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["a.jpg", "a_w.jpg", "b.png"]
+        {
+            std::fs::write(dir.join(name), b"image").unwrap();
+        }
+        let config = Configuration {
+            image_dir: dir.to_str().unwrap().to_owned(),
+            watermark: Some(crate::config::WatermarkConfig {
+                image: None,
+                text: Some(String::from("NSPic")),
+                position: crate::config::WatermarkPosition::BottomRight,
+                opacity: 0.5,
+                width_percent: 20,
+                color: String::from("#ffffff"),
+            }),
+            ..Default::default()
+        };
+        let mut post = Post::new();
+        post.id = 3;
+        for path in ["a.jpg", "b.png"]
+        {
+            post.images.push(crate::post::Image {
+                path: PathBuf::from(path),
+                ..Default::default()
+            });
+        }
+        let files = postFiles(&post, &config);
+        std::fs::remove_dir_all(&dir).ok();
+        let files: Vec<(&str, PathBuf)> = files.iter()
+            .map(|f| (f.name.as_str(), f.path.strip_prefix(&dir).unwrap().to_owned()))
+            .collect();
+        // The watermarked copy of b.png is missing.
+        assert_eq!(files, vec![("3-1.jpg", PathBuf::from("a_w.jpg")),
+                               ("3-2.png", PathBuf::from("b.png"))]);
+    }
+}
@@ -0,0 +1,194 @@
+// Finding images that look the same by their perceptual hashes (see
+// phash.rs). An upload with the same file as an image in the library
+// is already the same image, because images are named by their
+// contents; this finds the ones that are re-encoded, resized, or
+// slightly edited. Depending on `duplicate_check`, such an upload is
+// logged or refused, and the admin page lists the probable duplicates
+// in the library.
+
+use std::path::PathBuf;
+
+use log::warn;
+use serde::Serialize;
+use tera::Tera;
+use warp::Reply;
+use warp::http::status::StatusCode;
+use warp::reply::Response;
+
+use crate::error::Error;
+use crate::config::{Configuration, DuplicateCheck};
+use crate::data;
+use crate::i18n::Catalog;
+use crate::prefs::Prefs;
+use crate::post::Image;
+use crate::phash;
+use crate::auth::validateSession;
+use crate::post_pipeline::removeImageFiles;
+
+/// An image in a post with its perceptual hash.
+#[derive(Serialize, Clone)]
+pub struct ImageHash
+{
+    pub path: PathBuf,
+    pub thumbnail: PathBuf,
+    #[serde(skip)]
+    pub phash: u64,
+    pub post_id: i64,
+    pub post_url_arg: String,
+}
+
+#[derive(Serialize)]
+struct Pair
+{
+    first: ImageHash,
+    second: ImageHash,
+    distance: u32,
+}
+
+/// The pairs of images in different posts whose hashes differ in at
+/// most `max_distance` bits, closest first.
+fn probablePairs(hashes: &[ImageHash], max_distance: u32) -> Vec<Pair>
+{
+    let mut pairs = Vec::new();
+    for (i, first) in hashes.iter().enumerate()
+    {
+        for second in &hashes[i + 1..]
+        {
+            let distance = phash::distance(first.phash, second.phash);
+            if distance <= max_distance && first.post_id != second.post_id
+            {
+                pairs.push(Pair { first: first.clone(), second: second.clone(),
+                                  distance });
+            }
+        }
+    }
+    pairs.sort_by_key(|pair| pair.distance);
+    pairs
+}
+
+/// Check processed `images` for ones that look like images in the
+/// library. With `duplicate_check = "Block"`, this is an error, and
+/// the files of the new images are removed, because they won’t be in
+/// any post.
+pub fn admit(images: &[Image], data_manager: &data::Manager,
+             config: &Configuration) -> Result<(), Error>
+{
+    if config.duplicate_check == DuplicateCheck::Off ||
+        images.iter().all(|img| img.phash.is_none())
+    {
+        return Ok(());
+    }
+    let library = data_manager.getImageHashes()?;
+    let mut duplicates = Vec::new();
+    for (i, image) in images.iter().enumerate()
+    {
+        let phash = match image.phash
+        {
+            Some(h) => h,
+            None => continue,
+        };
+        if let Some(existing) = library.iter().find(
+            |existing| existing.path != image.path && phash::distance(
+                existing.phash, phash) <= config.duplicate_distance_max)
+        {
+            warn!("Upload {:?} looks like {:?} in post {}.", image.path,
+                  existing.path, existing.post_id);
+            duplicates.push(format!("Image {} looks like an image in post {}",
+                                    i + 1, existing.post_url_arg));
+        }
+    }
+    if duplicates.is_empty() || config.duplicate_check != DuplicateCheck::Block
+    {
+        return Ok(());
+    }
+    for image in images
+    {
+        // The same file may be in the library.
+        if !library.iter().any(|existing| existing.path == image.path)
+        {
+            removeImageFiles(image, config);
+        }
+    }
+    Err(Error::HTTPStatus(StatusCode::CONFLICT, duplicates.join("; ")))
+}
+
+/// The page that lists the probable duplicates in the library.
+pub fn handleDuplicates(token: Option<String>, templates: &Tera, catalog: &Catalog,
+                        prefs: &Prefs, data_manager: &data::Manager,
+                        config: &Configuration) -> Result<Response, Error>
+{
+    if !validateSession(&token, data_manager, config)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    let pairs = probablePairs(&data_manager.getImageHashes()?,
+                              config.duplicate_distance_max);
+    let mut context = tera::Context::new();
+    context.insert("site_info", &config.site_info);
+    catalog.fillContext(&mut context);
+    prefs.fillContext(&mut context);
+    context.insert("pairs", &pairs);
+    let html = templates.render("duplicates.html", &context).map_err(
+        |e| rterr!("Failed to render template: {}", e))?;
+    Ok(warp::reply::html(html).into_response())
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::post::Post;
+
+    #[test]
+    fn nearDuplicatesAreFound() -> Result<(), Error>
+    {
+        let dir = std::env::temp_dir().join(
+            "nspic-test-".to_owned() + &rand::random::<u64>().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut config = Configuration {
+            image_dir: dir.to_str().unwrap().to_owned(),
+            duplicate_check: DuplicateCheck::Block,
+            ..Default::default()
+        };
+        let mut data_manager = data::Manager::new(
+            crate::sqlite_connection::Source::File(dir.join("db.sqlite")));
+        let result = (|| -> Result<(), Error> {
+            data_manager.connect()?;
+            data_manager.init()?;
+            let image = |path: &str, phash| Image {
+                path: PathBuf::from(path),
+                phash: Some(phash),
+                ..Default::default()
+            };
+            for (path, phash) in [("a/a.jpg", 0xff00), ("b/b.jpg", 0xff01),
+                                  ("c/c.jpg", 0xffff_0000)]
+            {
+                let mut post = Post::new();
+                post.images = vec![image(path, phash)];
+                data_manager.addPost(&post, None)?;
+            }
+            let pairs = probablePairs(&data_manager.getImageHashes()?,
+                                      config.duplicate_distance_max);
+            assert_eq!(pairs.len(), 1);
+            assert_eq!(pairs[0].first.path, PathBuf::from("a/a.jpg"));
+            assert_eq!(pairs[0].second.path, PathBuf::from("b/b.jpg"));
+            assert_eq!(pairs[0].distance, 1);
+
+            std::fs::write(dir.join("d.jpg"), b"image").unwrap();
+            let upload = [image("d.jpg", 0xff03)];
+            assert!(admit(&upload, &data_manager, &config).is_err());
+            assert!(!dir.join("d.jpg").exists());
+            // The same file as an image in the library.
+            assert!(admit(&[image("c/c.jpg", 0xffff_0000)], &data_manager,
+                          &config).is_ok());
+            assert!(admit(&[image("e.jpg", 0x00ff)], &data_manager, &config).is_ok());
+            config.duplicate_check = DuplicateCheck::Warn;
+            assert!(admit(&upload, &data_manager, &config).is_ok());
+            Ok(())
+        })();
+        std::fs::remove_dir_all(&dir).ok();
+        result
+    }
+}
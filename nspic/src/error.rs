@@ -1,8 +1,14 @@
+use std::convert::Infallible;
 use std::error::Error as StdError;
 use std::fmt;
 
 use log::error as log_error;
+use serde::Serialize;
+use warp::{Filter, Reply};
+use warp::filters::BoxedFilter;
+use warp::http::header;
 use warp::http::status::StatusCode;
+use warp::reply::Response;
 
 #[macro_export]
 macro_rules! error
@@ -44,10 +50,143 @@ pub enum Error
     HTTPStatus(StatusCode, String),
 }
 
+/// The kind of an error, for clients that read errors in JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode
+{
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    TooManyRequests,
+    InsufficientStorage,
+    Unavailable,
+    /// A failure of the database or the disk.
+    Data,
+    /// Any other failure of the server.
+    Internal,
+    /// Any other HTTP status.
+    Other,
+}
+
+/// A rejection that carries the error, so that it can be rendered
+/// when no route handles the request.
+#[derive(Debug)]
+struct Rejected(Error);
+
+impl warp::reject::Reject for Rejected {}
+
 pub fn reject(e: Error) -> warp::Rejection
 {
     log_error!("{}", e);
-    warp::reject::reject()
+    warp::reject::custom(Rejected(e))
+}
+
+/// Whether the client of the request with the `Accept` header
+/// `accept` wants errors in JSON.
+pub fn wantsJson(accept: Option<&str>) -> bool
+{
+    accept.map(|a| a.contains("application/json")).unwrap_or(false)
+}
+
+impl Error
+{
+    pub fn status(&self) -> StatusCode
+    {
+        match self
+        {
+            Error::DataError(_) | Error::RuntimeError(_) =>
+                StatusCode::INTERNAL_SERVER_ERROR,
+            Error::HTTPStatus(c, _) => *c,
+        }
+    }
+
+    pub fn code(&self) -> ErrorCode
+    {
+        match self
+        {
+            Error::DataError(_) => ErrorCode::Data,
+            Error::RuntimeError(_) => ErrorCode::Internal,
+            Error::HTTPStatus(c, _) => match *c
+            {
+                StatusCode::BAD_REQUEST => ErrorCode::BadRequest,
+                StatusCode::UNAUTHORIZED => ErrorCode::Unauthorized,
+                StatusCode::FORBIDDEN => ErrorCode::Forbidden,
+                StatusCode::NOT_FOUND => ErrorCode::NotFound,
+                StatusCode::PAYLOAD_TOO_LARGE => ErrorCode::PayloadTooLarge,
+                StatusCode::UNSUPPORTED_MEDIA_TYPE =>
+                    ErrorCode::UnsupportedMediaType,
+                StatusCode::TOO_MANY_REQUESTS => ErrorCode::TooManyRequests,
+                StatusCode::INSUFFICIENT_STORAGE => ErrorCode::InsufficientStorage,
+                StatusCode::SERVICE_UNAVAILABLE => ErrorCode::Unavailable,
+                _ => ErrorCode::Other,
+            },
+        }
+    }
+
+    pub fn message(&self) -> &str
+    {
+        match self
+        {
+            Error::DataError(msg) | Error::RuntimeError(msg) |
+            Error::HTTPStatus(_, msg) => msg,
+        }
+    }
+
+    /// The response of the error as `{"error": {"code": ...,
+    /// "status": ..., "message": ...}}`.
+    pub fn toJson(&self) -> Response
+    {
+        let body = serde_json::json!({"error": {
+            "code": self.code(),
+            "status": self.status().as_u16(),
+            "message": self.message(),
+        }});
+        warp::reply::with_status(warp::reply::json(&body), self.status())
+            .into_response()
+    }
+}
+
+/// Render the errors of `route` in JSON if the client accepts it.
+/// The responses of errors are recognized by the error they carry,
+/// and the errors that handlers reject with are made into responses.
+/// Other rejections are kept for warp.
+pub fn negotiate(route: BoxedFilter<(Response,)>) -> BoxedFilter<(Response,)>
+{
+    let route = route.map(Ok).recover(|r: warp::Rejection| async move {
+        Ok::<_, Infallible>(match r.find::<Rejected>()
+        {
+            Some(Rejected(e)) => Ok(e.clone().into_response()),
+            None => Err(r),
+        })
+    }).unify();
+    warp::header::optional::<String>(header::ACCEPT.as_str()).and(route)
+        .and_then(|accept: Option<String>,
+                   result: Result<Response, warp::Rejection>| async move {
+            let response = result?;
+            if !wantsJson(accept.as_deref())
+            {
+                return Ok(response);
+            }
+            let error = match response.extensions().get::<Error>()
+            {
+                Some(e) => e.clone(),
+                None => return Ok(response),
+            };
+            // Keep the headers like Retry-After.
+            let mut json = error.toJson();
+            for (name, value) in response.headers()
+            {
+                if name != header::CONTENT_TYPE && name != header::CONTENT_LENGTH
+                {
+                    json.headers_mut().insert(name, value.clone());
+                }
+            }
+            Ok::<_, warp::Rejection>(json)
+        }).boxed()
 }
 
 impl fmt::Display for Error
@@ -69,20 +208,58 @@ impl StdError for Error
     fn source(&self) -> Option<&(dyn StdError + 'static)> {None}
 }
 
-impl warp::reply::Reply for Error
+impl Reply for Error
 {
     fn into_response(self) -> warp::reply::Response
     {
-        match self
-        {
-            Error::DataError(_) => warp::reply::with_status(
-                self.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
-                .into_response(),
-            Error::RuntimeError(_) => warp::reply::with_status(
-                self.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
-                .into_response(),
-            Error::HTTPStatus(c, _) => warp::reply::with_status(
-                self.to_string(), c).into_response(),
-        }
+        let mut response = warp::reply::with_status(
+            self.to_string(), self.status()).into_response();
+        // For negotiate().
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn request(route: &BoxedFilter<(Response,)>, path: &str, accept: &str) ->
+        warp::http::Response<bytes::Bytes>
+    {
+        tokio::runtime::Runtime::new().unwrap().block_on(
+            warp::test::request().path(path).header("accept", accept)
+                .reply(route))
+    }
+
+    fn json(response: &warp::http::Response<bytes::Bytes>) -> serde_json::Value
+    {
+        serde_json::from_slice(response.body()).unwrap()
+    }
+
+    #[test]
+    fn errorsAreNegotiated()
+    {
+        let route = negotiate(warp::path("a").map(
+            || Error::HTTPStatus(StatusCode::NOT_FOUND, String::from("No post"))
+                .into_response())
+            .or(warp::path("b").and_then(
+                || async { Err::<Response, _>(reject(rterr!("Broken"))) }))
+            .unify().boxed());
+
+        let response = request(&route, "/a", "text/html");
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(response.body().starts_with(b"HTTP status"));
+        let response = request(&route, "/a", "application/json");
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(json(&response), serde_json::json!({"error": {
+            "code": "not_found", "status": 404, "message": "No post"}}));
+        let response = request(&route, "/b", "application/json");
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(json(&response)["error"]["code"], "internal");
+        assert_eq!(json(&response)["error"]["message"], "Broken");
     }
 }
@@ -0,0 +1,210 @@
+// Media RSS (http://search.yahoo.com/mrss/) elements of the feed
+// entries, so that feed readers can show the images of a post as a
+// gallery, and the HTML content of the entries for the readers that
+// don’t. These are built here instead of in the template, because
+// the sizes and types of the thumbnails are only known in Rust.
+
+use std::fmt::Write;
+
+use crate::config::Configuration;
+use crate::post::{Image, Post, mimeTypeFromPath};
+use crate::app::absoluteUrl;
+
+/// Escape text for an XML attribute value or element content.
+pub fn xmlEscape(text: &str) -> String
+{
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars()
+    {
+        match c
+        {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&apos;"),
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+fn imageUrl(path: &str, config: &Configuration) -> String
+{
+    absoluteUrl("image_file", path, config)
+}
+
+fn mediaContent(image: &Image, config: &Configuration) -> Option<String>
+{
+    let path = image.path.to_str()?;
+    let thumbnail = image.thumbnail().ok()?;
+    let (thumb_width, thumb_height) =
+        image.thumbnailSize(config.thumb_pixel_size);
+    let mut xml = format!(
+        r#"<media:content url="{}" type="{}" medium="image" width="{}" height="{}""#,
+        xmlEscape(&imageUrl(path, config)), mimeTypeFromPath(&image.path),
+        image.width, image.height);
+    if image.size > 0
+    {
+        write!(xml, r#" fileSize="{}""#, image.size).unwrap();
+    }
+    write!(xml, r#"><media:thumbnail url="{}" width="{}" height="{}"/></media:content>"#,
+           xmlEscape(&imageUrl(thumbnail.to_str()?, config)), thumb_width,
+           thumb_height).unwrap();
+    Some(xml)
+}
+
+/// The media elements of a feed entry. The first image is also the
+/// thumbnail of the entry. A sensitive post only has a rating, so that
+/// readers don’t show its images.
+pub fn mediaElements(post: &Post, config: &Configuration) -> String
+{
+    if post.sensitive
+    {
+        return String::from(
+            r#"<media:rating scheme="urn:simple">adult</media:rating>"#);
+    }
+    let contents: Vec<String> = post.images.iter()
+        .filter_map(|img| mediaContent(img, config)).collect();
+    if contents.is_empty()
+    {
+        return String::new();
+    }
+    let mut xml = String::new();
+    if let Some(first) = post.images.first()
+    {
+        if let Some(thumbnail) = first.thumbnail().ok()
+            .and_then(|t| t.to_str().map(|s| s.to_owned()))
+        {
+            write!(xml, r#"<media:thumbnail url="{}"/>"#,
+                   xmlEscape(&imageUrl(&thumbnail, config))).unwrap();
+        }
+    }
+    if !post.desc.is_empty()
+    {
+        write!(xml, "<media:description>{}</media:description>",
+               xmlEscape(&post.desc)).unwrap();
+    }
+    if contents.len() == 1
+    {
+        xml.push_str(&contents[0]);
+    }
+    else
+    {
+        xml.push_str("<media:group>");
+        for content in contents
+        {
+            xml.push_str(&content);
+        }
+        xml.push_str("</media:group>");
+    }
+    xml
+}
+
+/// The HTML content of a feed entry: the description, and the
+/// thumbnails linking to the post, or only a link for a sensitive
+/// post. The result is not escaped for XML.
+pub fn contentHtml(post: &Post, config: &Configuration) -> String
+{
+    let post_url = xmlEscape(&absoluteUrl("post", &post.urlArg(), config));
+    let mut html = String::new();
+    if !post.desc.is_empty()
+    {
+        write!(html, "<p>{}</p>", xmlEscape(&post.desc)).unwrap();
+    }
+    if post.sensitive && !post.images.is_empty()
+    {
+        write!(html, r#"<p><a href="{}">Sensitive images</a></p>"#, post_url)
+            .unwrap();
+        return html;
+    }
+    for image in &post.images
+    {
+        let thumbnail = match image.thumbnail().ok()
+            .and_then(|t| t.to_str().map(|s| s.to_owned()))
+        {
+            Some(t) => t,
+            None => continue,
+        };
+        let (width, height) = image.thumbnailSize(config.thumb_pixel_size);
+        let alt = if image.alt_text.is_empty() { &post.desc } else { &image.alt_text };
+        write!(html, r#"<p><a href="{}"><img src="{}" alt="{}" width="{}" height="{}"/></a></p>"#,
+               post_url, xmlEscape(&imageUrl(&thumbnail, config)),
+               xmlEscape(alt), width, height).unwrap();
+        if !image.caption.is_empty()
+        {
+            write!(html, "<p>{}</p>", xmlEscape(&image.caption)).unwrap();
+        }
+    }
+    html
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn mediaOfPost()
+    {
+        let mut config = Configuration::default();
+        config.site_info.url_domain = String::from("https://example.org");
+        let mut post = Post::new();
+        post.desc = String::from("Cats & dogs");
+        assert_eq!(mediaElements(&post, &config), "");
+        post.images.push(Image {
+            path: PathBuf::from("a").join("bc.jpg"),
+            width: 400,
+            height: 296,
+            size: 1234,
+            ..Default::default()
+        });
+        assert_eq!(
+            mediaElements(&post, &config),
+            concat!(r#"<media:thumbnail url="https://example.org/image/a/bc_t.jpg"/>"#,
+                    "<media:description>Cats &amp; dogs</media:description>",
+                    r#"<media:content url="https://example.org/image/a/bc.jpg" "#,
+                    r#"type="image/jpeg" medium="image" width="400" height="296" "#,
+                    r#"fileSize="1234"><media:thumbnail "#,
+                    r#"url="https://example.org/image/a/bc_t.jpg" width="256" "#,
+                    r#"height="189"/></media:content>"#));
+        post.images.push(Image {
+            path: PathBuf::from("d").join("ef.png"),
+            ..Default::default()
+        });
+        assert!(mediaElements(&post, &config).contains("<media:group>"));
+        post.sensitive = true;
+        assert_eq!(mediaElements(&post, &config),
+                   r#"<media:rating scheme="urn:simple">adult</media:rating>"#);
+    }
+
+    #[test]
+    fn contentOfPost()
+    {
+        let mut config = Configuration::default();
+        config.site_info.url_domain = String::from("https://example.org");
+        let mut post = Post::new();
+        post.id = 5;
+        post.desc = String::from("Cats & dogs");
+        post.images.push(Image {
+            path: PathBuf::from("a").join("bc.jpg"),
+            width: 400,
+            height: 296,
+            caption: String::from("A cat"),
+            ..Default::default()
+        });
+        assert_eq!(
+            contentHtml(&post, &config),
+            concat!("<p>Cats &amp; dogs</p>",
+                    r#"<p><a href="https://example.org/p/5"><img "#,
+                    r#"src="https://example.org/image/a/bc_t.jpg" alt="Cats &amp; dogs" "#,
+                    r#"width="256" height="189"/></a></p><p>A cat</p>"#));
+        post.sensitive = true;
+        assert_eq!(contentHtml(&post, &config),
+                   concat!("<p>Cats &amp; dogs</p>",
+                           r#"<p><a href="https://example.org/p/5">Sensitive images</a></p>"#));
+    }
+}
@@ -0,0 +1,147 @@
+// Downloading images by URL for uploads. The server makes the request,
+// so it must not be usable to reach services that are only open to
+// it: the host is resolved by the agent here, and a request to an
+// address that is not public fails before it connects, also after a
+// redirect. The download is limited by `image_bytes_max` and
+// `url_fetch_timeout_sec` of the config.
+
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+
+use warp::http::status::StatusCode;
+
+use crate::error::Error;
+use crate::config::Configuration;
+
+const REDIRECTS_MAX: u32 = 5;
+
+/// Whether `ip` is an address on the internet, and not one of the
+/// loopback, private, link-local, or other special ranges.
+fn isPublicAddress(ip: IpAddr) -> bool
+{
+    match ip
+    {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            !(ip.is_loopback() || ip.is_private() || ip.is_link_local() ||
+              ip.is_broadcast() || ip.is_documentation() ||
+              ip.is_unspecified() || ip.is_multicast() || octets[0] == 0 ||
+              // Shared address space of carrier-grade NAT.
+              (octets[0] == 100 && (octets[1] & 0xc0) == 64))
+        },
+        IpAddr::V6(ip) => {
+            if let Some(v4) = ip.to_ipv4_mapped()
+            {
+                return isPublicAddress(IpAddr::V4(v4));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() ||
+              // Unique local and link-local.
+              (first & 0xfe00) == 0xfc00 || (first & 0xffc0) == 0xfe80)
+        },
+    }
+}
+
+/// Resolve `netloc`, keeping only the public addresses unless
+/// `allow_private`.
+fn resolve(netloc: &str, allow_private: bool) -> std::io::Result<Vec<SocketAddr>>
+{
+    let addrs: Vec<SocketAddr> = netloc.to_socket_addrs()?
+        .filter(|addr| allow_private || isPublicAddress(addr.ip())).collect();
+    if addrs.is_empty()
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("{} is not a public address", netloc)));
+    }
+    Ok(addrs)
+}
+
+/// The filename of the image at `url`, which is the last segment of
+/// its path.
+fn filenameOfUrl(url: &str) -> String
+{
+    url.split(['?', '#']).next().unwrap_or("")
+        .rsplit('/').next().filter(|name| !name.is_empty())
+        .unwrap_or("image").to_owned()
+}
+
+/// Download the image at `url`. Return the data and the filename.
+pub fn download(url: &str, config: &Configuration) ->
+    Result<(Vec<u8>, String), Error>
+{
+    let url = url.trim();
+    if !(url.starts_with("http://") || url.starts_with("https://"))
+    {
+        return Err(Error::HTTPStatus(StatusCode::BAD_REQUEST, format!(
+            "Only HTTP and HTTPS URLs can be uploaded: {}", url)));
+    }
+    let allow_private = config.url_fetch_allow_private;
+    let agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(config.url_fetch_timeout_sec))
+        .redirects(REDIRECTS_MAX)
+        .resolver(move |netloc: &str| resolve(netloc, allow_private))
+        .build();
+    let response = agent.get(url)
+        .set("User-Agent", concat!("nspic/", env!("CARGO_PKG_VERSION")))
+        .call().map_err(|e| match e
+        {
+            ureq::Error::Transport(t) if t.kind() == ureq::ErrorKind::Dns =>
+                Error::HTTPStatus(StatusCode::BAD_REQUEST, format!(
+                    "Cannot fetch {}: the host is unknown or not public", url)),
+            e => Error::HTTPStatus(StatusCode::BAD_GATEWAY, format!(
+                "Failed to fetch {}: {}", url, e)),
+        })?;
+    let filename = filenameOfUrl(response.get_url());
+    let mut data = Vec::new();
+    // One more byte to tell whether the image is too large.
+    response.into_reader().take(config.image_bytes_max + 1)
+        .read_to_end(&mut data).map_err(
+            |e| Error::HTTPStatus(StatusCode::BAD_GATEWAY, format!(
+                "Failed to download {}: {}", url, e)))?;
+    Ok((data, filename))
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn onlyPublicAddressesAreAllowed()
+    {
+        for ip in ["127.0.0.1", "10.1.2.3", "192.168.0.1", "172.16.0.1",
+                   "169.254.169.254", "100.64.0.1", "0.0.0.0", "::1",
+                   "fd00::1", "fe80::1", "::ffff:127.0.0.1"]
+        {
+            assert!(!isPublicAddress(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["93.184.216.34", "100.128.0.1", "2606:4700::1111"]
+        {
+            assert!(isPublicAddress(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn internalUrlsAreRejected()
+    {
+        let config = Configuration::default();
+        for url in ["http://127.0.0.1:1/a.jpg", "http://localhost:1/a.jpg",
+                    "file:///etc/passwd"]
+        {
+            assert!(matches!(download(url, &config),
+                             Err(Error::HTTPStatus(StatusCode::BAD_REQUEST, _))),
+                    "{}", url);
+        }
+    }
+
+    #[test]
+    fn filenameIsTheLastSegment()
+    {
+        assert_eq!(filenameOfUrl("https://a.org/b/cat.jpg?size=large"), "cat.jpg");
+        assert_eq!(filenameOfUrl("https://a.org/"), "image");
+    }
+}
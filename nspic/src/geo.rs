@@ -0,0 +1,134 @@
+// Locations of posts. New posts can get the name of their place by
+// reverse geocoding, and the located posts are shown at /map, which
+// draws its markers from the GeoJSON at /map.geojson.
+
+use std::time::Duration;
+
+use log::warn;
+use serde_json::{json, Value};
+use tera::Tera;
+use warp::Reply;
+use warp::reply::Response;
+
+use crate::error::Error;
+use crate::config::Configuration;
+use crate::data;
+use crate::i18n::Catalog;
+use crate::prefs::Prefs;
+use crate::post::{Location, Post};
+use crate::app::{pathPrefix, urlFor};
+
+const GEOCODE_TIMEOUT_SEC: u64 = 10;
+
+/// The name of the place at `location`, if reverse geocoding is
+/// configured. Failures are only logged.
+pub fn lookUpPlace(location: &Location, config: &Configuration) ->
+    Option<String>
+{
+    let url = config.geo.reverse_geocode_url.as_ref()?
+        .replace("{lat}", &location.latitude.to_string())
+        .replace("{lon}", &location.longitude.to_string());
+    let agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(GEOCODE_TIMEOUT_SEC)).build();
+    // Nominatim asks for a user agent that identifies the app.
+    let result = agent.get(&url)
+        .set("User-Agent", concat!("nspic/", env!("CARGO_PKG_VERSION")))
+        .call().map_err(|e| rterr!("{}", e))
+        .and_then(|res| res.into_json::<Value>().map_err(|e| rterr!("{}", e)));
+    match result
+    {
+        Ok(value) => value.get("display_name").and_then(|n| n.as_str())
+            .map(|n| n.to_owned()),
+        Err(e) => {
+            warn!("Failed to look up place: {}.", e);
+            None
+        },
+    }
+}
+
+/// A GeoJSON FeatureCollection of the posts with a location.
+fn featureCollection(posts: &[Post], config: &Configuration) -> Value
+{
+    let prefix = pathPrefix(&config.serve_under_path);
+    let features: Vec<Value> = posts.iter().filter_map(|post| {
+        let location = post.location.as_ref()?;
+        let thumbnail = post.images.first()
+            .and_then(|img| img.thumbnail().ok())
+            .and_then(|path| path.to_str().map(
+                |p| prefix.clone() + &urlFor("image_file", p)));
+        Some(json!({
+            "type": "Feature",
+            "geometry": {
+                "type": "Point",
+                "coordinates": [location.longitude, location.latitude],
+            },
+            "properties": {
+                "id": post.id,
+                "url": prefix.clone() + &urlFor("post", &post.urlArg()),
+                "desc": post.desc,
+                "place": location.place,
+                "thumbnail": thumbnail,
+                "upload_time": post.upload_time.unix_timestamp(),
+            },
+        }))
+    }).collect();
+    json!({"type": "FeatureCollection", "features": features})
+}
+
+pub fn handleGeoJson(data_manager: &data::Manager, config: &Configuration) ->
+    Result<Response, Error>
+{
+    let posts = data_manager.getLocatedPosts()?;
+    let body = serde_json::to_string(&featureCollection(&posts, config))
+        .map_err(|e| rterr!("Failed to serialize GeoJSON: {}", e))?;
+    Ok(warp::reply::with_header(body, "Content-Type", "application/geo+json")
+       .into_response())
+}
+
+pub fn handleMap(templates: &Tera, catalog: &Catalog, prefs: &Prefs,
+                 data_manager: &data::Manager, config: &Configuration) ->
+    Result<Response, Error>
+{
+    let mut context = tera::Context::new();
+    context.insert("site_info", &config.site_info);
+    catalog.fillContext(&mut context);
+    prefs.fillContext(&mut context);
+    context.insert("posts", &data_manager.getLocatedPosts()?);
+    context.insert("tile_url", &config.geo.tile_url);
+    context.insert("tile_attribution", &config.geo.tile_attribution);
+    let html = templates.render("map.html", &context).map_err(
+        |e| rterr!("Failed to render template: {}", e))?;
+    Ok(warp::reply::html(html).into_response())
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::path::PathBuf;
+    use crate::post::Image;
+
+    #[test]
+    fn featuresArePoints()
+    {
+        let config = Configuration {
+            serve_under_path: String::from("/pic"),
+            ..Default::default()
+        };
+        let mut post = Post::new();
+        post.id = 3;
+        post.images = vec![Image {
+            path: PathBuf::from("a/abc.jpg"),
+            ..Default::default()
+        }];
+        post.location = Location::new(35.5, -120.25);
+        let collection = featureCollection(&[post, Post::new()], &config);
+        let features = collection["features"].as_array().unwrap();
+        assert_eq!(features.len(), 1);
+        assert_eq!(features[0]["geometry"]["coordinates"], json!([-120.25, 35.5]));
+        assert_eq!(features[0]["properties"]["url"], "/pic/p/3");
+        assert_eq!(features[0]["properties"]["thumbnail"], "/pic/image/a/abc_t.jpg");
+    }
+}
@@ -0,0 +1,155 @@
+// HTTP caching. Image files are named by their content hash, so they
+// never change, and browsers can keep them forever. Thumbnails can be
+// regenerated, so they are only cached for a while. Pages get an ETag
+// from a hash of their content, and a request that already has the
+// page gets a 304 without the body.
+
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+use warp::Reply;
+use warp::http::HeaderValue;
+use warp::http::header;
+use warp::http::status::StatusCode;
+use warp::reply::Response;
+
+const IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+const THUMBNAIL_CACHE_CONTROL: &str = "public, max-age=86400";
+/// Pages can be stored, but have to be revalidated every time.
+const PAGE_CACHE_CONTROL: &str = "no-cache";
+
+/// Whether the file at `path` in the image dir is a full size image,
+/// which is named by its content hash.
+fn isImmutableImage(path: &Path) -> bool
+{
+    match path.file_stem().and_then(|s| s.to_str())
+    {
+        Some(stem) => !stem.ends_with("_t") && !stem.starts_with("temp-"),
+        None => false,
+    }
+}
+
+pub fn imageCacheControl(path: &Path) -> &'static str
+{
+    if isImmutableImage(path)
+    {
+        IMMUTABLE_CACHE_CONTROL
+    }
+    else
+    {
+        THUMBNAIL_CACHE_CONTROL
+    }
+}
+
+/// The response of an image file that was just made, like a
+/// regenerated thumbnail, with the cache headers of the file.
+pub fn generatedImage(data: Vec<u8>, path: &Path) -> Response
+{
+    let mut response = data.into_response();
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(crate::post::mimeTypeFromPath(path)));
+    response.headers_mut().insert(
+        header::CACHE_CONTROL, HeaderValue::from_static(imageCacheControl(path)));
+    response
+}
+
+/// The response of an image file that the reverse proxy sends, which
+/// has `header` to tell it the file instead of a body.
+pub fn delegatedImage(header: &'static str, target: HeaderValue, path: &Path) ->
+    Response
+{
+    let mut response = generatedImage(Vec::new(), path);
+    response.headers_mut().insert(header, target);
+    response
+}
+
+/// Format `time` as an HTTP date, which is RFC 2822 in GMT.
+pub fn httpDate(time: OffsetDateTime) -> String
+{
+    time.to_offset(time::UtcOffset::UTC)
+        .format(&time::format_description::well_known::Rfc2822)
+        .map(|t| t.replace("+0000", "GMT")).unwrap_or_default()
+}
+
+fn etag(html: &str) -> String
+{
+    let digest = format!("{:x}", Sha256::digest(html.as_bytes()));
+    format!("\"{}\"", &digest[..32])
+}
+
+/// Whether the If-None-Match header `if_none_match` includes `etag`.
+fn etagMatches(if_none_match: &str, etag: &str) -> bool
+{
+    if_none_match.split(',').map(|t| t.trim())
+        .any(|t| t == "*" || t == etag || t.strip_prefix("W/") == Some(etag))
+}
+
+/// The response of an HTML page, or a 304 if the client already has
+/// it. `last_modified` is the time of the newest content on the page,
+/// if there is one.
+pub fn page(html: String, if_none_match: &Option<String>,
+            last_modified: Option<OffsetDateTime>) -> Response
+{
+    let etag = etag(&html);
+    let mut response = if if_none_match.as_ref().map(
+        |h| etagMatches(h, &etag)).unwrap_or(false)
+    {
+        StatusCode::NOT_MODIFIED.into_response()
+    }
+    else
+    {
+        warp::reply::html(html).into_response()
+    };
+    let headers = response.headers_mut();
+    if let Ok(value) = HeaderValue::from_str(&etag)
+    {
+        headers.insert(header::ETAG, value);
+    }
+    if let Some(value) = last_modified.and_then(
+        |t| HeaderValue::from_str(&httpDate(t)).ok())
+    {
+        headers.insert(header::LAST_MODIFIED, value);
+    }
+    headers.insert(header::CACHE_CONTROL,
+                   HeaderValue::from_static(PAGE_CACHE_CONTROL));
+    // The page depends on the session and the language.
+    headers.insert(header::VARY, HeaderValue::from_static(
+        "Cookie, Accept-Language"));
+    response
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn pageIsNotModified()
+    {
+        let html = String::from("<p>hi</p>");
+        let response = page(html.clone(), &None,
+                            OffsetDateTime::from_unix_timestamp(784111777).ok());
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::LAST_MODIFIED],
+                   "Sun, 06 Nov 1994 08:49:37 GMT");
+        let tag = response.headers()[header::ETAG].to_str().unwrap().to_owned();
+
+        let response = page(html.clone(), &Some(format!("\"x\", W/{}", tag)),
+                            None);
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        let response = page(String::from("<p>changed</p>"), &Some(tag), None);
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn onlyFullImagesAreImmutable()
+    {
+        assert!(isImmutableImage(Path::new("ab/cd/abcdef.jpg")));
+        assert!(!isImmutableImage(Path::new("ab/cd/abcdef_t.jpg")));
+        assert!(!isImmutableImage(Path::new("temp-123.jpg")));
+    }
+}
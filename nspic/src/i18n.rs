@@ -0,0 +1,340 @@
+// UI strings in multiple languages. The language of a request is
+// picked from a cookie set by /lang/<code>, or else the `locale` in
+// the config, or else negotiated from the Accept-Language header.
+// Templates get the strings of that language as `strings`, and can
+// also look a string up with `t(key="...", lang=lang)`.
+//
+// Catalogs in `data_dir/locales`, e.g. `fr.toml`, add languages, or
+// change strings of the built-in ones.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::Serialize;
+use warp::Filter;
+use warp::Reply;
+use warp::http::status::StatusCode;
+use warp::reply::Response;
+
+use crate::error::Error;
+use crate::config::Configuration;
+use crate::utils::uriFromStr;
+use crate::app::{pathPrefix, urlFor};
+
+pub static LANG_COOKIE: &str = "nspic-lang";
+const LANG_COOKIE_LIFE_TIME_SEC: u64 = 365 * 24 * 3600;
+/// Strings missing in a catalog are taken from this one.
+const FALLBACK_LOCALE: &str = "en";
+
+/// The built-in catalogs by language code.
+const CATALOG_SOURCES: &[(&str, &str)] = &[
+    ("en", include_str!("../locales/en.toml")),
+    ("zh", include_str!("../locales/zh.toml")),
+];
+
+#[derive(Serialize, Clone)]
+pub struct Language
+{
+    pub code: String,
+    /// The name of the language in itself.
+    pub name: String,
+}
+
+pub struct Catalog
+{
+    pub code: String,
+    strings: HashMap<String, String>,
+    /// All available languages, for the language switcher.
+    languages: Vec<Language>,
+}
+
+impl Catalog
+{
+    /// Put the strings of this catalog into a template context.
+    pub fn fillContext(&self, context: &mut tera::Context)
+    {
+        context.insert("lang", &self.code);
+        context.insert("strings", &self.strings);
+        context.insert("languages", &self.languages);
+    }
+
+    /// The string of `key`, or the key itself if there is none.
+    pub fn get<'a>(&'a self, key: &'a str) -> &'a str
+    {
+        self.strings.get(key).map(|s| s.as_str()).unwrap_or(key)
+    }
+}
+
+pub struct Catalogs
+{
+    catalogs: HashMap<String, Arc<Catalog>>,
+    default: String,
+    /// Used instead of the Accept-Language header.
+    forced: Option<String>,
+}
+
+type Strings = HashMap<String, String>;
+
+/// Catalogs in `dir` by language code. The code is the file name
+/// without `.toml`.
+fn loadCatalogDir(dir: &Path) -> Result<Vec<(String, Strings)>, Error>
+{
+    let mut result = Vec::new();
+    let entries = std::fs::read_dir(dir).map_err(
+        |e| rterr!("Failed to read locale dir {:?}: {}", dir, e))?;
+    for entry in entries
+    {
+        let path = entry.map_err(|e| rterr!("Failed to read locale dir: {}", e))?
+            .path();
+        if path.extension().and_then(|e| e.to_str()) != Some("toml")
+        {
+            continue;
+        }
+        let code = path.file_stem().and_then(|s| s.to_str())
+            .ok_or_else(|| rterr!("Invalid catalog file name: {:?}", path))?
+            .to_owned();
+        let source = std::fs::read_to_string(&path).map_err(
+            |e| rterr!("Failed to read {:?}: {}", path, e))?;
+        result.push((code.clone(), toml::from_str(&source).map_err(
+            |e| rterr!("Invalid catalog of locale {}: {}", code, e))?));
+    }
+    Ok(result)
+}
+
+impl Catalogs
+{
+    /// Load the built-in catalogs and the ones in `data_dir/locales`,
+    /// with the locales in the config.
+    pub fn fromConfig(config: &Configuration) -> Result<Self, Error>
+    {
+        let dir = Path::new(&config.data_dir).join("locales");
+        let extra = if dir.is_dir()
+        {
+            loadCatalogDir(&dir)?
+        }
+        else
+        {
+            Vec::new()
+        };
+        Self::build(extra, &config.default_locale, config.locale.as_deref())
+    }
+
+    /// The built-in catalogs with the ones in `extra` merged in.
+    /// Requests that match no language get `default_locale`.
+    fn build(extra: Vec<(String, Strings)>, default_locale: &str,
+             forced: Option<&str>) -> Result<Self, Error>
+    {
+        let mut sources: HashMap<String, Strings> = HashMap::new();
+        for (code, source) in CATALOG_SOURCES
+        {
+            sources.insert(code.to_string(), toml::from_str(source).map_err(
+                |e| rterr!("Invalid catalog of locale {}: {}", code, e))?);
+        }
+        for (code, strings) in extra
+        {
+            sources.entry(code).or_default().extend(strings);
+        }
+        let mut languages: Vec<Language> = sources.iter().map(
+            |(code, strings)| Language {
+                code: code.to_string(),
+                name: strings.get("language_name").cloned()
+                    .unwrap_or_else(|| code.to_string()),
+            }).collect();
+        languages.sort_by(|a, b| a.code.cmp(&b.code));
+        let fallback = sources[FALLBACK_LOCALE].clone();
+        let catalogs = sources.into_iter().map(|(code, mut strings)| {
+            for (key, value) in &fallback
+            {
+                strings.entry(key.clone()).or_insert_with(|| value.clone());
+            }
+            (code.to_owned(), Arc::new(Catalog {
+                code: code.to_owned(),
+                strings,
+                languages: languages.clone(),
+            }))
+        }).collect();
+        let result = Self {
+            catalogs,
+            default: default_locale.to_owned(),
+            forced: forced.map(|c| c.to_owned()),
+        };
+        if !result.has(default_locale)
+        {
+            return Err(rterr!("Unknown default locale: {}", default_locale));
+        }
+        if let Some(code) = forced.filter(|c| !result.has(c))
+        {
+            return Err(rterr!("Unknown locale: {}", code));
+        }
+        Ok(result)
+    }
+
+    pub fn has(&self, code: &str) -> bool
+    {
+        self.catalogs.contains_key(code)
+    }
+
+    /// The catalog for a request with the language cookie `cookie`
+    /// and the Accept-Language header `accept_language`.
+    pub fn select(&self, cookie: Option<&str>, accept_language: Option<&str>) ->
+        Arc<Catalog>
+    {
+        let code = cookie.filter(|c| self.has(c)).map(|c| c.to_owned())
+            .or_else(|| self.forced.clone())
+            .or_else(|| accept_language.and_then(
+                |h| negotiate(h, |c| self.has(c))))
+            .unwrap_or_else(|| self.default.clone());
+        self.catalogs[&code].clone()
+    }
+
+    /// The Tera function `t`, which takes the `key` of a string and
+    /// the `lang` of the page.
+    pub fn teraFunction(self: &Arc<Self>) -> impl tera::Function
+    {
+        let catalogs = self.clone();
+        move |args: &HashMap<String, tera::Value>| -> tera::Result<tera::Value> {
+            let key = args.get("key").and_then(|k| k.as_str())
+                .ok_or_else(|| tera::Error::msg("t() needs a key"))?;
+            let catalog = args.get("lang").and_then(|l| l.as_str())
+                .and_then(|l| catalogs.catalogs.get(l))
+                .unwrap_or_else(|| &catalogs.catalogs[&catalogs.default]);
+            Ok(tera::Value::String(catalog.get(key).to_owned()))
+        }
+    }
+}
+
+/// The first language in the Accept-Language header `header` that
+/// `available` accepts, by preference. A language with a region,
+/// e.g. zh-CN, also matches the language without one.
+fn negotiate(header: &str, available: impl Fn(&str) -> bool) -> Option<String>
+{
+    let mut ranges: Vec<(String, f32)> = header.split(',').filter_map(|item| {
+        let mut parts = item.split(';');
+        let tag = parts.next()?.trim().to_lowercase();
+        if tag.is_empty()
+        {
+            return None;
+        }
+        let q = parts.find_map(|p| p.trim().strip_prefix("q=")
+                               .and_then(|q| q.parse().ok()))
+            .unwrap_or(1.0);
+        Some((tag, q))
+    }).filter(|(_, q)| *q > 0.0).collect();
+    // The sort is stable, so the order of the header breaks ties.
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+    for (tag, _) in ranges
+    {
+        if available(&tag)
+        {
+            return Some(tag);
+        }
+        if let Some((primary, _)) = tag.split_once('-')
+        {
+            if available(primary)
+            {
+                return Some(primary.to_owned());
+            }
+        }
+    }
+    None
+}
+
+/// A filter that extracts the catalog of the request.
+pub fn locale(catalogs: Arc<Catalogs>) ->
+    impl Filter<Extract = (Arc<Catalog>,), Error = warp::Rejection> + Clone
+{
+    warp::filters::cookie::optional(LANG_COOKIE)
+        .and(warp::header::optional::<String>("accept-language"))
+        .map(move |cookie: Option<String>, accept_language: Option<String>| {
+            catalogs.select(cookie.as_deref(), accept_language.as_deref())
+        })
+}
+
+/// Remember the language `code` in a cookie, and go back to the page
+/// in `referer`.
+pub fn handleSetLanguage(code: &str, referer: Option<String>,
+                         catalogs: &Catalogs, config: &Configuration) ->
+    Result<Response, Error>
+{
+    if !catalogs.has(code)
+    {
+        return Err(Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()));
+    }
+    // Only the path of the referer is used, so that this can’t
+    // redirect to another site.
+    let back = referer.and_then(|r| r.parse::<warp::http::Uri>().ok())
+        .and_then(|uri| uri.path_and_query().map(|p| p.as_str().to_owned()))
+        .unwrap_or_else(
+            || pathPrefix(&config.serve_under_path) + &urlFor("index", ""));
+    let cookie = format!("{}={}; Max-Age={}; Path=/; SameSite=Lax",
+                         LANG_COOKIE, code, LANG_COOKIE_LIFE_TIME_SEC);
+    Ok(warp::reply::with_header(
+        warp::redirect::see_other(uriFromStr(&back)?),
+        "Set-Cookie", cookie).into_response())
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use tera::Tera;
+
+    #[test]
+    fn languageIsNegotiated()
+    {
+        let available = |c: &str| c == "en" || c == "zh";
+        assert_eq!(negotiate("zh-CN,zh;q=0.9,en;q=0.8", available),
+                   Some(String::from("zh")));
+        assert_eq!(negotiate("de;q=0.9, en;q=0.5, zh;q=0.7", available),
+                   Some(String::from("zh")));
+        assert_eq!(negotiate("fr, de", available), None);
+        assert_eq!(negotiate("zh;q=0, en", available), Some(String::from("en")));
+        assert_eq!(negotiate("", available), None);
+    }
+
+    #[test]
+    fn catalogIsSelected() -> Result<(), Error>
+    {
+        let catalogs = Catalogs::build(Vec::new(), "en", None)?;
+        assert_eq!(catalogs.select(None, None).code, "en");
+        assert_eq!(catalogs.select(None, Some("zh-TW")).code, "zh");
+        assert_eq!(catalogs.select(Some("en"), Some("zh")).code, "en");
+        assert_eq!(catalogs.select(Some("xx"), Some("zh")).code, "zh");
+        assert!(Catalogs::build(Vec::new(), "xx", None).is_err());
+        // Every catalog has all the strings of the fallback.
+        let en = catalogs.select(Some("en"), None);
+        let zh = catalogs.select(Some("zh"), None);
+        assert!(en.strings.keys().all(|k| zh.strings.contains_key(k)));
+        Ok(())
+    }
+
+    #[test]
+    fn extraCatalogsAreMerged() -> Result<(), Error>
+    {
+        let extra = vec![
+            (String::from("fr"), Strings::from([
+                (String::from("nav_admin"), String::from("Administration"))])),
+            (String::from("en"), Strings::from([
+                (String::from("nav_new"), String::from("Upload"))])),
+        ];
+        let catalogs = Arc::new(Catalogs::build(extra, "en", Some("fr"))?);
+        let fr = catalogs.select(None, Some("zh"));
+        assert_eq!(fr.code, "fr");
+        assert_eq!(fr.get("nav_admin"), "Administration");
+        assert_eq!(fr.get("nav_shuffle"), "Shuffle");
+        assert_eq!(catalogs.select(Some("en"), None).get("nav_new"), "Upload");
+        assert!(Catalogs::build(Vec::new(), "en", Some("xx")).is_err());
+
+        let mut tera = Tera::default();
+        tera.register_function("t", catalogs.teraFunction());
+        tera.add_raw_template("t", "{{ t(key='nav_admin', lang='fr') }} \
+                                    {{ t(key='nav_admin', lang='xx') }}")
+            .map_err(|e| rterr!("{}", e))?;
+        assert_eq!(tera.render("t", &tera::Context::new())
+                   .map_err(|e| rterr!("{}", e))?, "Administration Admin");
+        Ok(())
+    }
+}
@@ -0,0 +1,304 @@
+// Serving the files in the image dir under /image/. Only plain paths
+// of files in the image dir are served, also after resolving symlinks.
+// A Range request gets the part of the file that it asks for, which
+// lets browsers seek in large files. The Content-Type comes from the
+// extension of the file, which the pipeline names after the encoding.
+// With a `[watermark]`, an image with a watermarked copy is served as
+// that copy, so that the image in the library is never served.
+// Files that are not in the image dir are passed on to the routes that
+// make them, like missing thumbnails.
+
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+
+use time::OffsetDateTime;
+use warp::Filter;
+use warp::http::HeaderValue;
+use warp::http::header;
+use warp::http::status::StatusCode;
+use warp::hyper::Body;
+use warp::reply::Response;
+
+use crate::config::Configuration;
+use crate::http_cache;
+use crate::post::{Image, mimeTypeFromPath};
+use crate::sendfile;
+
+/// Files are sent in chunks of this size.
+const CHUNK_BYTES: u64 = 64 * 1024;
+
+/// The path of the image file at `tail` of /image/, relative to the
+/// image dir. Return None if there is no such file, or `tail` is not
+/// a plain path in the image dir.
+pub fn imagePath(tail: &str, config: &Configuration) -> Option<PathBuf>
+{
+    if tail.is_empty() || tail.contains(['%', '\\']) ||
+        tail.split('/').any(|segment| segment.starts_with('.'))
+    {
+        return None;
+    }
+    let path = PathBuf::from(tail);
+    if !path.components().all(|c| matches!(c, Component::Normal(_)))
+    {
+        return None;
+    }
+    let image_dir = std::fs::canonicalize(&config.image_dir).ok()?;
+    let file = std::fs::canonicalize(image_dir.join(&path)).ok()?;
+    if file.starts_with(&image_dir) && file.is_file()
+    {
+        Some(path)
+    }
+    else
+    {
+        None
+    }
+}
+
+/// The file to serve for the image at `path` in the image dir, which
+/// is its watermarked copy if there should be one.
+pub(crate) fn servedPath(path: PathBuf, config: &Configuration) -> PathBuf
+{
+    if config.watermark.is_none()
+    {
+        return path;
+    }
+    Image { path: path.clone(), ..Default::default() }.watermarked().ok()
+        .and_then(|file| imagePath(file.to_str()?, config))
+        .unwrap_or(path)
+}
+
+#[derive(PartialEq, Debug)]
+enum ByteRange
+{
+    Whole,
+    /// The first and the last byte.
+    Part(u64, u64),
+    Unsatisfiable,
+}
+
+/// The range in the Range header `value` of a file of `len` bytes.
+/// Invalid headers, and ones with more than one range, get the whole
+/// file, which is allowed.
+fn parseRange(value: &str, len: u64) -> ByteRange
+{
+    let spec = match value.trim().strip_prefix("bytes=")
+    {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Whole,
+    };
+    let (first, last) = match spec.split_once('-')
+    {
+        Some(bounds) => bounds,
+        None => return ByteRange::Whole,
+    };
+    let range = match (first.parse::<u64>(), last.parse::<u64>())
+    {
+        (Ok(first), Ok(last)) if first <= last =>
+            Some((first, last.min(len.saturating_sub(1)))),
+        (Ok(first), Err(_)) if last.is_empty() =>
+            Some((first, len.saturating_sub(1))),
+        // The last bytes of the file.
+        (Err(_), Ok(suffix)) if first.is_empty() => {
+            if suffix == 0
+            {
+                return ByteRange::Unsatisfiable;
+            }
+            Some((len.saturating_sub(suffix), len.saturating_sub(1)))
+        },
+        _ => None,
+    };
+    match range
+    {
+        Some((first, last)) if first < len => ByteRange::Part(first, last),
+        Some(_) => ByteRange::Unsatisfiable,
+        None => ByteRange::Whole,
+    }
+}
+
+/// The body of `len` bytes of `file` from `start`, read in chunks.
+fn fileBody(file: std::fs::File, start: u64, len: u64) -> Body
+{
+    let chunks = futures_util::stream::try_unfold(
+        (file, start, len), |(mut file, offset, remaining)| async move {
+            if remaining == 0
+            {
+                return Ok(None);
+            }
+            let chunk_len = remaining.min(CHUNK_BYTES);
+            let (file, chunk) = tokio::task::spawn_blocking(
+                move || -> std::io::Result<(std::fs::File, Vec<u8>)> {
+                    file.seek(SeekFrom::Start(offset))?;
+                    let mut chunk = vec![0; chunk_len as usize];
+                    file.read_exact(&mut chunk)?;
+                    Ok((file, chunk))
+                }).await.map_err(std::io::Error::other)??;
+            Ok::<_, std::io::Error>(Some((
+                bytes::Bytes::from(chunk),
+                (file, offset + chunk_len, remaining - chunk_len))))
+        });
+    Body::wrap_stream(chunks)
+}
+
+/// The response of the image at `path` in the image dir.
+fn imageResponse(path: &Path, range: Option<String>, if_range: Option<String>,
+                 if_modified_since: Option<String>, config: &Configuration) ->
+    std::io::Result<Response>
+{
+    let file = std::fs::File::open(Path::new(&config.image_dir).join(path))?;
+    let metadata = file.metadata()?;
+    let len = metadata.len();
+    let last_modified = metadata.modified().ok()
+        .map(|t| http_cache::httpDate(OffsetDateTime::from(t)));
+
+    let mut response = Response::new(Body::empty());
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_TYPE,
+                   HeaderValue::from_static(mimeTypeFromPath(path)));
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static(
+        http_cache::imageCacheControl(path)));
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    if let Some(value) = last_modified.as_deref()
+        .and_then(|t| HeaderValue::from_str(t).ok())
+    {
+        headers.insert(header::LAST_MODIFIED, value);
+    }
+    if last_modified.is_some() && if_modified_since == last_modified
+    {
+        *response.status_mut() = StatusCode::NOT_MODIFIED;
+        return Ok(response);
+    }
+
+    // A range of a file that has changed since the client got the
+    // other parts would mix two files.
+    let range = match range
+    {
+        Some(range) if if_range.is_none() || if_range == last_modified =>
+            parseRange(&range, len),
+        _ => ByteRange::Whole,
+    };
+    let (start, part_len) = match range
+    {
+        ByteRange::Whole => (0, len),
+        ByteRange::Part(first, last) => {
+            *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+            response.headers_mut().insert(header::CONTENT_RANGE, HeaderValue::from_str(
+                &format!("bytes {}-{}/{}", first, last, len)).unwrap());
+            (first, last - first + 1)
+        },
+        ByteRange::Unsatisfiable => {
+            *response.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+            response.headers_mut().insert(header::CONTENT_RANGE, HeaderValue::from_str(
+                &format!("bytes */{}", len)).unwrap());
+            return Ok(response);
+        },
+    };
+    response.headers_mut().insert(header::CONTENT_LENGTH, HeaderValue::from(part_len));
+    *response.body_mut() = fileBody(file, start, part_len);
+    Ok(response)
+}
+
+/// A filter of the files in the image dir, which are sent by the
+/// reverse proxy if `[sendfile]` is configured.
+pub fn imageFiles(config: Configuration) ->
+    impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone
+{
+    warp::get().and(warp::path("image")).and(warp::path::tail())
+        .and(warp::header::optional::<String>("range"))
+        .and(warp::header::optional::<String>("if-range"))
+        .and(warp::header::optional::<String>("if-modified-since"))
+        .and_then(move |tail: warp::path::Tail, range: Option<String>,
+                  if_range: Option<String>, if_modified_since: Option<String>| {
+            let config = config.clone();
+            async move {
+                let path = imagePath(tail.as_str(), &config)
+                    .map(|path| servedPath(path, &config))
+                    .ok_or_else(warp::reject::not_found)?;
+                if let Some(response) = sendfile::response(&path, &config)
+                {
+                    return Ok(response);
+                }
+                imageResponse(&path, range, if_range, if_modified_since, &config)
+                    .map_err(|_| warp::reject::not_found())
+            }
+        })
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn onlyFilesInImageDirAreServed()
+    {
+        let image_dir = std::env::temp_dir().join(
+            "nspic-test-".to_owned() + &rand::random::<u64>().to_string());
+        std::fs::create_dir_all(image_dir.join("ab")).unwrap();
+        std::fs::write(image_dir.join("ab/abcd.jpg"), b"image").unwrap();
+        let outside = image_dir.with_extension("outside");
+        std::fs::write(&outside, b"secret").unwrap();
+        std::os::unix::fs::symlink(&outside, image_dir.join("ab/link.jpg")).unwrap();
+        let config = Configuration {
+            image_dir: image_dir.to_str().unwrap().to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(imagePath("ab/abcd.jpg", &config),
+                   Some(PathBuf::from("ab/abcd.jpg")));
+        for tail in ["ab/none.jpg", "ab", "../ab/abcd.jpg", "ab/./abcd.jpg",
+                     "/etc/passwd", "ab%2Fabcd.jpg", "ab/link.jpg", ""]
+        {
+            assert_eq!(imagePath(tail, &config), None, "{}", tail);
+        }
+        std::fs::remove_dir_all(&image_dir).unwrap();
+        std::fs::remove_file(&outside).unwrap();
+    }
+
+    #[test]
+    fn watermarkedCopiesAreServed()
+    {
+        let image_dir = std::env::temp_dir().join(
+            "nspic-test-".to_owned() + &rand::random::<u64>().to_string());
+        std::fs::create_dir_all(image_dir.join("ab")).unwrap();
+        for name in ["abcd.jpg", "abcd_w.jpg", "abcd_t.jpg", "abcd.webp"]
+        {
+            std::fs::write(image_dir.join("ab").join(name), b"image").unwrap();
+        }
+        let mut config = Configuration {
+            image_dir: image_dir.to_str().unwrap().to_owned(),
+            ..Default::default()
+        };
+        let served = |tail: &str, config: &Configuration|
+            servedPath(PathBuf::from(tail), config);
+        assert_eq!(served("ab/abcd.jpg", &config), PathBuf::from("ab/abcd.jpg"));
+        config.watermark = Some(crate::config::WatermarkConfig {
+            image: None,
+            text: Some(String::from("NSPic")),
+            position: crate::config::WatermarkPosition::BottomRight,
+            opacity: 0.5,
+            width_percent: 20,
+            color: String::from("#ffffff"),
+        });
+        assert_eq!(served("ab/abcd.jpg", &config), PathBuf::from("ab/abcd_w.jpg"));
+        assert_eq!(served("ab/abcd_t.jpg", &config), PathBuf::from("ab/abcd_t.jpg"));
+        assert_eq!(served("ab/abcd.webp", &config), PathBuf::from("ab/abcd.webp"));
+        std::fs::remove_dir_all(&image_dir).unwrap();
+    }
+
+    #[test]
+    fn rangesAreParsed()
+    {
+        assert_eq!(parseRange("bytes=0-99", 1000), ByteRange::Part(0, 99));
+        assert_eq!(parseRange("bytes=900-", 1000), ByteRange::Part(900, 999));
+        assert_eq!(parseRange("bytes=-100", 1000), ByteRange::Part(900, 999));
+        assert_eq!(parseRange("bytes=-2000", 1000), ByteRange::Part(0, 999));
+        assert_eq!(parseRange("bytes=500-2000", 1000), ByteRange::Part(500, 999));
+        assert_eq!(parseRange("bytes=1000-", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parseRange("bytes=-0", 1000), ByteRange::Unsatisfiable);
+        for value in ["bytes=0-1,5-6", "items=0-1", "bytes=5-1", "bytes=x-"]
+        {
+            assert_eq!(parseRange(value, 1000), ByteRange::Whole, "{}", value);
+        }
+    }
+}
@@ -0,0 +1,136 @@
+// The layout of the index: a list of posts in one column, or a grid
+// of them with some posts per row. A visitor picks one with
+// /layout/<layout>, which remembers it in a cookie, and a `layout`
+// query parameter overrides the cookie for one page. Layouts are
+// written as `list` or `grid-<columns>`, e.g. `grid-4`.
+
+use serde::Serialize;
+use warp::Filter;
+use warp::Reply;
+use warp::http::status::StatusCode;
+use warp::reply::Response;
+
+use crate::error::Error;
+use crate::config::Configuration;
+use crate::utils::uriFromStr;
+use crate::app::{pathPrefix, urlFor};
+
+pub static LAYOUT_COOKIE: &str = "nspic-layout";
+const LAYOUT_COOKIE_LIFE_TIME_SEC: u64 = 365 * 24 * 3600;
+const GRID_COLUMNS_DEFAULT: u32 = 3;
+/// `static/style.css` has a `Columns<n>` class for each count up to
+/// this.
+const GRID_COLUMNS_MAX: u32 = 8;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LayoutMode
+{
+    List,
+    Grid,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+pub struct Layout
+{
+    pub mode: LayoutMode,
+    /// Posts per row of the grid. This is 1 for the list.
+    pub columns: u32,
+}
+
+impl Default for Layout
+{
+    fn default() -> Self
+    {
+        Self { mode: LayoutMode::List, columns: 1 }
+    }
+}
+
+impl Layout
+{
+    /// Parse a layout like `list`, `grid`, or `grid-4`.
+    pub fn parse(value: &str) -> Option<Self>
+    {
+        match value.split_once('-')
+        {
+            None if value == "list" => Some(Self::default()),
+            None if value == "grid" => Some(Self {
+                mode: LayoutMode::Grid,
+                columns: GRID_COLUMNS_DEFAULT,
+            }),
+            Some(("grid", columns)) => {
+                let columns: u32 = columns.parse().ok()?;
+                if columns == 0 || columns > GRID_COLUMNS_MAX
+                {
+                    return None;
+                }
+                Some(Self { mode: LayoutMode::Grid, columns })
+            },
+            _ => None,
+        }
+    }
+
+    /// The layout in the form that `parse()` reads.
+    pub fn value(&self) -> String
+    {
+        match self.mode
+        {
+            LayoutMode::List => String::from("list"),
+            LayoutMode::Grid => format!("grid-{}", self.columns),
+        }
+    }
+}
+
+/// A filter that extracts the layout of the request from the cookie.
+/// Invalid values are ignored.
+pub fn layout() ->
+    impl Filter<Extract = (Layout,), Error = std::convert::Infallible> + Clone
+{
+    warp::filters::cookie::optional(LAYOUT_COOKIE).map(
+        |cookie: Option<String>| cookie.as_deref().and_then(Layout::parse)
+            .unwrap_or_default())
+}
+
+/// Remember `value` as the layout in a cookie, and go back to the page
+/// in `referer`.
+pub fn handleSetLayout(value: &str, referer: Option<String>,
+                       config: &Configuration) -> Result<Response, Error>
+{
+    let layout = Layout::parse(value).ok_or_else(
+        || Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()))?;
+    // Only the path of the referer is used, so that this can’t
+    // redirect to another site.
+    let back = referer.and_then(|r| r.parse::<warp::http::Uri>().ok())
+        .and_then(|uri| uri.path_and_query().map(|p| p.as_str().to_owned()))
+        .unwrap_or_else(
+            || pathPrefix(&config.serve_under_path) + &urlFor("index", ""));
+    let cookie = format!("{}={}; Max-Age={}; Path=/; SameSite=Lax",
+                         LAYOUT_COOKIE, layout.value(),
+                         LAYOUT_COOKIE_LIFE_TIME_SEC);
+    Ok(warp::reply::with_header(
+        warp::redirect::see_other(uriFromStr(&back)?),
+        "Set-Cookie", cookie).into_response())
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn layoutsAreParsed()
+    {
+        assert_eq!(Layout::parse("list"), Some(Layout::default()));
+        assert_eq!(Layout::parse("grid").unwrap().columns, GRID_COLUMNS_DEFAULT);
+        let grid = Layout::parse("grid-4").unwrap();
+        assert_eq!(grid.mode, LayoutMode::Grid);
+        assert_eq!(grid.columns, 4);
+        assert_eq!(grid.value(), "grid-4");
+        for value in ["grid-0", "grid-9", "grid-x", "list-2", "tiles"]
+        {
+            assert_eq!(Layout::parse(value), None, "{}", value);
+        }
+    }
+}
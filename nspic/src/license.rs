@@ -0,0 +1,157 @@
+// Licenses of posts. A license is a string, which is either one of the
+// Creative Commons identifiers below, “All rights reserved”, or any
+// other text for a custom license. A post without a license has the
+// `default_license` of the config. The license is shown on the post
+// page and in the feed, and is embedded as XMP in the full-size images
+// that the pipeline encodes.
+
+use serde::Serialize;
+
+use crate::config::Configuration;
+use crate::feed::xmlEscape;
+use crate::post::Post;
+
+/// The known licenses, by identifier, with their names and URLs.
+const KNOWN: [(&str, &str, &str); 7] = [
+    ("CC-BY-4.0", "CC BY 4.0", "https://creativecommons.org/licenses/by/4.0/"),
+    ("CC-BY-SA-4.0", "CC BY-SA 4.0",
+     "https://creativecommons.org/licenses/by-sa/4.0/"),
+    ("CC-BY-NC-4.0", "CC BY-NC 4.0",
+     "https://creativecommons.org/licenses/by-nc/4.0/"),
+    ("CC-BY-NC-SA-4.0", "CC BY-NC-SA 4.0",
+     "https://creativecommons.org/licenses/by-nc-sa/4.0/"),
+    ("CC-BY-ND-4.0", "CC BY-ND 4.0",
+     "https://creativecommons.org/licenses/by-nd/4.0/"),
+    ("CC-BY-NC-ND-4.0", "CC BY-NC-ND 4.0",
+     "https://creativecommons.org/licenses/by-nc-nd/4.0/"),
+    ("CC0-1.0", "CC0 1.0", "https://creativecommons.org/publicdomain/zero/1.0/"),
+];
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct License
+{
+    pub name: String,
+    /// The deed of a known license.
+    pub url: Option<String>,
+    /// Whether the work is in the public domain.
+    pub public_domain: bool,
+}
+
+impl License
+{
+    pub fn fromStr(value: &str) -> Self
+    {
+        let value = value.trim();
+        match KNOWN.iter().find(|(id, _, _)| id.eq_ignore_ascii_case(value))
+        {
+            Some((id, name, url)) => Self {
+                name: name.to_string(),
+                url: Some(url.to_string()),
+                public_domain: id.starts_with("CC0"),
+            },
+            None => Self {
+                name: value.to_owned(),
+                url: None,
+                public_domain: false,
+            },
+        }
+    }
+
+    /// An XMP packet with the rights of the license, for embedding in
+    /// an image.
+    pub fn xmpPacket(&self) -> String
+    {
+        let url = self.url.as_deref().map(xmlEscape);
+        let statement = url.as_ref().map(|u| format!(
+            "\n   xmpRights:WebStatement=\"{0}\"\n   cc:license=\"{0}\"", u))
+            .unwrap_or_default();
+        format!(r#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+   xmlns:dc="http://purl.org/dc/elements/1.1/"
+   xmlns:xmpRights="http://ns.adobe.com/xap/1.0/rights/"
+   xmlns:cc="http://creativecommons.org/ns#"
+   xmpRights:Marked="{}"{}>
+   <dc:rights><rdf:Alt><rdf:li xml:lang="x-default">{}</rdf:li></rdf:Alt></dc:rights>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>
+"#, if self.public_domain { "False" } else { "True" }, statement,
+                xmlEscape(&self.name))
+    }
+}
+
+/// The `license` of a post, or the default of the config if it is
+/// None.
+pub fn effectiveLicense(license: Option<&str>, config: &Configuration) ->
+    Option<License>
+{
+    license.or(config.default_license.as_deref())
+        .filter(|l| !l.trim().is_empty()).map(License::fromStr)
+}
+
+/// The license of `post`, if it or the config has one.
+pub fn postLicense(post: &Post, config: &Configuration) -> Option<License>
+{
+    effectiveLicense(post.license.as_deref(), config)
+}
+
+/// The license from the upload form, which is None for the default.
+pub fn licenseFromForm(value: &str) -> Option<String>
+{
+    Some(value.trim()).filter(|l| !l.is_empty()).map(|l| {
+        match KNOWN.iter().find(|(id, _, _)| id.eq_ignore_ascii_case(l))
+        {
+            Some((id, _, _)) => id.to_string(),
+            None => l.to_owned(),
+        }
+    })
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn licensesAreKnownOrCustom()
+    {
+        let license = License::fromStr("cc-by-4.0");
+        assert_eq!(license.name, "CC BY 4.0");
+        assert_eq!(license.url.as_deref(),
+                   Some("https://creativecommons.org/licenses/by/4.0/"));
+        assert!(License::fromStr("CC0-1.0").public_domain);
+        assert_eq!(License::fromStr(" Ask me first "), License {
+            name: String::from("Ask me first"),
+            url: None,
+            public_domain: false,
+        });
+        assert_eq!(licenseFromForm("cc0-1.0").as_deref(), Some("CC0-1.0"));
+        assert_eq!(licenseFromForm(" "), None);
+
+        let mut config = Configuration::default();
+        let mut post = Post::new();
+        assert_eq!(postLicense(&post, &config), None);
+        config.default_license = Some(String::from("All rights reserved"));
+        assert_eq!(postLicense(&post, &config).unwrap().name, "All rights reserved");
+        post.license = Some(String::from("CC-BY-SA-4.0"));
+        assert_eq!(postLicense(&post, &config).unwrap().name, "CC BY-SA 4.0");
+    }
+
+    #[test]
+    fn xmpHasRights()
+    {
+        let xmp = License::fromStr("CC-BY-4.0").xmpPacket();
+        assert!(xmp.contains(r#"xmpRights:Marked="True""#));
+        assert!(xmp.contains(
+            r#"cc:license="https://creativecommons.org/licenses/by/4.0/""#));
+        assert!(xmp.contains(r#"<rdf:li xml:lang="x-default">CC BY 4.0</rdf:li>"#));
+        let custom = License::fromStr("Me & you").xmpPacket();
+        assert!(custom.contains("Me &amp; you"));
+        assert!(!custom.contains("cc:license"));
+    }
+}
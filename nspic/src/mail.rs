@@ -0,0 +1,419 @@
+// A minimal SMTP server for mail-in posting. This is not meant to
+// face the internet. Put it behind a real MTA, and let the MTA relay
+// the emails for the posting address to it, over SMTP or LMTP.
+
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+use log::{info, warn};
+use log::error as log_error;
+use mailparse::MailHeaderMap;
+
+use crate::error::Error;
+use crate::config::{Configuration, MailInConfig};
+use crate::data;
+use crate::post::Visibility;
+use crate::post_pipeline::RawImage;
+use crate::app::createPost;
+
+struct Attachment
+{
+    filename: String,
+    data: Vec<u8>,
+}
+
+/// An email that is about to become a post.
+struct MailPost
+{
+    /// Address in the From header.
+    sender: String,
+    subject: String,
+    /// The plain text body, without the signature.
+    body: String,
+    attachments: Vec<Attachment>,
+}
+
+/// Extract the address from a `MAIL FROM:<address>` or `RCPT
+/// TO:<address>` argument.
+fn envelopeAddress(arg: &str) -> Option<String>
+{
+    let begin = arg.find('<')?;
+    let end = arg[begin..].find('>')? + begin;
+    Some(arg[begin+1..end].trim().to_lowercase())
+}
+
+fn isAllowed(address: &str, mail_config: &MailInConfig) -> bool
+{
+    mail_config.allowed_senders.iter().any(
+        |a| a.to_lowercase() == address.to_lowercase())
+}
+
+fn collectImages(part: &mailparse::ParsedMail,
+                 result: &mut Vec<Attachment>) -> Result<(), Error>
+{
+    if part.ctype.mimetype.starts_with("image/")
+    {
+        let disposition = part.get_content_disposition();
+        let filename = disposition.params.get("filename")
+            .or_else(|| part.ctype.params.get("name")).cloned()
+            .unwrap_or_else(|| format!(
+                "attachment.{}", &part.ctype.mimetype["image/".len()..]));
+        let data = part.get_body_raw().map_err(
+            |e| rterr!("Failed to decode attachment {}: {}", filename, e))?;
+        result.push(Attachment { filename, data });
+    }
+    for sub in &part.subparts
+    {
+        collectImages(sub, result)?;
+    }
+    Ok(())
+}
+
+/// The first plain text part that is not an attachment.
+fn textBody(part: &mailparse::ParsedMail) -> Option<String>
+{
+    if part.get_content_disposition().disposition ==
+        mailparse::DispositionType::Attachment
+    {
+        return None;
+    }
+    if part.ctype.mimetype == "text/plain"
+    {
+        return part.get_body().ok();
+    }
+    part.subparts.iter().find_map(textBody)
+}
+
+/// Remove the signature, which starts at a “-- ” line, from a text
+/// body.
+fn stripSignature(body: &str) -> &str
+{
+    let mut offset = 0;
+    for line in body.split_inclusive('\n')
+    {
+        if line.trim_end_matches(['\r', '\n']) == "-- "
+        {
+            return &body[..offset];
+        }
+        offset += line.len();
+    }
+    body
+}
+
+/// The description of a post from an email. If `secret` is set, the
+/// subject has to contain it as a word, and it is removed. Return
+/// `None` if the secret is missing.
+fn description(subject: &str, body: &str, secret: Option<&str>) ->
+    Option<String>
+{
+    let subject = match secret
+    {
+        Some(secret) => {
+            let words: Vec<&str> = subject.split_whitespace().collect();
+            if !words.contains(&secret)
+            {
+                return None;
+            }
+            words.into_iter().filter(|w| w != &secret).collect::<Vec<_>>()
+                .join(" ")
+        },
+        None => subject.trim().to_owned(),
+    };
+    let body = stripSignature(body).replace("\r\n", "\n");
+    let body = body.trim();
+    Some(match (subject.is_empty(), body.is_empty())
+    {
+        (_, true) => subject,
+        (true, false) => body.to_owned(),
+        (false, false) => subject + "\n\n" + body,
+    })
+}
+
+fn parseMessage(raw: &[u8]) -> Result<MailPost, Error>
+{
+    let mail = mailparse::parse_mail(raw).map_err(
+        |e| rterr!("Failed to parse email: {}", e))?;
+    let from = mail.headers.get_first_header("From").ok_or_else(
+        || rterr!("No From header in email"))?;
+    let addrs = mailparse::addrparse_header(from).map_err(
+        |e| rterr!("Invalid From header: {}", e))?;
+    let sender = addrs.extract_single_info().ok_or_else(
+        || rterr!("Invalid From header"))?.addr;
+    let subject = mail.headers.get_first_value("Subject").unwrap_or_default();
+    let body = textBody(&mail).unwrap_or_default();
+    let mut attachments = Vec::new();
+    collectImages(&mail, &mut attachments)?;
+    Ok(MailPost { sender, subject, body, attachments })
+}
+
+fn postMail(raw: &[u8], mail_config: &MailInConfig,
+            data_manager: &data::Manager, config: &Configuration) ->
+    Result<i64, Error>
+{
+    let mail = parseMessage(raw)?;
+    if !isAllowed(&mail.sender, mail_config)
+    {
+        return Err(rterr!("Sender {} is not allowed to post", mail.sender));
+    }
+    let desc = description(&mail.subject, &mail.body,
+                           mail_config.secret.as_deref()).ok_or_else(
+        || rterr!("Email from {} doesn’t have the secret", mail.sender))?;
+    if mail.attachments.is_empty()
+    {
+        return Err(rterr!("No image attachment in email"));
+    }
+    let mut images = Vec::new();
+    for attachment in mail.attachments
+    {
+        let img = RawImage::fromBytes(&attachment.data, &attachment.filename,
+                                      config)?;
+        images.push(img.process(config)?);
+    }
+    let id = createPost(desc, None, Visibility::Public, false, None, None, images,
+                        data_manager, config)?;
+    info!("Created post {} from email by {}.", id, mail.sender);
+    Ok(id)
+}
+
+fn reply(stream: &mut TcpStream, line: &str) -> std::io::Result<()>
+{
+    stream.write_all(line.as_bytes())?;
+    stream.write_all(b"\r\n")
+}
+
+/// Read the content after a DATA command, until the terminating
+/// line. Return `None` if the message is larger than `size_max`. In
+/// that case the content is still consumed.
+fn readData<R: BufRead>(reader: &mut R, size_max: u64) ->
+    std::io::Result<Option<Vec<u8>>>
+{
+    let mut data: Vec<u8> = Vec::new();
+    let mut too_large = false;
+    loop
+    {
+        let mut line: Vec<u8> = Vec::new();
+        if reader.read_until(b'\n', &mut line)? == 0
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof, "Connection closed in DATA"));
+        }
+        if line == b".\r\n" || line == b".\n"
+        {
+            break;
+        }
+        if too_large
+        {
+            continue;
+        }
+        // Undo dot-stuffing.
+        let content = if line.starts_with(b"..") { &line[1..] } else { &line };
+        data.extend_from_slice(content);
+        if data.len() as u64 > size_max
+        {
+            too_large = true;
+            data.clear();
+        }
+    }
+    if too_large
+    {
+        Ok(None)
+    }
+    else
+    {
+        Ok(Some(data))
+    }
+}
+
+fn handleConnection(mut stream: TcpStream, mail_config: &MailInConfig,
+                    data_manager: &data::Manager, config: &Configuration) ->
+    std::io::Result<()>
+{
+    stream.set_read_timeout(Some(Duration::from_secs(300)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let lmtp = mail_config.lmtp;
+    reply(&mut stream, if lmtp { "220 nspic LMTP" } else { "220 nspic ESMTP" })?;
+    let mut sender: Option<String> = None;
+    // LMTP replies to the data once for each recipient.
+    let mut recipient_count = 0;
+    loop
+    {
+        let mut line: Vec<u8> = Vec::new();
+        if reader.read_until(b'\n', &mut line)? == 0
+        {
+            return Ok(());
+        }
+        let line = String::from_utf8_lossy(&line);
+        let command = line.trim_end();
+        let (verb, arg) = command.split_once(' ').unwrap_or((command, ""));
+        match verb.to_uppercase().as_str()
+        {
+            "HELO" | "EHLO" | "LHLO" => reply(&mut stream, "250 nspic")?,
+            "MAIL" => {
+                match envelopeAddress(arg)
+                {
+                    Some(addr) if isAllowed(&addr, mail_config) => {
+                        sender = Some(addr);
+                        recipient_count = 0;
+                        reply(&mut stream, "250 OK")?;
+                    },
+                    Some(addr) => {
+                        warn!("Rejected email from {}.", addr);
+                        reply(&mut stream, "550 Sender not allowed")?;
+                    },
+                    None => reply(&mut stream, "501 Invalid address")?,
+                }
+            },
+            "RCPT" => {
+                if sender.is_none()
+                {
+                    reply(&mut stream, "503 Need MAIL first")?;
+                }
+                else
+                {
+                    recipient_count += 1;
+                    reply(&mut stream, "250 OK")?;
+                }
+            },
+            "DATA" => {
+                if sender.is_none() || recipient_count == 0
+                {
+                    reply(&mut stream, "503 Need MAIL and RCPT first")?;
+                    continue;
+                }
+                reply(&mut stream, "354 End data with <CR><LF>.<CR><LF>")?;
+                let status = match readData(&mut reader, config.upload_bytes_max)?
+                {
+                    None => "552 Message too large",
+                    Some(data) => {
+                        match postMail(&data, mail_config, data_manager, config)
+                        {
+                            Ok(_) => "250 OK",
+                            Err(e) => {
+                                log_error!("Mail-in failed: {}", e);
+                                "554 Failed to create post"
+                            },
+                        }
+                    },
+                };
+                // The post is made once however many recipients there
+                // are.
+                let reply_count = if lmtp { recipient_count } else { 1 };
+                for _ in 0..reply_count
+                {
+                    reply(&mut stream, status)?;
+                }
+                sender = None;
+                recipient_count = 0;
+            },
+            "RSET" => {
+                sender = None;
+                recipient_count = 0;
+                reply(&mut stream, "250 OK")?;
+            },
+            "NOOP" => reply(&mut stream, "250 OK")?,
+            "QUIT" => {
+                reply(&mut stream, "221 Bye")?;
+                return Ok(());
+            },
+            _ => reply(&mut stream, "502 Command not implemented")?,
+        }
+    }
+}
+
+/// Start the SMTP server in the background.
+pub fn spawn(mail_config: MailInConfig, data_manager: data::Manager,
+             config: Configuration) -> Result<(), Error>
+{
+    let listener = TcpListener::bind(
+        (mail_config.listen_address.as_str(), mail_config.listen_port))
+        .map_err(|e| rterr!("Failed to listen for mail-in: {}", e))?;
+    info!("Accepting mail-in posts at {}:{}...", mail_config.listen_address,
+          mail_config.listen_port);
+    std::thread::spawn(move || {
+        for stream in listener.incoming()
+        {
+            let stream = match stream
+            {
+                Ok(s) => s,
+                Err(e) => {
+                    log_error!("Failed to accept mail-in connection: {}", e);
+                    continue;
+                },
+            };
+            let mail_config = mail_config.clone();
+            let data_manager = data_manager.clone();
+            let config = config.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = handleConnection(
+                    stream, &mail_config, &data_manager, &config)
+                {
+                    log_error!("Mail-in connection failed: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn parseEnvelopeAddress()
+    {
+        assert_eq!(envelopeAddress("FROM:<Me@Example.org>"),
+                   Some(String::from("me@example.org")));
+        assert_eq!(envelopeAddress("TO: <a@b> SIZE=100"),
+                   Some(String::from("a@b")));
+        assert_eq!(envelopeAddress("FROM:me@example.org"), None);
+    }
+
+    #[test]
+    fn parseMessageWithAttachment() -> Result<(), Error>
+    {
+        let raw = concat!(
+            "From: Me <me@example.org>\r\n",
+            "Subject: A cat\r\n",
+            "MIME-Version: 1.0\r\n",
+            "Content-Type: multipart/mixed; boundary=\"XX\"\r\n",
+            "\r\n",
+            "--XX\r\n",
+            "Content-Type: text/plain\r\n",
+            "\r\n",
+            "Body text\r\n",
+            "--XX\r\n",
+            "Content-Type: image/png\r\n",
+            "Content-Disposition: attachment; filename=\"cat.png\"\r\n",
+            "Content-Transfer-Encoding: base64\r\n",
+            "\r\n",
+            "aGVsbG8=\r\n",
+            "--XX--\r\n");
+        let mail = parseMessage(raw.as_bytes())?;
+        assert_eq!(mail.sender, "me@example.org");
+        assert_eq!(mail.subject, "A cat");
+        assert_eq!(mail.body.trim(), "Body text");
+        assert_eq!(mail.attachments.len(), 1);
+        assert_eq!(mail.attachments[0].filename, "cat.png");
+        assert_eq!(mail.attachments[0].data, b"hello");
+        Ok(())
+    }
+
+    #[test]
+    fn descriptionNeedsSecret()
+    {
+        let body = "Look at it.\r\n\r\n-- \r\nMe\r\n";
+        assert_eq!(description("A cat", body, None).as_deref(),
+                   Some("A cat\n\nLook at it."));
+        assert_eq!(description("A cat", body, Some("s3cret")), None);
+        assert_eq!(description("A s3cret cat", "", Some("s3cret")).as_deref(),
+                   Some("A cat"));
+        assert_eq!(description("s3cret", body, Some("s3cret")).as_deref(),
+                   Some("Look at it."));
+    }
+}
@@ -5,21 +5,87 @@ mod error;
 mod config;
 mod post;
 mod sqlite_connection;
+mod database;
 mod utils;
 mod data;
 mod to_response;
 mod auth;
 mod app;
 mod post_pipeline;
+mod webhook;
+mod delivery;
+mod telegram;
+mod websub;
+mod mail;
+mod matrix;
+mod archive;
+mod rate_limit;
+mod api;
+mod quarantine;
+mod tls;
+mod acme;
+mod watch;
+mod setup;
+mod feed;
+mod views;
+mod schedule;
+mod cleanup;
+mod trash;
+mod print;
+mod meta;
+mod card;
+mod blurhash;
+mod phash;
+mod i18n;
+mod layout;
+mod prefs;
+mod sitemap;
+mod robots;
+mod http_cache;
+mod page_cache;
+mod access_log;
+mod proxy;
+mod sendfile;
+mod image_files;
+mod security_headers;
+mod geo;
+mod zip;
+mod download;
+mod fetch;
+mod quota;
+mod duplicates;
+mod classifier;
+mod license;
+mod backup;
 
 use std::path::Path;
 
 use log::warn;
 
 use error::Error;
-use config::Configuration;
+use config::{ConfigFiles, Configuration};
 
-fn main() -> Result<(), Error>
+/// Open the database for the subcommands that do not run the server.
+fn openDatabase(config: &Configuration) -> Result<data::Manager, Error>
+{
+    let mut data_manager = data::Manager::fromConfig(config)?;
+    data_manager.connect()?;
+    data_manager.init()?;
+    Ok(data_manager)
+}
+
+fn main()
+{
+    // The error is printed with Display instead of Debug, which keeps
+    // the lines of a config parse error.
+    if let Err(e) = run()
+    {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Error>
 {
     env_logger::Builder::from_default_env().format_timestamp(None).init();
     let opts = clap::Command::new("MeTube")
@@ -30,20 +96,159 @@ fn main() -> Result<(), Error>
              .value_name("FILE")
              .default_value("/etc/nspic.toml")
              .help("Path of config file."))
+        .subcommand(clap::Command::new("export")
+                    .about("Export the library into a tar.gz archive")
+                    .arg(clap::Arg::new("file")
+                         .value_name("FILE")
+                         .required(true)
+                         .help("Path of the archive to create.")))
+        .subcommand(clap::Command::new("import")
+                    .about("Import an archive created by the export command \
+                            into an empty library")
+                    .arg(clap::Arg::new("file")
+                         .value_name("FILE")
+                         .required(true)
+                         .help("Path of the archive to import.")))
+        .subcommand(clap::Command::new("backup")
+                    .about("Back up the library into the dir in the [backup] \
+                            section of the config"))
+        .subcommand(clap::Command::new("rethumb")
+                    .about("Regenerate the thumbnails of all images")
+                    .arg(clap::Arg::new("size")
+                         .long("size")
+                         .value_name("N")
+                         .value_parser(clap::value_parser!(u32))
+                         .help("Size of the thumbnails in pixels. Default is \
+                                thumb_pixel_size in the config.")))
+        .subcommand(clap::Command::new("reencode")
+                    .about("Encode the images that are missing in the \
+                            alternate_encodings of the config"))
+        .subcommand(clap::Command::new("watermark")
+                    .about("Make the watermarked copies that are missing \
+                            with the [watermark] of the config"))
+        .subcommand(clap::Command::new("phash")
+                    .about("Compute the perceptual hashes that are missing, \
+                            which find duplicate images"))
+        .subcommand(clap::Command::new("reshard")
+                    .about("Move images into the directories given by the \
+                            shard_levels and shard_width config"))
+        .subcommand(clap::Command::new("config-dump")
+                    .about("Print the effective config, with the file that \
+                            each value came from"))
+        .subcommand(clap::Command::new("hash-password")
+                    .about("Print the hash of a password, for password_hash \
+                            in the config"))
+        .subcommand(clap::Command::new("token")
+                    .about("Manage the API tokens for scripts")
+                    .subcommand_required(true)
+                    .subcommand(clap::Command::new("create")
+                                .about("Create a token and print it")
+                                .arg(clap::Arg::new("name")
+                                     .value_name("NAME")
+                                     .required(true)
+                                     .help("What the token is for.")))
+                    .subcommand(clap::Command::new("list")
+                                .about("List the tokens"))
+                    .subcommand(clap::Command::new("revoke")
+                                .about("Revoke a token")
+                                .arg(clap::Arg::new("id")
+                                     .value_name("ID")
+                                     .required(true)
+                                     .value_parser(clap::value_parser!(i64))
+                                     .help("ID of the token, as listed."))))
+        .subcommand(clap::Command::new("passwd")
+                    .about("Set or reset the password of a user")
+                    .arg(clap::Arg::new("username")
+                         .value_name("USERNAME")
+                         .default_value(auth::DEFAULT_USERNAME)
+                         .help("The user to set password for.")))
         .get_matches();
 
+    if let Some(("hash-password", _)) = opts.subcommand()
+    {
+        return auth::printPasswordHash();
+    }
     let config_path = opts.get_one::<String>("config").unwrap();
-    let config = if Path::new(&config_path).exists()
+    let config_files = if Path::new(&config_path).exists()
     {
-        Configuration::fromFile(&config_path)?
+        ConfigFiles::load(config_path)?
     }
     else
     {
         warn!("Config file not found. Using default config...");
-        Configuration::default()
+        ConfigFiles::fromConfig(Configuration::default())
     };
+    if let Some(("config-dump", _)) = opts.subcommand()
+    {
+        print!("{}", config_files.dump()?);
+        return Ok(());
+    }
+    let config = config_files.config;
+    post::setUrlScheme(config.post_url);
 
-    let a = app::App::new(config)?;
-    tokio::runtime::Runtime::new().unwrap().block_on(a.serve())?;
-    Ok(())
+    match opts.subcommand()
+    {
+        Some(("export", sub_opts)) => {
+            let data_manager = openDatabase(&config)?;
+            let path = sub_opts.get_one::<String>("file").unwrap();
+            archive::export(Path::new(path), &data_manager, &config)
+        },
+        Some(("import", sub_opts)) => {
+            let data_manager = openDatabase(&config)?;
+            let path = sub_opts.get_one::<String>("file").unwrap();
+            archive::import(Path::new(path), &data_manager, &config)
+        },
+        Some(("backup", _)) => {
+            let data_manager = openDatabase(&config)?;
+            backup::backup(&data_manager, &config).map(|_| ())
+        },
+        Some(("rethumb", sub_opts)) => {
+            let data_manager = openDatabase(&config)?;
+            let size = sub_opts.get_one::<u32>("size").copied()
+                .unwrap_or(config.thumb_pixel_size);
+            post_pipeline::regenerateAllThumbnails(size, &data_manager, &config)
+        },
+        Some(("reencode", _)) => {
+            let data_manager = openDatabase(&config)?;
+            post_pipeline::encodeAllAlternates(&data_manager, &config)
+        },
+        Some(("watermark", _)) => {
+            let data_manager = openDatabase(&config)?;
+            post_pipeline::watermarkAllImages(&data_manager, &config)
+        },
+        Some(("phash", _)) => {
+            let data_manager = openDatabase(&config)?;
+            post_pipeline::hashAllImages(&data_manager, &config)
+        },
+        Some(("reshard", _)) => {
+            let data_manager = openDatabase(&config)?;
+            post_pipeline::reshardLibrary(&data_manager, &config)
+        },
+        Some(("token", sub_opts)) => {
+            let data_manager = openDatabase(&config)?;
+            match sub_opts.subcommand()
+            {
+                Some(("create", token_opts)) => {
+                    let name = token_opts.get_one::<String>("name").unwrap();
+                    let (_, token) = auth::createApiToken(name, &data_manager)?;
+                    println!("{}", token);
+                    Ok(())
+                },
+                Some(("revoke", token_opts)) => auth::revokeApiToken(
+                    *token_opts.get_one::<i64>("id").unwrap(), &data_manager),
+                _ => auth::printApiTokens(&data_manager),
+            }
+        },
+        Some(("passwd", sub_opts)) => {
+            let data_manager = openDatabase(&config)?;
+            let username = sub_opts.get_one::<String>("username").unwrap();
+            auth::setPasswordInteractively(username, &data_manager)
+        },
+        _ => {
+            config.checkEnvironment()?;
+            let a = app::App::new(config)?;
+            tokio::runtime::Runtime::new().unwrap().block_on(a.serve())?;
+            Ok(())
+        },
+    }
 }
@@ -0,0 +1,322 @@
+// A Matrix bot that turns images sent to it into posts. It uses the
+// client-server API with long-polling sync, so it doesn’t need
+// anything exposed to the internet.
+
+use std::io::prelude::*;
+use std::time::Duration;
+
+use log::{info, warn};
+use log::error as log_error;
+use serde_json::json;
+
+use crate::error::Error;
+use crate::config::{Configuration, MatrixConfig};
+use crate::data;
+use crate::post::{Post, Visibility};
+use crate::post_pipeline::RawImage;
+use crate::app::{absoluteUrl, createPost};
+
+struct Bot
+{
+    agent: ureq::Agent,
+    matrix_config: MatrixConfig,
+    data_manager: data::Manager,
+    config: Configuration,
+    txn_count: u64,
+}
+
+/// An image message from an allowed user.
+struct ImageMessage
+{
+    room_id: String,
+    sender: String,
+    filename: String,
+    caption: String,
+    /// An `mxc://` URI.
+    url: String,
+}
+
+/// Find image messages from allowed users in the `rooms.join` part of
+/// a sync response.
+fn imageMessages(sync: &serde_json::Value, matrix_config: &MatrixConfig) ->
+    Vec<ImageMessage>
+{
+    let mut result = Vec::new();
+    let rooms = if let Some(rooms) = sync["rooms"]["join"].as_object()
+    {
+        rooms
+    }
+    else
+    {
+        return result;
+    };
+    for (room_id, room) in rooms
+    {
+        let events = if let Some(events) = room["timeline"]["events"].as_array()
+        {
+            events
+        }
+        else
+        {
+            continue;
+        };
+        for event in events
+        {
+            if event["type"] != "m.room.message" ||
+                event["content"]["msgtype"] != "m.image"
+            {
+                continue;
+            }
+            let sender = event["sender"].as_str().unwrap_or("");
+            if !matrix_config.allowed_users.iter().any(|u| u == sender)
+            {
+                continue;
+            }
+            let url = if let Some(url) = event["content"]["url"].as_str()
+            {
+                url
+            }
+            else
+            {
+                // Probably an encrypted image, which is not supported.
+                warn!("Ignoring image without URL from {}.", sender);
+                continue;
+            };
+            let body = event["content"]["body"].as_str().unwrap_or("");
+            // If there is a filename field, the body is the caption.
+            let (filename, caption) =
+                match event["content"]["filename"].as_str()
+            {
+                Some(f) if f != body => (f, body),
+                Some(f) => (f, ""),
+                None => (body, ""),
+            };
+            result.push(ImageMessage {
+                room_id: room_id.clone(),
+                sender: sender.to_owned(),
+                filename: filename.to_owned(),
+                caption: caption.to_owned(),
+                url: url.to_owned(),
+            });
+        }
+    }
+    result
+}
+
+impl Bot
+{
+    fn endpoint(&self, path: &str) -> String
+    {
+        self.matrix_config.homeserver.trim_end_matches('/').to_owned() + path
+    }
+
+    fn authHeader(&self) -> String
+    {
+        format!("Bearer {}", self.matrix_config.access_token)
+    }
+
+    fn sync(&self, since: Option<&str>, timeout_ms: u64) ->
+        Result<serde_json::Value, Error>
+    {
+        let mut request = self.agent.get(&self.endpoint("/_matrix/client/v3/sync"))
+            .set("Authorization", &self.authHeader())
+            .query("timeout", &timeout_ms.to_string());
+        if let Some(since) = since
+        {
+            request = request.query("since", since);
+        }
+        request.call().map_err(|e| rterr!("Failed to sync: {}", e))?
+            .into_json().map_err(|e| rterr!("Invalid sync response: {}", e))
+    }
+
+    /// Accept invites from allowed users, so that they can start a DM
+    /// with the bot.
+    fn acceptInvites(&self, sync: &serde_json::Value)
+    {
+        let invites = if let Some(invites) = sync["rooms"]["invite"].as_object()
+        {
+            invites
+        }
+        else
+        {
+            return;
+        };
+        for (room_id, room) in invites
+        {
+            let inviter = room["invite_state"]["events"].as_array().and_then(
+                |events| events.iter().find(
+                    |e| e["type"] == "m.room.member" &&
+                        e["content"]["membership"] == "invite"))
+                .and_then(|e| e["sender"].as_str());
+            match inviter
+            {
+                Some(user) if self.matrix_config.allowed_users.iter()
+                    .any(|u| u == user) => {},
+                _ => continue,
+            }
+            info!("Joining Matrix room {}...", room_id);
+            let result = self.agent.post(&self.endpoint(&format!(
+                "/_matrix/client/v3/rooms/{}/join", urlencoding::encode(room_id))))
+                .set("Authorization", &self.authHeader())
+                .send_json(json!({}));
+            if let Err(e) = result
+            {
+                log_error!("Failed to join room {}: {}", room_id, e);
+            }
+        }
+    }
+
+    fn download(&self, mxc_url: &str) -> Result<Vec<u8>, Error>
+    {
+        let media = mxc_url.strip_prefix("mxc://").ok_or_else(
+            || rterr!("Invalid media URL: {}", mxc_url))?;
+        let response = self.agent.get(&self.endpoint(
+            &format!("/_matrix/client/v1/media/download/{}", media)))
+            .set("Authorization", &self.authHeader()).call();
+        let response = match response
+        {
+            // Older servers don’t have authenticated media.
+            Err(ureq::Error::Status(404, _)) => self.agent.get(&self.endpoint(
+                &format!("/_matrix/media/v3/download/{}", media))).call(),
+            r => r,
+        }.map_err(|e| rterr!("Failed to download {}: {}", mxc_url, e))?;
+        let mut data = Vec::new();
+        response.into_reader().take(self.config.upload_bytes_max)
+            .read_to_end(&mut data)
+            .map_err(|e| rterr!("Failed to download {}: {}", mxc_url, e))?;
+        Ok(data)
+    }
+
+    fn sendNotice(&mut self, room_id: &str, text: &str)
+    {
+        self.txn_count += 1;
+        let txn_id = format!("nspic-{}-{}",
+                             time::OffsetDateTime::now_utc().unix_timestamp(),
+                             self.txn_count);
+        let result = self.agent.put(&self.endpoint(&format!(
+            "/_matrix/client/v3/rooms/{}/send/m.room.message/{}",
+            urlencoding::encode(room_id), txn_id)))
+            .set("Authorization", &self.authHeader())
+            .send_json(json!({"msgtype": "m.notice", "body": text}));
+        if let Err(e) = result
+        {
+            log_error!("Failed to reply in room {}: {}", room_id, e);
+        }
+    }
+
+    fn post(&self, msg: &ImageMessage) -> Result<Post, Error>
+    {
+        let data = self.download(&msg.url)?;
+        let img = RawImage::fromBytes(&data, &msg.filename, &self.config)?
+            .process(&self.config)?;
+        let id = createPost(msg.caption.clone(), None, Visibility::Public, false,
+                            None, None, vec![img], &self.data_manager,
+                            &self.config)?;
+        info!("Created post {} from Matrix user {}.", id, msg.sender);
+        self.data_manager.findPostByID(id)?.ok_or_else(
+            || rterr!("Post {} is gone after it is created", id))
+    }
+
+    fn run(&mut self)
+    {
+        // Skip everything that happened before the bot started.
+        let mut since = loop
+        {
+            match self.sync(None, 0)
+            {
+                Ok(sync) => match sync["next_batch"].as_str()
+                {
+                    Some(batch) => break batch.to_owned(),
+                    None => log_error!("No next_batch in sync response."),
+                },
+                Err(e) => log_error!("{}", e),
+            }
+            std::thread::sleep(Duration::from_secs(30));
+        };
+        info!("Matrix bot is running as {}.", self.matrix_config.user_id);
+        loop
+        {
+            let sync = match self.sync(Some(&since), 30000)
+            {
+                Ok(sync) => sync,
+                Err(e) => {
+                    log_error!("{}", e);
+                    std::thread::sleep(Duration::from_secs(30));
+                    continue;
+                },
+            };
+            if let Some(batch) = sync["next_batch"].as_str()
+            {
+                since = batch.to_owned();
+            }
+            self.acceptInvites(&sync);
+            for msg in imageMessages(&sync, &self.matrix_config)
+            {
+                let reply = match self.post(&msg)
+                {
+                    Ok(post) => absoluteUrl("post", &post.urlArg(), &self.config),
+                    Err(e) => {
+                        log_error!("Failed to post from Matrix: {}", e);
+                        String::from("Failed to create post.")
+                    },
+                };
+                self.sendNotice(&msg.room_id, &reply);
+            }
+        }
+    }
+}
+
+/// Start the bot in the background.
+pub fn spawn(matrix_config: MatrixConfig, data_manager: data::Manager,
+             config: Configuration)
+{
+    let mut bot = Bot {
+        agent: ureq::AgentBuilder::new().timeout_read(Duration::from_secs(90))
+            .build(),
+        matrix_config,
+        data_manager,
+        config,
+        txn_count: 0,
+    };
+    std::thread::spawn(move || bot.run());
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn findImageMessages()
+    {
+        let matrix_config = MatrixConfig {
+            homeserver: String::new(),
+            user_id: String::new(),
+            access_token: String::new(),
+            allowed_users: vec![String::from("@me:example.org")],
+        };
+        let sync = json!({"rooms": {"join": {"!room:example.org": {
+            "timeline": {"events": [
+                {"type": "m.room.message", "sender": "@me:example.org",
+                 "content": {"msgtype": "m.image", "body": "A cat",
+                             "filename": "cat.jpg", "url": "mxc://a/b"}},
+                {"type": "m.room.message", "sender": "@me:example.org",
+                 "content": {"msgtype": "m.image", "body": "dog.jpg",
+                             "url": "mxc://a/c"}},
+                {"type": "m.room.message", "sender": "@me:example.org",
+                 "content": {"msgtype": "m.text", "body": "hello"}},
+                {"type": "m.room.message", "sender": "@other:example.org",
+                 "content": {"msgtype": "m.image", "body": "x.jpg",
+                             "url": "mxc://a/d"}},
+            ]}}}}});
+        let msgs = imageMessages(&sync, &matrix_config);
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(msgs[0].filename, "cat.jpg");
+        assert_eq!(msgs[0].caption, "A cat");
+        assert_eq!(msgs[1].filename, "dog.jpg");
+        assert_eq!(msgs[1].caption, "");
+        assert_eq!(msgs[1].room_id, "!room:example.org");
+    }
+}
@@ -0,0 +1,155 @@
+// Open Graph (https://ogp.me/) and Twitter Card metadata of a post
+// page, for the link previews of social media sites.
+
+use serde::Serialize;
+
+use crate::config::Configuration;
+use crate::post::{Post, mimeTypeFromPath};
+use crate::app::absoluteUrl;
+use crate::card;
+
+/// Maximal number of characters in the description.
+const DESCRIPTION_MAX_CHARS: usize = 200;
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct MetaImage
+{
+    pub url: String,
+    pub mime_type: &'static str,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PageMeta
+{
+    pub title: String,
+    pub description: String,
+    /// The canonical URL of the page.
+    pub url: String,
+    pub image: Option<MetaImage>,
+    /// The `twitter:card` value.
+    pub twitter_card: &'static str,
+}
+
+/// Shorten `text` to at most `max_chars` characters, at a word
+/// boundary if there is one.
+pub fn truncate(text: &str, max_chars: usize) -> String
+{
+    let text = text.trim();
+    if text.chars().count() <= max_chars
+    {
+        return text.to_owned();
+    }
+    let cut: String = text.chars().take(max_chars - 1).collect();
+    let cut = match cut.rfind(char::is_whitespace)
+    {
+        Some(i) if i > 0 => &cut[..i],
+        _ => &cut,
+    };
+    cut.trim_end().to_owned() + "…"
+}
+
+/// The metadata of `post`. If `share_token` is given, the page is
+/// shown by that share link, which is then the URL of the page.
+pub fn postMeta(post: &Post, share_token: Option<&str>, config: &Configuration)
+                -> PageMeta
+{
+    let url = match share_token
+    {
+        Some(token) => absoluteUrl("shared", token, config),
+        None => absoluteUrl("post", &post.urlArg(), config),
+    };
+    // Link previews are shown without asking.
+    let image = if post.sensitive
+    {
+        None
+    }
+    else if config.social_cards && card::isTextHeavy(post)
+    {
+        card::cardImage(post, config)
+    }
+    else
+    {
+        post.images.first().and_then(|img| Some(MetaImage {
+            url: absoluteUrl("image_file", img.path.to_str()?, config),
+            mime_type: mimeTypeFromPath(&img.path),
+            width: img.width,
+            height: img.height,
+        }))
+    };
+    // The large card shows the image prominently, which is what a
+    // picture site wants.
+    let twitter_card = if image.is_some()
+    {
+        "summary_large_image"
+    }
+    else
+    {
+        "summary"
+    };
+    PageMeta {
+        title: config.site_info.site_title.clone(),
+        description: truncate(&post.desc, DESCRIPTION_MAX_CHARS),
+        url,
+        image,
+        twitter_card,
+    }
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::path::PathBuf;
+    use crate::post::Image;
+
+    #[test]
+    fn metaOfPost()
+    {
+        let mut config = Configuration::default();
+        config.site_info.url_domain = String::from("https://example.org");
+        config.serve_under_path = String::from("/pic");
+        let mut post = Post::new();
+        post.id = 3;
+        let meta = postMeta(&post, None, &config);
+        assert_eq!(meta.url, "https://example.org/pic/p/3");
+        assert_eq!(meta.image, None);
+        assert_eq!(meta.twitter_card, "summary");
+        // A post of only text has a card.
+        post.desc = String::from("A cat");
+        let card = postMeta(&post, None, &config).image.unwrap();
+        assert!(card.url.starts_with("https://example.org/pic/image/cards/3-"));
+        assert_eq!(card.mime_type, "image/png");
+
+        post.images.push(Image {
+            path: PathBuf::from("a").join("bc.jpg"),
+            width: 400,
+            height: 296,
+            ..Default::default()
+        });
+        let meta = postMeta(&post, Some("xyz"), &config);
+        assert_eq!(meta.url, "https://example.org/pic/s/xyz");
+        assert_eq!(meta.image, Some(MetaImage {
+            url: String::from("https://example.org/pic/image/a/bc.jpg"),
+            mime_type: "image/jpeg",
+            width: 400,
+            height: 296,
+        }));
+        assert_eq!(meta.twitter_card, "summary_large_image");
+        post.sensitive = true;
+        let meta = postMeta(&post, None, &config);
+        assert_eq!(meta.image, None);
+        assert_eq!(meta.twitter_card, "summary");
+    }
+
+    #[test]
+    fn descriptionIsTruncated()
+    {
+        assert_eq!(truncate(" short ", 10), "short");
+        assert_eq!(truncate("one two three", 10), "one two…");
+        assert_eq!(truncate("abcdefghijkl", 5), "abcd…");
+    }
+}
@@ -0,0 +1,152 @@
+// An in-memory cache of the pages that are the same for every visitor,
+// which are the index and the feed, so that a burst of traffic
+// doesn’t query the database and render the templates for every
+// request. A cached page is dropped when posts are added, deleted, or
+// published, and after `ttl_sec` of the `[page_cache]` config.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use time::OffsetDateTime;
+
+use crate::config::Configuration;
+use crate::data;
+
+#[derive(Clone)]
+pub struct CachedPage
+{
+    pub body: String,
+    /// The time of the newest content on the page.
+    pub last_modified: Option<OffsetDateTime>,
+}
+
+struct Entry
+{
+    page: CachedPage,
+    time: Instant,
+    /// The generation of posts that the page was rendered from.
+    generation: u64,
+}
+
+pub struct PageCache
+{
+    /// None if the cache is disabled.
+    ttl: Option<Duration>,
+    entries_max: usize,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+/// The cache key of the page at `path` with the query `params`, in
+/// the language `locale`.
+pub fn key(path: &str, params: &HashMap<String, String>, locale: &str) -> String
+{
+    let mut query: Vec<String> = params.iter()
+        .map(|(k, v)| format!("{}={}", k, v)).collect();
+    query.sort();
+    format!("{}?{} {}", path, query.join("&"), locale)
+}
+
+impl PageCache
+{
+    pub fn new(config: &Configuration) -> Self
+    {
+        Self {
+            ttl: config.page_cache.as_ref().map(
+                |c| Duration::from_secs(c.ttl_sec)),
+            entries_max: config.page_cache.as_ref().map(|c| c.entries_max)
+                .unwrap_or(0),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn isFresh(&self, entry: &Entry, generation: u64) -> bool
+    {
+        entry.generation == generation &&
+            self.ttl.map(|ttl| entry.time.elapsed() < ttl).unwrap_or(false)
+    }
+
+    /// The cached page of `key`, if it is still fresh.
+    pub fn get(&self, key: &str, data_manager: &data::Manager) ->
+        Option<CachedPage>
+    {
+        self.ttl?;
+        let entries = self.entries.lock().unwrap();
+        entries.get(key).filter(|e| self.isFresh(e, data_manager.generation()))
+            .map(|e| e.page.clone())
+    }
+
+    /// Cache `page` as `key`. `generation` should be taken from the
+    /// data manager before the page is rendered, so that a page
+    /// rendered while posts change is not kept.
+    pub fn put(&self, key: String, page: CachedPage, generation: u64)
+    {
+        if self.ttl.is_none()
+        {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.entries_max && !entries.contains_key(&key)
+        {
+            entries.retain(|_, e| self.isFresh(e, generation));
+            if entries.len() >= self.entries_max
+            {
+                let oldest = entries.iter().min_by_key(|(_, e)| e.time)
+                    .map(|(k, _)| k.clone());
+                if let Some(oldest) = oldest
+                {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(key, Entry { page, time: Instant::now(), generation });
+    }
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::config::PageCacheConfig;
+    use crate::sqlite_connection;
+
+    fn page(body: &str) -> CachedPage
+    {
+        CachedPage { body: body.to_owned(), last_modified: None }
+    }
+
+    #[test]
+    fn pagesAreCachedUntilPostsChange()
+    {
+        let data_manager = data::Manager::new(sqlite_connection::Source::Memory);
+        let mut config = Configuration::default();
+        assert!(PageCache::new(&config).get("a", &data_manager).is_none());
+        let disabled = PageCache::new(&config);
+        disabled.put(String::from("a"), page("A"), 0);
+        assert!(disabled.get("a", &data_manager).is_none());
+
+        config.page_cache = Some(PageCacheConfig { ttl_sec: 60, entries_max: 2 });
+        let cache = PageCache::new(&config);
+        cache.put(String::from("a"), page("A"), data_manager.generation());
+        assert_eq!(cache.get("a", &data_manager).unwrap().body, "A");
+        // Rendered before a change of posts.
+        cache.put(String::from("b"), page("B"), data_manager.generation() + 1);
+        assert!(cache.get("b", &data_manager).is_none());
+        // The oldest page makes room.
+        cache.put(String::from("c"), page("C"), data_manager.generation());
+        assert!(cache.get("a", &data_manager).is_some());
+        cache.put(String::from("d"), page("D"), data_manager.generation());
+        assert!(cache.get("a", &data_manager).is_none());
+        assert!(cache.get("d", &data_manager).is_some());
+    }
+
+    #[test]
+    fn keysIgnoreParameterOrder()
+    {
+        let params: HashMap<String, String> = [("page", "2"), ("order", "old")]
+            .iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        assert_eq!(key("/", &params, "en"), "/?order=old&page=2 en");
+    }
+}
@@ -0,0 +1,87 @@
+// Perceptual hashes of images, which are close for images that look
+// the same even if their files differ, e.g. after resizing or
+// re-encoding. The hash is the sign of the lowest 8 × 8 frequencies
+// of the DCT of a 32 × 32 grayscale copy of the image, compared to
+// their median. Images are probably the same if their hashes differ
+// in only a few bits.
+
+use std::f64::consts::PI;
+
+/// The size of the grayscale copy that is hashed.
+pub const PIXEL_SIZE: usize = 32;
+/// The number of frequencies in each direction that are kept.
+const FREQUENCIES: usize = 8;
+
+/// The lowest frequencies of the DCT-II of `values`, which has
+/// `PIXEL_SIZE` values spaced by `stride`.
+fn dct(values: &[f64], stride: usize) -> [f64; FREQUENCIES]
+{
+    let mut result = [0.0; FREQUENCIES];
+    for (u, r) in result.iter_mut().enumerate()
+    {
+        *r = (0..PIXEL_SIZE).map(|x| values[x * stride] *
+                                 (PI * (2 * x + 1) as f64 * u as f64 /
+                                  (2 * PIXEL_SIZE) as f64).cos()).sum();
+    }
+    result
+}
+
+/// The hash of `PIXEL_SIZE` × `PIXEL_SIZE` 8-bit grayscale `pixels`.
+pub fn hash(pixels: &[u8]) -> u64
+{
+    let values: Vec<f64> = pixels.iter().map(|p| *p as f64).collect();
+    // Transform the rows, and then the columns of the result.
+    let rows: Vec<f64> = (0..PIXEL_SIZE).flat_map(
+        |y| dct(&values[y * PIXEL_SIZE..], 1)).collect();
+    let mut coefficients = Vec::with_capacity(FREQUENCIES * FREQUENCIES);
+    for u in 0..FREQUENCIES
+    {
+        coefficients.extend(dct(&rows[u..], FREQUENCIES));
+    }
+    let mut sorted = coefficients.clone();
+    sorted.sort_by(f64::total_cmp);
+    let middle = sorted.len() / 2;
+    let median = (sorted[middle - 1] + sorted[middle]) / 2.0;
+    coefficients.iter().enumerate().fold(
+        0, |hash, (i, c)| if *c > median { hash | (1 << i) } else { hash })
+}
+
+/// How many bits two hashes differ in.
+pub fn distance(a: u64, b: u64) -> u32
+{
+    (a ^ b).count_ones()
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    /// A picture of a bright disk on a gradient.
+    fn picture(brightness: i32, mirrored: bool) -> Vec<u8>
+    {
+        let mut pixels = Vec::new();
+        for y in 0..PIXEL_SIZE as i32
+        {
+            for x in 0..PIXEL_SIZE as i32
+            {
+                let x = if mirrored { PIXEL_SIZE as i32 - 1 - x } else { x };
+                let disk = (x - 10).pow(2) + (y - 12).pow(2) < 36;
+                let value = if disk { 230 } else { x * 4 + y * 2 };
+                pixels.push((value + brightness).clamp(0, 255) as u8);
+            }
+        }
+        pixels
+    }
+
+    #[test]
+    fn similarPicturesHaveCloseHashes()
+    {
+        let original = hash(&picture(0, false));
+        assert_eq!(distance(original, hash(&picture(0, false))), 0);
+        assert!(distance(original, hash(&picture(10, false))) <= 2);
+        assert!(distance(original, hash(&picture(0, true))) > 10);
+    }
+}
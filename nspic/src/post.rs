@@ -1,20 +1,207 @@
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde::ser::{Serializer, SerializeStruct};
 use time::OffsetDateTime;
 
 use crate::error::Error;
+use crate::config::PostUrlScheme;
 
+/// The URL scheme of posts, which is set from the config at start,
+/// because posts are linked from places that don’t have the config,
+/// like templates.
+static URL_SCHEME: OnceLock<PostUrlScheme> = OnceLock::new();
+
+/// The first segments of the URLs of NSPic’s own pages, which posts
+/// can’t have under the `Slug` scheme.
+const RESERVED_SEGMENTS: &[&str] = &[
+    "admin", "api", "archive", "delete", "delete-confirm", "feed.xml",
+    "image", "lang", "layout", "login", "logout", "map", "map.geojson", "p",
+    "prefs", "redact", "robots.txt", "s", "sessions", "setup", "share",
+    "sitemap.xml", "static", "unshare", "upload",
+];
+
+/// Set the URL scheme of posts. Only the first call has an effect.
+pub fn setUrlScheme(scheme: PostUrlScheme)
+{
+    URL_SCHEME.set(scheme).ok();
+}
+
+/// The argument of `urlFor("post", ...)` for the post with `id`,
+/// `slug`, and `upload_time`, which is its path under `scheme`
+/// without the leading slash.
+pub fn postPath(scheme: PostUrlScheme, id: i64, slug: Option<&str>,
+                upload_time: OffsetDateTime) -> String
+{
+    let post_ref = slug.map(|s| s.to_owned()).unwrap_or_else(|| id.to_string());
+    match scheme
+    {
+        PostUrlScheme::Id => format!("p/{}", post_ref),
+        PostUrlScheme::Date => {
+            let time = upload_time.to_offset(time::UtcOffset::UTC);
+            format!("{}/{:02}/{}", time.year(), u8::from(time.month()), post_ref)
+        },
+        PostUrlScheme::Slug => match slug
+        {
+            Some(slug) if !RESERVED_SEGMENTS.contains(&slug) => slug.to_owned(),
+            _ => format!("p/{}", id),
+        },
+    }
+}
+
+/// `postPath()` under the scheme of the config.
+pub fn urlArgOf(id: i64, slug: Option<&str>, upload_time: OffsetDateTime) ->
+    String
+{
+    postPath(URL_SCHEME.get().copied().unwrap_or(PostUrlScheme::Id), id, slug,
+             upload_time)
+}
+
+/// A rendition of an image that a browser could choose from.
+#[derive(Serialize, Clone)]
+pub struct ImageSource
+{
+    pub width: u32,
+    pub url: String,
+}
+
+/// All renditions of an image in one format. This is what a
+/// `<source>` element in a `<picture>` needs.
+#[derive(Clone)]
+pub struct SourceSet
+{
+    pub mime_type: String,
+    /// Sorted from small to large.
+    pub sources: Vec<ImageSource>,
+}
+
+impl SourceSet
+{
+    /// The value of the `srcset` attribute.
+    pub fn srcset(&self) -> String
+    {
+        let items: Vec<String> = self.sources.iter()
+            .map(|s| format!("{} {}w", s.url, s.width)).collect();
+        items.join(", ")
+    }
+}
+
+impl Serialize for SourceSet
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("SourceSet", 3)?;
+        state.serialize_field("mime_type", &self.mime_type)?;
+        state.serialize_field("sources", &self.sources)?;
+        state.serialize_field("srcset", &self.srcset())?;
+        state.end()
+    }
+}
+
+/// Guess the MIME type of an image or video file from its extension.
+pub fn mimeTypeFromPath(path: &Path) -> &'static str
+{
+    match path.extension().and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase()).as_deref()
+    {
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("avif") => "image/avif",
+        Some("jxl") => "image/jxl",
+        Some("webp") => "image/webp",
+        Some("gif") => "image/gif",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        Some("mov") => "video/quicktime",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Where a photo was taken.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Location
+{
+    pub latitude: f64,
+    pub longitude: f64,
+    /// A human readable name of the place, from reverse geocoding.
+    #[serde(default)]
+    pub place: Option<String>,
+}
+
+impl Location
+{
+    /// A location from coordinates in degrees, if they are valid.
+    pub fn new(latitude: f64, longitude: f64) -> Option<Self>
+    {
+        if (-90.0..=90.0).contains(&latitude) &&
+            (-180.0..=180.0).contains(&longitude)
+        {
+            Some(Self { latitude, longitude, place: None })
+        }
+        else
+        {
+            None
+        }
+    }
+}
+
+#[derive(Default)]
 pub struct Image
 {
     pub path: PathBuf,
     pub width: u32,
     pub height: u32,
+    /// Byte size of the image file.
+    pub size: u64,
+    /// Byte size of the thumbnail file.
+    pub thumbnail_size: u64,
+    /// Text that describes the image for screen readers. Empty if the
+    /// uploader gave none, in which case pages use the description
+    /// of the post.
+    pub alt_text: String,
+    /// Shown under the image. Empty if there is none.
+    pub caption: String,
+    /// The average color of the image, like `#8a9bb0`, and its
+    /// BlurHash, which pages show while the image loads. Empty for
+    /// images from before these.
+    pub color: String,
+    pub blurhash: String,
+    /// The perceptual hash of the image, which finds images that look
+    /// the same. None for images from before it.
+    pub phash: Option<u64>,
+    /// Where the image was taken, from its EXIF data. This is not
+    /// stored in the database. A post takes the location of its first
+    /// image that has one.
+    pub location: Option<Location>,
+    /// The IPTC and XMP keywords of the image, which become tags of
+    /// the post if `import_keyword_tags` is on. This is not stored in
+    /// the database.
+    pub keywords: Vec<String>,
+    /// The renditions of this image grouped by format, preferred
+    /// format first. This is not stored in the database, and is
+    /// empty until filled by the web app.
+    pub sources: Vec<SourceSet>,
 }
 
 impl Image
 {
+    /// The dimension of the thumbnail, if the thumbnail was made to
+    /// fit in `thumb_pixel_size`. Small images are not enlarged.
+    pub fn thumbnailSize(&self, thumb_pixel_size: u32) -> (u32, u32)
+    {
+        let long_side = std::cmp::max(self.width, self.height);
+        if long_side <= thumb_pixel_size
+        {
+            return (self.width, self.height);
+        }
+        let scale = thumb_pixel_size as f64 / long_side as f64;
+        ((self.width as f64 * scale).round() as u32,
+         (self.height as f64 * scale).round() as u32)
+    }
+
     pub fn thumbnail(&self) -> Result<PathBuf, Error>
     {
         let dir = self.path.parent().or(Some(&Path::new(""))).unwrap();
@@ -27,6 +214,23 @@ impl Image
         Ok(dir.to_owned().join(Path::new(&(String::from(stem) + "_t")))
              .with_extension(ext))
     }
+
+    /// The path of the watermarked copy of this image, which is served
+    /// in its place if a watermark is configured.
+    pub fn watermarked(&self) -> Result<PathBuf, Error>
+    {
+        let stem = self.path.file_stem().and_then(|s| s.to_str()).ok_or_else(
+            || rterr!("Invalid image path: {}", self.path.display()))?;
+        let ext = self.path.extension().unwrap_or_default();
+        Ok(self.path.with_file_name(String::from(stem) + "_w").with_extension(ext))
+    }
+
+    /// The paths of this image and its thumbnail in the alternate
+    /// encoding with extension `ext`.
+    pub fn alternate(&self, ext: &str) -> Result<(PathBuf, PathBuf), Error>
+    {
+        Ok((self.path.with_extension(ext), self.thumbnail()?.with_extension(ext)))
+    }
 }
 
 impl Serialize for Image
@@ -35,18 +239,91 @@ impl Serialize for Image
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("Image", 4)?;
+        let mut state = serializer.serialize_struct("Image", 11)?;
         state.serialize_field("path", self.path.to_str().ok_or_else(
             || serde::ser::Error::custom("Invalid image path"))?)?;
         state.serialize_field("thumbnail", self.thumbnail().map_err(
-            |e| serde::ser::Error::custom(e))?.to_str().ok_or_else(
+            serde::ser::Error::custom)?.to_str().ok_or_else(
             || serde::ser::Error::custom("Invalid thumbnail path"))?)?;
         state.serialize_field("width", &self.width)?;
         state.serialize_field("height", &self.height)?;
+        state.serialize_field("size", &self.size)?;
+        state.serialize_field("thumbnail_size", &self.thumbnail_size)?;
+        state.serialize_field("alt_text", &Some(&self.alt_text)
+                              .filter(|t| !t.is_empty()))?;
+        state.serialize_field("caption", &Some(&self.caption)
+                              .filter(|t| !t.is_empty()))?;
+        state.serialize_field("color", &Some(&self.color)
+                              .filter(|t| !t.is_empty()))?;
+        state.serialize_field("blurhash", &Some(&self.blurhash)
+                              .filter(|t| !t.is_empty()))?;
+        state.serialize_field("sources", &self.sources)?;
         state.end()
     }
 }
 
+#[derive(Serialize, Clone)]
+pub struct Album
+{
+    pub id: i64,
+    pub title: String,
+    /// The path of the image chosen as the cover. The first image of
+    /// the newest post is the cover if this is None.
+    pub cover: Option<String>,
+    /// The album that this album is in. None for a top level album.
+    pub parent: Option<i64>,
+}
+
+/// An album in the album grid.
+#[derive(Serialize)]
+pub struct AlbumSummary
+{
+    pub id: i64,
+    pub title: String,
+    /// Number of live public posts in the album.
+    pub post_count: u64,
+    /// None if there is no image in the album.
+    pub cover: Option<Image>,
+}
+
+/// Who can see a post.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility
+{
+    /// Listed in the index and the feed.
+    #[default]
+    Public,
+    /// Not listed, but anyone with the URL can see it.
+    Unlisted,
+    /// Only the logged-in user can see it.
+    Private,
+}
+
+impl Visibility
+{
+    pub fn fromStr(s: &str) -> Option<Self>
+    {
+        match s
+        {
+            "public" => Some(Self::Public),
+            "unlisted" => Some(Self::Unlisted),
+            "private" => Some(Self::Private),
+            _ => None,
+        }
+    }
+
+    pub fn asStr(&self) -> &'static str
+    {
+        match self
+        {
+            Self::Public => "public",
+            Self::Unlisted => "unlisted",
+            Self::Private => "private",
+        }
+    }
+}
+
 pub struct Post
 {
     pub id: i64,
@@ -54,6 +331,29 @@ pub struct Post
     pub desc: String,
     pub upload_time: OffsetDateTime,
     pub album_id: Option<i64>,
+    /// The images of the post were removed, but the post is kept.
+    pub redacted: bool,
+    /// A human readable alternative to the ID in the URL.
+    pub slug: Option<String>,
+    /// Drafts are only visible to the logged-in user until published.
+    pub draft: bool,
+    pub visibility: Visibility,
+    /// Number of likes from visitors.
+    pub likes: u64,
+    /// Number of views, not including the ones not saved yet.
+    pub views: u64,
+    /// The post goes live at `upload_time`, which is in the future
+    /// when the post is submitted. This is cleared once the post is
+    /// announced.
+    pub scheduled: bool,
+    pub location: Option<Location>,
+    pub tags: Vec<String>,
+    /// The images are hidden until the visitor clicks on them, and are
+    /// left out of feeds and link previews.
+    pub sensitive: bool,
+    /// The license of the images, or None for the `default_license`
+    /// of the config.
+    pub license: Option<String>,
 }
 
 impl Post
@@ -66,8 +366,104 @@ impl Post
             desc: String::new(),
             upload_time: OffsetDateTime::UNIX_EPOCH,
             album_id: None,
+            redacted: false,
+            slug: None,
+            draft: false,
+            visibility: Visibility::Public,
+            likes: 0,
+            views: 0,
+            scheduled: false,
+            location: None,
+            tags: Vec::new(),
+            sensitive: false,
+            license: None,
+        }
+    }
+
+    /// The argument of `urlFor("post", ...)` for this post.
+    pub fn urlArg(&self) -> String
+    {
+        urlArgOf(self.id, self.slug.as_deref(), self.upload_time)
+    }
+
+    /// Whether the post can only be seen by the logged-in user.
+    pub fn needsSession(&self) -> bool
+    {
+        self.draft || self.visibility == Visibility::Private ||
+            self.isPending()
+    }
+
+    /// Whether the post is scheduled and not live yet.
+    pub fn isPending(&self) -> bool
+    {
+        self.scheduled && self.upload_time > OffsetDateTime::now_utc()
+    }
+}
+
+/// Maximal length of a generated slug.
+const SLUG_LENGTH_MAX: usize = 60;
+
+/// Make a slug from some text by joining its ASCII words with `-`. If
+/// there is nothing usable in the text, return None. Slugs made only
+/// of digits would be taken as IDs, so they are rejected too.
+pub fn slugify(text: &str) -> Option<String>
+{
+    let mut slug = String::new();
+    for word in text.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|w| !w.is_empty())
+    {
+        if !slug.is_empty() && slug.len() + word.len() + 1 > SLUG_LENGTH_MAX
+        {
+            break;
+        }
+        if !slug.is_empty()
+        {
+            slug.push('-');
+        }
+        slug.push_str(&word.to_ascii_lowercase());
+    }
+    slug.truncate(SLUG_LENGTH_MAX);
+    if slug.is_empty() || slug.chars().all(|c| c.is_ascii_digit())
+    {
+        None
+    }
+    else
+    {
+        Some(slug)
+    }
+}
+
+/// Maximal number of characters in a tag.
+const TAG_LENGTH_MAX: usize = 64;
+
+/// Trim the tags, drop the empty ones, and drop the ones that only
+/// differ from an earlier one in case.
+pub fn cleanTags<I: IntoIterator<Item = String>>(tags: I) -> Vec<String>
+{
+    let mut result: Vec<String> = Vec::new();
+    for tag in tags
+    {
+        let tag: String = tag.trim().chars().take(TAG_LENGTH_MAX).collect();
+        if !tag.is_empty() &&
+            !result.iter().any(|t| t.to_lowercase() == tag.to_lowercase())
+        {
+            result.push(tag);
         }
     }
+    result
+}
+
+/// Replace the placeholders in a caption snippet. See
+/// `config::CaptionSnippet`.
+pub fn expandSnippet(text: &str, time: OffsetDateTime, image_count: usize) ->
+    String
+{
+    let date = time.date();
+    text.replace("{date}", &format!("{}-{:02}-{:02}", date.year(),
+                                    date.month() as u8, date.day()))
+        .replace("{year}", &date.year().to_string())
+        .replace("{week}", &date.iso_week().to_string())
+        .replace("{count}", &image_count.to_string())
 }
 
 impl Serialize for Post
@@ -76,7 +472,7 @@ impl Serialize for Post
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("Post", 5)?;
+        let mut state = serializer.serialize_struct("Post", 18)?;
         state.serialize_field("id", &self.id)?;
         state.serialize_field("images", &self.images)?;
         state.serialize_field("desc", &self.desc)?;
@@ -93,6 +489,46 @@ impl Serialize for Post
                 &time::format_description::well_known::Rfc3339).map_err(
                 |_| serde::ser::Error::custom("Invalid upload time"))?)?;
         state.serialize_field("album_id", &self.album_id)?;
+        state.serialize_field("redacted", &self.redacted)?;
+        state.serialize_field("slug", &self.slug)?;
+        state.serialize_field("url_arg", &self.urlArg())?;
+        state.serialize_field("draft", &self.draft)?;
+        state.serialize_field("visibility", &self.visibility)?;
+        state.serialize_field("likes", &self.likes)?;
+        state.serialize_field("views", &self.views)?;
+        state.serialize_field("pending", &self.isPending())?;
+        state.serialize_field("location", &self.location)?;
+        state.serialize_field("tags", &self.tags)?;
+        state.serialize_field("sensitive", &self.sensitive)?;
+        state.serialize_field("license", &self.license)?;
+        state.end()
+    }
+}
+
+/// A link that shows a post to anyone with it until it expires.
+pub struct ShareLink
+{
+    pub token: String,
+    pub post_id: i64,
+    pub expire_time: OffsetDateTime,
+}
+
+impl Serialize for ShareLink
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("ShareLink", 4)?;
+        state.serialize_field("token", &self.token)?;
+        state.serialize_field("post_id", &self.post_id)?;
+        state.serialize_field("expire_time",
+                              &self.expire_time.unix_timestamp())?;
+        let format = time::format_description::parse_borrowed::<2>(
+            "[year]-[month]-[day] [hour]:[minute]:[second] UTC").unwrap();
+        state.serialize_field(
+            "expire_time_utc_str", &self.expire_time.format(&format).map_err(
+                |_| serde::ser::Error::custom("Invalid expire time"))?)?;
         state.end()
     }
 }
@@ -112,6 +548,7 @@ mod tests
             path: PathBuf::from("a").join("bc.jpg"),
             width: 0,
             height: 0,
+            ..Default::default()
         };
 
         assert_eq!(image.thumbnail()?.to_str().unwrap(), "a/bc_t.jpg");
@@ -122,4 +559,62 @@ mod tests
         assert_eq!(image.thumbnail()?.to_str().unwrap(), "aaa_t");
         Ok(())
     }
+
+    #[test]
+    fn thumbnailSize()
+    {
+        let mut image = Image
+        {
+            path: PathBuf::from("a.jpg"),
+            width: 400,
+            height: 296,
+            ..Default::default()
+        };
+        assert_eq!(image.thumbnailSize(256), (256, 189));
+        assert_eq!(image.thumbnailSize(400), (400, 296));
+        image.width = 100;
+        image.height = 1000;
+        assert_eq!(image.thumbnailSize(256), (26, 256));
+    }
+
+    #[test]
+    fn snippetPlaceholders()
+    {
+        let time = OffsetDateTime::from_unix_timestamp(1709251200).unwrap();
+        assert_eq!(expandSnippet("Weeknotes {year}-W{week} ({date}), {count} photos {other}",
+                                 time, 3),
+                   "Weeknotes 2024-W9 (2024-03-01), 3 photos {other}");
+    }
+
+    #[test]
+    fn slugFromText()
+    {
+        assert_eq!(slugify("Sunset at the Beach!"),
+                   Some(String::from("sunset-at-the-beach")));
+        assert_eq!(slugify("  2023: a year  "), Some(String::from("2023-a-year")));
+        assert_eq!(slugify("猫"), None);
+        assert_eq!(slugify("1234"), None);
+        assert!(slugify(&"word ".repeat(100)).unwrap().len() <= SLUG_LENGTH_MAX);
+    }
+
+    #[test]
+    fn tagsAreCleaned()
+    {
+        let tags = ["Cat ", "", "cat", "  ", "New York"].map(String::from);
+        assert_eq!(cleanTags(tags), vec!["Cat", "New York"]);
+    }
+
+    #[test]
+    fn postPathsFollowTheScheme()
+    {
+        let time = OffsetDateTime::from_unix_timestamp(1715000000).unwrap();
+        assert_eq!(postPath(PostUrlScheme::Id, 3, None, time), "p/3");
+        assert_eq!(postPath(PostUrlScheme::Id, 3, Some("cat"), time), "p/cat");
+        assert_eq!(postPath(PostUrlScheme::Date, 3, Some("cat"), time),
+                   "2024/05/cat");
+        assert_eq!(postPath(PostUrlScheme::Date, 3, None, time), "2024/05/3");
+        assert_eq!(postPath(PostUrlScheme::Slug, 3, Some("cat"), time), "cat");
+        assert_eq!(postPath(PostUrlScheme::Slug, 3, None, time), "p/3");
+        assert_eq!(postPath(PostUrlScheme::Slug, 3, Some("admin"), time), "p/3");
+    }
 }
@@ -3,25 +3,115 @@ use std::io::prelude::*;
 use std::io::BufWriter;
 use std::fs::File;
 use std::ffi::OsStr;
-use std::process::Command;
+use std::process::{Child, Command, Output, Stdio};
 use std::str;
+use std::sync::{Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 use futures_util::StreamExt;
 use bytes::buf::Buf;
-use log::debug;
+use log::{debug, info};
 use log::error as log_error;
 use warp::http::status::StatusCode;
 use sha2::Digest;
 
 use crate::error::Error;
-use crate::post::Image;
+use crate::post::{Image, Location};
 use crate::config::Configuration;
+use crate::data;
+use crate::quarantine::{quarantine, Upload};
+use crate::blurhash;
+use crate::phash;
+use crate::license::effectiveLicense;
+
+/// Limits how many images go through the pipeline at the same time,
+/// so that a burst of uploads doesn’t start dozens of ImageMagick
+/// processes.
+struct PipelineSlots
+{
+    /// Numbers of running and waiting jobs.
+    jobs: Mutex<(usize, usize)>,
+    freed: Condvar,
+}
+
+/// Holds a slot in the pipeline until dropped.
+struct PipelineSlot<'a>
+{
+    slots: &'a PipelineSlots,
+}
+
+impl PipelineSlots
+{
+    const fn new() -> Self
+    {
+        Self { jobs: Mutex::new((0, 0)), freed: Condvar::new() }
+    }
+
+    /// Wait until less than `jobs_max` jobs are running.
+    fn acquire(&self, jobs_max: usize) -> PipelineSlot<'_>
+    {
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.1 += 1;
+        while jobs.0 >= jobs_max
+        {
+            jobs = self.freed.wait(jobs).unwrap();
+        }
+        jobs.1 -= 1;
+        jobs.0 += 1;
+        PipelineSlot { slots: self }
+    }
+
+    /// Whether all slots are taken and no more jobs should wait.
+    fn isFull(&self, jobs_max: usize, queue_max: usize) -> bool
+    {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.0 >= jobs_max && jobs.1 >= queue_max
+    }
+}
+
+impl Drop for PipelineSlot<'_>
+{
+    fn drop(&mut self)
+    {
+        self.slots.jobs.lock().unwrap().0 -= 1;
+        self.slots.freed.notify_one();
+    }
+}
+
+static PIPELINE_SLOTS: PipelineSlots = PipelineSlots::new();
+
+/// The placeholders of images are computed from a copy of this many
+/// pixels square.
+const PLACEHOLDER_PIXEL_SIZE: usize = 32;
+
+/// Whether the pipeline is too busy to accept an upload.
+pub fn pipelineIsFull(config: &Configuration) -> bool
+{
+    PIPELINE_SLOTS.isFull(config.pipelineJobsMax(), config.pipeline_queue_max)
+}
 
 pub fn imagePath(image: &Image, config: &Configuration) -> PathBuf
 {
     Path::new(&config.image_dir).join(&image.path)
 }
 
+/// The directory, relative to the image dir, that the image with hash
+/// `hash` belongs in.
+pub fn shardDir(hash: &str, config: &Configuration) -> PathBuf
+{
+    let mut dir = PathBuf::new();
+    for level in 0..config.shard_levels
+    {
+        let start = level * config.shard_width;
+        if let Some(part) = hash.get(start..start + config.shard_width)
+        {
+            dir.push(part);
+        }
+    }
+    dir
+}
+
 fn randomTempFilename<P: AsRef<Path>>(dir: P) -> PathBuf
 {
     loop
@@ -35,6 +125,99 @@ fn randomTempFilename<P: AsRef<Path>>(dir: P) -> PathBuf
     }
 }
 
+/// The extension of the image type of a file that starts with
+/// `head`, if it is one of the accepted types. The filename of an
+/// upload is not trusted, so that e.g. an HTML file named .jpg is
+/// rejected.
+pub fn sniffImageType(head: &[u8]) -> Option<&'static str>
+{
+    // ISO base media files (AVIF, HEIC) start with an ftyp box, which
+    // has the major brand at offset 8.
+    let brand = if head.len() >= 12 && &head[4..8] == b"ftyp"
+    {
+        Some(&head[8..12])
+    }
+    else
+    {
+        None
+    };
+    if head.starts_with(&[0xFF, 0xD8, 0xFF])
+    {
+        Some("jpg")
+    }
+    else if head.starts_with(b"\x89PNG\r\n\x1a\n")
+    {
+        Some("png")
+    }
+    else if head.starts_with(b"GIF87a") || head.starts_with(b"GIF89a")
+    {
+        Some("gif")
+    }
+    else if head.len() >= 12 && head.starts_with(b"RIFF") &&
+        &head[8..12] == b"WEBP"
+    {
+        Some("webp")
+    }
+    else if head.starts_with(&[0xFF, 0x0A]) ||
+        head.starts_with(b"\0\0\0\x0cJXL \r\n\x87\n")
+    {
+        Some("jxl")
+    }
+    else if head.starts_with(b"II*\0") || head.starts_with(b"MM\0*")
+    {
+        Some("tiff")
+    }
+    else
+    {
+        match brand
+        {
+            Some(b"avif") | Some(b"avis") => Some("avif"),
+            Some(b"heic") | Some(b"heix") | Some(b"mif1") => Some("heic"),
+            _ => None,
+        }
+    }
+}
+
+fn imageTooLarge(filename: &str, config: &Configuration) -> Error
+{
+    Error::HTTPStatus(StatusCode::PAYLOAD_TOO_LARGE, format!(
+        "{} is larger than the limit of {} bytes", filename,
+        config.image_bytes_max))
+}
+
+/// Give a temp file the extension of its image type, or remove it if
+/// it is not an accepted image.
+fn renameBySniffedType(temp_file: &Path, original_filename: &str) ->
+    Result<PathBuf, Error>
+{
+    let mut head = [0u8; 16];
+    let len = File::open(temp_file).and_then(|mut f| f.read(&mut head))
+        .map_err(|e| rterr!("Failed to read temp file: {}", e))?;
+    let extension = match sniffImageType(&head[..len])
+    {
+        Some(ext) => ext,
+        None => {
+            std::fs::remove_file(temp_file).ok();
+            return Err(Error::HTTPStatus(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                format!("{} is not a supported image", original_filename)));
+        },
+    };
+    let claimed = Path::new(original_filename).extension()
+        .and_then(|e| e.to_str()).map(|e| e.to_lowercase());
+    if claimed.as_deref() != Some(extension) &&
+        !(extension == "jpg" && claimed.as_deref() == Some("jpeg"))
+    {
+        info!("{} is a {} file.", original_filename, extension);
+    }
+    let path = temp_file.with_extension(extension);
+    std::fs::rename(temp_file, &path).map_err(|e| {
+        std::fs::remove_file(temp_file).ok();
+        rterr!("Failed to rename temp file: {}", e)
+    })?;
+    Ok(path)
+}
+
 struct ImageMetadata
 {
     width: u32,
@@ -49,39 +232,117 @@ impl ImageMetadata
     }
 }
 
-fn resizeImage(img: &Path, output: &Path, size: u32, quality: i32) ->
-    Result<(), Error>
+/// How often to check whether an external command has finished.
+const COMMAND_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Read all of `pipe` in a thread, so that a command doesn’t block on a
+/// full pipe while it is waited for.
+fn readInBackground<R: Read + Send + 'static>(pipe: Option<R>) ->
+    JoinHandle<Vec<u8>>
 {
-    let status = Command::new("magick").args(
-        &[img.to_str().ok_or_else(
+    std::thread::spawn(move || {
+        let mut data = Vec::new();
+        if let Some(mut pipe) = pipe
+        {
+            pipe.read_to_end(&mut data).ok();
+        }
+        data
+    })
+}
+
+/// Run `command` and collect its output, killing it if it is still
+/// running after `timeout`.
+fn runWithTimeout(command: &mut Command, timeout: Duration) ->
+    Result<Output, Error>
+{
+    let mut child: Child = command.stdin(Stdio::null()).stdout(Stdio::piped())
+        .stderr(Stdio::piped()).spawn()
+        .map_err(|e| rterr!("Failed to run {:?}: {}", command.get_program(), e))?;
+    let stdout = readInBackground(child.stdout.take());
+    let stderr = readInBackground(child.stderr.take());
+    let deadline = Instant::now() + timeout;
+    let status = loop
+    {
+        match child.try_wait().map_err(
+            |e| rterr!("Failed to wait for {:?}: {}", command.get_program(), e))?
+        {
+            Some(status) => break status,
+            None if Instant::now() >= deadline => {
+                child.kill().ok();
+                child.wait().ok();
+                return Err(rterr!("{:?} did not finish in {} seconds, and was \
+                                   killed", command.get_program(),
+                                  timeout.as_secs_f64()));
+            },
+            None => std::thread::sleep(COMMAND_POLL_INTERVAL),
+        }
+    };
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+/// Run ImageMagick with `args`, in the time and resource limits of
+/// the config.
+fn runMagick<I, S>(args: I, config: &Configuration) -> Result<Output, Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let mut command = Command::new("magick");
+    command.args(args)
+        .env("MAGICK_MEMORY_LIMIT", &config.magick_memory_max)
+        .env("MAGICK_MAP_LIMIT", &config.magick_memory_max)
+        .env("MAGICK_DISK_LIMIT", &config.magick_disk_max)
+        .env("MAGICK_TIME_LIMIT", config.magick_timeout_sec.to_string());
+    runWithTimeout(&mut command, Duration::from_secs(config.magick_timeout_sec))
+}
+
+/// Resize `img` to fit in `size` pixels, and encode it to `output`.
+/// If there is an `xmp` file, it replaces the XMP of the image.
+fn resizeImage(img: &Path, output: &Path, size: u32, quality: i32,
+               xmp: Option<&Path>, config: &Configuration) -> Result<(), Error>
+{
+    let mut args = vec![img.to_str().ok_or_else(
+        || rterr!("Invalid image path: {:?}", img))?.to_owned()];
+    if let Some(xmp) = xmp
+    {
+        args.push(String::from("-profile"));
+        args.push(xmp.to_str().ok_or_else(
+            || rterr!("Invalid XMP path: {:?}", xmp))?.to_owned());
+    }
+    args.extend([
+        "-colorspace", "RGB", "-resize", &format!("{size}x{size}>"),
+        "-colorspace", "sRGB", "-quality", &quality.to_string(),
+        output.to_str().ok_or_else(
             || rterr!("Invalid image path: {:?}", img))?,
-          "-colorspace", "RGB", "-resize", &format!("{size}x{size}>"),
-          "-colorspace", "sRGB", "-quality", &quality.to_string(),
-          output.to_str().ok_or_else(
-              || rterr!("Invalid image path: {:?}", img))?,
-        ])
-        .status().map_err(|e| rterr!("Failed to run imagemagick: {}", e))?;
-    if status.success()
+    ].map(String::from));
+    let result = runMagick(args, config)?;
+    if result.status.success()
     {
         Ok(())
     }
     else
     {
-        Err(rterr!("Imagemagick failed."))
+        Err(rterr!("Imagemagick failed: {}",
+                   String::from_utf8_lossy(&result.stderr).trim()))
     }
 }
 
-fn probeImage(f: &Path) -> Result<ImageMetadata, Error>
+fn probeImage(f: &Path, config: &Configuration) -> Result<ImageMetadata, Error>
 {
-    let output = Command::new("magick").arg("identify").arg("-format")
-        .arg("%[fx:w]\n%[fx:h]\n")
-        .arg(f.to_str().ok_or_else(|| rterr!("Invalid image path: {:?}", f))?)
-        .output().map_err(|e| rterr!("Failed to run imagemagick: {}", e))?;
+    let output = runMagick(
+        ["identify", "-format", "%[fx:w]\n%[fx:h]\n",
+         f.to_str().ok_or_else(|| rterr!("Invalid image path: {:?}", f))?],
+        config)?;
     if !output.status.success()
     {
         if let Some(code) = output.status.code()
         {
-            return Err(rterr!("Identify failed with code {}.", code));
+            return Err(rterr!("Identify failed with code {}: {}", code,
+                              String::from_utf8_lossy(&output.stderr).trim()));
         }
         else
         {
@@ -101,14 +362,170 @@ fn probeImage(f: &Path) -> Result<ImageMetadata, Error>
     Ok(data)
 }
 
+/// The average color and the BlurHash of the `width` by `height`
+/// image at `f`, from a copy of it of `PLACEHOLDER_PIXEL_SIZE` pixels
+/// square.
+fn probePlaceholder(f: &Path, width: u32, height: u32, config: &Configuration) ->
+    Result<(String, String), Error>
+{
+    let size = PLACEHOLDER_PIXEL_SIZE;
+    let output = runMagick(
+        [f.to_str().ok_or_else(|| rterr!("Invalid image path: {:?}", f))?,
+         "-resize", &format!("{size}x{size}!"), "-colorspace", "sRGB",
+         "-depth", "8", "RGB:-"], config)?;
+    if !output.status.success()
+    {
+        return Err(rterr!("Imagemagick failed: {}",
+                          String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    if output.stdout.len() != size * size * 3
+    {
+        return Err(rterr!("Invalid pixels from imagemagick"));
+    }
+    // More components along the longer side.
+    let (x_components, y_components) = if width >= height { (4, 3) } else { (3, 4) };
+    Ok((blurhash::averageColor(&output.stdout, size, size),
+        blurhash::encode(&output.stdout, size, size, x_components, y_components)))
+}
+
+/// The perceptual hash of the image at `f`.
+fn probePHash(f: &Path, config: &Configuration) -> Result<u64, Error>
+{
+    let size = phash::PIXEL_SIZE;
+    let output = runMagick(
+        [f.to_str().ok_or_else(|| rterr!("Invalid image path: {:?}", f))?,
+         "-colorspace", "Gray", "-resize", &format!("{size}x{size}!"),
+         "-depth", "8", "GRAY:-"], config)?;
+    if !output.status.success()
+    {
+        return Err(rterr!("Imagemagick failed: {}",
+                          String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    if output.stdout.len() != size * size
+    {
+        return Err(rterr!("Invalid pixels from imagemagick"));
+    }
+    Ok(phash::hash(&output.stdout))
+}
+
+/// Parse a GPS coordinate in EXIF, which ImageMagick gives as
+/// degrees, minutes, and seconds in rationals, like `37/1, 46/1,
+/// 2973/100`. `reference` is N, S, E, or W.
+fn parseGpsCoordinate(value: &str, reference: &str) -> Option<f64>
+{
+    let mut degrees = 0.0;
+    let mut unit = 1.0;
+    for part in value.split(',').map(|p| p.trim())
+    {
+        let number: f64 = match part.split_once('/')
+        {
+            Some((n, d)) => {
+                let d: f64 = d.trim().parse().ok()?;
+                if d == 0.0
+                {
+                    return None;
+                }
+                n.trim().parse::<f64>().ok()? / d
+            },
+            None => part.parse().ok()?,
+        };
+        degrees += number / unit;
+        unit *= 60.0;
+    }
+    if unit == 1.0
+    {
+        return None;
+    }
+    match reference.trim()
+    {
+        "N" | "E" | "" => Some(degrees),
+        "S" | "W" => Some(-degrees),
+        _ => None,
+    }
+}
+
+/// The location in the EXIF of an image file, if there is one.
+fn probeLocation(f: &Path, config: &Configuration) -> Option<Location>
+{
+    let output = runMagick(
+        ["identify", "-format",
+         "%[EXIF:GPSLatitude]\n%[EXIF:GPSLatitudeRef]\n\
+          %[EXIF:GPSLongitude]\n%[EXIF:GPSLongitudeRef]\n", f.to_str()?],
+        config).ok()?;
+    if !output.status.success()
+    {
+        debug!("Failed to read the location of {:?}.", f);
+        return None;
+    }
+    let output = String::from_utf8_lossy(&output.stdout);
+    let mut lines = output.lines();
+    let latitude = parseGpsCoordinate(lines.next()?, lines.next()?)?;
+    let longitude = parseGpsCoordinate(lines.next()?, lines.next()?)?;
+    Location::new(latitude, longitude)
+}
+
+/// The subjects in an XMP packet, which are the `rdf:li` items of
+/// `dc:subject`.
+fn xmpSubjects(xmp: &str) -> Vec<String>
+{
+    let subject = match xmp.split_once("<dc:subject").and_then(
+        |(_, rest)| rest.split_once("</dc:subject>"))
+    {
+        Some((subject, _)) => subject,
+        None => return Vec::new(),
+    };
+    subject.split("<rdf:li").skip(1).filter_map(|item| {
+        let (_, text) = item.split_once('>')?;
+        let (text, _) = text.split_once("</rdf:li>")?;
+        Some(text.replace("&lt;", "<").replace("&gt;", ">")
+             .replace("&quot;", "\"").replace("&apos;", "'")
+             .replace("&amp;", "&"))
+    }).collect()
+}
+
+/// The IPTC keywords and XMP subjects of the first frame of an image
+/// file. ImageMagick joins the IPTC keywords with `;`.
+fn probeKeywords(f: &Path, config: &Configuration) -> Vec<String>
+{
+    let mut keywords = Vec::new();
+    let path = match f.to_str()
+    {
+        Some(path) => format!("{}[0]", path),
+        None => return keywords,
+    };
+    match runMagick(["identify", "-format", "%[IPTC:2:25]", &path], config)
+    {
+        Ok(output) if output.status.success() => keywords.extend(
+            String::from_utf8_lossy(&output.stdout).split(';')
+                .map(|k| k.to_owned())),
+        _ => debug!("Failed to read the IPTC keywords of {:?}.", f),
+    }
+    // This fails if there is no XMP.
+    if let Ok(output) = runMagick([path.as_str(), "XMP:-"], config)
+    {
+        if output.status.success()
+        {
+            keywords.extend(xmpSubjects(&String::from_utf8_lossy(&output.stdout)));
+        }
+    }
+    keywords
+}
+
+/// The error of an upload whose body can’t be read, e.g. because the
+/// client aborted it.
+pub fn brokenUpload(e: warp::Error) -> Error
+{
+    Error::HTTPStatus(StatusCode::BAD_REQUEST,
+                      format!("The upload is incomplete or malformed: {}", e))
+}
+
 pub async fn uploadPart(part: warp::multipart::Part) -> Result<Vec<u8>, Error>
 {
     let mut data: Vec<u8> = Vec::new();
     let mut buffers = part.stream();
     while let Some(buffer) = buffers.next().await
     {
-        let mut buffer = buffer.map_err(
-            |e| rterr!("Failed to acquire buffer from form data: {}", e))?;
+        let mut buffer = buffer.map_err(brokenUpload)?;
         while buffer.has_remaining()
         {
             let bytes = buffer.chunk();
@@ -133,6 +550,12 @@ pub struct RawImage
     pub path: PathBuf,
     pub hash: String,
     pub original_filename: String,
+    /// The license of the post, which is embedded in the full-size
+    /// image. None is the `default_license` of the config.
+    pub license: Option<String>,
+    /// The upload that the image came from, which is kept with the
+    /// image if it is quarantined.
+    pub upload: Option<Box<Upload>>,
 }
 
 impl UploadingImage
@@ -147,9 +570,8 @@ impl UploadingImage
         let orig_name = self.part.filename().map(|n| n.to_owned()).ok_or_else(
             || Error::HTTPStatus(StatusCode::BAD_REQUEST,
                                  String::from("No filename in upload")))?;
-        let temp_file = randomTempFilename(&config.image_dir)
-            .with_extension(Path::new(&orig_name).extension()
-                            .or(Some(OsStr::new(""))).unwrap());
+        // The extension is added once the type is known.
+        let temp_file = randomTempFilename(&config.image_dir);
         let mut f = match File::create(&temp_file)
         {
             Ok(f) => BufWriter::new(f),
@@ -158,6 +580,7 @@ impl UploadingImage
             },
         };
         let mut hasher = sha2::Sha256::new();
+        let mut size: u64 = 0;
         let mut buffers = self.part.stream();
         while let Some(buffer) = buffers.next().await
         {
@@ -168,11 +591,17 @@ impl UploadingImage
                     log_error!("Failed to remove temp file at {:?}.", temp_file);
                 }
             }
-            let mut buffer = buffer.map_err(
-                |e| rterr!("Failed to acquire buffer from form data: {}", e))?;
+            let mut buffer = buffer.map_err(brokenUpload)?;
             while buffer.has_remaining()
             {
                 let bytes = buffer.chunk();
+                size += bytes.len() as u64;
+                if size > config.image_bytes_max
+                {
+                    drop(f);
+                    std::fs::remove_file(&temp_file).ok();
+                    return Err(imageTooLarge(&orig_name, config));
+                }
                 hasher.update(bytes);
                 if let Err(e) = f.write_all(bytes)
                 {
@@ -186,21 +615,41 @@ impl UploadingImage
                 buffer.advance(bytes.len());
             }
         }
-
-        let hash = hasher.finalize();
-        // A full hex-encoded 256 bit hash is 64 characters. That’s
-        // pretty long. Here we just take the first half.
-        let byte_strs: Vec<_> = hash[..16].iter().map(|b| format!("{:02x}", b))
-            .collect();
+        f.flush().map_err(|e| rterr!("Failed to write temp file: {}", e))?;
+        drop(f);
 
         Ok(RawImage {
-            path: temp_file,
-            hash: byte_strs.join(""),
+            path: renameBySniffedType(&temp_file, &orig_name)?,
+            hash: hashString(hasher),
             original_filename: orig_name,
+            license: None,
+            upload: None,
         })
     }
 }
 
+/// The hex-encoded full SHA-256 digest of a file.
+pub fn fileDigest(path: &Path) -> Result<String, Error>
+{
+    let mut f = File::open(path).map_err(
+        |e| rterr!("Failed to open {:?}: {}", path, e))?;
+    let mut hasher = sha2::Sha256::new();
+    std::io::copy(&mut f, &mut hasher).map_err(
+        |e| rterr!("Failed to read {:?}: {}", path, e))?;
+    let hash = hasher.finalize();
+    Ok(hash.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn hashString(hasher: sha2::Sha256) -> String
+{
+    let hash = hasher.finalize();
+    // A full hex-encoded 256 bit hash is 64 characters. That’s
+    // pretty long. Here we just take the first half.
+    let byte_strs: Vec<_> = hash[..16].iter().map(|b| format!("{:02x}", b))
+        .collect();
+    byte_strs.join("")
+}
+
 /// A uploaded image file with resized version.
 pub struct ResizedImage
 {
@@ -210,10 +659,124 @@ pub struct ResizedImage
     pub path: PathBuf,
     pub hash: String,
     pub original_filename: String,
+    pub upload: Option<Box<Upload>>,
 }
 
 impl RawImage
 {
+    /// Save some image data that is already in memory (e.g. an email
+    /// attachment) to a temp file under the image directory, the same
+    /// way `UploadingImage::saveToTemp` does.
+    pub fn fromBytes(data: &[u8], filename: &str, config: &Configuration) ->
+        Result<Self, Error>
+    {
+        if data.len() as u64 > config.image_bytes_max
+        {
+            return Err(imageTooLarge(filename, config));
+        }
+        Self::fromReader(&mut &data[..], filename, config)
+    }
+
+    /// Like `fromBytes`, but the data is read from `reader` as it is
+    /// written, e.g. a file being extracted from an archive.
+    pub fn fromReader(reader: &mut dyn Read, filename: &str,
+                      config: &Configuration) -> Result<Self, Error>
+    {
+        let temp_file = randomTempFilename(&config.image_dir);
+        let mut hasher = sha2::Sha256::new();
+        let result = File::create(&temp_file).map_err(
+            |e| rterr!("Failed to open temp file: {}", e)).and_then(|f| {
+                let mut f = BufWriter::new(f);
+                let mut buffer = vec![0u8; 64 * 1024];
+                let mut size: u64 = 0;
+                loop
+                {
+                    let len = reader.read(&mut buffer).map_err(
+                        |e| rterr!("Failed to read {}: {}", filename, e))?;
+                    if len == 0
+                    {
+                        break;
+                    }
+                    size += len as u64;
+                    if size > config.image_bytes_max
+                    {
+                        return Err(imageTooLarge(filename, config));
+                    }
+                    hasher.update(&buffer[..len]);
+                    f.write_all(&buffer[..len]).map_err(
+                        |e| rterr!("Failed to write temp file: {}", e))?;
+                }
+                f.flush().map_err(|e| rterr!("Failed to write temp file: {}", e))
+            });
+        if let Err(e) = result
+        {
+            std::fs::remove_file(&temp_file).ok();
+            return Err(e);
+        }
+        let temp_file = renameBySniffedType(&temp_file, filename)?;
+        Ok(Self {
+            path: temp_file,
+            hash: hashString(hasher),
+            original_filename: filename.to_owned(),
+            license: None,
+            upload: None,
+        })
+    }
+
+    /// Like `process`, but on a thread for blocking work, so that
+    /// waiting for a slot and for ImageMagick doesn’t stall the async
+    /// runtime.
+    pub async fn processBlocking(self, config: &Configuration) ->
+        Result<Image, Error>
+    {
+        let config = config.clone();
+        tokio::task::spawn_blocking(move || self.process(&config)).await
+            .map_err(|e| rterr!("Image processing task failed: {}", e))?
+    }
+
+    /// Run the whole pipeline on the image, and return the image in
+    /// the library. This waits if too many images are being
+    /// processed.
+    pub fn process(self, config: &Configuration) -> Result<Image, Error>
+    {
+        let _slot = PIPELINE_SLOTS.acquire(config.pipelineJobsMax());
+        // Read the location before the original is gone.
+        let location = if config.geo.read_exif
+        {
+            probeLocation(&self.path, config)
+        }
+        else
+        {
+            None
+        };
+        let keywords = if config.import_keyword_tags
+        {
+            probeKeywords(&self.path, config)
+        }
+        else
+        {
+            Vec::new()
+        };
+        let mut image = self.resize(config)?
+            .makeThumbnail(config)?
+            .moveToLibrary(config)?
+            .makeRelativePath(config)?
+            .probeMetadata(config)?;
+        image.location = location;
+        image.keywords = keywords;
+        // The image is still good without the watermark and the
+        // alternates.
+        if let Err(e) = watermarkImage(&image, config)
+        {
+            log_error!("Failed to watermark {:?}: {}", image.path, e);
+        }
+        if let Err(e) = encodeAlternates(&image, config)
+        {
+            log_error!("Failed to encode alternates of {:?}: {}", image.path, e);
+        }
+        Ok(image)
+    }
+
     pub fn resize(self, config: &Configuration) -> Result<ResizedImage, Error>
     {
         let target_file = self.path.with_file_name(
@@ -221,11 +784,22 @@ impl RawImage
                     self.path.file_stem().unwrap().to_str().unwrap().to_owned(),
                     config.image_encoding.extension()));
 
-        if let Err(e) = resizeImage(
+        let license = effectiveLicense(self.license.as_deref(), config);
+        let xmp_file = randomTempFilename(&config.image_dir).with_extension("xmp");
+        if let Some(license) = &license
+        {
+            std::fs::write(&xmp_file, license.xmpPacket()).map_err(
+                |e| rterr!("Failed to write XMP file: {}", e))?;
+        }
+        let result = resizeImage(
             &self.path, &target_file, config.image_pixel_size,
-            config.image_encoding_quality)
+            config.image_encoding_quality,
+            license.as_ref().map(|_| xmp_file.as_path()), config);
+        std::fs::remove_file(&xmp_file).ok();
+        if let Err(e) = result
         {
-            std::fs::remove_file(&self.path).ok();
+            quarantine(&self.path, "resize", &self.original_filename,
+                       self.upload.as_deref(), &e, config);
             std::fs::remove_file(&target_file).ok();
             return Err(e);
         }
@@ -234,6 +808,7 @@ impl RawImage
             path: target_file,
             hash: self.hash,
             original_filename: self.original_filename,
+            upload: self.upload,
         })
     }
 }
@@ -247,6 +822,7 @@ pub struct ImageWithThumbnail
     pub thumbnail: PathBuf,
     pub hash: String,
     pub original_filename: String,
+    pub upload: Option<Box<Upload>>,
 }
 
 impl ResizedImage
@@ -258,10 +834,11 @@ impl ResizedImage
             .with_extension(config.image_encoding.extension());
         if let Err(e) = resizeImage(
             &self.uploaded, &thumb_file, config.thumb_pixel_size,
-            config.image_encoding_quality)
+            config.image_encoding_quality, None, config)
         {
+            quarantine(&self.uploaded, "thumbnail", &self.original_filename,
+                       self.upload.as_deref(), &e, config);
             std::fs::remove_file(&self.path).ok();
-            std::fs::remove_file(&self.uploaded).ok();
             std::fs::remove_file(&thumb_file).ok();
             return Err(e);
         }
@@ -270,7 +847,8 @@ impl ResizedImage
             path: self.path,
             thumbnail: thumb_file,
             hash: self.hash,
-            original_filename: self.original_filename
+            original_filename: self.original_filename,
+            upload: self.upload,
         })
     }
 }
@@ -280,10 +858,11 @@ impl ImageWithThumbnail
     pub fn moveToLibrary(self, config: &Configuration) ->
         Result<Self, Error>
     {
-        let subdir = Path::new(&config.image_dir).join(&self.hash[..1]);
+        let subdir = Path::new(&config.image_dir).join(
+            shardDir(&self.hash, config));
         if !subdir.exists()
         {
-            std::fs::create_dir(&subdir).map_err(
+            std::fs::create_dir_all(&subdir).map_err(
                 |_| rterr!("Failed to create sub dir"))?;
         }
         let ext = config.image_encoding.extension();
@@ -292,10 +871,12 @@ impl ImageWithThumbnail
         assert!(self.path.exists());
         if let Err(e) = std::fs::rename(&self.path, &image_file)
         {
-            std::fs::remove_file(&self.path).ok();
+            let e = rterr!("Failed to rename temp file: {}", e);
+            quarantine(&self.path, "move", &self.original_filename,
+                       self.upload.as_deref(), &e, config);
             std::fs::remove_file(&self.thumbnail).ok();
             std::fs::remove_file(&image_file).ok();
-            return Err(rterr!("Failed to rename temp file: {}", e));
+            return Err(e);
         }
         let thumb_file: PathBuf = subdir.join(
             format!("{}_t.{}", self.hash, ext));
@@ -303,73 +884,568 @@ impl ImageWithThumbnail
         debug!("Moving thumbnail {:?} --> {:?}...", self.thumbnail, thumb_file);
         if let Err(e) = std::fs::rename(&self.thumbnail, &thumb_file)
         {
-            std::fs::remove_file(&self.path).ok();
+            let e = rterr!("Failed to rename temp file: {}", e);
+            quarantine(&image_file, "move", &self.original_filename,
+                       self.upload.as_deref(), &e, config);
             std::fs::remove_file(&self.thumbnail).ok();
-            std::fs::remove_file(&image_file).ok();
             std::fs::remove_file(&thumb_file).ok();
-            return Err(rterr!("Failed to rename temp file: {}", e));
+            return Err(e);
         }
         Ok(Self {
             path: image_file,
             thumbnail: thumb_file,
             hash: self.hash,
-            original_filename: self.original_filename
+            original_filename: self.original_filename,
+            upload: self.upload,
         })
     }
 
     pub fn makeRelativePath(mut self, config: &Configuration) ->
         Result<Self, Error>
     {
+        let fail = |e: Error| {
+            quarantine(&self.path, "relative_path", &self.original_filename,
+                       self.upload.as_deref(), &e, config);
+            std::fs::remove_file(&self.thumbnail).ok();
+            e
+        };
         let full_path = self.path.canonicalize().map_err(
-            |e| {
-                std::fs::remove_file(&self.path).ok();
-                rterr!("Failed to canonicalize path {:?}: {}", self.path, e)
-            })?;
+            |e| fail(rterr!("Failed to canonicalize path {:?}: {}", self.path, e)))?;
         let video_dir = Path::new(&config.image_dir).canonicalize().map_err(
-            |e| {
-                std::fs::remove_file(&self.path).ok();
-                rterr!("Failed to canonicalize path {:?}: {}",
-                       config.image_dir, e)
-            })?;
+            |e| fail(rterr!("Failed to canonicalize path {:?}: {}",
+                            config.image_dir, e)))?;
         if !full_path.exists()
         {
-            std::fs::remove_file(&self.path).ok();
-            return Err(rterr!("Image not found: {:?}", full_path));
+            return Err(fail(rterr!("Image not found: {:?}", full_path)));
         }
         let path = full_path.strip_prefix(video_dir).map_err(
-            |_| {
-                std::fs::remove_file(&full_path).ok();
-                rterr!("Image is not in the image directory.")
-            })?;
+            |_| fail(rterr!("Image is not in the image directory.")))?;
         self.path = path.to_owned();
         Ok(self)
     }
 
     pub fn probeMetadata(self, config: &Configuration) -> Result<Image, Error>
     {
-        let metadata = match probeImage(&PathBuf::from(&config.image_dir)
-                                        .join(&self.path))
+        let full_path = PathBuf::from(&config.image_dir).join(&self.path);
+        let metadata = match probeImage(&full_path, config)
         {
             Ok(data) => data,
             Err(e) => {
-                std::fs::remove_file(&self.path).ok();
+                quarantine(&full_path, "probe", &self.original_filename,
+                           self.upload.as_deref(), &e, config);
                 std::fs::remove_file(&self.thumbnail).ok();
                 return Err(e);
             },
         };
+        let size = |path: &Path| std::fs::metadata(path).map(|m| m.len())
+            .map_err(|e| rterr!("Failed to stat {:?}: {}", path, e));
+        let image_size = size(&full_path)?;
+        let thumbnail_size = size(&self.thumbnail)?;
+        // The image is still good without a placeholder.
+        let (color, blurhash) = probePlaceholder(
+            &full_path, metadata.width, metadata.height, config)
+            .unwrap_or_else(|e| {
+                log_error!("Failed to make placeholder of {:?}: {}", full_path, e);
+                Default::default()
+            });
+        // Without a hash, the image is just not checked for duplicates.
+        let phash = probePHash(&full_path, config).map_err(|e| {
+            log_error!("Failed to hash {:?}: {}", full_path, e);
+        }).ok();
         Ok(Image {
             path: self.path,
             width: metadata.width,
             height: metadata.height,
+            size: image_size,
+            thumbnail_size,
+            color,
+            blurhash,
+            phash,
+            ..Default::default()
         })
     }
 }
 
+/// Regenerate the thumbnail of a image in the library from the
+/// full-size image, at `size` pixels.
+pub fn regenerateThumbnail(image: &Image, size: u32, config: &Configuration) ->
+    Result<(), Error>
+{
+    let source = imagePath(image, config);
+    let thumb_file = Path::new(&config.image_dir).join(image.thumbnail()?);
+    // Write to a temp file first, so that a failure does not destroy
+    // the existing thumbnail.
+    let temp_file = randomTempFilename(&config.image_dir)
+        .with_extension(thumb_file.extension().unwrap_or(OsStr::new("")));
+    if let Err(e) = resizeImage(&source, &temp_file, size,
+                                config.image_encoding_quality, None, config)
+    {
+        std::fs::remove_file(&temp_file).ok();
+        return Err(e);
+    }
+    std::fs::rename(&temp_file, &thumb_file).map_err(|e| {
+        std::fs::remove_file(&temp_file).ok();
+        rterr!("Failed to rename temp file: {}", e)
+    })
+}
+
+/// The full-size image of the thumbnail at `thumbnail` in the image
+/// dir, which is in the same dir with the same extension. None if
+/// `thumbnail` is not the path of a thumbnail.
+fn thumbnailSource(thumbnail: &Path) -> Option<PathBuf>
+{
+    if !thumbnail.components().all(|c| matches!(c, std::path::Component::Normal(_)))
+    {
+        return None;
+    }
+    let stem = thumbnail.file_stem()?.to_str()?.strip_suffix("_t")?;
+    if stem.is_empty() || stem.starts_with("temp-")
+    {
+        return None;
+    }
+    let mut source = thumbnail.with_file_name(stem);
+    if let Some(ext) = thumbnail.extension()
+    {
+        source.set_extension(ext);
+    }
+    Some(source)
+}
+
+/// Make the thumbnail at `thumbnail` in the image dir, which is
+/// missing, from its full-size image. Return the path of the
+/// thumbnail accessible from the CWD, or None if there is no image
+/// for it.
+pub fn regenerateMissingThumbnail(thumbnail: &Path, config: &Configuration) ->
+    Result<Option<PathBuf>, Error>
+{
+    let source = match thumbnailSource(thumbnail)
+    {
+        Some(source) => source,
+        None => return Ok(None),
+    };
+    let image = Image { path: source, ..Default::default() };
+    if !imagePath(&image, config).is_file()
+    {
+        return Ok(None);
+    }
+    let _slot = PIPELINE_SLOTS.acquire(config.pipelineJobsMax());
+    let thumb_file = Path::new(&config.image_dir).join(thumbnail);
+    // Another request may have made it while this one waited.
+    if !thumb_file.exists()
+    {
+        info!("Regenerating missing thumbnail {:?}...", thumb_file);
+        regenerateThumbnail(&image, config.thumb_pixel_size, config)?;
+    }
+    Ok(Some(thumb_file))
+}
+
+/// `text` as the text of a `caption:` or `label:` of ImageMagick,
+/// which would otherwise read a file for a leading `@`, and expand
+/// `%` and `\` escapes.
+fn magickText(text: &str) -> String
+{
+    let text = text.replace('\\', "\\\\").replace('%', "%%");
+    if text.starts_with('@')
+    {
+        format!("\\{}", text)
+    }
+    else
+    {
+        text
+    }
+}
+
+/// Render a PNG card of `width` by `height` pixels at `output`, with
+/// `text` filling most of it, and `footer` in a line at the bottom.
+/// The text is sized to fit. The file appears only when it is
+/// complete.
+pub fn renderCard(text: &str, footer: &str, width: u32, height: u32,
+                  output: &Path, config: &Configuration) -> Result<(), Error>
+{
+    const MARGIN: u32 = 60;
+    const FOOTER_HEIGHT: u32 = 48;
+    let dir = output.parent().ok_or_else(
+        || rterr!("Invalid card path: {:?}", output))?;
+    std::fs::create_dir_all(dir).map_err(
+        |e| rterr!("Failed to create {:?}: {}", dir, e))?;
+    let temp_file = randomTempFilename(dir).with_extension("png");
+    let inner_width = width - 2 * MARGIN;
+    let text_height = height - 3 * MARGIN - FOOTER_HEIGHT;
+    let _slot = PIPELINE_SLOTS.acquire(config.pipelineJobsMax());
+    let result = runMagick(
+        ["-size", &format!("{}x{}", width, height), "xc:#2f3542",
+         "-gravity", "northwest", "-background", "none", "-fill", "#ced6e0",
+         "(", "-size", &format!("{}x{}", inner_width, text_height),
+         &format!("caption:{}", magickText(text)), ")",
+         "-geometry", &format!("+{}+{}", MARGIN, MARGIN), "-composite",
+         "(", "-size", &format!("{}x{}", inner_width, FOOTER_HEIGHT),
+         "-fill", "#57606f", &format!("label:{}", magickText(footer)), ")",
+         "-geometry", &format!("+{}+{}", MARGIN, height - MARGIN - FOOTER_HEIGHT),
+         "-composite",
+         &format!("PNG:{}", temp_file.to_str().ok_or_else(
+             || rterr!("Invalid card path: {:?}", temp_file))?),
+        ], config);
+    match result
+    {
+        Ok(result) if result.status.success() => (),
+        Ok(result) => {
+            std::fs::remove_file(&temp_file).ok();
+            return Err(rterr!("Imagemagick failed: {}",
+                              String::from_utf8_lossy(&result.stderr).trim()));
+        },
+        Err(e) => {
+            std::fs::remove_file(&temp_file).ok();
+            return Err(e);
+        },
+    }
+    std::fs::rename(&temp_file, output).map_err(|e| {
+        std::fs::remove_file(&temp_file).ok();
+        rterr!("Failed to rename temp file: {}", e)
+    })
+}
+
+/// The alternate encodings of `image` and its thumbnail, and the
+/// watermarked copy of the image with its alternates, accessible from
+/// the CWD. The files may not exist.
+pub fn alternateFiles(image: &Image, config: &Configuration) ->
+    Result<Vec<PathBuf>, Error>
+{
+    let image_dir = Path::new(&config.image_dir);
+    let watermarked = image.watermarked()?;
+    let mut files = vec![image_dir.join(&watermarked)];
+    for encoding in &config.alternate_encodings
+    {
+        let (alt_image, alt_thumb) = image.alternate(encoding.extension())?;
+        files.push(image_dir.join(alt_image));
+        files.push(image_dir.join(alt_thumb));
+        files.push(image_dir.join(watermarked.with_extension(encoding.extension())));
+    }
+    Ok(files)
+}
+
+/// Make the watermarked copy of `image` with the `[watermark]` of the
+/// config, unless it already exists or there is no watermark. The
+/// watermark is as wide as `width_percent` of the image.
+pub fn watermarkImage(image: &Image, config: &Configuration) -> Result<(), Error>
+{
+    let watermark = match &config.watermark
+    {
+        Some(watermark) => watermark,
+        None => return Ok(()),
+    };
+    let image_dir = Path::new(&config.image_dir);
+    let output = image_dir.join(image.watermarked()?);
+    if output.exists()
+    {
+        return Ok(());
+    }
+    let source = imagePath(image, config);
+    let source_str = source.to_str().ok_or_else(
+        || rterr!("Invalid image path: {:?}", source))?;
+    let width = std::cmp::max(
+        probeImage(&source, config)?.width * watermark.width_percent / 100, 1);
+    let margin = (width / 10).to_string();
+    let mut overlay: Vec<String> = match (&watermark.image, &watermark.text)
+    {
+        (Some(file), _) => vec![file.clone(), String::from("-resize"),
+                                format!("{}x", width)],
+        (None, Some(text)) => vec![
+            String::from("-background"), String::from("none"),
+            String::from("-fill"), watermark.color.clone(),
+            String::from("-size"), format!("{}x", width),
+            format!("label:{}", magickText(text))],
+        (None, None) => return Err(rterr!("Watermark has no image or text")),
+    };
+    overlay.extend(["-alpha", "set", "-channel", "A", "-evaluate", "multiply"]
+                   .map(String::from));
+    overlay.push(watermark.opacity.to_string());
+    overlay.push(String::from("+channel"));
+
+    let temp_file = randomTempFilename(image_dir).with_extension(
+        image.path.extension().unwrap_or_default());
+    let temp_str = temp_file.to_str().ok_or_else(
+        || rterr!("Invalid image path: {:?}", temp_file))?;
+    let mut args = vec![source_str.to_owned(), String::from("(")];
+    args.extend(overlay);
+    args.extend([")", "-gravity", watermark.position.gravity(), "-geometry",
+                 &format!("+{}+{}", margin, margin), "-composite", "-quality",
+                 &config.image_encoding_quality.to_string(), temp_str]
+                .map(String::from));
+    match runMagick(&args, config)
+    {
+        Ok(result) if result.status.success() => (),
+        Ok(result) => {
+            std::fs::remove_file(&temp_file).ok();
+            return Err(rterr!("Imagemagick failed: {}",
+                              String::from_utf8_lossy(&result.stderr).trim()));
+        },
+        Err(e) => {
+            std::fs::remove_file(&temp_file).ok();
+            return Err(e);
+        },
+    }
+    std::fs::rename(&temp_file, &output).map_err(|e| {
+        std::fs::remove_file(&temp_file).ok();
+        rterr!("Failed to rename temp file: {}", e)
+    })
+}
+
+/// Make the missing watermarked copies of all images in the library,
+/// and their alternate encodings. Failures are logged, and do not
+/// stop the process.
+pub fn watermarkAllImages(data_manager: &data::Manager,
+                          config: &Configuration) -> Result<(), Error>
+{
+    if config.watermark.is_none()
+    {
+        return Err(rterr!("There is no [watermark] in the config"));
+    }
+    let mut failed = 0;
+    for path in data_manager.allImagePaths()?
+    {
+        let image = Image { path, ..Default::default() };
+        debug!("Watermarking {:?}...", image.path);
+        if let Err(e) = watermarkImage(&image, config)
+            .and_then(|_| encodeAlternates(&image, config))
+        {
+            log_error!("Failed to watermark {:?}: {}", image.path, e);
+            failed += 1;
+        }
+    }
+    if failed > 0
+    {
+        Err(rterr!("Failed to watermark {} images", failed))
+    }
+    else
+    {
+        Ok(())
+    }
+}
+
+/// Compute the perceptual hashes of the images in the library that
+/// don’t have one.
+pub fn hashAllImages(data_manager: &data::Manager, config: &Configuration) ->
+    Result<(), Error>
+{
+    let mut failed = 0;
+    for path in data_manager.imagesWithoutPHash()?
+    {
+        debug!("Hashing {:?}...", path);
+        let file = Path::new(&config.image_dir).join(&path);
+        match probePHash(&file, config)
+        {
+            Ok(hash) => data_manager.setImagePHash(&path, hash)?,
+            Err(e) => {
+                log_error!("Failed to hash {:?}: {}", path, e);
+                failed += 1;
+            },
+        }
+    }
+    if failed > 0
+    {
+        Err(rterr!("Failed to hash {} images", failed))
+    }
+    else
+    {
+        Ok(())
+    }
+}
+
+/// Remove the files of processed `image` that is not in any post: the
+/// image, its thumbnail, and the files derived from them.
+pub fn removeImageFiles(image: &Image, config: &Configuration)
+{
+    std::fs::remove_file(imagePath(image, config)).ok();
+    if let Ok(thumbnail) = image.thumbnail()
+    {
+        std::fs::remove_file(Path::new(&config.image_dir).join(thumbnail)).ok();
+    }
+    for file in alternateFiles(image, config).unwrap_or_default()
+    {
+        std::fs::remove_file(file).ok();
+    }
+}
+
+/// Make the files of `image` and its thumbnail in the
+/// `alternate_encodings` of the config, unless they already exist.
+/// The watermarked copy of the image gets them too, if it exists.
+/// Each file appears only when it is complete, so that an image is
+/// never offered in a format that is half written.
+pub fn encodeAlternates(image: &Image, config: &Configuration) ->
+    Result<(), Error>
+{
+    let image_dir = Path::new(&config.image_dir);
+    let source = imagePath(image, config);
+    let watermarked = image_dir.join(image.watermarked()?);
+    for encoding in &config.alternate_encodings
+    {
+        let (alt_image, alt_thumb) = image.alternate(encoding.extension())?;
+        let mut files = vec![(alt_image, config.image_pixel_size, &source),
+                             (alt_thumb, config.thumb_pixel_size, &source)];
+        if watermarked.exists()
+        {
+            files.push((image.watermarked()?.with_extension(encoding.extension()),
+                        config.image_pixel_size, &watermarked));
+        }
+        for (file, size, source) in files
+        {
+            let file = image_dir.join(file);
+            if file.exists()
+            {
+                continue;
+            }
+            let temp_file = randomTempFilename(image_dir)
+                .with_extension(encoding.extension());
+            if let Err(e) = resizeImage(source, &temp_file, size,
+                                        config.image_encoding_quality, None,
+                                        config)
+            {
+                std::fs::remove_file(&temp_file).ok();
+                return Err(e);
+            }
+            std::fs::rename(&temp_file, &file).map_err(|e| {
+                std::fs::remove_file(&temp_file).ok();
+                rterr!("Failed to rename temp file: {}", e)
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Make the missing alternate encodings of all images in the library.
+/// Failures are logged, and do not stop the process.
+pub fn encodeAllAlternates(data_manager: &data::Manager,
+                           config: &Configuration) -> Result<(), Error>
+{
+    let mut failed = 0;
+    for path in data_manager.allImagePaths()?
+    {
+        let image = Image { path, ..Default::default() };
+        debug!("Encoding alternates of {:?}...", image.path);
+        if let Err(e) = encodeAlternates(&image, config)
+        {
+            log_error!("Failed to encode alternates of {:?}: {}", image.path, e);
+            failed += 1;
+        }
+    }
+    if failed > 0
+    {
+        Err(rterr!("Failed to encode alternates of {} images", failed))
+    }
+    else
+    {
+        Ok(())
+    }
+}
+
+/// Regenerate the thumbnails of all images in the library. Failures
+/// are logged, and do not stop the process.
+pub fn regenerateAllThumbnails(size: u32, data_manager: &data::Manager,
+                               config: &Configuration) -> Result<(), Error>
+{
+    let posts = data_manager.getAllPosts()?;
+    let mut count = 0;
+    let mut failed = 0;
+    for post in posts
+    {
+        for image in &post.images
+        {
+            debug!("Regenerating thumbnail for {:?}...", image.path);
+            if let Err(e) = regenerateThumbnail(image, size, config)
+            {
+                log_error!("Failed to regenerate thumbnail for {:?}: {}",
+                           image.path, e);
+                failed += 1;
+            }
+            else
+            {
+                count += 1;
+            }
+        }
+    }
+    info!("Regenerated {} thumbnails, {} failed.", count, failed);
+    if failed > 0
+    {
+        Err(rterr!("Failed to regenerate {} thumbnails", failed))
+    }
+    else
+    {
+        Ok(())
+    }
+}
+
+/// Move all images and thumbnails in the library to the directories
+/// given by the current sharding config, and update their paths in
+/// the database.
+pub fn reshardLibrary(data_manager: &data::Manager, config: &Configuration) ->
+    Result<(), Error>
+{
+    let image_dir = Path::new(&config.image_dir);
+    let mut count = 0;
+    for path in data_manager.allImagePaths()?
+    {
+        let image = Image { path, ..Default::default() };
+        let hash = image.path.file_stem().and_then(|s| s.to_str()).ok_or_else(
+            || rterr!("Invalid image path: {:?}", image.path))?;
+        let filename = image.path.file_name().unwrap();
+        let new_image = Image {
+            path: shardDir(hash, config).join(filename),
+            ..Default::default()
+        };
+        if new_image.path == image.path
+        {
+            continue;
+        }
+        debug!("Moving {:?} --> {:?}...", image.path, new_image.path);
+        let new_dir = image_dir.join(new_image.path.parent().unwrap());
+        std::fs::create_dir_all(&new_dir).map_err(
+            |e| rterr!("Failed to create {:?}: {}", new_dir, e))?;
+        std::fs::rename(image_dir.join(&image.path),
+                        image_dir.join(&new_image.path)).map_err(
+            |e| rterr!("Failed to move {:?}: {}", image.path, e))?;
+        let mut files = vec![(image.thumbnail()?, new_image.thumbnail()?),
+                             (image.watermarked()?, new_image.watermarked()?)];
+        for encoding in &config.alternate_encodings
+        {
+            let ext = encoding.extension();
+            let (old_image, old_thumb) = image.alternate(ext)?;
+            let (new_alt_image, new_thumb) = new_image.alternate(ext)?;
+            files.push((old_image, new_alt_image));
+            files.push((old_thumb, new_thumb));
+            files.push((image.watermarked()?.with_extension(ext),
+                        new_image.watermarked()?.with_extension(ext)));
+        }
+        for (from, to) in files
+        {
+            let from = image_dir.join(from);
+            if from.exists()
+            {
+                std::fs::rename(&from, image_dir.join(to))
+                    .map_err(|e| rterr!("Failed to move {:?}: {}", from, e))?;
+            }
+        }
+        data_manager.setImagePath(&image.path, &new_image.path)?;
+        // Remove the old directories if they are now empty.
+        let mut dir = image.path.parent();
+        while let Some(d) = dir
+        {
+            if d.as_os_str().is_empty() ||
+                std::fs::remove_dir(image_dir.join(d)).is_err()
+            {
+                break;
+            }
+            dir = d.parent();
+        }
+        count += 1;
+    }
+    info!("Moved {} images.", count);
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests
 {
     use super::*;
-    use crate::data;
 
     struct FileDeleter
     {
@@ -420,6 +1496,111 @@ mod tests
         Ok(image_dir)
     }
 
+    #[test]
+    fn shardDirByConfig()
+    {
+        let mut config = Configuration::default();
+        assert_eq!(shardDir("abcdef", &config), Path::new("a"));
+        config.shard_levels = 2;
+        config.shard_width = 2;
+        assert_eq!(shardDir("abcdef", &config), Path::new("ab").join("cd"));
+        config.shard_levels = 0;
+        assert_eq!(shardDir("abcdef", &config), PathBuf::new());
+    }
+
+    #[test]
+    fn gpsCoordinatesAreParsed()
+    {
+        assert_eq!(parseGpsCoordinate("37/1, 46/1, 3000/100", "N"),
+                   Some(37.0 + 46.0 / 60.0 + 30.0 / 3600.0));
+        assert_eq!(parseGpsCoordinate("122/1,30/1,0/1", "W"), Some(-122.5));
+        assert_eq!(parseGpsCoordinate("", ""), None);
+        assert_eq!(parseGpsCoordinate("1/0, 2/1, 3/1", "N"), None);
+        assert_eq!(parseGpsCoordinate("1/1, 2/1, 3/1", "X"), None);
+    }
+
+    #[test]
+    fn xmpSubjectsAreFound()
+    {
+        let xmp = r#"<x:xmpmeta><rdf:RDF><rdf:Description>
+            <dc:subject><rdf:Bag><rdf:li>cat</rdf:li>
+            <rdf:li>Tom &amp; Jerry</rdf:li></rdf:Bag></dc:subject>
+            <lr:hierarchicalSubject><rdf:Bag><rdf:li>animal|cat</rdf:li>
+            </rdf:Bag></lr:hierarchicalSubject>
+            </rdf:Description></rdf:RDF></x:xmpmeta>"#;
+        assert_eq!(xmpSubjects(xmp), vec!["cat", "Tom & Jerry"]);
+        assert!(xmpSubjects("<x:xmpmeta/>").is_empty());
+    }
+
+    #[test]
+    fn reshardMovesImagesAndThumbnails() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let mut clean_up = FileDeleter::new();
+        let image_dir = uniqueTempDir()?;
+        clean_up.register(&image_dir);
+        let mut config = Configuration {
+            image_dir: image_dir.to_str().unwrap().to_owned(),
+            ..Default::default()
+        };
+        std::fs::create_dir_all(image_dir.join("a"))?;
+        std::fs::write(image_dir.join("a").join("abcd.jpg"), b"image")?;
+        std::fs::write(image_dir.join("a").join("abcd_t.jpg"), b"thumbnail")?;
+        let mut data_manager = data::Manager::newWithFilename(
+            image_dir.join("db.sqlite"));
+        data_manager.connect()?;
+        data_manager.init()?;
+        let mut post = crate::post::Post::new();
+        post.images = vec![Image {
+            path: Path::new("a").join("abcd.jpg"),
+            ..Default::default()
+        }];
+        data_manager.addPost(&post, None)?;
+
+        config.shard_levels = 2;
+        config.shard_width = 2;
+        reshardLibrary(&data_manager, &config)?;
+        let new_dir = image_dir.join("ab").join("cd");
+        assert_eq!(std::fs::read(new_dir.join("abcd.jpg"))?, b"image");
+        assert_eq!(std::fs::read(new_dir.join("abcd_t.jpg"))?, b"thumbnail");
+        assert!(!image_dir.join("a").exists());
+        assert_eq!(data_manager.allImagePaths()?,
+                   vec![Path::new("ab").join("cd").join("abcd.jpg")]);
+        Ok(())
+    }
+
+    #[test]
+    fn uploadsAreSniffed() -> Result<(), Box<dyn std::error::Error>>
+    {
+        assert_eq!(sniffImageType(b"\xff\xd8\xff\xe0\0\x10JFIF"), Some("jpg"));
+        assert_eq!(sniffImageType(b"RIFF\0\0\0\0WEBPVP8 "), Some("webp"));
+        assert_eq!(sniffImageType(b"\0\0\0\x1cftypavif"), Some("avif"));
+        assert_eq!(sniffImageType(b"\0\0\0\x1cftypisom"), None);
+        assert_eq!(sniffImageType(b"<!DOCTYPE html>"), None);
+        assert_eq!(sniffImageType(b""), None);
+
+        let mut clean_up = FileDeleter::new();
+        let image_dir = uniqueTempDir()?;
+        clean_up.register(&image_dir);
+        let config = Configuration {
+            image_dir: image_dir.to_str().ok_or(
+                rterr!("Invalid image dir"))?.to_owned(),
+            ..Default::default()
+        };
+        let png = std::fs::read("test-data/test.png")?;
+        let raw = RawImage::fromBytes(&png, "photo.jpg", &config)?;
+        clean_up.register(&raw.path);
+        assert_eq!(raw.path.extension(), Some(OsStr::new("png")));
+        assert!(matches!(
+            RawImage::fromBytes(b"<html></html>", "photo.jpg", &config),
+            Err(Error::HTTPStatus(StatusCode::UNSUPPORTED_MEDIA_TYPE, _))));
+        assert_eq!(std::fs::read_dir(&image_dir)?.count(), 1);
+        let config = Configuration { image_bytes_max: 10, ..config };
+        assert!(matches!(
+            RawImage::fromBytes(&png, "photo.png", &config),
+            Err(Error::HTTPStatus(StatusCode::PAYLOAD_TOO_LARGE, _))));
+        Ok(())
+    }
+
     #[test]
     fn postPipelineWontShrinkSmallImage() -> Result<(), Box<dyn std::error::Error>>
     {
@@ -429,6 +1610,7 @@ mod tests
         let mut config = Configuration::default();
         config.image_dir = image_dir.to_str().ok_or(
             rterr!("Invalid image dir"))?.to_owned();
+        config.data_dir = config.image_dir.clone();
         let temp_file = image_dir.join("test.png");
         std::fs::copy("test-data/test.png", &temp_file)?;
         clean_up.register(&temp_file);
@@ -436,6 +1618,8 @@ mod tests
             path: temp_file,
             hash: "12345".to_owned(),
             original_filename: "test.png".to_owned(),
+            license: None,
+            upload: None,
         };
         let mut data_manager = data::Manager::new(
             crate::sqlite_connection::Source::Memory);
@@ -465,6 +1649,7 @@ mod tests
         config.image_pixel_size = 256;
         config.image_dir = image_dir.to_str().ok_or(
             rterr!("Invalid image dir"))?.to_owned();
+        config.data_dir = config.image_dir.clone();
         let temp_file = image_dir.join("test.png");
         std::fs::copy("test-data/test.png", &temp_file)?;
         clean_up.register(&temp_file);
@@ -472,6 +1657,8 @@ mod tests
             path: temp_file,
             hash: "12345".to_owned(),
             original_filename: "test.png".to_owned(),
+            license: None,
+            upload: None,
         };
         let mut data_manager = data::Manager::new(
             crate::sqlite_connection::Source::Memory);
@@ -490,4 +1677,58 @@ mod tests
         assert_eq!(img.height, 189);
         Ok(())
     }
+
+    #[test]
+    fn thumbnailSourceIsFound()
+    {
+        assert_eq!(thumbnailSource(Path::new("ab/abcd_t.jpg")),
+                   Some(PathBuf::from("ab/abcd.jpg")));
+        assert_eq!(thumbnailSource(Path::new("abcd_t.avif")),
+                   Some(PathBuf::from("abcd.avif")));
+        assert_eq!(thumbnailSource(Path::new("ab/abcd.jpg")), None);
+        assert_eq!(thumbnailSource(Path::new("../abcd_t.jpg")), None);
+        assert_eq!(thumbnailSource(Path::new("/abcd_t.jpg")), None);
+        assert_eq!(thumbnailSource(Path::new("temp-1_t.jpg")), None);
+    }
+
+    #[test]
+    fn magickTextIsEscaped()
+    {
+        assert_eq!(magickText("A cat"), "A cat");
+        assert_eq!(magickText("@/etc/passwd"), "\\@/etc/passwd");
+        assert_eq!(magickText("100% \\n"), "100%% \\\\n");
+    }
+
+    #[test]
+    fn pipelineSlotsLimitJobs()
+    {
+        let slots = PipelineSlots::new();
+        let first = slots.acquire(2);
+        let _second = slots.acquire(2);
+        assert!(!slots.isFull(2, 1));
+        std::thread::scope(|scope| {
+            let waiter = scope.spawn(|| { slots.acquire(2); });
+            while slots.jobs.lock().unwrap().1 == 0
+            {
+                std::thread::yield_now();
+            }
+            assert!(slots.isFull(2, 1));
+            drop(first);
+            waiter.join().unwrap();
+        });
+        assert!(!slots.isFull(2, 0));
+        assert_eq!(*slots.jobs.lock().unwrap(), (1, 0));
+    }
+
+    #[test]
+    fn commandsAreKilledAfterTimeout()
+    {
+        let output = runWithTimeout(Command::new("echo").arg("hi"),
+                                    Duration::from_secs(10)).unwrap();
+        assert_eq!(output.stdout, b"hi\n");
+        let start = Instant::now();
+        assert!(runWithTimeout(Command::new("sleep").arg("10"),
+                               Duration::from_millis(100)).is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
 }
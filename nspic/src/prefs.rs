@@ -0,0 +1,161 @@
+// Preferences of a visitor, which are kept in a cookie and read on the
+// server, so that pages are rendered with them from the start.
+// Templates get them as `prefs`. A visitor sets a preference with
+// /prefs/<name>/<value>. The cookie has the preferences as
+// `name:value` pairs joined by `&`, e.g. `theme:light`, and unknown
+// or invalid pairs in it are ignored.
+//
+// The only preference for now is the color scheme, `theme`, which
+// is `dark`, `light`, or `auto` to follow the system.
+
+use serde::Serialize;
+use warp::Filter;
+use warp::Reply;
+use warp::http::status::StatusCode;
+use warp::reply::Response;
+
+use crate::error::Error;
+use crate::config::Configuration;
+use crate::utils::uriFromStr;
+use crate::app::{pathPrefix, urlFor};
+
+pub static PREFS_COOKIE: &str = "nspic-prefs";
+const PREFS_COOKIE_LIFE_TIME_SEC: u64 = 365 * 24 * 3600;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Theme
+{
+    #[default]
+    Dark,
+    Light,
+    Auto,
+}
+
+impl Theme
+{
+    pub fn parse(value: &str) -> Option<Self>
+    {
+        match value
+        {
+            "dark" => Some(Self::Dark),
+            "light" => Some(Self::Light),
+            "auto" => Some(Self::Auto),
+            _ => None,
+        }
+    }
+
+    pub fn value(&self) -> &'static str
+    {
+        match self
+        {
+            Self::Dark => "dark",
+            Self::Light => "light",
+            Self::Auto => "auto",
+        }
+    }
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug, Default)]
+pub struct Prefs
+{
+    pub theme: Theme,
+}
+
+impl Prefs
+{
+    /// Read the preferences in the value of the cookie.
+    pub fn parse(cookie: &str) -> Self
+    {
+        let mut prefs = Self::default();
+        for (name, value) in cookie.split('&').filter_map(|p| p.split_once(':'))
+        {
+            prefs.set(name, value);
+        }
+        prefs
+    }
+
+    /// The preferences as the value of the cookie.
+    pub fn value(&self) -> String
+    {
+        format!("theme:{}", self.theme.value())
+    }
+
+    /// Set the preference `name` to `value`. Return false if either
+    /// is invalid.
+    pub fn set(&mut self, name: &str, value: &str) -> bool
+    {
+        match name
+        {
+            "theme" => match Theme::parse(value)
+            {
+                Some(theme) => { self.theme = theme; true },
+                None => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// Put the preferences into a template context.
+    pub fn fillContext(&self, context: &mut tera::Context)
+    {
+        context.insert("prefs", self);
+    }
+}
+
+/// A filter that extracts the preferences of the request from the
+/// cookie.
+pub fn prefs() ->
+    impl Filter<Extract = (Prefs,), Error = std::convert::Infallible> + Clone
+{
+    warp::filters::cookie::optional(PREFS_COOKIE).map(
+        |cookie: Option<String>| cookie.as_deref().map(Prefs::parse)
+            .unwrap_or_default())
+}
+
+/// Set the preference `name` to `value` in the cookie, keeping the
+/// other ones, and go back to the page in `referer`.
+pub fn handleSetPref(name: &str, value: &str, cookie: Option<String>,
+                     referer: Option<String>, config: &Configuration) ->
+    Result<Response, Error>
+{
+    let mut prefs = cookie.as_deref().map(Prefs::parse).unwrap_or_default();
+    if !prefs.set(name, value)
+    {
+        return Err(Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()));
+    }
+    // Only the path of the referer is used, so that this can’t
+    // redirect to another site.
+    let back = referer.and_then(|r| r.parse::<warp::http::Uri>().ok())
+        .and_then(|uri| uri.path_and_query().map(|p| p.as_str().to_owned()))
+        .unwrap_or_else(
+            || pathPrefix(&config.serve_under_path) + &urlFor("index", ""));
+    let cookie = format!("{}={}; Max-Age={}; Path=/; SameSite=Lax",
+                         PREFS_COOKIE, prefs.value(),
+                         PREFS_COOKIE_LIFE_TIME_SEC);
+    Ok(warp::reply::with_header(
+        warp::redirect::see_other(uriFromStr(&back)?),
+        "Set-Cookie", cookie).into_response())
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn prefsAreParsed()
+    {
+        assert_eq!(Prefs::parse(""), Prefs::default());
+        assert_eq!(Prefs::parse("theme:light").theme, Theme::Light);
+        assert_eq!(Prefs::parse("size:big&theme:auto&theme:purple").theme,
+                   Theme::Auto);
+        let mut prefs = Prefs::default();
+        assert!(prefs.set("theme", "light"));
+        assert!(!prefs.set("theme", "purple"));
+        assert!(!prefs.set("size", "big"));
+        assert_eq!(Prefs::parse(&prefs.value()), prefs);
+    }
+}
@@ -0,0 +1,190 @@
+// A single page archive of the posts in a date range, with the full
+// size images, for printing. A range can have a lot of posts, so the
+// page is rendered and sent in chunks, instead of loading all posts
+// into memory first.
+
+use std::collections::HashMap;
+
+use futures_util::{stream, StreamExt};
+use log::error as log_error;
+use tera::Tera;
+use time::{Date, Month, OffsetDateTime, Time};
+use warp::http::status::StatusCode;
+use warp::hyper::Body;
+use warp::reply::Response;
+
+use crate::error::Error;
+use crate::config::Configuration;
+use crate::data;
+use crate::auth::validateSession;
+
+/// Number of posts rendered at a time.
+const CHUNK_SIZE: u64 = 20;
+
+/// Parse a YYYY-MM-DD date from the query into the Unix time at the
+/// start of that day in UTC.
+fn dayStart(value: &str) -> Result<i64, Error>
+{
+    let invalid = || Error::HTTPStatus(StatusCode::BAD_REQUEST,
+                                       format!("Invalid date: {}", value));
+    let parts: Vec<&str> = value.split('-').collect();
+    if parts.len() != 3
+    {
+        return Err(invalid());
+    }
+    let year: i32 = parts[0].parse().map_err(|_| invalid())?;
+    let month: u8 = parts[1].parse().map_err(|_| invalid())?;
+    let day: u8 = parts[2].parse().map_err(|_| invalid())?;
+    let month = Month::try_from(month).map_err(|_| invalid())?;
+    let date = Date::from_calendar_date(year, month, day)
+        .map_err(|_| invalid())?;
+    Ok(date.with_time(Time::MIDNIGHT).assume_utc().unix_timestamp())
+}
+
+/// The time range of the page from the `from` and `to` query
+/// parameters. Both days are included, and both are optional.
+fn timeRange(params: &HashMap<String, String>) -> Result<(i64, i64), Error>
+{
+    let from = match params.get("from").filter(|s| !s.is_empty())
+    {
+        Some(s) => dayStart(s)?,
+        None => OffsetDateTime::UNIX_EPOCH.unix_timestamp(),
+    };
+    let to = match params.get("to").filter(|s| !s.is_empty())
+    {
+        Some(s) => dayStart(s)? + 24 * 3600,
+        None => i64::MAX,
+    };
+    if from >= to
+    {
+        return Err(Error::HTTPStatus(StatusCode::BAD_REQUEST, String::from(
+            "The start of the range is after the end")));
+    }
+    Ok((from, to))
+}
+
+/// What is left to render of the page.
+enum Part
+{
+    Head,
+    /// Posts starting from this index.
+    Posts(u64),
+    Foot,
+    Done,
+}
+
+struct PrintState
+{
+    templates: Tera,
+    data_manager: data::Manager,
+    config: Configuration,
+    params: HashMap<String, String>,
+    range: (i64, i64),
+    include_hidden: bool,
+}
+
+impl PrintState
+{
+    fn render(&self, part: &str, posts: &[crate::post::Post]) ->
+        Result<String, Error>
+    {
+        let mut context = tera::Context::new();
+        context.insert("site_info", &self.config.site_info);
+        context.insert("part", part);
+        context.insert("posts", posts);
+        context.insert("from", self.params.get("from").map(|s| s.as_str())
+                       .unwrap_or(""));
+        context.insert("to", self.params.get("to").map(|s| s.as_str())
+                       .unwrap_or(""));
+        self.templates.render("print.html", &context).map_err(
+            |e| rterr!("Failed to render template: {}", e))
+    }
+
+    /// Render `part`, and return the rendered HTML with the next
+    /// part.
+    fn next(&self, part: Part) -> Result<(String, Part), Error>
+    {
+        match part
+        {
+            Part::Head => Ok((self.render("head", &[])?, Part::Posts(0))),
+            Part::Posts(start) => {
+                let posts = self.data_manager.getPostsInRange(
+                    self.range.0, self.range.1, self.include_hidden, start,
+                    CHUNK_SIZE)?;
+                let next = if (posts.len() as u64) < CHUNK_SIZE
+                {
+                    Part::Foot
+                }
+                else
+                {
+                    Part::Posts(start + CHUNK_SIZE)
+                };
+                Ok((self.render("posts", &posts)?, next))
+            },
+            Part::Foot => Ok((self.render("foot", &[])?, Part::Done)),
+            Part::Done => unreachable!(),
+        }
+    }
+}
+
+/// The print page. Anyone can see the public posts. The logged-in
+/// user also gets the unlisted and private ones.
+pub fn handlePrint(templates: Tera, params: HashMap<String, String>,
+                   data_manager: data::Manager, config: Configuration,
+                   token: Option<String>) -> Result<Response, Error>
+{
+    let range = timeRange(&params)?;
+    let include_hidden = validateSession(&token, &data_manager, &config)?;
+    let state = PrintState {
+        templates, data_manager, config, params, range, include_hidden,
+    };
+    // Render the head now, so that a bad template is a proper error
+    // instead of a broken page.
+    let (head, next) = state.next(Part::Head)?;
+    let chunks = stream::once(async move { Ok::<_, Error>(head) }).chain(
+        stream::unfold((state, next), |(state, part)| async move {
+            if let Part::Done = part
+            {
+                return None;
+            }
+            // The database is blocking.
+            match tokio::task::block_in_place(|| state.next(part))
+            {
+                Ok((html, next)) => Some((Ok(html), (state, next))),
+                Err(e) => {
+                    log_error!("Failed to render print page: {}", e);
+                    Some((Err(e), (state, Part::Done)))
+                },
+            }
+        }));
+    let mut response = Response::new(Body::wrap_stream(chunks));
+    response.headers_mut().insert(
+        "Content-Type", warp::http::HeaderValue::from_static(
+            "text/html; charset=utf-8"));
+    Ok(response)
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn printRangeIsParsed() -> Result<(), Error>
+    {
+        let params: HashMap<String, String> = [
+            ("from", "2023-01-01"), ("to", "2023-12-31"),
+        ].into_iter().map(|(k, v)| (k.to_owned(), v.to_owned())).collect();
+        assert_eq!(timeRange(&params)?, (1672531200, 1704067200));
+        assert_eq!(timeRange(&HashMap::new())?, (0, i64::MAX));
+
+        let mut params = params;
+        params.insert(String::from("to"), String::from("2022-12-31"));
+        assert!(timeRange(&params).is_err());
+        params.insert(String::from("to"), String::from("2023-02-30"));
+        assert!(timeRange(&params).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,288 @@
+// Running behind reverse proxies. A request from an address in
+// `trusted_proxies` of the config can tell the address of the client
+// with the Forwarded or X-Forwarded-For header, and the URL that the
+// client asked for with Forwarded, or X-Forwarded-Proto and
+// X-Forwarded-Host. The absolute URLs in the pages are then made
+// from that URL instead of `url_domain`. These headers are ignored in
+// requests from other addresses, because anyone can send them.
+
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use warp::Filter;
+use warp::http::HeaderMap;
+
+use crate::error::Error;
+use crate::config::Configuration;
+
+/// An address like `10.0.0.1`, or a network like `10.0.0.0/8`, and
+/// the length of its prefix.
+fn parseNetwork(value: &str) -> Option<(IpAddr, u8)>
+{
+    let (addr, prefix) = match value.split_once('/')
+    {
+        Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?,
+                                 Some(prefix.parse::<u8>().ok()?)),
+        None => (value.parse::<IpAddr>().ok()?, None),
+    };
+    let bits = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = prefix.unwrap_or(bits);
+    if prefix > bits
+    {
+        return None;
+    }
+    Some((addr, prefix))
+}
+
+/// Check the networks in `trusted_proxies` of the config.
+pub fn validate(networks: &[String]) -> Result<(), Error>
+{
+    match networks.iter().find(|n| parseNetwork(n).is_none())
+    {
+        Some(network) => Err(rterr!("Invalid address in trusted_proxies: {}",
+                                    network)),
+        None => Ok(()),
+    }
+}
+
+pub struct TrustedProxies
+{
+    networks: Vec<(IpAddr, u8)>,
+}
+
+impl TrustedProxies
+{
+    pub fn new(config: &Configuration) -> Self
+    {
+        Self {
+            networks: config.trusted_proxies.iter()
+                .filter_map(|n| parseNetwork(n)).collect(),
+        }
+    }
+
+    pub fn isEmpty(&self) -> bool
+    {
+        self.networks.is_empty()
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool
+    {
+        let ip = match ip
+        {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        self.networks.iter().any(|(network, prefix)| match (network, ip)
+        {
+            (IpAddr::V4(n), IpAddr::V4(ip)) =>
+                (u32::from(*n) ^ u32::from(ip)).checked_shr(32 - *prefix as u32)
+                .unwrap_or(0) == 0,
+            (IpAddr::V6(n), IpAddr::V6(ip)) =>
+                (u128::from(*n) ^ u128::from(ip)).checked_shr(128 - *prefix as u32)
+                .unwrap_or(0) == 0,
+            _ => false,
+        })
+    }
+}
+
+/// The values of the parameter `name` in the Forwarded header, one
+/// for each proxy, the one closest to the client first.
+fn forwardedValues(headers: &HeaderMap, name: &str) -> Vec<String>
+{
+    headers.get_all("forwarded").iter().filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .filter_map(|element| element.split(';').filter_map(
+            |pair| pair.split_once('='))
+                    .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+                    .map(|(_, value)| value.trim().trim_matches('"').to_owned()))
+        .collect()
+}
+
+/// The values in a header like X-Forwarded-For, the first one first.
+fn listValues(headers: &HeaderMap, name: &str) -> Vec<String>
+{
+    headers.get_all(name).iter().filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .map(|value| value.trim().to_owned())
+        .filter(|value| !value.is_empty()).collect()
+}
+
+/// The address in a value of X-Forwarded-For, or of `for` of
+/// Forwarded, which can have a port, and brackets around IPv6.
+fn hopAddress(value: &str) -> Option<IpAddr>
+{
+    if let Some(rest) = value.strip_prefix('[')
+    {
+        return rest.split(']').next()?.parse().ok();
+    }
+    value.parse().ok().or_else(
+        || value.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+/// The address of the client of a request from `remote`. The proxies
+/// append the address that they get a request from to the header, so
+/// the client is the last address that is not a trusted proxy.
+pub fn clientAddress(headers: &HeaderMap, remote: Option<SocketAddr>,
+                     proxies: &TrustedProxies) -> Option<SocketAddr>
+{
+    let remote = remote?;
+    if !proxies.contains(remote.ip())
+    {
+        return Some(remote);
+    }
+    let mut hops = forwardedValues(headers, "for");
+    if hops.is_empty()
+    {
+        hops = listValues(headers, "x-forwarded-for");
+    }
+    let mut client = remote;
+    for hop in hops.iter().rev()
+    {
+        match hopAddress(hop)
+        {
+            Some(ip) => {
+                client = SocketAddr::new(ip, 0);
+                if !proxies.contains(ip)
+                {
+                    break;
+                }
+            },
+            // E.g. an obfuscated identifier, after which nothing can
+            // be trusted.
+            None => break,
+        }
+    }
+    Some(client)
+}
+
+/// The beginning of the URL that the client asked for, like
+/// `https://example.org`, if a trusted proxy tells it. Without the
+/// protocol, it is the one of `url_domain`.
+pub fn origin(headers: &HeaderMap, remote: Option<SocketAddr>,
+              proxies: &TrustedProxies, config: &Configuration) ->
+    Option<String>
+{
+    if !proxies.contains(remote?.ip())
+    {
+        return None;
+    }
+    let first = |forwarded: &str, header: &str| {
+        forwardedValues(headers, forwarded).into_iter().next()
+            .or_else(|| listValues(headers, header).into_iter().next())
+    };
+    let host = first("host", "x-forwarded-host").filter(
+        |h| !h.is_empty() && h.chars().all(
+            |c| c.is_ascii_alphanumeric() || "-.:[]".contains(c)))?;
+    let proto = first("proto", "x-forwarded-proto")
+        .map(|p| p.to_ascii_lowercase())
+        .or_else(|| config.site_info.url_domain.split_once("://")
+                 .map(|(proto, _)| proto.to_owned()))
+        .filter(|p| p == "http" || p == "https")?;
+    Some(format!("{}://{}", proto, host))
+}
+
+/// A filter that extracts the address of the client.
+pub fn remote(proxies: Arc<TrustedProxies>) ->
+    impl Filter<Extract = (Option<SocketAddr>,), Error = Infallible> + Clone
+{
+    warp::header::headers_cloned().and(warp::addr::remote()).map(
+        move |headers: HeaderMap, remote: Option<SocketAddr>|
+        clientAddress(&headers, remote, &proxies))
+}
+
+/// A filter that extracts the config of the request, which has the
+/// `url_domain` that the client asked for.
+pub fn siteConfig(config: Configuration, proxies: Arc<TrustedProxies>) ->
+    impl Filter<Extract = (Arc<Configuration>,), Error = Infallible> + Clone
+{
+    let config = Arc::new(config);
+    warp::header::headers_cloned().and(warp::addr::remote()).map(
+        move |headers: HeaderMap, remote: Option<SocketAddr>|
+        match origin(&headers, remote, &proxies, &config)
+        {
+            Some(origin) if origin != config.site_info.url_domain => {
+                let mut site_config = Configuration::clone(&config);
+                site_config.site_info.url_domain = origin;
+                Arc::new(site_config)
+            },
+            _ => config.clone(),
+        })
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use warp::http::HeaderValue;
+
+    fn proxies(networks: &[&str]) -> TrustedProxies
+    {
+        TrustedProxies::new(&Configuration {
+            trusted_proxies: networks.iter().map(|n| n.to_string()).collect(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn networksAreMatched()
+    {
+        let proxies = proxies(&["127.0.0.1", "10.0.0.0/8", "fd00::/8"]);
+        for ip in ["127.0.0.1", "10.1.2.3", "fd12::1", "::ffff:10.0.0.1"]
+        {
+            assert!(proxies.contains(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["127.0.0.2", "11.0.0.1", "fe80::1"]
+        {
+            assert!(!proxies.contains(ip.parse().unwrap()), "{}", ip);
+        }
+        assert!(validate(&[String::from("0.0.0.0/0")]).is_ok());
+        assert!(validate(&[String::from("10.0.0.0/33")]).is_err());
+        assert!(validate(&[String::from("proxy")]).is_err());
+    }
+
+    #[test]
+    fn clientIsFoundBehindProxies()
+    {
+        let proxies = proxies(&["10.0.0.0/8"]);
+        let remote: Option<SocketAddr> = "10.0.0.1:1234".parse().ok();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for",
+                       HeaderValue::from_static("1.1.1.1, 203.0.113.5, 10.0.0.2"));
+        // The first address could be made up by the client.
+        assert_eq!(clientAddress(&headers, remote, &proxies),
+                   "203.0.113.5:0".parse().ok());
+        let stranger = "198.51.100.1:1234".parse().ok();
+        assert_eq!(clientAddress(&headers, stranger, &proxies), stranger);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("forwarded", HeaderValue::from_static(
+            "for=\"[2001:db8::1]:4711\";proto=https;host=pic.example.org"));
+        assert_eq!(clientAddress(&headers, remote, &proxies),
+                   "[2001:db8::1]:0".parse().ok());
+        let config = Configuration::default();
+        assert_eq!(origin(&headers, remote, &proxies, &config).as_deref(),
+                   Some("https://pic.example.org"));
+        assert_eq!(origin(&headers, stranger, &proxies, &config), None);
+    }
+
+    #[test]
+    fn originNeedsValidHost()
+    {
+        let proxies = proxies(&["127.0.0.1"]);
+        let remote = "127.0.0.1:1234".parse().ok();
+        let config = Configuration::default();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-host", HeaderValue::from_static("a.org"));
+        assert_eq!(origin(&headers, remote, &proxies, &config).as_deref(),
+                   Some("http://a.org"));
+        headers.insert("x-forwarded-proto", HeaderValue::from_static("HTTPS"));
+        assert_eq!(origin(&headers, remote, &proxies, &config).as_deref(),
+                   Some("https://a.org"));
+        headers.insert("x-forwarded-host",
+                       HeaderValue::from_static("a.org/evil?"));
+        assert_eq!(origin(&headers, remote, &proxies, &config), None);
+    }
+}
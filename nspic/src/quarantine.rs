@@ -0,0 +1,434 @@
+// Uploads that failed in the post pipeline. Instead of deleting the
+// files, they are kept under `<data_dir>/quarantine/<id>/` together
+// with a `diagnostic.json`, so that they can be retried or discarded
+// later from the admin page. An image from the upload form also keeps
+// the fields of its upload, so that a retry puts it in the post that
+// the upload made, or makes that post if none of the images made it.
+
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use log::error as log_error;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use warp::http::status::StatusCode;
+
+use crate::error::Error;
+use crate::config::Configuration;
+use crate::data;
+use crate::post::{Image, Visibility};
+use crate::post_pipeline::RawImage;
+use crate::app::{createPost, makePosts, UploadFields};
+
+const DIAGNOSTIC_FILE: &str = "diagnostic.json";
+
+#[derive(Serialize, Deserialize)]
+pub struct Diagnostic
+{
+    /// Name of the pipeline stage that failed.
+    pub stage: String,
+    /// The error, including the stderr of ImageMagick if it was
+    /// ImageMagick that failed.
+    pub error: String,
+    pub original_filename: String,
+    /// Name of the quarantined file in the quarantine directory.
+    pub file: String,
+    pub time: String,
+    /// None for an image that didn’t come from the upload form.
+    #[serde(default)]
+    pub upload: Option<Upload>,
+}
+
+/// The upload that a quarantined image came from.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Upload
+{
+    /// Shared by the images of one upload.
+    pub group: String,
+    /// Place of the image in the upload.
+    pub position: usize,
+    pub alt_text: Option<String>,
+    pub caption: Option<String>,
+    pub fields: UploadFields,
+    /// The post that the rest of the upload made, if it is not split.
+    pub post_id: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct QuarantinedUpload
+{
+    pub id: String,
+    pub diagnostic: Diagnostic,
+}
+
+fn quarantineDir(config: &Configuration) -> PathBuf
+{
+    Path::new(&config.data_dir).join("quarantine")
+}
+
+/// The directory of a quarantined upload. The ID comes from the
+/// client, so make sure it doesn’t point outside of the quarantine.
+fn entryDir(id: &str, config: &Configuration) -> Result<PathBuf, Error>
+{
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        return Err(Error::HTTPStatus(StatusCode::BAD_REQUEST,
+                                     format!("Invalid quarantine ID: {}", id)));
+    }
+    let dir = quarantineDir(config).join(id);
+    if dir.is_dir()
+    {
+        Ok(dir)
+    }
+    else
+    {
+        Err(Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()))
+    }
+}
+
+/// Rename a file, falling back to copying if the quarantine is on a
+/// different volume from the image directory.
+pub fn moveFile(from: &Path, to: &Path) -> Result<(), Error>
+{
+    if std::fs::rename(from, to).is_ok()
+    {
+        return Ok(());
+    }
+    std::fs::copy(from, to).map_err(
+        |e| rterr!("Failed to copy {:?} to {:?}: {}", from, to, e))?;
+    std::fs::remove_file(from).ok();
+    Ok(())
+}
+
+fn quarantineFile(file: &Path, stage: &str, original_filename: &str,
+                  upload: Option<&Upload>, error: &Error, config: &Configuration) ->
+    Result<String, Error>
+{
+    let id = format!("{}-{:08x}", OffsetDateTime::now_utc().unix_timestamp(),
+                     rand::random::<u32>());
+    let dir = quarantineDir(config).join(&id);
+    std::fs::create_dir_all(&dir).map_err(
+        |e| rterr!("Failed to create quarantine dir: {}", e))?;
+    let filename = file.file_name().ok_or_else(
+        || rterr!("Invalid file to quarantine: {:?}", file))?;
+    moveFile(file, &dir.join(filename))?;
+    let format = time::format_description::parse_borrowed::<2>(
+        "[year]-[month]-[day] [hour]:[minute]:[second] UTC").unwrap();
+    let diagnostic = Diagnostic {
+        stage: stage.to_owned(),
+        error: error.to_string(),
+        original_filename: original_filename.to_owned(),
+        file: filename.to_string_lossy().into_owned(),
+        time: OffsetDateTime::now_utc().format(&format)
+            .map_err(|e| rterr!("Failed to format time: {}", e))?,
+        upload: upload.cloned(),
+    };
+    writeDiagnostic(&dir, &diagnostic)?;
+    Ok(id)
+}
+
+fn writeDiagnostic(dir: &Path, diagnostic: &Diagnostic) -> Result<(), Error>
+{
+    let content = serde_json::to_vec_pretty(diagnostic).map_err(
+        |e| rterr!("Failed to serialize diagnostic: {}", e))?;
+    std::fs::write(dir.join(DIAGNOSTIC_FILE), content).map_err(
+        |e| rterr!("Failed to write diagnostic: {}", e))
+}
+
+fn readDiagnostic(dir: &Path) -> Result<Diagnostic, Error>
+{
+    let content = std::fs::read(dir.join(DIAGNOSTIC_FILE)).map_err(
+        |e| rterr!("Failed to read diagnostic: {}", e))?;
+    serde_json::from_slice(&content).map_err(
+        |e| rterr!("Invalid diagnostic: {}", e))
+}
+
+/// Record the post that the upload `group` made, which its
+/// quarantined images go into when they are retried.
+pub fn setPost(group: &str, post_id: i64, config: &Configuration) ->
+    Result<(), Error>
+{
+    for mut entry in list(config)?
+    {
+        if let Some(upload) = &mut entry.diagnostic.upload
+        {
+            if upload.group == group
+            {
+                upload.post_id = Some(post_id);
+                writeDiagnostic(&quarantineDir(config).join(&entry.id),
+                                &entry.diagnostic)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Move `file`, which failed at the pipeline stage `stage`, into the
+/// quarantine. If even that fails, the file is left where it is.
+pub fn quarantine(file: &Path, stage: &str, original_filename: &str,
+                  upload: Option<&Upload>, error: &Error, config: &Configuration)
+{
+    match quarantineFile(file, stage, original_filename, upload, error, config)
+    {
+        Ok(id) => warn!("Upload {} failed at stage {}, quarantined as {}.",
+                        original_filename, stage, id),
+        Err(e) => log_error!("Failed to quarantine {:?}: {}", file, e),
+    }
+}
+
+/// All quarantined uploads, newest first.
+pub fn list(config: &Configuration) -> Result<Vec<QuarantinedUpload>, Error>
+{
+    let dir = quarantineDir(config);
+    if !dir.exists()
+    {
+        return Ok(Vec::new());
+    }
+    let mut result = Vec::new();
+    for entry in std::fs::read_dir(&dir).map_err(
+        |e| rterr!("Failed to read quarantine dir: {}", e))?
+    {
+        let entry = entry.map_err(
+            |e| rterr!("Failed to read quarantine dir: {}", e))?;
+        let diagnostic = match std::fs::read(entry.path().join(DIAGNOSTIC_FILE))
+            .map_err(|e| e.to_string()).and_then(
+                |c| serde_json::from_slice(&c).map_err(|e| e.to_string()))
+        {
+            Ok(d) => d,
+            Err(e) => {
+                warn!("Invalid quarantine entry at {:?}: {}", entry.path(), e);
+                continue;
+            },
+        };
+        result.push(QuarantinedUpload {
+            id: entry.file_name().to_string_lossy().into_owned(),
+            diagnostic,
+        });
+    }
+    result.sort_by(|a, b| b.id.cmp(&a.id));
+    Ok(result)
+}
+
+/// Delete a quarantined upload for good.
+pub fn discard(id: &str, config: &Configuration) -> Result<(), Error>
+{
+    let dir = entryDir(id, config)?;
+    info!("Discarding quarantined upload {}...", id);
+    std::fs::remove_dir_all(&dir).map_err(
+        |e| rterr!("Failed to remove {:?}: {}", dir, e))
+}
+
+/// A quarantined image to retry, with what it was uploaded with, and
+/// the dir of its entry, which is removed once it is processed.
+fn takeOut(id: &str, config: &Configuration) ->
+    Result<(RawImage, Option<Upload>, PathBuf), Error>
+{
+    let dir = entryDir(id, config)?;
+    let diagnostic = readDiagnostic(&dir)?;
+    info!("Retrying quarantined upload {}...", id);
+    let data = std::fs::read(dir.join(&diagnostic.file)).map_err(
+        |e| rterr!("Failed to read quarantined file: {}", e))?;
+    let mut raw = RawImage::fromBytes(&data, &diagnostic.original_filename,
+                                      config)?;
+    if let Some(upload) = &diagnostic.upload
+    {
+        raw.license = upload.fields.license.clone();
+    }
+    raw.upload = diagnostic.upload.clone().map(Box::new);
+    Ok((raw, diagnostic.upload, dir))
+}
+
+/// Process a quarantined image from `takeOut`. If it fails, it is
+/// quarantined again under a new ID.
+fn processAgain(raw: RawImage, dir: &Path, config: &Configuration) ->
+    Result<Image, Error>
+{
+    let result = raw.process(config);
+    std::fs::remove_dir_all(dir).map_err(
+        |e| rterr!("Failed to remove {:?}: {}", dir, e))?;
+    result
+}
+
+/// Run a quarantined upload through the pipeline again, and put it in
+/// a post. An image of an upload that is not split is retried with
+/// the other quarantined images of the upload, and goes into the post
+/// of the upload, which is made if it doesn’t exist. If it fails
+/// again, it goes back into the quarantine with a new diagnostic.
+/// Return the ID of the post.
+pub fn retry(id: &str, data_manager: &data::Manager, config: &Configuration) ->
+    Result<i64, Error>
+{
+    let diagnostic = readDiagnostic(&entryDir(id, config)?)?;
+    let upload = match diagnostic.upload
+    {
+        Some(upload) => upload,
+        None => {
+            let (raw, _, dir) = takeOut(id, config)?;
+            let img = processAgain(raw, &dir, config)?;
+            return createPost(String::new(), None, Visibility::Public, false,
+                              None, None, vec![img], data_manager, config);
+        },
+    };
+    let ids: Vec<String> = if upload.fields.split
+    {
+        vec![id.to_owned()]
+    }
+    else
+    {
+        list(config)?.into_iter().filter(|entry| {
+            entry.diagnostic.upload.as_ref()
+                .map(|u| u.group == upload.group).unwrap_or(false)
+        }).map(|entry| entry.id).collect()
+    };
+    // Read them all before processing any, so that a bad entry
+    // doesn’t leave the others half done.
+    let entries = ids.iter().map(|id| takeOut(id, config))
+        .collect::<Result<Vec<_>, Error>>()?;
+    let mut images: Vec<(usize, Image)> = Vec::new();
+    let mut failure = None;
+    for (raw, upload, dir) in entries
+    {
+        // Every entry in the group has an upload.
+        let upload = upload.unwrap();
+        match processAgain(raw, &dir, config)
+        {
+            Ok(mut image) => {
+                upload.fields.finishImage(&mut image, upload.alt_text.as_deref(),
+                                          upload.caption.as_deref());
+                images.push((upload.position, image));
+            },
+            Err(e) => {failure.get_or_insert(e);},
+        }
+    }
+    images.sort_by_key(|(position, _)| *position);
+    let images: Vec<Image> = images.into_iter().map(|(_, image)| image).collect();
+    if images.is_empty()
+    {
+        return Err(failure.unwrap_or_else(|| rterr!("Nothing to retry")));
+    }
+    let post = match upload.post_id
+    {
+        Some(post_id) => data_manager.findPostByID(post_id)?,
+        None => None,
+    };
+    let post_id = match post
+    {
+        Some(post) => {
+            data_manager.addImagesToPost(post.id, &images)?;
+            post.id
+        },
+        None => makePosts(&upload.fields, images, data_manager, config)?[0],
+    };
+    match failure
+    {
+        Some(e) => {
+            if !upload.fields.split
+            {
+                setPost(&upload.group, post_id, config)?;
+            }
+            Err(e)
+        },
+        None => Ok(post_id),
+    }
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn quarantineListAndDiscard() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let data_dir = std::env::temp_dir().join(
+            "nspic-test-".to_owned() + &rand::random::<u64>().to_string());
+        std::fs::create_dir_all(&data_dir)?;
+        let config = Configuration {
+            data_dir: data_dir.to_str().unwrap().to_owned(),
+            ..Default::default()
+        };
+        let file = data_dir.join("temp-1.jpg");
+        std::fs::write(&file, b"hello")?;
+
+        quarantine(&file, "resize", "cat.jpg", None,
+                   &rterr!("Imagemagick failed: bad image"), &config);
+        let entries = list(&config);
+        let result = (|| -> Result<(), Box<dyn std::error::Error>> {
+            let entries = entries?;
+            assert!(!file.exists());
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].diagnostic.stage, "resize");
+            assert_eq!(entries[0].diagnostic.original_filename, "cat.jpg");
+            assert!(entries[0].diagnostic.error.contains("bad image"));
+            assert!(discard("../..", &config).is_err());
+            discard(&entries[0].id, &config)?;
+            assert!(list(&config)?.is_empty());
+            Ok(())
+        })();
+        std::fs::remove_dir_all(&data_dir).ok();
+        result
+    }
+
+    #[test]
+    fn retryKeepsTheUpload() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let dir = std::env::temp_dir().join(
+            "nspic-test-".to_owned() + &rand::random::<u64>().to_string());
+        std::fs::create_dir_all(&dir)?;
+        let config = Configuration {
+            data_dir: dir.to_str().unwrap().to_owned(),
+            image_dir: dir.join("images").to_str().unwrap().to_owned(),
+            ..Default::default()
+        };
+        std::fs::create_dir_all(&config.image_dir)?;
+        let file = dir.join("temp-1.png");
+        std::fs::copy("test-data/test.png", &file)?;
+        let upload = Upload {
+            group: String::from("abc"),
+            position: 1,
+            alt_text: Some(String::from("A cat")),
+            caption: None,
+            fields: UploadFields {
+                desc: String::from("Cats"),
+                visibility: Visibility::Private,
+                ..Default::default()
+            },
+            post_id: None,
+        };
+        quarantine(&file, "resize", "cat.png", Some(&upload),
+                   &rterr!("Imagemagick failed"), &config);
+        let mut data_manager = data::Manager::newWithFilename(dir.join("db.sqlite"));
+        let result = (|| -> Result<(), Box<dyn std::error::Error>> {
+            data_manager.connect()?;
+            data_manager.init()?;
+            setPost("abc", 10, &config)?;
+            let id = list(&config)?[0].id.clone();
+            match retry(&id, &data_manager, &config)
+            {
+                // Post 10 doesn’t exist, so the post of the upload is
+                // made again.
+                Ok(post_id) => {
+                    let post = data_manager.findPostByID(post_id)?.unwrap();
+                    assert_eq!(post.desc, "Cats");
+                    assert_eq!(post.visibility, Visibility::Private);
+                    assert_eq!(post.images[0].alt_text, "A cat");
+                },
+                // Without ImageMagick, it is quarantined again with
+                // the upload.
+                Err(_) => {
+                    let entries = list(&config)?;
+                    assert_eq!(entries.len(), 1);
+                    assert_ne!(entries[0].id, id);
+                    let upload = entries[0].diagnostic.upload.as_ref().unwrap();
+                    assert_eq!(upload.fields.desc, "Cats");
+                    assert_eq!(upload.post_id, Some(10));
+                },
+            }
+            Ok(())
+        })();
+        std::fs::remove_dir_all(&dir).ok();
+        result
+    }
+}
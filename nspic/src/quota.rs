@@ -0,0 +1,138 @@
+// The disk quota of the image library. The usage is the sum of the
+// sizes of the images and thumbnails in the database, which are
+// recorded when the images are processed. Alternate encodings are not
+// counted.
+
+use serde::Serialize;
+use warp::http::status::StatusCode;
+
+use crate::error::Error;
+use crate::config::Configuration;
+use crate::data;
+use crate::post::Image;
+use crate::post_pipeline::removeImageFiles;
+
+#[derive(Serialize)]
+pub struct Usage
+{
+    pub bytes_used: u64,
+    /// None if there is no quota.
+    pub bytes_max: Option<u64>,
+}
+
+/// A byte size for people, like “1.5 GiB”.
+pub fn formatBytes(bytes: u64) -> String
+{
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024
+    {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1
+    {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+pub fn usage(data_manager: &data::Manager, config: &Configuration) ->
+    Result<Usage, Error>
+{
+    Ok(Usage {
+        bytes_used: data_manager.libraryBytes()?,
+        bytes_max: config.library_bytes_max,
+    })
+}
+
+/// Return an error if adding `incoming` bytes to the library would
+/// exceed the quota.
+pub fn check(incoming: u64, data_manager: &data::Manager,
+             config: &Configuration) -> Result<(), Error>
+{
+    let max = match config.library_bytes_max
+    {
+        Some(max) => max,
+        None => return Ok(()),
+    };
+    let used = data_manager.libraryBytes()?;
+    if used.saturating_add(incoming) > max
+    {
+        let mut message = format!("The library is full: {} of {} is used",
+                                  formatBytes(used), formatBytes(max));
+        if incoming > 0
+        {
+            message += &format!(", and the upload needs {} more",
+                                formatBytes(incoming));
+        }
+        return Err(Error::HTTPStatus(StatusCode::INSUFFICIENT_STORAGE, message));
+    }
+    Ok(())
+}
+
+/// Check that processed `images` fit in the quota. If they don’t,
+/// their files are removed, because they won’t be in any post.
+pub fn admit(images: &[Image], data_manager: &data::Manager,
+             config: &Configuration) -> Result<(), Error>
+{
+    let incoming = images.iter().map(|img| img.size + img.thumbnail_size).sum();
+    let result = check(incoming, data_manager, config);
+    if result.is_err()
+    {
+        for image in images
+        {
+            removeImageFiles(image, config);
+        }
+    }
+    result
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::path::PathBuf;
+    use crate::post::Post;
+
+    #[test]
+    fn bytesAreFormatted()
+    {
+        assert_eq!(formatBytes(1000), "1000 B");
+        assert_eq!(formatBytes(1536), "1.5 KiB");
+        assert_eq!(formatBytes(5 << 30), "5.0 GiB");
+    }
+
+    #[test]
+    fn checkEnforcesQuota() -> Result<(), Error>
+    {
+        // Each connection to an in-memory database has its own.
+        let db = std::env::temp_dir().join(
+            format!("nspic-test-{}.sqlite", rand::random::<u64>()));
+        let mut data_manager = data::Manager::new(
+            crate::sqlite_connection::Source::File(db.clone()));
+        data_manager.connect()?;
+        data_manager.init()?;
+        let mut post = Post::new();
+        post.images = vec![Image {
+            path: PathBuf::from("a/abc.jpg"),
+            size: 700,
+            thumbnail_size: 100,
+            ..Default::default()
+        }];
+        data_manager.addPost(&post, None)?;
+
+        let mut config = Configuration::default();
+        assert_eq!(usage(&data_manager, &config)?.bytes_used, 800);
+        assert!(check(1 << 40, &data_manager, &config).is_ok());
+        config.library_bytes_max = Some(1000);
+        assert!(check(200, &data_manager, &config).is_ok());
+        assert!(matches!(check(201, &data_manager, &config),
+                         Err(Error::HTTPStatus(StatusCode::INSUFFICIENT_STORAGE, _))));
+        std::fs::remove_file(&db).ok();
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct Bucket
+{
+    tokens: f64,
+    last_update: Instant,
+}
+
+/// A token-bucket rate limiter. Each client (identified by a string,
+/// e.g. IP address or API key) gets a bucket, which is refilled at
+/// the configured rate, and can hold at most a minute’s worth of
+/// requests.
+pub struct RateLimiter
+{
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+/// Don’t let the buckets grow indefinitely. When there are this many
+/// buckets, full ones are dropped.
+const MAX_BUCKETS: usize = 10000;
+
+impl RateLimiter
+{
+    pub fn new() -> Self
+    {
+        Self { buckets: Mutex::new(HashMap::new()) }
+    }
+
+    /// Take a token from the bucket of `client`. If the bucket is
+    /// empty, return how long until the next token is available.
+    pub fn check(&self, client: &str, per_minute: u32) -> Result<(), Duration>
+    {
+        self.checkAt(client, per_minute, Instant::now())
+    }
+
+    fn checkAt(&self, client: &str, per_minute: u32, now: Instant) ->
+        Result<(), Duration>
+    {
+        if per_minute == 0
+        {
+            return Err(Duration::from_secs(60));
+        }
+        let capacity = per_minute as f64;
+        let rate = capacity / 60.0;
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS
+        {
+            buckets.retain(|_, b| b.tokens + rate * now.duration_since(
+                b.last_update).as_secs_f64() < capacity);
+        }
+        let bucket = buckets.entry(client.to_owned()).or_insert(Bucket {
+            tokens: capacity,
+            last_update: now,
+        });
+        let elapsed = now.duration_since(bucket.last_update).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.last_update = now;
+        if bucket.tokens >= 1.0
+        {
+            bucket.tokens -= 1.0;
+            Ok(())
+        }
+        else
+        {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn limitAndRefill()
+    {
+        let limiter = RateLimiter::new();
+        let start = Instant::now();
+        assert!(limiter.checkAt("a", 2, start).is_ok());
+        assert!(limiter.checkAt("a", 2, start).is_ok());
+        let wait = limiter.checkAt("a", 2, start).unwrap_err();
+        assert_eq!(wait.as_secs(), 30);
+        // Other clients are not affected.
+        assert!(limiter.checkAt("b", 2, start).is_ok());
+        assert!(limiter.checkAt("a", 2, start + Duration::from_secs(30)).is_ok());
+        assert!(limiter.checkAt("a", 2, start + Duration::from_secs(30))
+                .is_err());
+    }
+}
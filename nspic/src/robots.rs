@@ -0,0 +1,87 @@
+// /robots.txt, generated from the `robots` section of the config.
+
+use std::fmt::Write;
+
+use warp::Reply;
+use warp::reply::Response;
+
+use crate::config::Configuration;
+use crate::app::{absoluteUrl, pathPrefix};
+
+/// User agents of crawlers that collect training data for AI models.
+const AI_CRAWLERS: &[&str] = &[
+    "GPTBot", "ChatGPT-User", "OAI-SearchBot", "CCBot", "ClaudeBot",
+    "anthropic-ai", "Google-Extended", "Applebot-Extended", "PerplexityBot",
+    "Bytespider", "Amazonbot", "meta-externalagent", "cohere-ai",
+    "Diffbot", "ImagesiftBot", "Omgilibot",
+];
+
+fn robotsTxt(config: &Configuration) -> String
+{
+    let robots = &config.robots;
+    let prefix = pathPrefix(&config.serve_under_path);
+    let mut text = String::new();
+    if robots.block_ai_crawlers
+    {
+        for agent in AI_CRAWLERS
+        {
+            writeln!(text, "User-agent: {}", agent).unwrap();
+        }
+        text.push_str("Disallow: /\n\n");
+    }
+    text.push_str("User-agent: *\n");
+    if robots.allow_crawling
+    {
+        for path in &robots.disallow
+        {
+            writeln!(text, "Disallow: {}{}", prefix, path).unwrap();
+        }
+        if robots.disallow.is_empty()
+        {
+            // An empty Disallow allows everything.
+            text.push_str("Disallow:\n");
+        }
+        writeln!(text, "\nSitemap: {}", absoluteUrl("sitemap", "", config))
+            .unwrap();
+    }
+    else
+    {
+        text.push_str("Disallow: /\n");
+    }
+    text
+}
+
+pub fn handleRobots(config: &Configuration) -> Response
+{
+    warp::reply::with_header(robotsTxt(config), "Content-Type",
+                             "text/plain; charset=utf-8").into_response()
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn robotsFollowConfig()
+    {
+        let mut config = Configuration::default();
+        config.site_info.url_domain = String::from("https://example.org");
+        config.serve_under_path = String::from("/pic");
+        config.robots.disallow = vec![String::from("/admin")];
+        assert_eq!(robotsTxt(&config),
+                   "User-agent: *\nDisallow: /pic/admin\n\n\
+                    Sitemap: https://example.org/pic/sitemap.xml\n");
+
+        config.robots.block_ai_crawlers = true;
+        let text = robotsTxt(&config);
+        assert!(text.starts_with("User-agent: GPTBot\n"));
+        assert!(text.contains("User-agent: CCBot\n"));
+
+        config.robots.block_ai_crawlers = false;
+        config.robots.allow_crawling = false;
+        assert_eq!(robotsTxt(&config), "User-agent: *\nDisallow: /\n");
+    }
+}
@@ -0,0 +1,103 @@
+// Announcing scheduled posts. The images of a scheduled post are
+// processed when it is submitted, and the post becomes visible by
+// itself once its time comes, because the listings compare the upload
+// time with the current time. Pages are rendered on each request, so
+// there is nothing else to prepare. What’s left to do at publish time
+// is to queue the deliveries to the webhook and other services, which
+// is done here.
+
+use std::time::Duration;
+
+use time::OffsetDateTime;
+use log::info;
+use log::error as log_error;
+
+use crate::error::Error;
+use crate::config::Configuration;
+use crate::data;
+use crate::delivery;
+
+/// How often to look for scheduled posts that went live.
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Announce the scheduled posts that went live since the last check.
+/// Return the number of announced posts.
+pub fn announceDuePosts(data_manager: &data::Manager, config: &Configuration)
+                        -> Result<usize, Error>
+{
+    let mut count = 0;
+    for post in data_manager.getDueScheduledPosts()?
+    {
+        if data_manager.clearScheduled(post.id)?
+        {
+            info!("Scheduled post {} is live.", post.id);
+            delivery::enqueue(&post, data_manager, config);
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// The publish time of a post added to the queue, which is
+/// `queue_interval_sec` after the newest post. None if that is not in
+/// the future, in which case the post goes live now.
+pub fn nextQueueSlot(data_manager: &data::Manager, config: &Configuration) ->
+    Result<Option<OffsetDateTime>, Error>
+{
+    let now = OffsetDateTime::now_utc();
+    Ok(data_manager.latestPostTime()?
+       .map(|t| t + time::Duration::seconds(config.queue_interval_sec as i64))
+       .filter(|t| t > &now))
+}
+
+/// Check for scheduled posts periodically in the background.
+pub fn spawn(data_manager: data::Manager, config: Configuration)
+{
+    std::thread::spawn(move || {
+        loop
+        {
+            if let Err(e) = announceDuePosts(&data_manager, &config)
+            {
+                log_error!("Failed to announce scheduled posts: {}", e);
+            }
+            std::thread::sleep(CHECK_INTERVAL);
+        }
+    });
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::post::Post;
+
+    #[test]
+    fn queuedPostsAreSpacedOut() -> Result<(), Error>
+    {
+        // Each connection to an in-memory database has its own.
+        let db = std::env::temp_dir().join(
+            format!("nspic-test-{}.sqlite", rand::random::<u64>()));
+        let mut data_manager = data::Manager::new(
+            crate::sqlite_connection::Source::File(db.clone()));
+        data_manager.connect()?;
+        data_manager.init()?;
+        let config = Configuration::default();
+        assert!(nextQueueSlot(&data_manager, &config)?.is_none());
+
+        let mut post = Post::new();
+        post.upload_time = OffsetDateTime::now_utc() - time::Duration::hours(30);
+        data_manager.addPost(&post, None)?;
+        assert!(nextQueueSlot(&data_manager, &config)?.is_none());
+
+        post.upload_time = OffsetDateTime::now_utc() + time::Duration::hours(1);
+        post.scheduled = true;
+        data_manager.addPost(&post, None)?;
+        assert_eq!(nextQueueSlot(&data_manager, &config)?,
+                   Some(post.upload_time.replace_nanosecond(0).unwrap()
+                        + time::Duration::days(1)));
+        std::fs::remove_file(&db).ok();
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+// Security headers of the HTML pages, e.g. Content-Security-Policy,
+// which tell browsers what a page is allowed to do. Other responses,
+// e.g. images and feeds, are left alone.
+
+use warp::{Filter, Reply};
+use warp::filters::BoxedFilter;
+use warp::http::HeaderValue;
+use warp::http::header;
+use warp::reply::Response;
+
+use crate::config::SecurityHeadersConfig;
+
+fn isHtml(response: &Response) -> bool
+{
+    response.headers().get(header::CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .map(|t| t.starts_with("text/html")).unwrap_or(false)
+}
+
+/// Add the headers in `config` to `response` if it is an HTML page.
+/// Headers that the response already has are kept.
+fn addHeaders(response: &mut Response, config: &SecurityHeadersConfig)
+{
+    if !isHtml(response)
+    {
+        return;
+    }
+    let no_sniff = if config.no_sniff { "nosniff" } else { "" };
+    let headers = [
+        (header::CONTENT_SECURITY_POLICY, config.content_security_policy.as_str()),
+        (header::REFERRER_POLICY, config.referrer_policy.as_str()),
+        (header::X_FRAME_OPTIONS, config.frame_options.as_str()),
+        (header::X_CONTENT_TYPE_OPTIONS, no_sniff),
+    ];
+    for (name, value) in headers
+    {
+        if value.is_empty() || response.headers().contains_key(&name)
+        {
+            continue;
+        }
+        if let Ok(value) = HeaderValue::from_str(value)
+        {
+            response.headers_mut().insert(name, value);
+        }
+    }
+}
+
+pub fn wrap(route: BoxedFilter<(Response,)>, config: SecurityHeadersConfig) ->
+    BoxedFilter<(Response,)>
+{
+    route.map(move |mut response: Response| {
+        addHeaders(&mut response, &config);
+        response
+    }).map(Reply::into_response).boxed()
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn onlyPagesGetHeaders()
+    {
+        let config = SecurityHeadersConfig {
+            frame_options: String::new(),
+            ..Default::default()
+        };
+        let mut page = warp::reply::html("<p>hi</p>").into_response();
+        addHeaders(&mut page, &config);
+        assert_eq!(page.headers()[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(page.headers()[header::REFERRER_POLICY],
+                   "strict-origin-when-cross-origin");
+        assert!(page.headers()[header::CONTENT_SECURITY_POLICY].to_str()
+                .unwrap().starts_with("default-src 'self';"));
+        assert!(!page.headers().contains_key(header::X_FRAME_OPTIONS));
+
+        let mut feed = warp::reply::with_header(
+            "<feed/>", "Content-Type", "application/atom+xml").into_response();
+        addHeaders(&mut feed, &config);
+        assert!(!feed.headers().contains_key(header::CONTENT_SECURITY_POLICY));
+    }
+}
@@ -0,0 +1,84 @@
+// Letting the reverse proxy send the image files. With `[sendfile]`
+// in the config, the response of an image has no body, but a
+// X-Accel-Redirect header for nginx, or X-Sendfile for Apache and
+// lighttpd, with the path of the file, and the proxy sends the file
+// itself. This also applies to the thumbnails and cards that are made
+// on request. For nginx, `prefix` is a location with `internal;` and
+// an `alias` of the image dir.
+
+use std::path::Path;
+
+use warp::http::HeaderValue;
+use warp::reply::Response;
+
+use crate::config::{Configuration, SendfileHeader};
+use crate::http_cache;
+
+/// What the header tells the proxy for the image at `path` in the
+/// image dir.
+fn target(path: &Path, header: SendfileHeader, prefix: Option<&str>,
+          config: &Configuration) -> Option<String>
+{
+    let path = path.to_str()?;
+    let prefix = match (header, prefix)
+    {
+        (_, Some(prefix)) => prefix.to_owned(),
+        (SendfileHeader::XSendfile, None) =>
+            std::fs::canonicalize(&config.image_dir).ok()?.to_str()?.to_owned(),
+        (SendfileHeader::XAccelRedirect, None) => return None,
+    };
+    Some(format!("{}/{}", prefix.trim_end_matches('/'), path))
+}
+
+/// The response of the image at `path` in the image dir that the
+/// proxy sends, or None if this is not configured.
+pub fn response(path: &Path, config: &Configuration) -> Option<Response>
+{
+    let sendfile = config.sendfile.as_ref()?;
+    let target = target(path, sendfile.header, sendfile.prefix.as_deref(),
+                        config)?;
+    let header = match sendfile.header
+    {
+        SendfileHeader::XAccelRedirect => "x-accel-redirect",
+        SendfileHeader::XSendfile => "x-sendfile",
+    };
+    Some(http_cache::delegatedImage(header, HeaderValue::from_str(&target).ok()?,
+                                    path))
+}
+
+/// The response of a file in the image dir, which is at `file` from
+/// the CWD, like a thumbnail that was just made, that the proxy sends.
+pub fn fileResponse(file: &Path, config: &Configuration) -> Option<Response>
+{
+    response(file.strip_prefix(&config.image_dir).ok()?, config)
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn targetsAreInPrefix()
+    {
+        let image_dir = std::env::temp_dir().join(
+            "nspic-test-".to_owned() + &rand::random::<u64>().to_string());
+        std::fs::create_dir_all(&image_dir).unwrap();
+        let config = Configuration {
+            image_dir: image_dir.to_str().unwrap().to_owned(),
+            ..Default::default()
+        };
+        let path = Path::new("ab/abcd.jpg");
+        assert_eq!(target(path, SendfileHeader::XAccelRedirect,
+                          Some("/nspic-images/"), &config).as_deref(),
+                   Some("/nspic-images/ab/abcd.jpg"));
+        assert_eq!(target(path, SendfileHeader::XAccelRedirect, None, &config),
+                   None);
+        let absolute = std::fs::canonicalize(&image_dir).unwrap().join(path);
+        assert_eq!(target(path, SendfileHeader::XSendfile, None, &config),
+                   absolute.to_str().map(|p| p.to_owned()));
+        std::fs::remove_dir_all(&image_dir).unwrap();
+    }
+}
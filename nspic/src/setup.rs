@@ -0,0 +1,190 @@
+// First-run setup. If there is no password in the config and no user
+// in the database, anyone can visit /setup once to create the admin
+// user, and get a starter config with the chosen image directory. The
+// data directory and the database stay the ones of the running
+// config, because that is where the user is created.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use log::info;
+use serde::Serialize;
+use tera::Tera;
+use warp::Reply;
+use warp::http::status::StatusCode;
+use warp::reply::Response;
+
+use crate::error::Error;
+use crate::config::{Configuration, SiteInfo};
+use crate::data;
+use crate::auth::{hashPassword, DEFAULT_USERNAME};
+use crate::i18n::Catalog;
+use crate::prefs::Prefs;
+
+/// The config shown at the end of the setup.
+#[derive(Serialize)]
+struct StarterConfig<'a>
+{
+    listen_address: &'a str,
+    listen_port: u16,
+    static_dir: &'a str,
+    data_dir: &'a str,
+    database_url: Option<&'a str>,
+    image_dir: &'a str,
+    site_info: SiteInfo,
+}
+
+/// Whether nobody can log in yet.
+pub fn needsSetup(data_manager: &data::Manager, config: &Configuration) ->
+    Result<bool, Error>
+{
+    Ok(config.password.is_empty() && config.password_hash.is_empty() &&
+       !data_manager.hasUsers()?)
+}
+
+fn renderForm(templates: &Tera, form: &HashMap<String, String>,
+              error_msg: Option<&str>, config: &Configuration,
+              catalog: &Catalog, prefs: &Prefs) -> Result<Response, Error>
+{
+    let field = |name: &str, default: &str| form.get(name).cloned()
+        .unwrap_or_else(|| default.to_owned());
+    let mut context = tera::Context::new();
+    context.insert("site_info", &config.site_info);
+    catalog.fillContext(&mut context);
+    prefs.fillContext(&mut context);
+    context.insert("error", &error_msg);
+    context.insert("username", &field("Username", DEFAULT_USERNAME));
+    context.insert("data_dir", &config.data_dir);
+    context.insert("image_dir", &field("ImageDir", &config.image_dir));
+    context.insert("site_title", &field("SiteTitle",
+                                        &config.site_info.site_title));
+    context.insert("url_domain", &field("UrlDomain",
+                                        &config.site_info.url_domain));
+    let html = templates.render("setup.html", &context).map_err(
+        |e| rterr!("Failed to render template: {}", e))?;
+    Ok(warp::reply::html(html).into_response())
+}
+
+pub fn handleSetupPage(templates: &Tera, data_manager: &data::Manager,
+                       config: &Configuration, catalog: &Catalog,
+                       prefs: &Prefs) ->
+    Result<Response, Error>
+{
+    if !needsSetup(data_manager, config)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()));
+    }
+    renderForm(templates, &HashMap::new(), None, config, catalog, prefs)
+}
+
+/// Check the setup form, and return the problem if there is one.
+/// Nothing is created, because the form is not authenticated.
+fn validateForm(form: &HashMap<String, String>) -> Option<String>
+{
+    let field = |name: &str| form.get(name).map(|s| s.trim()).unwrap_or("");
+    if field("Username").is_empty()
+    {
+        return Some(String::from("Username cannot be empty."));
+    }
+    let password = form.get("Password").map(|s| s.as_str()).unwrap_or("");
+    if password.is_empty()
+    {
+        return Some(String::from("Password cannot be empty."));
+    }
+    if form.get("PasswordAgain").map(|s| s.as_str()) != Some(password)
+    {
+        return Some(String::from("Passwords do not match."));
+    }
+    let image_dir = field("ImageDir");
+    if image_dir.is_empty()
+    {
+        return Some(String::from("The image directory cannot be empty."));
+    }
+    // It is created when the server starts with the new config.
+    if Path::new(image_dir).exists() && !Path::new(image_dir).is_dir()
+    {
+        return Some(format!("{} is not a directory.", image_dir));
+    }
+    None
+}
+
+pub fn handleSetup(templates: &Tera, form: &HashMap<String, String>,
+                   data_manager: &data::Manager, config: &Configuration,
+                   catalog: &Catalog, prefs: &Prefs) ->
+    Result<Response, Error>
+{
+    if !needsSetup(data_manager, config)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()));
+    }
+    if let Some(problem) = validateForm(form)
+    {
+        return renderForm(templates, form, Some(&problem), config, catalog,
+                          prefs);
+    }
+    let field = |name: &str| form.get(name).map(|s| s.trim()).unwrap_or("");
+    let username = field("Username");
+    // If someone else finished the setup in the meantime, this does
+    // nothing.
+    if !data_manager.createFirstUser(username, &hashPassword(&form["Password"])?)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()));
+    }
+    info!("Setup created user {}.", username);
+
+    let mut site_info = config.site_info.clone();
+    site_info.site_title = field("SiteTitle").to_owned();
+    site_info.url_domain = field("UrlDomain").trim_end_matches('/').to_owned();
+    let starter = StarterConfig {
+        listen_address: &config.listen_address,
+        listen_port: config.listen_port,
+        static_dir: &config.static_dir,
+        data_dir: &config.data_dir,
+        database_url: config.database_url.as_deref(),
+        image_dir: field("ImageDir"),
+        site_info,
+    };
+    let mut context = tera::Context::new();
+    context.insert("site_info", &config.site_info);
+    catalog.fillContext(&mut context);
+    prefs.fillContext(&mut context);
+    context.insert("username", username);
+    context.insert("config_text", &toml::to_string(&starter).map_err(
+        |e| rterr!("Failed to generate config: {}", e))?);
+    let html = templates.render("setup_done.html", &context).map_err(
+        |e| rterr!("Failed to render template: {}", e))?;
+    Ok(warp::reply::html(html).into_response())
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn setupFormIsValidated()
+    {
+        let dir = std::env::temp_dir().join(
+            "nspic-test-".to_owned() + &rand::random::<u64>().to_string());
+        let dir_str = dir.to_str().unwrap().to_owned();
+        let mut form: HashMap<String, String> = [
+            ("Username", "user"), ("Password", "secret"),
+            ("PasswordAgain", "secret"), ("ImageDir", &dir_str),
+        ].into_iter().map(|(k, v)| (k.to_owned(), v.to_owned())).collect();
+        assert_eq!(validateForm(&form), None);
+        assert!(!dir.exists());
+        std::fs::write(&dir, b"").unwrap();
+        assert!(validateForm(&form).is_some());
+        std::fs::remove_file(&dir).ok();
+        form.insert(String::from("ImageDir"), String::new());
+        assert!(validateForm(&form).is_some());
+        form.insert(String::from("ImageDir"), dir_str);
+
+        form.insert(String::from("PasswordAgain"), String::from("other"));
+        assert!(validateForm(&form).is_some());
+        form.insert(String::from("Password"), String::new());
+        assert!(validateForm(&form).is_some());
+    }
+}
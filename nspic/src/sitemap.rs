@@ -0,0 +1,145 @@
+// sitemap.xml (https://www.sitemaps.org/protocol.html) of the public
+// posts, for search engines. A sitemap can have at most 50,000 URLs,
+// so with more posts /sitemap.xml is a sitemap index, which lists the
+// pages of the sitemap at /sitemap.xml?page=<n>.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use time::OffsetDateTime;
+use warp::Reply;
+use warp::http::status::StatusCode;
+use warp::reply::Response;
+
+use crate::error::Error;
+use crate::config::Configuration;
+use crate::data;
+use crate::app::absoluteUrl;
+use crate::feed::xmlEscape;
+
+/// Maximal number of URLs in one sitemap.
+const SITEMAP_URLS_MAX: u64 = 50000;
+
+fn lastmod(time: i64) -> String
+{
+    OffsetDateTime::from_unix_timestamp(time).ok()
+        .and_then(|t| t.format(&time::format_description::well_known::Rfc3339)
+                  .ok())
+        .unwrap_or_default()
+}
+
+/// The sitemap for the `page` query parameter. The URLs are the index
+/// page followed by the posts, `per_page` URLs to a page.
+fn sitemap(page: Option<u64>, per_page: u64, data_manager: &data::Manager,
+           config: &Configuration) -> Result<String, Error>
+{
+    let post_count = data_manager.countPosts()?;
+    let page_count = (post_count + 1).div_ceil(per_page);
+    let mut xml = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    let page = match page
+    {
+        Some(page) => page,
+        None if page_count > 1 => {
+            xml.push_str(
+                r#"<sitemapindex xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">"#);
+            let base = absoluteUrl("sitemap", "", config);
+            for p in 1..=page_count
+            {
+                write!(xml, "<sitemap><loc>{}</loc></sitemap>",
+                       xmlEscape(&format!("{}?page={}", base, p))).unwrap();
+            }
+            xml.push_str("</sitemapindex>");
+            return Ok(xml);
+        },
+        None => 1,
+    };
+    if page == 0 || page > page_count
+    {
+        return Err(Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()));
+    }
+    xml.push_str(r#"<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">"#);
+    // The index page takes the first URL of the first page.
+    let (post_start, post_count) = if page == 1
+    {
+        let newest = data_manager.getPostUrlArgs(0, 1)?;
+        write!(xml, "<url><loc>{}</loc>",
+               xmlEscape(&absoluteUrl("index", "", config))).unwrap();
+        if let Some((_, time)) = newest.first()
+        {
+            write!(xml, "<lastmod>{}</lastmod>", lastmod(*time)).unwrap();
+        }
+        xml.push_str("</url>");
+        (0, per_page - 1)
+    }
+    else
+    {
+        ((page - 1) * per_page - 1, per_page)
+    };
+    for (url_arg, time) in data_manager.getPostUrlArgs(post_start, post_count)?
+    {
+        write!(xml, "<url><loc>{}</loc><lastmod>{}</lastmod></url>",
+               xmlEscape(&absoluteUrl("post", &url_arg, config)),
+               lastmod(time)).unwrap();
+    }
+    xml.push_str("</urlset>");
+    Ok(xml)
+}
+
+pub fn handleSitemap(params: &HashMap<String, String>,
+                     data_manager: &data::Manager, config: &Configuration) ->
+    Result<Response, Error>
+{
+    let page = params.get("page").map(|p| p.parse().map_err(
+        |_| Error::HTTPStatus(StatusCode::BAD_REQUEST,
+                              String::from("Invalid page")))).transpose()?;
+    let xml = sitemap(page, SITEMAP_URLS_MAX, data_manager, config)?;
+    Ok(warp::reply::with_header(xml, "Content-Type", "application/xml")
+       .into_response())
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::post::Post;
+
+    #[test]
+    fn sitemapIsPaginated() -> Result<(), Error>
+    {
+        let mut data_manager = data::Manager::new(
+            crate::sqlite_connection::Source::Memory);
+        data_manager.connect()?;
+        data_manager.init()?;
+        let mut config = Configuration::default();
+        config.site_info.url_domain = String::from("https://example.org");
+        for i in 1..=4
+        {
+            let mut post = Post::new();
+            post.upload_time = OffsetDateTime::from_unix_timestamp(i)
+                .map_err(|e| rterr!("{}", e))?;
+            data_manager.addPost(&post, None)?;
+        }
+
+        let xml = sitemap(None, 10, &data_manager, &config)?;
+        assert!(xml.contains("<urlset"));
+        assert_eq!(xml.matches("<url>").count(), 5);
+        assert!(xml.contains("<loc>https://example.org/p/4</loc>\
+                              <lastmod>1970-01-01T00:00:04Z</lastmod>"));
+
+        let xml = sitemap(None, 2, &data_manager, &config)?;
+        assert!(xml.contains("<sitemapindex"));
+        assert!(xml.contains("<loc>https://example.org/sitemap.xml?page=3</loc>"));
+        assert!(!xml.contains("page=4"));
+        // Page 1 has the index page and the newest post.
+        let page1 = sitemap(Some(1), 2, &data_manager, &config)?;
+        assert!(page1.contains("<loc>https://example.org/</loc>"));
+        assert!(page1.contains("/p/4<"));
+        let page3 = sitemap(Some(3), 2, &data_manager, &config)?;
+        assert_eq!(page3.matches("<url>").count(), 1);
+        assert!(page3.contains("/p/1<"));
+        assert!(sitemap(Some(4), 2, &data_manager, &config).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,214 @@
+// Cross-posting to a Telegram channel with the Bot API. The images are
+// uploaded, so NSPic doesn’t have to be reachable from Telegram. A
+// post with more than one image is sent as albums of up to 10 images,
+// which is the limit of the API.
+
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use crate::error::Error;
+use crate::config::{Configuration, TelegramConfig};
+use crate::post::{Post, Visibility};
+use crate::app::absoluteUrl;
+use crate::post_pipeline::imagePath;
+
+const TIMEOUT_SEC: u64 = 60;
+const ALBUM_SIZE_MAX: usize = 10;
+/// The length limit of a photo caption.
+const CAPTION_LENGTH_MAX: usize = 1024;
+
+/// A multipart/form-data body.
+struct Multipart
+{
+    boundary: String,
+    body: Vec<u8>,
+}
+
+impl Multipart
+{
+    fn new() -> Self
+    {
+        Self {
+            boundary: format!("nspic-{:016x}", rand::random::<u64>()),
+            body: Vec::new(),
+        }
+    }
+
+    fn field(&mut self, name: &str, value: &str)
+    {
+        self.body.extend(format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+            self.boundary, name, value).as_bytes());
+    }
+
+    fn file(&mut self, name: &str, filename: &str, data: &[u8])
+    {
+        self.body.extend(format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"{}\"; \
+             filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+            self.boundary, name, filename).as_bytes());
+        self.body.extend(data);
+        self.body.extend(b"\r\n");
+    }
+
+    fn finish(mut self) -> (String, Vec<u8>)
+    {
+        self.body.extend(format!("--{}--\r\n", self.boundary).as_bytes());
+        (format!("multipart/form-data; boundary={}", self.boundary), self.body)
+    }
+}
+
+/// Whether the post should be sent to the channel. Only public posts
+/// are sent.
+pub fn wants(post: &Post, config: &Configuration) -> bool
+{
+    config.telegram.is_some() && post.visibility == Visibility::Public
+}
+
+/// The caption of a post: the description, shortened to fit, and the
+/// link to the post.
+fn caption(post: &Post, config: &Configuration) -> String
+{
+    let url = absoluteUrl("post", &post.urlArg(), config);
+    let room = CAPTION_LENGTH_MAX.saturating_sub(url.chars().count() + 2);
+    let desc = post.desc.trim();
+    if desc.is_empty()
+    {
+        url
+    }
+    else if desc.chars().count() <= room
+    {
+        format!("{}\n\n{}", desc, url)
+    }
+    else
+    {
+        let short: String = desc.chars().take(room.saturating_sub(1)).collect();
+        format!("{}…\n\n{}", short.trim_end(), url)
+    }
+}
+
+fn call(telegram: &TelegramConfig, method: &str, content_type: &str,
+        body: &[u8]) -> Result<(), Error>
+{
+    let agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(TIMEOUT_SEC)).build();
+    let url = format!("{}/bot{}/{}", telegram.api_url, telegram.bot_token,
+                      method);
+    let result = agent.post(&url).set("Content-Type", content_type)
+        .send_bytes(body);
+    // Errors of the Bot API come with a description.
+    let response: Value = match result
+    {
+        Ok(res) => res.into_json(),
+        Err(ureq::Error::Status(_, res)) => res.into_json(),
+        Err(e) => return Err(rterr!("Telegram request failed: {}", e)),
+    }.map_err(|e| rterr!("Invalid Telegram response: {}", e))?;
+    if response["ok"] != true
+    {
+        return Err(rterr!("Telegram {} failed: {}", method,
+                          response["description"].as_str().unwrap_or("")));
+    }
+    Ok(())
+}
+
+/// Send the post to the configured channel. This is called by the
+/// delivery queue, which retries on errors.
+pub fn send(post: &Post, config: &Configuration) -> Result<(), Error>
+{
+    let telegram = config.telegram.as_ref().ok_or_else(
+        || rterr!("Telegram is not configured"))?;
+    let text = caption(post, config);
+    if post.images.is_empty()
+    {
+        // The images of a redacted post are gone.
+        let body = serde_json::to_vec(&json!({
+            "chat_id": telegram.chat_id,
+            "text": text,
+        })).map_err(|e| rterr!("{}", e))?;
+        return call(telegram, "sendMessage", "application/json", &body);
+    }
+    for (i, album) in post.images.chunks(ALBUM_SIZE_MAX).enumerate()
+    {
+        let mut form = Multipart::new();
+        form.field("chat_id", &telegram.chat_id);
+        let mut media = Vec::new();
+        for (j, image) in album.iter().enumerate()
+        {
+            let path = imagePath(image, config);
+            let data = std::fs::read(&path).map_err(
+                |e| rterr!("Failed to read {:?}: {}", path, e))?;
+            let filename = image.path.file_name().and_then(|n| n.to_str())
+                .unwrap_or("image");
+            form.file(&format!("photo{}", j), filename, &data);
+            media.push(json!({
+                "type": "photo",
+                "media": format!("attach://photo{}", j),
+            }));
+        }
+        // Only the first photo of the first album has the caption, so
+        // that it is shown once.
+        if i == 0
+        {
+            media[0]["caption"] = json!(text);
+        }
+        let method = if album.len() == 1
+        {
+            if i == 0
+            {
+                form.field("caption", &text);
+            }
+            form.field("photo", media[0]["media"].as_str().unwrap());
+            "sendPhoto"
+        }
+        else
+        {
+            form.field("media", &Value::from(media).to_string());
+            "sendMediaGroup"
+        };
+        let (content_type, body) = form.finish();
+        call(telegram, method, &content_type, &body)?;
+    }
+    Ok(())
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn captionFits()
+    {
+        let mut config = Configuration::default();
+        config.site_info.url_domain = String::from("https://example.org");
+        let mut post = Post::new();
+        post.id = 3;
+        assert_eq!(caption(&post, &config), "https://example.org/p/3");
+        post.desc = String::from("A cat");
+        assert_eq!(caption(&post, &config), "A cat\n\nhttps://example.org/p/3");
+        post.desc = "猫".repeat(2000);
+        let text = caption(&post, &config);
+        assert_eq!(text.chars().count(), CAPTION_LENGTH_MAX);
+        assert!(text.ends_with("猫…\n\nhttps://example.org/p/3"));
+    }
+
+    #[test]
+    fn multipartIsFramed()
+    {
+        let mut form = Multipart::new();
+        let boundary = form.boundary.clone();
+        form.field("chat_id", "@cats");
+        form.file("photo0", "a.jpg", b"hello");
+        let (content_type, body) = form.finish();
+        assert_eq!(content_type, format!("multipart/form-data; boundary={}",
+                                         boundary));
+        assert_eq!(String::from_utf8(body).unwrap(), format!(
+            "--{b}\r\nContent-Disposition: form-data; name=\"chat_id\"\r\n\r\n\
+             @cats\r\n--{b}\r\nContent-Disposition: form-data; name=\"photo0\"; \
+             filename=\"a.jpg\"\r\nContent-Type: application/octet-stream\r\n\r\n\
+             hello\r\n--{b}--\r\n", b = boundary));
+    }
+}
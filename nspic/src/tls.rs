@@ -0,0 +1,199 @@
+// Serving HTTPS directly, without a reverse proxy. The certificate
+// files are watched, and the server is restarted when they change, so
+// that renewed certificates are picked up without a restart of NSPic.
+// An optional plain HTTP listener serves ACME HTTP-01 challenges from
+// `data_dir/acme-challenge`, and redirects everything else to HTTPS.
+// With `acme` on, NSPic answers those challenges itself (see the acme
+// module).
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use log::{info, warn};
+use log::error as log_error;
+use warp::{Filter, Reply};
+use warp::filters::BoxedFilter;
+use warp::reply::Response;
+
+use crate::error::Error;
+use crate::config::{Configuration, TlsConfig};
+use crate::utils::uriFromStr;
+use crate::acme;
+
+/// How often to check the certificate files for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The directory where ACME HTTP-01 challenge files are served from.
+/// An ACME client should write the challenge responses here.
+pub fn challengeDir(config: &Configuration) -> PathBuf
+{
+    Path::new(&config.data_dir).join("acme-challenge")
+}
+
+/// The latest modification time of the certificate and the key.
+fn certModifiedTime(tls_config: &TlsConfig) -> Option<SystemTime>
+{
+    let mtime = |path: &str| std::fs::metadata(path).and_then(|m| m.modified())
+        .ok();
+    std::cmp::max(mtime(&tls_config.cert_path), mtime(&tls_config.key_path))
+}
+
+/// The HTTPS URL of the same resource as a plain HTTP request to
+/// `host` and `path`. The port of the plain HTTP listener is dropped
+/// from the host.
+fn httpsUri(host: &str, path: &str, query: Option<&str>, https_port: u16) ->
+    String
+{
+    let host = match host.rfind(':')
+    {
+        // An IPv6 address without port has colons inside brackets.
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+    let mut uri = if https_port == 443
+    {
+        format!("https://{}{}", host, path)
+    }
+    else
+    {
+        format!("https://{}:{}{}", host, https_port, path)
+    };
+    if let Some(query) = query
+    {
+        uri.push('?');
+        uri.push_str(query);
+    }
+    uri
+}
+
+fn spawnHttpListener(tls_config: &TlsConfig, https_port: u16,
+                     config: &Configuration) -> Result<(), Error>
+{
+    let port = if let Some(port) = tls_config.http_port
+    {
+        port
+    }
+    else
+    {
+        return Ok(());
+    };
+    let dir = challengeDir(config);
+    std::fs::create_dir_all(&dir).map_err(
+        |e| rterr!("Failed to create ACME challenge dir: {}", e))?;
+    let challenge = warp::get().and(warp::path(".well-known"))
+        .and(warp::path("acme-challenge")).and(warp::fs::dir(dir));
+    let default_host = config.site_info.url_domain.split("://").last()
+        .unwrap_or("").to_owned();
+    let redirect = warp::header::optional::<String>("Host")
+        .and(warp::path::full())
+        .and(warp::query::raw().map(Some).or(warp::any().map(|| None)).unify())
+        .map(move |host: Option<String>, path: warp::path::FullPath,
+                   query: Option<String>| {
+            // Use the host from the request, so that all names of
+            // the site work.
+            let uri = httpsUri(host.as_deref().unwrap_or(&default_host),
+                               path.as_str(), query.as_deref(), https_port);
+            match uriFromStr(&uri)
+            {
+                Ok(uri) => warp::redirect::permanent(uri).into_response(),
+                Err(e) => e.into_response(),
+            }
+        });
+    let addr = SocketAddr::new(
+        config.listen_address.parse().map_err(
+            |_| rterr!("Invalid listen address: {}", config.listen_address))?,
+        port);
+    let (_, server) = warp::serve(challenge.or(redirect)).try_bind_ephemeral(addr)
+        .map_err(|e| rterr!("Failed to listen at {}: {}", addr, e))?;
+    info!("Serving ACME challenges at {}...", addr);
+    tokio::spawn(server);
+    Ok(())
+}
+
+/// Serve `route` with TLS at `addr`, until the process ends.
+pub async fn serve(route: BoxedFilter<(Response,)>, addr: SocketAddr,
+                   tls_config: &TlsConfig, config: &Configuration) ->
+    Result<(), Error>
+{
+    spawnHttpListener(tls_config, addr.port(), config)?;
+    if tls_config.acme
+    {
+        let (t, c) = (tls_config.clone(), config.clone());
+        let result = tokio::task::spawn_blocking(
+            move || acme::renewIfNeeded(&t, &c)).await
+            .map_err(|e| rterr!("ACME task failed: {}", e))?;
+        if let Err(e) = result
+        {
+            // The old certificate is still good for a while.
+            if !Path::new(&tls_config.cert_path).exists()
+            {
+                return Err(e);
+            }
+            log_error!("Failed to renew certificate: {}", e);
+        }
+        acme::spawn(tls_config.clone(), config.clone());
+    }
+    let mut first = true;
+    loop
+    {
+        let stamp = certModifiedTime(tls_config);
+        let (notify, changed) = tokio::sync::oneshot::channel::<()>();
+        let watched = tls_config.clone();
+        let watcher = tokio::spawn(async move {
+            loop
+            {
+                tokio::time::sleep(WATCH_INTERVAL).await;
+                if certModifiedTime(&watched) != stamp
+                {
+                    notify.send(()).ok();
+                    break;
+                }
+            }
+        });
+        let result = warp::serve(route.clone()).tls()
+            .cert_path(&tls_config.cert_path).key_path(&tls_config.key_path)
+            .try_bind_with_graceful_shutdown(addr, async {
+                changed.await.ok();
+            });
+        match result
+        {
+            Ok((_, server)) => {
+                info!("Listening at {} with TLS...", addr);
+                server.await;
+                info!("Certificate changed. Reloading...");
+            },
+            Err(e) if first => {
+                watcher.abort();
+                return Err(rterr!("Failed to listen at {}: {}", addr, e));
+            },
+            Err(e) => {
+                // Maybe the certificate is still being written.
+                watcher.abort();
+                log_error!("Failed to reload certificate: {}", e);
+                warn!("Trying again in {} seconds...", WATCH_INTERVAL.as_secs());
+                tokio::time::sleep(WATCH_INTERVAL).await;
+            },
+        }
+        first = false;
+    }
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn redirectToHttps()
+    {
+        assert_eq!(httpsUri("example.org:80", "/p/1", None, 443),
+                   "https://example.org/p/1");
+        assert_eq!(httpsUri("example.org", "/", Some("page=2"), 8443),
+                   "https://example.org:8443/?page=2");
+        assert_eq!(httpsUri("[::1]:80", "/", None, 443), "https://[::1]/");
+        assert_eq!(httpsUri("[::1]", "/", None, 443), "https://[::1]/");
+    }
+}
@@ -0,0 +1,338 @@
+// The trash of deleted posts. Deleting a post doesn’t remove it right
+// away: it is flagged in the database, and its files are moved to
+// `<data_dir>/trash/`, under their paths in the image dir, so that
+// nothing of it is shown or served anymore. From the trash page a
+// post can be restored or purged, and it is purged by itself after
+// `trash_days`.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::info;
+use log::error as log_error;
+use serde::Serialize;
+use tera::Tera;
+use time::OffsetDateTime;
+use warp::Reply;
+use warp::http::status::StatusCode;
+use warp::reply::Response;
+
+use crate::error::Error;
+use crate::config::Configuration;
+use crate::data;
+use crate::i18n::Catalog;
+use crate::prefs::Prefs;
+use crate::post::{Image, Post};
+use crate::auth::validateSession;
+use crate::quarantine::moveFile;
+use crate::post_pipeline::alternateFiles;
+use crate::utils::uriFromStr;
+use crate::app::{pathPrefix, urlFor};
+
+/// How often to look for expired posts in the trash while running.
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Serialize)]
+struct TrashedPost
+{
+    post: Post,
+    trashed_utc_str: String,
+    /// Days until the post is purged.
+    days_left: u64,
+}
+
+fn trashDir(config: &Configuration) -> PathBuf
+{
+    Path::new(&config.data_dir).join("trash")
+}
+
+/// The files of `image` relative to the image dir, which are the
+/// image, its thumbnail, and the files derived from them. The files
+/// may not exist.
+fn imageFiles(image: &Image, config: &Configuration) -> Result<Vec<PathBuf>, Error>
+{
+    let image_dir = Path::new(&config.image_dir);
+    let mut files = vec![image.path.clone(), image.thumbnail()?];
+    files.extend(alternateFiles(image, config)?.into_iter().filter_map(
+        |file| file.strip_prefix(image_dir).ok().map(Path::to_path_buf)));
+    Ok(files)
+}
+
+/// Remove the dirs of `file` under `root` that are empty.
+fn removeEmptyDirs(file: &Path, root: &Path)
+{
+    let mut dir = file.parent();
+    while let Some(d) = dir
+    {
+        if d.as_os_str().is_empty() || std::fs::remove_dir(root.join(d)).is_err()
+        {
+            break;
+        }
+        dir = d.parent();
+    }
+}
+
+/// Move the files of `post` from the dir `from` to `to`. Nothing is
+/// overwritten.
+fn moveFiles(post: &Post, from: &Path, to: &Path, config: &Configuration) ->
+    Result<(), Error>
+{
+    for image in &post.images
+    {
+        for file in imageFiles(image, config)?
+        {
+            let source = from.join(&file);
+            if !source.exists()
+            {
+                continue;
+            }
+            let target = to.join(&file);
+            if target.exists()
+            {
+                return Err(rterr!("Failed to move {:?}: {:?} exists", source,
+                                  target));
+            }
+            if let Some(dir) = target.parent()
+            {
+                std::fs::create_dir_all(dir).map_err(
+                    |e| rterr!("Failed to create {:?}: {}", dir, e))?;
+            }
+            moveFile(&source, &target)?;
+            removeEmptyDirs(&file, from);
+        }
+    }
+    Ok(())
+}
+
+/// Remove the files of `post` in `dir`.
+fn removeFiles(post: &Post, dir: &Path, config: &Configuration) -> Result<(), Error>
+{
+    for image in &post.images
+    {
+        info!("Deleting image file at {}...", image.path.display());
+        for file in imageFiles(image, config)?
+        {
+            std::fs::remove_file(dir.join(&file)).ok();
+            removeEmptyDirs(&file, dir);
+        }
+    }
+    Ok(())
+}
+
+/// Delete a post, which moves it into the trash, or removes it if
+/// `trash_days` is 0.
+pub fn delete(post_id: i64, data_manager: &data::Manager,
+              config: &Configuration) -> Result<(), Error>
+{
+    let post = data_manager.findPostByID(post_id)?.ok_or_else(
+        || Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()))?;
+    if config.trash_days == 0
+    {
+        info!("Deleting post {}...", post_id);
+        data_manager.deletePost(post_id)?;
+        return removeFiles(&post, Path::new(&config.image_dir), config);
+    }
+    info!("Moving post {} to the trash...", post_id);
+    let image_dir = Path::new(&config.image_dir);
+    let trash_dir = trashDir(config);
+    // The files go first, so that the post is only flagged once none
+    // of them is served anymore.
+    let result = moveFiles(&post, image_dir, &trash_dir, config).and_then(|_| {
+        if data_manager.trashPost(post_id)?
+        {
+            Ok(())
+        }
+        else
+        {
+            Err(Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()))
+        }
+    });
+    if result.is_err()
+    {
+        if let Err(e) = moveFiles(&post, &trash_dir, image_dir, config)
+        {
+            log_error!("Failed to move the files of post {} back: {}", post_id, e);
+        }
+    }
+    result
+}
+
+/// Take a post out of the trash, and put its files back.
+pub fn restore(post_id: i64, data_manager: &data::Manager,
+               config: &Configuration) -> Result<(), Error>
+{
+    let post = data_manager.findTrashedPost(post_id)?.ok_or_else(
+        || Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()))?;
+    info!("Restoring post {} from the trash...", post_id);
+    moveFiles(&post, &trashDir(config), Path::new(&config.image_dir), config)?;
+    data_manager.restorePost(post_id)?;
+    Ok(())
+}
+
+/// Delete a post in the trash for good.
+pub fn purge(post_id: i64, data_manager: &data::Manager,
+             config: &Configuration) -> Result<(), Error>
+{
+    let post = data_manager.findTrashedPost(post_id)?.ok_or_else(
+        || Error::HTTPStatus(StatusCode::NOT_FOUND, String::new()))?;
+    info!("Purging post {}...", post_id);
+    data_manager.deletePost(post_id)?;
+    removeFiles(&post, &trashDir(config), config)
+}
+
+/// Purge the posts that have been in the trash for `trash_days`.
+/// Return the number of purged posts.
+pub fn purgeExpired(data_manager: &data::Manager, config: &Configuration) ->
+    Result<usize, Error>
+{
+    let deadline = OffsetDateTime::now_utc() -
+        time::Duration::days(config.trash_days as i64);
+    let mut count = 0;
+    for (post, trashed) in data_manager.getTrashedPosts()?
+    {
+        if trashed <= deadline
+        {
+            purge(post.id, data_manager, config)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Purge expired posts in the trash periodically in the background.
+pub fn spawn(data_manager: data::Manager, config: Configuration)
+{
+    std::thread::spawn(move || {
+        loop
+        {
+            match purgeExpired(&data_manager, &config)
+            {
+                Ok(0) => {},
+                Ok(count) => info!("Purged {} posts from the trash.", count),
+                Err(e) => log_error!("Failed to purge the trash: {}", e),
+            }
+            std::thread::sleep(PURGE_INTERVAL);
+        }
+    });
+}
+
+/// The page that lists the posts in the trash.
+pub fn handleTrash(token: Option<String>, templates: &Tera, catalog: &Catalog,
+                   prefs: &Prefs, data_manager: &data::Manager,
+                   config: &Configuration) -> Result<Response, Error>
+{
+    if !validateSession(&token, data_manager, config)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    let format = time::format_description::parse_borrowed::<2>(
+        "[year]-[month]-[day] [hour]:[minute]:[second] UTC").unwrap();
+    let now = OffsetDateTime::now_utc();
+    let posts = data_manager.getTrashedPosts()?.into_iter().map(
+        |(post, trashed)| Ok(TrashedPost {
+            post,
+            trashed_utc_str: trashed.format(&format).map_err(
+                |e| rterr!("Invalid trash time: {}", e))?,
+            days_left: config.trash_days.saturating_sub(
+                (now - trashed).whole_days().max(0) as u64),
+        })).collect::<Result<Vec<_>, Error>>()?;
+    let mut context = tera::Context::new();
+    context.insert("site_info", &config.site_info);
+    catalog.fillContext(&mut context);
+    prefs.fillContext(&mut context);
+    context.insert("trashed_posts", &posts);
+    let html = templates.render("trash.html", &context).map_err(
+        |e| rterr!("Failed to render template: {}", e))?;
+    Ok(warp::reply::html(html).into_response())
+}
+
+/// Restore or purge a post in the trash, and go back to the trash
+/// page.
+pub fn handleTrashAction(post_id: i64, action: &str,
+                         data_manager: &data::Manager, config: &Configuration,
+                         token: Option<String>) -> Result<Response, Error>
+{
+    if !validateSession(&token, data_manager, config)?
+    {
+        return Err(Error::HTTPStatus(StatusCode::UNAUTHORIZED, String::new()));
+    }
+    match action
+    {
+        "restore" => restore(post_id, data_manager, config)?,
+        "purge" => purge(post_id, data_manager, config)?,
+        _ => return Err(Error::HTTPStatus(StatusCode::NOT_FOUND, String::new())),
+    }
+    Ok(warp::redirect::see_other(uriFromStr(
+        &(pathPrefix(&config.serve_under_path) + &urlFor("trash", "")))?)
+       .into_response())
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn trashedPostsAreRestoredAndPurged() -> Result<(), Error>
+    {
+        let dir = std::env::temp_dir().join(
+            "nspic-test-".to_owned() + &rand::random::<u64>().to_string());
+        let config = Configuration {
+            data_dir: dir.to_str().unwrap().to_owned(),
+            image_dir: dir.join("images").to_str().unwrap().to_owned(),
+            ..Default::default()
+        };
+        std::fs::create_dir_all(dir.join("images/a")).unwrap();
+        std::fs::write(dir.join("images/a/abc.jpg"), b"image").unwrap();
+        std::fs::write(dir.join("images/a/abc_t.jpg"), b"thumb").unwrap();
+        let mut data_manager = data::Manager::new(
+            crate::sqlite_connection::Source::File(dir.join("db.sqlite")));
+        let result = (|| -> Result<(), Error> {
+            data_manager.connect()?;
+            data_manager.init()?;
+            let mut post = Post::new();
+            post.images = vec![Image {
+                path: PathBuf::from("a/abc.jpg"),
+                ..Default::default()
+            }];
+            let id = data_manager.addPost(&post, None)?;
+
+            // A file in the way stops the move, and the post stays.
+            std::fs::create_dir_all(dir.join("trash/a")).unwrap();
+            std::fs::write(dir.join("trash/a/abc_t.jpg"), b"other").unwrap();
+            assert!(delete(id, &data_manager, &config).is_err());
+            assert!(data_manager.findPostByID(id)?.is_some());
+            assert!(dir.join("images/a/abc.jpg").exists());
+            assert!(!dir.join("trash/a/abc.jpg").exists());
+            std::fs::remove_dir_all(dir.join("trash")).unwrap();
+
+            delete(id, &data_manager, &config)?;
+            assert!(data_manager.findPostByID(id)?.is_none());
+            assert!(data_manager.getAllPosts()?.is_empty());
+            assert!(data_manager.allImagePaths()?.is_empty());
+            // Which is still a post that an import would clash with.
+            assert!(data_manager.hasPosts()?);
+            assert!(!dir.join("images/a").exists());
+            assert!(dir.join("trash/a/abc_t.jpg").exists());
+            assert!(delete(id, &data_manager, &config).is_err());
+
+            restore(id, &data_manager, &config)?;
+            assert!(data_manager.findPostByID(id)?.is_some());
+            assert!(dir.join("images/a/abc.jpg").exists());
+            assert!(data_manager.getTrashedPosts()?.is_empty());
+
+            delete(id, &data_manager, &config)?;
+            assert_eq!(purgeExpired(&data_manager, &config)?, 0);
+            let expired = Configuration { trash_days: 0, ..config.clone() };
+            assert_eq!(purgeExpired(&data_manager, &expired)?, 1);
+            assert!(data_manager.findTrashedPost(id)?.is_none());
+            assert!(!data_manager.hasPosts()?);
+            assert!(!dir.join("trash/a").exists());
+            Ok(())
+        })();
+        std::fs::remove_dir_all(&dir).ok();
+        result
+    }
+}
@@ -0,0 +1,107 @@
+// Counting views of posts. Views are counted in memory, and written
+// to the database periodically, so that viewing a post doesn’t write
+// to the database.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::error as log_error;
+
+use crate::error::Error;
+use crate::data;
+
+/// How often are the counted views written to the database.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct ViewCounter
+{
+    /// Views since the last flush, by post ID.
+    pending: Mutex<HashMap<i64, u64>>,
+}
+
+impl ViewCounter
+{
+    pub fn new() -> Self
+    {
+        Self { pending: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn record(&self, post_id: i64)
+    {
+        *self.pending.lock().unwrap().entry(post_id).or_insert(0) += 1;
+    }
+
+    /// Views of a post that are not in the database yet.
+    pub fn pending(&self, post_id: i64) -> u64
+    {
+        self.pending.lock().unwrap().get(&post_id).copied().unwrap_or(0)
+    }
+
+    /// Write the counted views to the database. If that fails, the
+    /// views are kept for the next flush.
+    pub fn flush(&self, data_manager: &data::Manager) -> Result<(), Error>
+    {
+        let views: Vec<(i64, u64)> = self.pending.lock().unwrap().drain()
+            .collect();
+        if views.is_empty()
+        {
+            return Ok(());
+        }
+        if let Err(e) = data_manager.addViews(&views)
+        {
+            let mut pending = self.pending.lock().unwrap();
+            for (id, count) in views
+            {
+                *pending.entry(id).or_insert(0) += count;
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+/// Flush `counter` periodically in the background.
+pub fn spawnFlusher(counter: Arc<ViewCounter>, data_manager: data::Manager)
+{
+    std::thread::spawn(move || {
+        loop
+        {
+            std::thread::sleep(FLUSH_INTERVAL);
+            if let Err(e) = counter.flush(&data_manager)
+            {
+                log_error!("Failed to save view counts: {}", e);
+            }
+        }
+    });
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::post::Post;
+
+    #[test]
+    fn viewsAreFlushed() -> Result<(), Error>
+    {
+        let mut data_manager = data::Manager::new(
+            crate::sqlite_connection::Source::Memory);
+        data_manager.connect()?;
+        data_manager.init()?;
+        let id = data_manager.addPost(&Post::new(), None)?;
+
+        let counter = ViewCounter::new();
+        counter.record(id);
+        counter.record(id);
+        assert_eq!(counter.pending(id), 2);
+        counter.flush(&data_manager)?;
+        assert_eq!(counter.pending(id), 0);
+        counter.record(id);
+        counter.flush(&data_manager)?;
+        assert_eq!(data_manager.findPostByID(id)?.unwrap().views, 3);
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+// The watch folder. Image files that appear in the folder (e.g.
+// synced from a phone) are run through the pipeline, and become
+// drafts that wait for a description on the admin page.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use log::{debug, info};
+use log::error as log_error;
+
+use crate::error::Error;
+use crate::config::{Configuration, WatchFolderConfig};
+use crate::data;
+use crate::post::mimeTypeFromPath;
+use crate::post_pipeline::RawImage;
+use crate::app::createDraft;
+
+/// Size and modification time of a file, to tell whether it is still
+/// being written.
+type FileStamp = (u64, SystemTime);
+
+/// Files that are not finished, e.g. temp files of Syncthing, or
+/// files that are not images.
+fn shouldIgnore(path: &Path) -> bool
+{
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    name.starts_with('.') || name.starts_with('~') ||
+        !mimeTypeFromPath(path).starts_with("image/")
+}
+
+/// Find the image files under `dir` recursively.
+fn scan(dir: &Path, result: &mut HashMap<PathBuf, FileStamp>) -> Result<(), Error>
+{
+    for entry in std::fs::read_dir(dir).map_err(
+        |e| rterr!("Failed to read watch folder {:?}: {}", dir, e))?
+    {
+        let entry = entry.map_err(
+            |e| rterr!("Failed to read watch folder {:?}: {}", dir, e))?;
+        let path = entry.path();
+        let name = entry.file_name();
+        let meta = match entry.metadata()
+        {
+            Ok(m) => m,
+            Err(_) => continue,
+        };
+        if meta.is_dir()
+        {
+            if !name.to_string_lossy().starts_with('.')
+            {
+                scan(&path, result)?;
+            }
+        }
+        else if meta.is_file() && !shouldIgnore(&path)
+        {
+            if let Ok(mtime) = meta.modified()
+            {
+                result.insert(path, (meta.len(), mtime));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Turn an image file into a draft. The file is removed from the watch
+/// folder once it is copied into the pipeline. If the pipeline fails,
+/// the copy is in the quarantine.
+fn postFile(path: &Path, data_manager: &data::Manager, config: &Configuration) ->
+    Result<i64, Error>
+{
+    let data = std::fs::read(path).map_err(
+        |e| rterr!("Failed to read {:?}: {}", path, e))?;
+    let filename = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    let raw = RawImage::fromBytes(&data, filename, config)?;
+    std::fs::remove_file(path).map_err(
+        |e| rterr!("Failed to remove {:?} from watch folder: {}", path, e))?;
+    let img = raw.process(config)?;
+    createDraft(vec![img], data_manager, config)
+}
+
+/// Start watching the folder in the background.
+pub fn spawn(watch_config: WatchFolderConfig, data_manager: data::Manager,
+             config: Configuration)
+{
+    info!("Watching {} for new images...", watch_config.path);
+    std::thread::spawn(move || {
+        // Files from the previous scan. A file is only picked up if
+        // it hasn’t changed since then.
+        let mut previous: HashMap<PathBuf, FileStamp> = HashMap::new();
+        loop
+        {
+            let mut current = HashMap::new();
+            if let Err(e) = scan(Path::new(&watch_config.path), &mut current)
+            {
+                log_error!("{}", e);
+            }
+            for (path, stamp) in &current
+            {
+                if previous.get(path) != Some(stamp)
+                {
+                    debug!("Waiting for {:?} to settle...", path);
+                    continue;
+                }
+                match postFile(path, &data_manager, &config)
+                {
+                    Ok(id) => info!("Created draft {} from {:?}.", id, path),
+                    Err(e) => log_error!("Failed to post {:?}: {}", path, e),
+                }
+            }
+            previous = current;
+            std::thread::sleep(Duration::from_secs(watch_config.interval_sec));
+        }
+    });
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn scanFindsFinishedImages() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let dir = std::env::temp_dir().join(
+            "nspic-test-".to_owned() + &rand::random::<u64>().to_string());
+        std::fs::create_dir_all(dir.join("sub"))?;
+        std::fs::create_dir_all(dir.join(".stversions"))?;
+        for name in ["a.jpg", "sub/b.PNG", ".stversions/c.jpg", "~syncthing~d.jpg.tmp",
+                     ".syncthing.e.jpg.tmp", "notes.txt"]
+        {
+            std::fs::write(dir.join(name), b"hello")?;
+        }
+        let mut files = HashMap::new();
+        let result = scan(&dir, &mut files);
+        std::fs::remove_dir_all(&dir).ok();
+        result?;
+        let mut found: Vec<_> = files.keys()
+            .map(|p| p.strip_prefix(&dir).unwrap().to_owned()).collect();
+        found.sort();
+        assert_eq!(found, vec![PathBuf::from("a.jpg"), Path::new("sub").join("b.PNG")]);
+        Ok(())
+    }
+}
@@ -0,0 +1,242 @@
+use std::time::Duration;
+
+use log::warn;
+use serde::Serialize;
+use serde_json::json;
+use time::OffsetDateTime;
+use warp::http::status::StatusCode;
+
+use crate::error::Error;
+use crate::config::Configuration;
+use crate::data;
+use crate::post::{Image, Post, Visibility};
+use crate::app::absoluteUrl;
+use crate::post_pipeline::{imagePath, fileDigest};
+
+/// The version of the format of the payload, which changes when a
+/// field is removed or changes its meaning. New fields don’t change
+/// it.
+const PAYLOAD_VERSION: u32 = 1;
+/// The event of a payload about a post that went live.
+const EVENT_PUBLISHED: &str = "post.published";
+/// The event of a payload that the admin sent to test the webhook.
+const EVENT_TEST: &str = "webhook.test";
+
+/// Details of an image for receivers outside of NSPic, with absolute
+/// URLs.
+pub fn mediaInfo(img: &Image, config: &Configuration) ->
+    Result<serde_json::value::Value, Error>
+{
+    let url_for = |name: &str, arg: &str| absoluteUrl(name, arg, config);
+    let path = img.path.to_str().ok_or_else(
+        || rterr!("Invalid image path: {:?}", img.path))?;
+    let thumbnail = img.thumbnail()?;
+    Ok(json!({
+        "url": url_for("image_file", path),
+        "thumbnail_url": url_for("image_file", thumbnail.to_str().ok_or_else(
+            || rterr!("Invalid thumbnail path: {:?}", thumbnail))?),
+        "width": img.width,
+        "height": img.height,
+        "size": img.size,
+        "alt_text": Some(&img.alt_text).filter(|t| !t.is_empty()),
+        "caption": Some(&img.caption).filter(|t| !t.is_empty()),
+        // For a placeholder while the image loads.
+        "color": Some(&img.color).filter(|c| !c.is_empty()),
+        "blurhash": Some(&img.blurhash).filter(|h| !h.is_empty()),
+        // A missing file shouldn’t break the whole payload.
+        "sha256": fileDigest(&imagePath(img, config)).map_err(
+            |e| warn!("{}", e)).ok(),
+    }))
+}
+
+/// The ID and the permalink of a post, which is the response of an
+/// upload, and the start of the webhook payload.
+pub fn postLink(post: &Post, config: &Configuration) -> serde_json::value::Value
+{
+    json!({
+        "id": post.id,
+        "url": absoluteUrl("post", &post.urlArg(), config),
+    })
+}
+
+fn webhookPayload(post: &Post, config: &Configuration) ->
+    Result<serde_json::value::Value, Error>
+{
+    let mut payload = postLink(post, config);
+    payload["event"] = json!(EVENT_PUBLISHED);
+    payload["version"] = json!(PAYLOAD_VERSION);
+    payload["desc"] = json!(post.desc);
+    payload["images"] = json!([]);
+    payload["media"] = json!([]);
+    payload["time"] = json!(post.upload_time.unix_timestamp());
+    payload["album_id"] = json!(post.album_id);
+    payload["visibility"] = json!(post.visibility);
+    payload["tags"] = json!(post.tags);
+    payload["sensitive"] = json!(post.sensitive);
+    for img in &post.images
+    {
+        let media = mediaInfo(img, config)?;
+        // Kept for receivers that only know about the URLs.
+        payload["images"].as_array_mut().unwrap().push(media["url"].clone());
+        payload["media"].as_array_mut().unwrap().push(media);
+    }
+    Ok(payload)
+}
+
+/// Whether the post should be sent to the webhook. Private posts are
+/// not sent.
+pub fn wants(post: &Post, config: &Configuration) -> bool
+{
+    config.webhook_url.is_some() && post.visibility != Visibility::Private
+}
+
+/// POST `payload` to `url`, and return the status of the response,
+/// which can be an error status. This blocks for at most
+/// `webhook_timeout_sec`.
+fn postPayload(url: &str, payload: &serde_json::value::Value,
+               config: &Configuration) -> Result<u16, Error>
+{
+    let body = serde_json::to_vec(payload)
+        .map_err(|e| rterr!("Invalid payload: {}", e))?;
+    let agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(config.webhook_timeout_sec)).build();
+    match agent.post(url).set("Content-Type", "application/json")
+        .send_bytes(&body)
+    {
+        Ok(response) => Ok(response.status()),
+        Err(ureq::Error::Status(status, _)) => Ok(status),
+        Err(e) => Err(rterr!("Webhook failed: {}", e)),
+    }
+}
+
+/// POST the post to the configured webhook. This is called by the
+/// delivery queue, which retries on errors.
+pub fn send(post: &Post, config: &Configuration) -> Result<(), Error>
+{
+    let url = config.webhook_url.as_ref().ok_or_else(
+        || rterr!("Webhook is not configured"))?;
+    let status = postPayload(url, &webhookPayload(post, config)?, config)?;
+    if !(200..300).contains(&status)
+    {
+        return Err(rterr!("Webhook failed with status {}", status));
+    }
+    Ok(())
+}
+
+/// What the webhook did with a test payload.
+#[derive(Serialize)]
+pub struct TestResult
+{
+    pub ok: bool,
+    /// The status of the response, if there is one.
+    pub status: Option<u16>,
+    /// Why there is no response.
+    pub error: Option<String>,
+}
+
+/// The test payload, which is the payload of the newest public post,
+/// or of an example post if there is none, with the test event.
+fn testPayload(data_manager: &data::Manager, config: &Configuration) ->
+    Result<serde_json::value::Value, Error>
+{
+    let post = match data_manager.getPosts(0, 1, data::PostOrder::NewFirst)?.pop()
+    {
+        Some(post) => post,
+        None => {
+            let mut post = Post::new();
+            post.desc = String::from("A test from NSPic");
+            post.upload_time = OffsetDateTime::now_utc();
+            post
+        },
+    };
+    let mut payload = webhookPayload(&post, config)?;
+    payload["event"] = json!(EVENT_TEST);
+    Ok(payload)
+}
+
+/// Send a test payload to the configured webhook, which is not
+/// retried, and report how it went.
+pub fn sendTest(data_manager: &data::Manager, config: &Configuration) ->
+    Result<TestResult, Error>
+{
+    let url = config.webhook_url.as_ref().ok_or_else(
+        || Error::HTTPStatus(StatusCode::NOT_FOUND,
+                             String::from("No webhook is configured")))?;
+    let payload = testPayload(data_manager, config)?;
+    Ok(match postPayload(url, &payload, config)
+    {
+        Ok(status) => TestResult {
+            ok: (200..300).contains(&status),
+            status: Some(status),
+            error: None,
+        },
+        Err(e) => TestResult { ok: false, status: None, error: Some(e.to_string()) },
+    })
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::path::PathBuf;
+    use crate::post::Image;
+
+    #[test]
+    fn payloadHasImageDetails() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let image_dir = std::env::temp_dir().join(
+            "nspic-test-".to_owned() + &rand::random::<u64>().to_string());
+        std::fs::create_dir_all(image_dir.join("a"))?;
+        std::fs::write(image_dir.join("a").join("abc.jpg"), b"hello")?;
+        let config = Configuration {
+            image_dir: image_dir.to_str().unwrap().to_owned(),
+            ..Default::default()
+        };
+        let mut post = Post::new();
+        post.images = vec![Image {
+            path: PathBuf::from("a/abc.jpg"),
+            width: 3,
+            height: 4,
+            size: 5,
+            ..Default::default()
+        }];
+        post.id = 7;
+        post.tags = vec![String::from("cat")];
+        let payload = webhookPayload(&post, &config);
+        std::fs::remove_dir_all(&image_dir).ok();
+        let payload = payload?;
+
+        assert_eq!(payload["id"], 7);
+        assert_eq!(payload["event"], "post.published");
+        assert_eq!(payload["version"], 1);
+        assert_eq!(payload["tags"][0], "cat");
+        assert_eq!(payload["url"], "http://example.org/p/7");
+        assert_eq!(payload["images"][0], "http://example.org/image/a/abc.jpg");
+        let media = &payload["media"][0];
+        assert_eq!(media["thumbnail_url"],
+                   "http://example.org/image/a/abc_t.jpg");
+        assert_eq!(media["width"], 3);
+        assert_eq!(media["size"], 5);
+        assert_eq!(media["blurhash"], serde_json::Value::Null);
+        assert_eq!(media["sha256"], "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824");
+        Ok(())
+    }
+
+    #[test]
+    fn testPayloadIsAnExample() -> Result<(), Error>
+    {
+        let mut data_manager = data::Manager::new(
+            crate::sqlite_connection::Source::Memory);
+        data_manager.connect()?;
+        data_manager.init()?;
+        let config = Configuration::default();
+        let payload = testPayload(&data_manager, &config)?;
+        assert_eq!(payload["event"], "webhook.test");
+        assert_eq!(payload["desc"], "A test from NSPic");
+        assert!(matches!(sendTest(&data_manager, &config),
+                         Err(Error::HTTPStatus(StatusCode::NOT_FOUND, _))));
+        Ok(())
+    }
+}
@@ -0,0 +1,58 @@
+// WebSub (https://www.w3.org/TR/websub/) publishing. The feed
+// advertises the hub, and the hub is told when the feed changes, so
+// that subscribers don’t have to poll.
+
+use std::time::Duration;
+
+use crate::error::Error;
+use crate::config::Configuration;
+use crate::post::{Post, Visibility};
+use crate::app::absoluteUrl;
+
+const PING_TIMEOUT_SEC: u64 = 10;
+
+/// The URL of the feed, which is the topic at the hub.
+pub fn topicUrl(config: &Configuration) -> String
+{
+    absoluteUrl("feed", "", config)
+}
+
+/// Whether the hub should be told about `post`, which is when there
+/// is a hub and the post is in the feed.
+pub fn wants(post: &Post, config: &Configuration) -> bool
+{
+    config.websub_hub_url.is_some() && post.visibility == Visibility::Public
+}
+
+/// Tell the configured hub that the feed has changed.
+pub fn ping(config: &Configuration) -> Result<(), Error>
+{
+    let hub = config.websub_hub_url.as_ref().ok_or_else(
+        || rterr!("WebSub hub is not configured"))?;
+    let agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(PING_TIMEOUT_SEC)).build();
+    agent.post(hub).send_form(&[("hub.mode", "publish"),
+                                ("hub.url", &topicUrl(config))])
+        .map_err(|e| rterr!("WebSub ping failed: {}", e))?;
+    Ok(())
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn publicPostsArePinged()
+    {
+        let mut config = Configuration::default();
+        let mut post = Post::new();
+        assert!(!wants(&post, &config));
+        config.websub_hub_url = Some(String::from("https://hub.example.org"));
+        assert!(wants(&post, &config));
+        post.visibility = Visibility::Unlisted;
+        assert!(!wants(&post, &config));
+    }
+}
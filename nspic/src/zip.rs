@@ -0,0 +1,381 @@
+// A small reader of ZIP archives, for uploading many images at once,
+// and a writer of the headers of stored entries, for downloading them.
+// Only stored and deflated entries are supported, which is what
+// archivers make by default. ZIP64 and encrypted archives are not.
+
+use std::io::Read;
+
+use time::OffsetDateTime;
+
+use crate::error::Error;
+
+const END_SIGNATURE: u32 = 0x06054b50;
+const CENTRAL_SIGNATURE: u32 = 0x02014b50;
+const LOCAL_SIGNATURE: u32 = 0x04034b50;
+const END_SIZE: usize = 22;
+/// The end of central directory record can be followed by a comment
+/// of at most this size.
+const COMMENT_SIZE_MAX: usize = 0xffff;
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+/// The version needed to extract stored entries, which is 2.0.
+const VERSION: u16 = 20;
+/// The general purpose flag that the name is in UTF-8.
+const FLAG_UTF8: u16 = 1 << 11;
+
+/// Limits of the files in an archive, which are checked while they
+/// are inflated, because a small archive can be inflated to a lot.
+pub struct Limits
+{
+    pub entry_bytes_max: u64,
+    /// The files together.
+    pub total_bytes_max: u64,
+}
+
+fn u16At(data: &[u8], offset: usize) -> Result<u16, Error>
+{
+    data.get(offset..offset + 2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| rterr!("Truncated ZIP archive"))
+}
+
+fn u32At(data: &[u8], offset: usize) -> Result<u32, Error>
+{
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| rterr!("Truncated ZIP archive"))
+}
+
+/// Offset of the end of central directory record.
+fn findEnd(data: &[u8]) -> Result<usize, Error>
+{
+    if data.len() < END_SIZE
+    {
+        return Err(rterr!("Not a ZIP archive"));
+    }
+    let earliest = data.len().saturating_sub(END_SIZE + COMMENT_SIZE_MAX);
+    (earliest..=data.len() - END_SIZE).rev()
+        .find(|&i| u32At(data, i).ok() == Some(END_SIGNATURE))
+        .ok_or_else(|| rterr!("Not a ZIP archive"))
+}
+
+/// Whether an entry is a directory, or something an OS left in the
+/// archive, like `__MACOSX/` or `.DS_Store`.
+fn isSkipped(path: &str) -> bool
+{
+    path.ends_with('/') || path.split('/').any(
+        |part| part.starts_with('.') || part == "__MACOSX")
+}
+
+/// Call `save` with the name without its directory and the content
+/// of each file in the ZIP archive `data`, in the order of the central
+/// directory. The content is inflated while `save` reads it, so that
+/// it doesn’t have to be in memory. A file that breaks `limits` is an
+/// error, also if `save` fails on it. Return the bytes of all the
+/// files.
+pub fn readEntries<F>(data: &[u8], limits: &Limits, mut save: F) ->
+    Result<u64, Error>
+where F: FnMut(&str, &mut dyn Read) -> Result<(), Error>
+{
+    let end = findEnd(data)?;
+    let count = u16At(data, end + 10)?;
+    let mut offset = u32At(data, end + 16)? as usize;
+    let mut total: u64 = 0;
+    for _ in 0..count
+    {
+        if u32At(data, offset)? != CENTRAL_SIGNATURE
+        {
+            return Err(rterr!("Invalid ZIP central directory"));
+        }
+        let flags = u16At(data, offset + 8)?;
+        let method = u16At(data, offset + 10)?;
+        let compressed_size = u32At(data, offset + 20)?;
+        let size = u32At(data, offset + 24)?;
+        let name_len = u16At(data, offset + 28)? as usize;
+        let extra_len = u16At(data, offset + 30)? as usize;
+        let comment_len = u16At(data, offset + 32)? as usize;
+        let local_offset = u32At(data, offset + 42)? as usize;
+        let path = data.get(offset + 46..offset + 46 + name_len).ok_or_else(
+            || rterr!("Truncated ZIP archive"))?;
+        let path = String::from_utf8_lossy(path).into_owned();
+        offset += 46 + name_len + extra_len + comment_len;
+
+        if isSkipped(&path)
+        {
+            continue;
+        }
+        if flags & 1 != 0
+        {
+            return Err(rterr!("{} in the ZIP archive is encrypted", path));
+        }
+        if compressed_size == u32::MAX || size == u32::MAX
+        {
+            return Err(rterr!("ZIP64 archives are not supported"));
+        }
+        let entry_max = limits.entry_bytes_max.min(limits.total_bytes_max - total);
+        let too_large = || if entry_max < limits.entry_bytes_max
+        {
+            rterr!("The files in the ZIP archive are too large together")
+        }
+        else
+        {
+            rterr!("{} in the ZIP archive is too large", path)
+        };
+        if size as u64 > entry_max
+        {
+            return Err(too_large());
+        }
+        if u32At(data, local_offset)? != LOCAL_SIGNATURE
+        {
+            return Err(rterr!("Invalid ZIP local header of {}", path));
+        }
+        let start = local_offset + 30 + u16At(data, local_offset + 26)? as usize
+            + u16At(data, local_offset + 28)? as usize;
+        let compressed = data.get(start..start + compressed_size as usize)
+            .ok_or_else(|| rterr!("Truncated ZIP archive"))?;
+        let content: Box<dyn Read + '_> = match method
+        {
+            METHOD_STORED => Box::new(compressed),
+            METHOD_DEFLATED => Box::new(flate2::read::DeflateDecoder::new(compressed)),
+            _ => return Err(rterr!("Unsupported compression of {} in the ZIP \
+                                    archive", path)),
+        };
+        // Don’t trust the size in the header.
+        let mut content = content.take(entry_max.saturating_add(1));
+        let name = path.rsplit('/').next().unwrap_or_default();
+        let saved = save(name, &mut content);
+        let read = entry_max.saturating_add(1) - content.limit();
+        if read > entry_max
+        {
+            return Err(too_large());
+        }
+        saved?;
+        total += read;
+    }
+    Ok(total)
+}
+
+/// A stored entry of an archive that is being written.
+pub struct EntryHeader
+{
+    pub name: String,
+    pub crc: u32,
+    pub size: u32,
+    /// Where the local header of the entry is in the archive.
+    pub offset: u32,
+    pub modified: OffsetDateTime,
+}
+
+/// The MS-DOS time and date of `t`, which is what ZIP has. Times
+/// before 1980 are 1980-01-01.
+fn dosTime(t: OffsetDateTime) -> (u16, u16)
+{
+    if t.year() < 1980
+    {
+        return (0, (1 << 5) | 1);
+    }
+    let time = ((t.hour() as u16) << 11) | ((t.minute() as u16) << 5) |
+        (t.second() as u16 / 2);
+    let date = (((t.year() - 1980) as u16) << 9) | ((t.month() as u16) << 5) |
+        t.day() as u16;
+    (time, date)
+}
+
+/// The fields that the local and the central header of an entry
+/// share, from the version needed to the extra field length.
+fn commonFields(entry: &EntryHeader) -> Vec<u8>
+{
+    let (time, date) = dosTime(entry.modified);
+    let mut fields = Vec::new();
+    fields.extend(VERSION.to_le_bytes());
+    fields.extend(FLAG_UTF8.to_le_bytes());
+    fields.extend(METHOD_STORED.to_le_bytes());
+    fields.extend(time.to_le_bytes());
+    fields.extend(date.to_le_bytes());
+    fields.extend(entry.crc.to_le_bytes());
+    fields.extend(entry.size.to_le_bytes());
+    fields.extend(entry.size.to_le_bytes());
+    fields.extend((entry.name.len() as u16).to_le_bytes());
+    fields.extend(0u16.to_le_bytes());
+    fields
+}
+
+/// The local header of an entry, which goes right before its data.
+pub fn localHeader(entry: &EntryHeader) -> Vec<u8>
+{
+    let mut header = LOCAL_SIGNATURE.to_le_bytes().to_vec();
+    header.extend(commonFields(entry));
+    header.extend(entry.name.as_bytes());
+    header
+}
+
+/// The central directory and the end record of an archive of
+/// `entries`, which goes at `offset`, after all the entries.
+pub fn centralDirectory(entries: &[EntryHeader], offset: u32) -> Vec<u8>
+{
+    let mut central = Vec::new();
+    for entry in entries
+    {
+        central.extend(CENTRAL_SIGNATURE.to_le_bytes());
+        // Made by version 2.0 on MS-DOS.
+        central.extend(VERSION.to_le_bytes());
+        central.extend(commonFields(entry));
+        // Comment length, disk, and attributes.
+        central.extend([0; 10]);
+        central.extend(entry.offset.to_le_bytes());
+        central.extend(entry.name.as_bytes());
+    }
+    let mut end = END_SIGNATURE.to_le_bytes().to_vec();
+    end.extend([0; 4]);
+    end.extend((entries.len() as u16).to_le_bytes());
+    end.extend((entries.len() as u16).to_le_bytes());
+    end.extend((central.len() as u32).to_le_bytes());
+    end.extend(offset.to_le_bytes());
+    end.extend(0u16.to_le_bytes());
+    central.extend(end);
+    central
+}
+
+// ========== Unit tests ============================================>
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::io::Write;
+
+    /// A ZIP archive of `files`, which are deflated if `deflate` is
+    /// true.
+    fn makeZip(files: &[(&str, &[u8])], deflate: bool) -> Vec<u8>
+    {
+        let mut data = Vec::new();
+        let mut central = Vec::new();
+        for (name, content) in files
+        {
+            let compressed = if deflate
+            {
+                let mut encoder = flate2::write::DeflateEncoder::new(
+                    Vec::new(), flate2::Compression::default());
+                encoder.write_all(content).unwrap();
+                encoder.finish().unwrap()
+            }
+            else
+            {
+                content.to_vec()
+            };
+            let method = if deflate { METHOD_DEFLATED } else { METHOD_STORED };
+            let mut fields = Vec::new();
+            fields.extend(method.to_le_bytes());
+            fields.extend([0; 8]); // Time, date, and CRC.
+            fields.extend((compressed.len() as u32).to_le_bytes());
+            fields.extend((content.len() as u32).to_le_bytes());
+            fields.extend((name.len() as u16).to_le_bytes());
+            fields.extend(0u16.to_le_bytes());
+
+            central.extend(CENTRAL_SIGNATURE.to_le_bytes());
+            central.extend([20, 0, 20, 0, 0, 0]);
+            central.extend(&fields);
+            // Comment length, disk, and attributes.
+            central.extend([0; 10]);
+            central.extend((data.len() as u32).to_le_bytes());
+            central.extend(name.as_bytes());
+
+            data.extend(LOCAL_SIGNATURE.to_le_bytes());
+            data.extend([20, 0, 0, 0]);
+            data.extend(&fields);
+            data.extend(name.as_bytes());
+            data.extend(&compressed);
+        }
+        let central_offset = data.len() as u32;
+        data.extend(&central);
+        data.extend(END_SIGNATURE.to_le_bytes());
+        data.extend([0; 4]);
+        data.extend((files.len() as u16).to_le_bytes());
+        data.extend((files.len() as u16).to_le_bytes());
+        data.extend((central.len() as u32).to_le_bytes());
+        data.extend(central_offset.to_le_bytes());
+        data.extend(0u16.to_le_bytes());
+        data
+    }
+
+    /// The names and contents of the files in `data`.
+    fn entries(data: &[u8], entry_bytes_max: u64, total_bytes_max: u64) ->
+        Result<Vec<(String, Vec<u8>)>, Error>
+    {
+        let limits = Limits { entry_bytes_max, total_bytes_max };
+        let mut result = Vec::new();
+        readEntries(data, &limits, |name, content| {
+            let mut data = Vec::new();
+            content.read_to_end(&mut data).map_err(|e| rterr!("{}", e))?;
+            result.push((name.to_owned(), data));
+            Ok(())
+        })?;
+        Ok(result)
+    }
+
+    #[test]
+    fn entriesAreRead() -> Result<(), Error>
+    {
+        let files: &[(&str, &[u8])] = &[
+            ("photos/", b""), ("photos/a.jpg", b"hello hello hello"),
+            ("__MACOSX/photos/._a.jpg", b"junk"), ("b.png", b"world")];
+        for deflate in [false, true]
+        {
+            let zip = makeZip(files, deflate);
+            let result = entries(&zip, 100, 100)?;
+            assert_eq!(result.len(), 2);
+            assert_eq!(result[0].0, "a.jpg");
+            assert_eq!(result[0].1, b"hello hello hello");
+            assert_eq!(result[1].0, "b.png");
+            assert!(entries(&zip, 10, 100).is_err());
+            // 17 and 5 bytes.
+            assert!(entries(&zip, 100, 22).is_ok());
+            assert!(entries(&zip, 100, 21).is_err());
+        }
+        assert!(entries(b"not a zip", 100, 100).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn sizesInHeadersAreNotTrusted() -> Result<(), Error>
+    {
+        let mut zip = makeZip(&[("a.jpg", &[0u8; 1000])], true);
+        // Claim that the file is 10 bytes in the central directory.
+        let central = zip.len() - 22 - 46 - 5;
+        zip[central + 24..central + 28].copy_from_slice(&10u32.to_le_bytes());
+        assert!(entries(&zip, 100, 1000).is_err());
+        assert_eq!(entries(&zip, 1000, 1000)?[0].1.len(), 1000);
+        Ok(())
+    }
+
+    #[test]
+    fn writtenArchiveIsRead() -> Result<(), Error>
+    {
+        let mut data = Vec::new();
+        let mut headers = Vec::new();
+        for (name, content) in [("1-1.jpg", &b"hello"[..]), ("1-2.jpg", b"world")]
+        {
+            let mut crc = flate2::Crc::new();
+            crc.update(content);
+            let header = EntryHeader {
+                name: name.to_owned(),
+                crc: crc.sum(),
+                size: content.len() as u32,
+                offset: data.len() as u32,
+                modified: OffsetDateTime::UNIX_EPOCH,
+            };
+            data.extend(localHeader(&header));
+            data.extend(content);
+            headers.push(header);
+        }
+        let offset = data.len() as u32;
+        data.extend(centralDirectory(&headers, offset));
+        let result = entries(&data, 100, 100)?;
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].0, "1-1.jpg");
+        assert_eq!(result[1].1, b"world");
+        // 2024-05-06 07:08:09 UTC
+        let t = OffsetDateTime::from_unix_timestamp(1714979289).unwrap();
+        assert_eq!(dosTime(t), ((7 << 11) | (8 << 5) | 4, (44 << 9) | (5 << 5) | 6));
+        Ok(())
+    }
+}
@@ -1,6 +1,9 @@
 const AllIndicatorLists = Array.from(document.querySelectorAll("ul.ScrollIndicators"));
 
-AllIndicatorLists.forEach(function(indi_list) {
+// Light up the indicator of the image in view as the images of a
+// post scroll. This is also called on posts loaded by scroll.js.
+function watchScrollIndicators(indi_list)
+{
     const Post = indi_list.parentElement;
     const ImageList = Post.querySelector("ul.ImageList");
     const Images = Array.from(Post.querySelectorAll("ul.ImageList > li"));
@@ -33,4 +36,29 @@ AllIndicatorLists.forEach(function(indi_list) {
     Images.forEach(item => {
         Observer.observe(item);
     });
-});
+}
+
+AllIndicatorLists.forEach(watchScrollIndicators);
+
+// Show the average color of each image until it loads. The color is
+// in an attribute, because the CSP blocks inline styles. This is also
+// called on posts loaded by scroll.js.
+function fillImageColors(root)
+{
+    root.querySelectorAll("img[data-color]").forEach(img => {
+        img.style.backgroundColor = img.dataset.color;
+    });
+}
+
+fillImageColors(document);
+
+// Show the images of a sensitive post on the first click. The click
+// is caught on the document, so that this also works for the posts
+// loaded by scroll.js.
+document.addEventListener("click", event => {
+    const List = event.target.closest("ul.ImageList.Sensitive");
+    if (List !== null) {
+        event.preventDefault();
+        List.classList.remove("Sensitive");
+    }
+}, true);
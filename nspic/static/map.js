@@ -0,0 +1,89 @@
+// Draw the located posts on a Web Mercator map, at the largest zoom
+// that shows all of them.
+const TILE_SIZE = 256;
+const ZOOM_MAX = 16;
+
+// Pixel coordinates of a point at a zoom level.
+function project(coordinates, zoom)
+{
+    let scale = TILE_SIZE * Math.pow(2, zoom);
+    let sin = Math.sin(coordinates[1] * Math.PI / 180);
+    sin = Math.min(Math.max(sin, -0.9999), 0.9999);
+    return [(coordinates[0] + 180) / 360 * scale,
+            (0.5 - Math.log((1 + sin) / (1 - sin)) / (4 * Math.PI)) * scale];
+}
+
+function bounds(points)
+{
+    let xs = points.map(p => p[0]);
+    let ys = points.map(p => p[1]);
+    return [Math.min(...xs), Math.min(...ys), Math.max(...xs), Math.max(...ys)];
+}
+
+function drawTiles(map, template, zoom, left, top)
+{
+    let count = Math.pow(2, zoom);
+    for(let y = Math.floor(top / TILE_SIZE);
+        y * TILE_SIZE < top + map.clientHeight; y++)
+    {
+        if(y < 0 || y >= count)
+        {
+            continue;
+        }
+        for(let x = Math.floor(left / TILE_SIZE);
+            x * TILE_SIZE < left + map.clientWidth; x++)
+        {
+            let tile = document.createElement('img');
+            tile.className = 'MapTile';
+            tile.alt = '';
+            tile.src = template.replace('{z}', zoom)
+                .replace('{x}', ((x % count) + count) % count)
+                .replace('{y}', y);
+            tile.style.left = (x * TILE_SIZE - left) + 'px';
+            tile.style.top = (y * TILE_SIZE - top) + 'px';
+            map.appendChild(tile);
+        }
+    }
+}
+
+function drawMap(map, features)
+{
+    if(features.length === 0)
+    {
+        return;
+    }
+    let width = map.clientWidth;
+    let height = map.clientHeight;
+    let zoom = ZOOM_MAX;
+    let points = features.map(f => project(f.geometry.coordinates, zoom));
+    let box = bounds(points);
+    // Leave some room around the markers.
+    while(zoom > 0 && (box[2] - box[0] > width * 0.8 ||
+                       box[3] - box[1] > height * 0.8))
+    {
+        zoom--;
+        points = features.map(f => project(f.geometry.coordinates, zoom));
+        box = bounds(points);
+    }
+    let left = (box[0] + box[2] - width) / 2;
+    let top = (box[1] + box[3] - height) / 2;
+    if(map.dataset.tiles)
+    {
+        drawTiles(map, map.dataset.tiles, zoom, left, top);
+    }
+    features.forEach((feature, i) => {
+        let marker = document.createElement('a');
+        marker.className = 'MapMarker';
+        marker.href = feature.properties.url;
+        marker.title = feature.properties.place || feature.properties.desc;
+        marker.style.left = (points[i][0] - left) + 'px';
+        marker.style.top = (points[i][1] - top) + 'px';
+        map.appendChild(marker);
+    });
+}
+
+window.addEventListener("DOMContentLoaded", function() {
+    let map = document.getElementById("Map");
+    fetch(map.dataset.geojson).then(response => response.json())
+        .then(collection => drawMap(map, collection.features));
+});
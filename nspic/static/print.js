@@ -0,0 +1,5 @@
+window.addEventListener("DOMContentLoaded", function() {
+    document.getElementById("PrintButton").addEventListener("click", function() {
+        window.print();
+    });
+});
@@ -0,0 +1,59 @@
+// Load the next posts of the index when the visitor scrolls to the
+// bottom, instead of going to the next page. The pagination stays as
+// the fallback without JavaScript.
+window.addEventListener("DOMContentLoaded", function() {
+    const List = document.querySelector("ul.PostList");
+    const Pagination = document.getElementById("Pagination");
+    if(!List.dataset.more)
+    {
+        return;
+    }
+    let start = parseInt(List.dataset.start);
+    let loading = false;
+    // Still laid out, so that it can be observed.
+    Pagination.style.visibility = "hidden";
+
+    const Observer = new IntersectionObserver(entries => {
+        if(!entries[0].isIntersecting || loading)
+        {
+            return;
+        }
+        loading = true;
+        fetch(List.dataset.more + "&start=" + start)
+            .then(response => {
+                if(!response.ok)
+                {
+                    throw new Error(response.statusText);
+                }
+                return response.json();
+            }).then(result => {
+                let template = document.createElement("template");
+                template.innerHTML = result.html;
+                let items = Array.from(template.content.children);
+                items.forEach(item => {
+                    List.appendChild(item);
+                    item.querySelectorAll("ul.ScrollIndicators")
+                        .forEach(watchScrollIndicators);
+                    fillImageColors(item);
+                });
+                start += items.length;
+                loading = false;
+                if(items.length === 0 || start >= result.total)
+                {
+                    Observer.disconnect();
+                }
+                else
+                {
+                    // Observing again checks whether the bottom is
+                    // still in view, on a tall window.
+                    Observer.unobserve(Pagination);
+                    Observer.observe(Pagination);
+                }
+            }).catch(() => {
+                // Let the visitor page normally from here.
+                Observer.disconnect();
+                Pagination.style.visibility = "";
+            });
+    }, {rootMargin: "0px 0px 400px 0px"});
+    Observer.observe(Pagination);
+});
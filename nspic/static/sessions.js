@@ -0,0 +1,20 @@
+// Delete all sessions, and leave the page, which needs a session.
+function logOutEverywhere(e)
+{
+    let button = e.target;
+    fetch(button.dataset.url, { method: "DELETE" }).then(function(response) {
+        if(response.ok)
+        {
+            window.location.href = button.dataset.next;
+        }
+        else
+        {
+            alert("Failed to log out: " + response.status);
+        }
+    });
+}
+
+window.addEventListener("DOMContentLoaded", function() {
+    document.getElementById("LogOutEverywhere")
+        .addEventListener("click", logOutEverywhere);
+});
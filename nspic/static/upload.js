@@ -11,12 +11,48 @@ function postFile()
 {
     var formdata = new FormData();
     formdata.append('Desc', document.getElementById('Desc').value);
+    formdata.append('Slug', document.getElementById('Slug').value);
+    formdata.append('Tags', document.getElementById('Tags').value);
+    formdata.append('Visibility', document.getElementById('Visibility').value);
+    formdata.append('Latitude', document.getElementById('Latitude').value);
+    formdata.append('Longitude', document.getElementById('Longitude').value);
+    formdata.append('Split', document.getElementById('Split').checked);
+    formdata.append('Queue', document.getElementById('Queue').checked);
+    formdata.append('Sensitive', document.getElementById('Sensitive').checked);
+    formdata.append('License', document.getElementById('License').value);
+    let snippet = document.getElementById('Snippet');
+    if(snippet !== null)
+    {
+        formdata.append('Snippet', snippet.value);
+    }
+    // The input has no time zone, so it is the local time of the
+    // browser.
+    let publish_at = document.getElementById('PublishAt').value;
+    if(publish_at !== "")
+    {
+        formdata.append('PublishAt',
+                        Math.floor(new Date(publish_at).getTime() / 1000));
+    }
     let files_control = document.getElementById('FilesToUpload');
     let total_size = 0;
     for(let i = 0; i < files_control.files.length; i++)
     {
-        formdata.append('FileToUpload', files_control.files[i]);
-        total_size += files_control.files[i].size;
+        let file = files_control.files[i];
+        formdata.append(isZip(file) ? 'ZipToUpload' : 'FileToUpload', file);
+        total_size += file.size;
+        for(const kind of ['Alt-', 'Caption-'])
+        {
+            let input = document.getElementById(kind + i);
+            if(input !== null && input.value !== "")
+            {
+                formdata.append(kind + i, input.value);
+            }
+        }
+    }
+    let url_to_upload = document.getElementById('UrlToUpload').value.trim();
+    if(url_to_upload !== "")
+    {
+        formdata.append('UrlToUpload', url_to_upload);
     }
     var request = new XMLHttpRequest();
 
@@ -34,10 +70,72 @@ function postFile()
         }
     });
     request.addEventListener("load", function() {
-        window.location.href = serve_prefix + "/";
+        if(request.status >= 400)
+        {
+            let message = "Upload failed: " + request.status;
+            try
+            {
+                message = JSON.parse(request.responseText).error.message;
+            }
+            catch(e) {}
+            alert(message);
+            return;
+        }
+        let url = serve_prefix + "/";
+        try
+        {
+            url = JSON.parse(request.responseText).url;
+        }
+        catch(e) {}
+        window.location.href = url;
     });
 
     request.open('post', serve_prefix + '/upload/');
+    request.setRequestHeader('Accept', 'application/json');
     request.timeout = 3600000;
     request.send(formdata);
 }
+
+function textInput(id, placeholder)
+{
+    let input = document.createElement('input');
+    input.type = 'text';
+    input.id = id;
+    input.autocomplete = 'off';
+    input.placeholder = placeholder;
+    return input;
+}
+
+function isZip(file)
+{
+    return file.name.toLowerCase().endsWith('.zip');
+}
+
+// Show an alt text input and a caption input for each chosen file.
+// The images in a ZIP archive are not known here, so there are no
+// inputs if there is one.
+function showImageTextInputs()
+{
+    let container = document.getElementById('ImageTexts');
+    container.replaceChildren();
+    let files = document.getElementById('FilesToUpload').files;
+    if(Array.from(files).some(isZip))
+    {
+        return;
+    }
+    for(let i = 0; i < files.length; i++)
+    {
+        let div = document.createElement('div');
+        div.appendChild(textInput('Alt-' + i, 'Alt text of ' + files[i].name));
+        div.appendChild(textInput('Caption-' + i,
+                                  'Caption of ' + files[i].name));
+        container.appendChild(div);
+    }
+}
+
+// Inline handlers are not allowed by the Content-Security-Policy.
+window.addEventListener("DOMContentLoaded", function() {
+    document.getElementById("PostButton").addEventListener("click", postFile);
+    document.getElementById("FilesToUpload")
+        .addEventListener("change", showImageTextInputs);
+});